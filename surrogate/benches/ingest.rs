@@ -0,0 +1,217 @@
+//! Drives a fixed synthetic batch of change events through decoding and the
+//! Postgres writer, reporting throughput and write latency, so changes to the
+//! ingest path can be compared before and after.
+//!
+//! Runs against the database `VE_POSTGRES_CONFIG` points at, which it
+//! migrates, so use a scratch one:
+//!
+//!     VE_POSTGRES_CONFIG="host=localhost user=postgres dbname=bench" cargo bench --bench ingest
+//!
+//! `BENCH_EVENTS` sets the number of events, 10000 by default, and
+//! `BENCH_CYCLE` how many are committed together, 100 by default. Every
+//! article goes through a create, two updates and a delete, and the rows are
+//! gone again once it's done. The writer's sentinel is kept under its own
+//! `avs_id`, leaving a real one alone.
+//!
+//! Setting `BENCH_JSON_DETOUR` puts every entity through JSON and back on its
+//! way to the writer, what changes cost before they carried a typed
+//! `Entity`, so the CPU that saves shows in the decode time reported.
+
+use parity_scale_codec::Encode;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+use surrogate::config::Config;
+use surrogate::db::{self, fixture, Change, Entity, Message};
+use surrogate::model::Model;
+use surrogate::rpc::{self, ChangeEvent};
+use vemodel::{Method, SubspaceId, VeArticle, VeSubspace, PREFIX_ARTICLE_KEY, PREFIX_SUBSPACE_KEY};
+
+// a dev account, not one a real nucleus would serve
+const AVS_ID: &str = "5FHneW46xGXgs5mUiveU4sbTyGBzmstUspZC92UhjJM694ty";
+// clear of the ids a real nucleus hands out
+const FIRST_ID: u64 = 1 << 40;
+const METHODS: [Method; 4] = [
+    Method::Create,
+    Method::Update,
+    Method::Update,
+    Method::Delete,
+];
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let events: u64 = env_or("BENCH_EVENTS", 10_000)?;
+    let cycle: u64 = env_or("BENCH_CYCLE", 100)?.max(1);
+    let json_detour = std::env::var_os("BENCH_JSON_DETOUR").is_some();
+    let config = Config {
+        avs_id: AVS_ID.parse()?,
+        ..Config::for_database_tests()
+    };
+
+    let mut client = db::connect(&config).await?;
+    db::setup_database(&mut client, &config, true).await?;
+    let (tx, rx) = mpsc::channel(100);
+    let writer = tokio::spawn(db::run_writer(client, config.clone(), 0, rx));
+
+    let subspace = VeSubspace {
+        title: "Bench".to_string(),
+        slug: "bench".to_string(),
+        ..fixture::subspace(FIRST_ID)
+    };
+    let key = [&PREFIX_SUBSPACE_KEY[..], &FIRST_ID.to_be_bytes()].concat();
+    let event = ChangeEvent {
+        reqnum: 0,
+        method: Method::Create,
+        key,
+        source_time: None,
+    };
+    apply(
+        &tx,
+        Change::new(
+            &event,
+            Method::Create,
+            Entity::Subspace(subspace),
+            "bench-subspace",
+        ),
+    )
+    .await?;
+
+    let mut latencies = Vec::with_capacity(events as usize);
+    let mut decoding = Duration::ZERO;
+    let started = Instant::now();
+    for first in (1..=events).step_by(cycle as usize) {
+        let last = (first + cycle - 1).min(events);
+        // what the nucleus would answer to the poll, hex-encoded SCALE
+        let page: Vec<(u64, Method, Vec<u8>)> = (first..=last)
+            .map(|reqnum| {
+                let key = [&PREFIX_ARTICLE_KEY[..], &article_id(reqnum).to_be_bytes()].concat();
+                (reqnum, method(reqnum), key)
+            })
+            .collect();
+        let response = serde_json::json!(hex::encode(Ok::<_, String>(page).encode()));
+        for event in rpc::decode_events(&response)??.events {
+            let decoded = Instant::now();
+            let mut change = change(&event)?;
+            // deletes carry only an id, there was never any JSON to them
+            if json_detour && !matches!(change.entity, Entity::Deleted(..)) {
+                let value = change.entity.to_json()?;
+                change.entity = Entity::from_json(change.entity.model(), value)?;
+            }
+            decoding += decoded.elapsed();
+            let sent = Instant::now();
+            apply(&tx, change).await?;
+            latencies.push(sent.elapsed());
+        }
+        let (ack, checkpointed) = oneshot::channel();
+        tx.send(Message::Checkpoint {
+            sentinel: last,
+            ack,
+        })
+        .await?;
+        checkpointed.await?;
+    }
+    let elapsed = started.elapsed();
+
+    let event = ChangeEvent {
+        reqnum: events + 1,
+        ..event
+    };
+    apply(
+        &tx,
+        Change::new(
+            &event,
+            Method::Delete,
+            Entity::Deleted(Model::Subspace, FIRST_ID),
+            "bench-subspace",
+        ),
+    )
+    .await?;
+    let (ack, checkpointed) = oneshot::channel();
+    tx.send(Message::Checkpoint {
+        sentinel: events + 1,
+        ack,
+    })
+    .await?;
+    checkpointed.await?;
+    drop(tx);
+    writer.await?;
+
+    latencies.sort();
+    println!(
+        "{} events in {:.2?}: {:.0} events/sec, decoding {:.2?}{}, write latency p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+        events,
+        elapsed,
+        events as f64 / elapsed.as_secs_f64(),
+        decoding,
+        if json_detour { " (through JSON)" } else { "" },
+        percentile(&latencies, 50),
+        percentile(&latencies, 99),
+        latencies.last().copied().unwrap_or_default(),
+    );
+    Ok(())
+}
+
+// Four events per article, in the order of `METHODS`.
+fn article_id(reqnum: u64) -> u64 {
+    FIRST_ID + (reqnum - 1) / METHODS.len() as u64
+}
+
+fn method(reqnum: u64) -> Method {
+    METHODS[((reqnum - 1) % METHODS.len() as u64) as usize]
+}
+
+// The change the polling loop would make of `event`, decoding the entity the
+// nucleus would have answered with.
+fn change(event: &ChangeEvent) -> Result<Change, Box<dyn std::error::Error>> {
+    let id = article_id(event.reqnum);
+    let correlation_id = format!("{}-article{}", event.reqnum, id);
+    if event.method == Method::Delete {
+        let entity = Entity::Deleted(Model::Article, id);
+        return Ok(Change::new(event, Method::Delete, entity, &correlation_id));
+    }
+    let article = VeArticle {
+        title: format!("Article {}", id),
+        content: "Lorem ipsum dolor sit amet. ".repeat(40),
+        author_nickname: "bench".to_string(),
+        subspace_id: SubspaceId(FIRST_ID),
+        updated_time: event.reqnum as i64,
+        ..fixture::article(id)
+    };
+    let response = serde_json::json!(hex::encode(Ok::<_, String>(Some(article)).encode()));
+    let article = rpc::decode_response::<Result<Option<VeArticle>, String>>(&response)??
+        .ok_or("article missing")?;
+    Ok(Change::new(
+        event,
+        event.method,
+        Entity::Article(article),
+        &correlation_id,
+    ))
+}
+
+// Sends `change` and waits for the writer to have applied it.
+async fn apply(
+    tx: &mpsc::Sender<Message>,
+    change: Change,
+) -> Result<(), Box<dyn std::error::Error>> {
+    tx.send(Message::Change(change)).await?;
+    let (ack, failed) = oneshot::channel();
+    tx.send(Message::Flush(ack)).await?;
+    if let Some((reqnum, error)) = failed.await?.pop() {
+        return Err(format!("event {} failed: {}", reqnum, error).into());
+    }
+    Ok(())
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[(sorted.len() * p / 100).min(sorted.len() - 1)]
+}
+
+fn env_or(key: &str, default: u64) -> Result<u64, String> {
+    match std::env::var(key) {
+        Ok(value) => value.parse().map_err(|e| format!("{}: {}", key, e)),
+        Err(_) => Ok(default),
+    }
+}
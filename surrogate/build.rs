@@ -0,0 +1,18 @@
+use std::process::Command;
+
+// Records `git describe` of the tree being built for `build_info`, or
+// "unknown" when it isn't a git checkout, e.g. built from a source tarball.
+fn main() {
+    let describe = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--tags"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|describe| describe.trim().to_string())
+        .filter(|describe| !describe.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=SURROGATE_GIT_DESCRIBE={}", describe);
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+}
@@ -0,0 +1,21 @@
+//! Feeds arbitrary bytes to the entity decoders, as the nucleus answers
+//! `get_subspace`, `get_article` and `get_comment`, which must turn them into
+//! an entity or an error, never panic:
+//!
+//!     cargo +nightly fuzz run entities
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use surrogate::rpc;
+use vemodel::{VeArticle, VeComment, VeSubspace};
+
+type Response<T> = Result<Option<T>, String>;
+
+fuzz_target!(|raw: &[u8]| {
+    let value = serde_json::Value::String(hex::encode(raw));
+    let _ = rpc::decode_response::<Response<VeSubspace>>(&value);
+    let _ = rpc::decode_response::<Response<VeArticle>>(&value);
+    let _ = rpc::decode_response::<Response<VeComment>>(&value);
+});
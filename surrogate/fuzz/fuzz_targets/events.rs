@@ -0,0 +1,22 @@
+//! Feeds arbitrary `get_from_common_key` results to the event decoders, as
+//! SCALE bytes and, when they're JSON, as the JSON codec has them. A batch
+//! either decodes or fails, it never panics:
+//!
+//!     cargo +nightly fuzz run events
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use surrogate::rpc;
+
+fuzz_target!(|raw: &[u8]| {
+    let value = serde_json::Value::String(hex::encode(raw));
+    if let Ok(Ok(batch)) = rpc::decode_events(&value) {
+        // every undecodable entry keeps its reqnum in the batch
+        assert!(batch.undecodable.len() <= batch.events.len());
+    }
+    if let Ok(value) = serde_json::from_slice::<serde_json::Value>(raw) {
+        let _ = rpc::decode_json_events(&value);
+    }
+});
@@ -0,0 +1,25 @@
+//! Feeds arbitrary storage keys to the key decoders, which must read every
+//! one as an id or as none, never panic:
+//!
+//!     cargo +nightly fuzz run key
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use surrogate::key::{split_key, vec_to_u64, Prefix, BIG_ENDIAN, SCALE_COMPACT};
+
+fuzz_target!(|key: &[u8]| {
+    split_key(key);
+    vec_to_u64(key);
+    if let Some(prefix) = Prefix::of(key) {
+        if let Some(fields) = prefix.decode(key) {
+            assert_eq!(prefix.encode(&fields).as_deref(), Some(key));
+        }
+    }
+    for codec in [BIG_ENDIAN, SCALE_COMPACT] {
+        if let Some(id) = codec.decode(key) {
+            assert_eq!(codec.decode(&(codec.encode)(id)), Some(id));
+        }
+    }
+});
@@ -0,0 +1,100 @@
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// The SS58 address of the AVS, as `VE_AVS_ID` has it, checked to be one
+/// when the config is read rather than on the first call to the nucleus.
+///
+/// The address has to decode to a network prefix and a 32-byte account id
+/// followed by the two checksum bytes. The checksum itself isn't checked,
+/// that would take a blake2 dependency for a typo the length nearly always
+/// catches already.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AvsId(String);
+
+impl AvsId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for AvsId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for AvsId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = base58_decode(s)
+            .map_err(|c| format!("{:?} is not an SS58 address, {:?} isn't base58", s, c))?;
+        // prefixes below 64 take one byte, the others two
+        let well_formed = match bytes.len() {
+            35 => bytes[0] < 64,
+            36 => (64..128).contains(&bytes[0]),
+            _ => false,
+        };
+        if !well_formed {
+            return Err(format!(
+                "{:?} is not an SS58 address of a 32-byte account id",
+                s
+            ));
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+// Big-endian, leading '1's standing for zero bytes. Fails with the first
+// character outside the alphabet.
+fn base58_decode(s: &str) -> Result<Vec<u8>, char> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in s.chars() {
+        let digit = BASE58_ALPHABET.iter().position(|&a| a as char == c);
+        let mut carry = digit.ok_or(c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += *byte as u32 * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    bytes.extend(s.chars().take_while(|&c| c == '1').map(|_| 0));
+    bytes.reverse();
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_ss58_addresses_only() {
+        let id: AvsId = "5FsXfPrUDqq6abYccExCTUxyzjYaaYTr5utLx2wwdBv1m8R8"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            id.as_str(),
+            "5FsXfPrUDqq6abYccExCTUxyzjYaaYTr5utLx2wwdBv1m8R8"
+        );
+
+        // a character short, one too many, and a mistyped 0 for O
+        assert!("5FsXfPrUDqq6abYccExCTUxyzjYaaYTr5utLx2wwdBv1m8R"
+            .parse::<AvsId>()
+            .is_err());
+        assert!("5FsXfPrUDqq6abYccExCTUxyzjYaaYTr5utLx2wwdBv1m8R8R"
+            .parse::<AvsId>()
+            .is_err());
+        let err = "5FsXfPrUDqq6abYccExCTUxyzjYaaYTr5utLx2wwdBv1m0R8"
+            .parse::<AvsId>()
+            .unwrap_err();
+        assert!(err.contains("'0'"), "{}", err);
+        assert!("".parse::<AvsId>().is_err());
+    }
+}
@@ -0,0 +1,393 @@
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::dead_letter::unix_now;
+use crate::{build_info, metrics};
+
+// alerts waiting to be posted, any past these are dropped
+const QUEUE: usize = 64;
+
+// how long posting one may take, connecting included
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// of the webhook's answer, only the status line is read
+const MAX_RESPONSE: usize = 1024;
+
+static ALERTER: OnceLock<Alerter> = OnceLock::new();
+
+/// What an alert is about. Each is throttled apart from the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Condition {
+    /// Polls the nucleus failed, `VE_ALERT_AFTER_FAILURES` in a row.
+    NucleusFailures,
+    /// Commits Postgres failed, `VE_ALERT_AFTER_FAILURES` in a row, or a
+    /// lost connection to it.
+    DatabaseFailures,
+    /// A burst of entities that failed to decode, see `DecodeAlarm`.
+    DecodeFailures,
+    /// A committed sentinel that hasn't moved for `VE_ALERT_STALE_SECS`
+    /// with the nucleus ahead of it.
+    StaleSentinel,
+    /// The surrogate stopping on an error.
+    Stopped,
+}
+
+impl Condition {
+    /// The name an alert gives for it.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NucleusFailures => "nucleus_failures",
+            Self::DatabaseFailures => "database_failures",
+            Self::DecodeFailures => "decode_failures",
+            Self::StaleSentinel => "stale_sentinel",
+            Self::Stopped => "stopped",
+        }
+    }
+}
+
+/// Where alerts are posted, an `http://<host>[:<port>]/<path>` URL of
+/// `VE_ALERT_WEBHOOK_URL`. There's no TLS, an `https://` webhook needs a
+/// relay in front of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl FromStr for Webhook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("expected an http:// URL, got {}", s))?;
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|e| format!("{}: {}", port, e))?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("no host in {}", s));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: if path.is_empty() { "/" } else { path }.to_string(),
+        })
+    }
+}
+
+// Without the path, which often holds the webhook's token.
+impl Serialize for Webhook {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("http://{}:{}/***", self.host, self.port))
+    }
+}
+
+// What's posted, e.g. `{"condition":"stale_sentinel","avs_id":"...",
+// "message":"...","suppressed":2,"time":1700000000,"build":"..."}`.
+#[derive(Serialize)]
+struct Alert<'a> {
+    condition: &'static str,
+    avs_id: &'a str,
+    message: &'a str,
+    /// Alerts of the condition held back by the throttle since the last one.
+    suppressed: u64,
+    time: i64,
+    build: String,
+}
+
+struct Alerter {
+    webhook: Webhook,
+    avs_id: String,
+    after_failures: u32,
+    tx: mpsc::Sender<(Condition, String)>,
+    // failures of each condition in a row so far
+    streaks: Mutex<HashMap<Condition, u32>>,
+}
+
+/// Starts posting alerts to `VE_ALERT_WEBHOOK_URL`, when there's one, at
+/// most one per condition every `VE_ALERT_INTERVAL_SECS`. Until then, or
+/// without one, alerts go nowhere.
+pub fn spawn(config: &Config) {
+    let Some(webhook) = &config.alert_webhook else {
+        return;
+    };
+    let (tx, rx) = mpsc::channel(QUEUE);
+    let alerter = Alerter {
+        webhook: webhook.clone(),
+        avs_id: config.avs_id.as_str().to_string(),
+        after_failures: config.alert_after_failures,
+        tx,
+        streaks: Mutex::new(HashMap::new()),
+    };
+    if ALERTER.set(alerter).is_ok() {
+        info!("Posting alerts to {}:{}", webhook.host, webhook.port);
+        tokio::spawn(deliver(rx, config.alert_interval));
+    }
+}
+
+/// Alerts of `condition` right away, throttled like every alert.
+pub fn raise(condition: Condition, message: String) {
+    let Some(alerter) = ALERTER.get() else {
+        return;
+    };
+    // the webhook falling behind must never hold ingest up
+    if alerter.tx.try_send((condition, message)).is_err() {
+        metrics::ALERT_FAILURES.inc();
+    }
+}
+
+/// Counts a failure of `condition`, alerting once it's failed
+/// `VE_ALERT_AFTER_FAILURES` times in a row.
+pub fn failed(condition: Condition, message: String) {
+    let Some(alerter) = ALERTER.get() else {
+        return;
+    };
+    let streak = {
+        let mut streaks = alerter.streaks.lock().unwrap();
+        let streak = streaks.entry(condition).or_default();
+        *streak += 1;
+        *streak
+    };
+    if streak >= alerter.after_failures {
+        raise(
+            condition,
+            format!("failed {} times in a row, last: {}", streak, message),
+        );
+    }
+}
+
+/// Ends the streak of failures of `condition`.
+pub fn recovered(condition: Condition) {
+    if let Some(alerter) = ALERTER.get() {
+        alerter.streaks.lock().unwrap().remove(&condition);
+    }
+}
+
+/// Alerts that the surrogate is stopping on `error`, waiting for the alert
+/// to be posted, as the process is about to exit.
+pub async fn stopped(error: String) {
+    let Some(alerter) = ALERTER.get() else {
+        return;
+    };
+    send(alerter, Condition::Stopped, &error, 0).await;
+}
+
+async fn deliver(mut rx: mpsc::Receiver<(Condition, String)>, interval: Duration) {
+    let mut throttle = Throttle::default();
+    while let Some((condition, message)) = rx.recv().await {
+        let Some(suppressed) = throttle.admit(condition, Instant::now(), interval) else {
+            continue;
+        };
+        if let Some(alerter) = ALERTER.get() {
+            send(alerter, condition, &message, suppressed).await;
+        }
+    }
+}
+
+async fn send(alerter: &Alerter, condition: Condition, message: &str, suppressed: u64) {
+    let alert = Alert {
+        condition: condition.as_str(),
+        avs_id: &alerter.avs_id,
+        message,
+        suppressed,
+        time: unix_now(),
+        build: build_info::summary(),
+    };
+    let body = match serde_json::to_string(&alert) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to serialize an alert: {}", e);
+            return;
+        }
+    };
+    let result = match timeout(POST_TIMEOUT, post(&alerter.webhook, &body)).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::other("timed out")),
+    };
+    match result {
+        Ok(()) => metrics::ALERTS.inc(),
+        Err(e) => {
+            metrics::ALERT_FAILURES.inc();
+            warn!(
+                condition = condition.as_str(),
+                "Failed to post an alert: {}", e
+            );
+        }
+    }
+}
+
+async fn post(webhook: &Webhook, body: &str) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect((webhook.host.as_str(), webhook.port)).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        webhook.path,
+        webhook.host,
+        webhook.port,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = vec![0; MAX_RESPONSE];
+    let len = stream.read(&mut response).await?;
+    let line = response[..len]
+        .split(|&b| b == b'\r' || b == b'\n')
+        .next()
+        .unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    match line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(std::io::Error::other(format!(
+            "webhook answered {:?}",
+            line
+        ))),
+    }
+}
+
+// When each condition was last alerted of, and how many alerts of it were
+// held back since.
+#[derive(Debug, Default)]
+struct Throttle {
+    last: HashMap<Condition, (Instant, u64)>,
+}
+
+impl Throttle {
+    // Whether an alert of `condition` goes out at `now`, with how many were
+    // held back since the last one if it does.
+    fn admit(&mut self, condition: Condition, now: Instant, interval: Duration) -> Option<u64> {
+        if let Some((at, held)) = self.last.get_mut(&condition) {
+            if now.duration_since(*at) < interval {
+                *held += 1;
+                return None;
+            }
+        }
+        let held = self.last.get(&condition).map_or(0, |&(_, held)| held);
+        self.last.insert(condition, (now, 0));
+        Some(held)
+    }
+}
+
+/// Tells a committed sentinel that has stopped moving while there's more to
+/// apply, ingest stuck though nothing fails outright.
+#[derive(Debug)]
+pub struct Staleness {
+    committed: u64,
+    since: Instant,
+}
+
+impl Staleness {
+    pub fn new(committed: u64, now: Instant) -> Self {
+        Self {
+            committed,
+            since: now,
+        }
+    }
+
+    /// Notes the committed sentinel as of `now`, and whether the nucleus is
+    /// `behind` it, returning for how long it's been stuck once that's
+    /// `after` or longer.
+    pub fn check(
+        &mut self,
+        committed: u64,
+        behind: bool,
+        now: Instant,
+        after: Duration,
+    ) -> Option<Duration> {
+        if committed != self.committed || !behind {
+            *self = Self::new(committed, now);
+            return None;
+        }
+        Some(now.duration_since(self.since)).filter(|&stuck| stuck >= after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_webhooks() {
+        assert_eq!(
+            "http://alerts.local:8080/hooks/abc".parse(),
+            Ok(Webhook {
+                host: "alerts.local".to_string(),
+                port: 8080,
+                path: "/hooks/abc".to_string(),
+            })
+        );
+        assert_eq!(
+            "http://10.0.0.1".parse(),
+            Ok(Webhook {
+                host: "10.0.0.1".to_string(),
+                port: 80,
+                path: "/".to_string(),
+            })
+        );
+        assert!("https://hooks.example.com/abc".parse::<Webhook>().is_err());
+        assert!("http://:80/abc".parse::<Webhook>().is_err());
+        assert!("http://host:http/abc".parse::<Webhook>().is_err());
+    }
+
+    #[test]
+    fn throttles_each_condition_apart() {
+        let interval = Duration::from_secs(600);
+        let mut throttle = Throttle::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(
+            throttle.admit(Condition::StaleSentinel, at(0), interval),
+            Some(0)
+        );
+        assert_eq!(
+            throttle.admit(Condition::StaleSentinel, at(10), interval),
+            None
+        );
+        assert_eq!(
+            throttle.admit(Condition::StaleSentinel, at(20), interval),
+            None
+        );
+        assert_eq!(
+            throttle.admit(Condition::NucleusFailures, at(30), interval),
+            Some(0)
+        );
+        // the next one says how many it stands for
+        assert_eq!(
+            throttle.admit(Condition::StaleSentinel, at(600), interval),
+            Some(2)
+        );
+        assert_eq!(
+            throttle.admit(Condition::StaleSentinel, at(1300), interval),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn a_sentinel_is_stale_only_while_behind() {
+        let after = Duration::from_secs(60);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut staleness = Staleness::new(5, start);
+
+        assert_eq!(staleness.check(5, true, at(30), after), None);
+        assert_eq!(staleness.check(5, true, at(60), after), Some(after));
+        // moving on starts over
+        assert_eq!(staleness.check(6, true, at(70), after), None);
+        assert_eq!(staleness.check(6, true, at(120), after), None);
+        // and so does catching up
+        assert_eq!(staleness.check(6, false, at(200), after), None);
+        assert_eq!(staleness.check(6, true, at(230), after), None);
+        assert_eq!(staleness.check(6, true, at(260), after), Some(after));
+    }
+}
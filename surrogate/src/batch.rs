@@ -0,0 +1,324 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use vemodel::Method;
+
+use crate::config::DuplicatePolicy;
+use crate::key::Prefix;
+use crate::model::Model;
+use crate::rpc::ChangeEvent;
+
+// ids fetched again per model for one gap, at most
+const GAP_RECONCILE_IDS: u64 = 100;
+
+/// Something off about a `get_from_common_key` batch, worth a log line.
+#[derive(Debug, PartialEq)]
+pub enum Anomaly {
+    /// The events didn't come in ascending reqnum order.
+    OutOfOrder,
+    /// More than one event carried the reqnum. Identical copies are collapsed
+    /// into one, differing ones are handled by the [`DuplicatePolicy`].
+    Duplicate { reqnum: u64, identical: bool },
+    /// No event for the reqnums between `after` and `next`.
+    Gap { after: u64, next: u64 },
+    /// An event the nucleus should have forgotten, at or below the committed sentinel.
+    Stale(u64),
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfOrder => write!(f, "events are out of reqnum order"),
+            Self::Duplicate {
+                reqnum,
+                identical: true,
+            } => write!(f, "reqnum {} is repeated", reqnum),
+            Self::Duplicate {
+                reqnum,
+                identical: false,
+            } => write!(f, "reqnum {} is shared by differing events", reqnum),
+            Self::Gap { after, next } => {
+                write!(f, "no events between reqnums {} and {}", after, next)
+            }
+            Self::Stale(reqnum) => write!(f, "reqnum {} is already committed", reqnum),
+        }
+    }
+}
+
+/// A batch turned down under [`DuplicatePolicy::Reject`].
+#[derive(Debug, PartialEq)]
+pub struct DuplicateReqnum(pub u64);
+
+impl fmt::Display for DuplicateReqnum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "reqnum {} is shared by differing events", self.0)
+    }
+}
+
+impl std::error::Error for DuplicateReqnum {}
+
+/// Puts a batch in ascending reqnum order and collapses identical duplicates,
+/// so the sentinel only ever moves forward while walking it. `committed` is
+/// the sentinel the batch was requested with.
+pub fn normalize(
+    mut events: Vec<ChangeEvent>,
+    committed: u64,
+    policy: DuplicatePolicy,
+) -> Result<(Vec<ChangeEvent>, Vec<Anomaly>), DuplicateReqnum> {
+    let mut anomalies = Vec::new();
+    if events.windows(2).any(|w| w[0].reqnum > w[1].reqnum) {
+        anomalies.push(Anomaly::OutOfOrder);
+        // stable, differing events of a reqnum stay in the order they came in
+        events.sort_by_key(|event| event.reqnum);
+    }
+
+    let mut normalized: Vec<ChangeEvent> = Vec::with_capacity(events.len());
+    let mut last = committed;
+    for event in events {
+        let reqnum = event.reqnum;
+        let mut same = normalized.iter().rev().take_while(|e| e.reqnum == reqnum);
+        if same.clone().any(|e| *e == event) {
+            anomalies.push(Anomaly::Duplicate {
+                reqnum,
+                identical: true,
+            });
+            continue;
+        }
+        if same.next().is_some() {
+            anomalies.push(Anomaly::Duplicate {
+                reqnum,
+                identical: false,
+            });
+            match policy {
+                DuplicatePolicy::Apply => {}
+                DuplicatePolicy::First => continue,
+                DuplicatePolicy::Reject => return Err(DuplicateReqnum(reqnum)),
+            }
+        } else if reqnum <= committed {
+            anomalies.push(Anomaly::Stale(reqnum));
+        } else if reqnum > last + 1 {
+            anomalies.push(Anomaly::Gap {
+                after: last,
+                next: reqnum,
+            });
+        }
+        last = last.max(reqnum);
+        normalized.push(event);
+    }
+    Ok((normalized, anomalies))
+}
+
+/// The gaps among `anomalies` missing more than `tolerance` reqnums, as
+/// `(after, next)`.
+pub fn gaps(anomalies: &[Anomaly], tolerance: u64) -> Vec<(u64, u64)> {
+    anomalies
+        .iter()
+        .filter_map(|anomaly| match *anomaly {
+            Anomaly::Gap { after, next } if next - after - 1 > tolerance => Some((after, next)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The highest id each model has been created with so far, to tell which
+/// entities the events of a reqnum gap may have created.
+#[derive(Debug, Default)]
+pub struct Created(HashMap<Model, u64>);
+
+impl Created {
+    pub fn note(&mut self, event: &ChangeEvent) {
+        if event.method != Method::Create {
+            return;
+        }
+        if let Some((model, id)) = model_and_id(&event.key) {
+            let highest = self.0.entry(model).or_default();
+            *highest = (*highest).max(id);
+        }
+    }
+
+    /// Keys of the entities a gap may hide the creates of, `from_gap` being
+    /// the events from the gap on: per model, the ids between the highest one
+    /// created before the gap and the lowest one created after it, as ids are
+    /// handed out in order. Nothing for a model without creates on both sides,
+    /// and updates and deletes lost in a gap leave no such trace at all.
+    pub fn gap_keys(&self, from_gap: &[ChangeEvent]) -> Vec<Vec<u8>> {
+        let mut after: HashMap<Model, u64> = HashMap::new();
+        for event in from_gap
+            .iter()
+            .filter(|event| event.method == Method::Create)
+        {
+            if let Some((model, id)) = model_and_id(&event.key) {
+                let lowest = after.entry(model).or_insert(id);
+                *lowest = (*lowest).min(id);
+            }
+        }
+        let mut keys = Vec::new();
+        for (model, lowest) in after {
+            let Some(&highest) = self.0.get(&model) else {
+                continue;
+            };
+            let ids = highest + 1..lowest.min(highest + 1 + GAP_RECONCILE_IDS);
+            keys.extend(ids.map(|id| Prefix::of_model(model).key(id)));
+        }
+        keys.sort();
+        keys
+    }
+}
+
+fn model_and_id(key: &[u8]) -> Option<(Model, u64)> {
+    let prefix = Prefix::of(key)?;
+    Some((prefix.model, prefix.decode_id(key)?))
+}
+
+/// Drops the events of an entity that follow another of its creates or
+/// updates in a normalized batch, up to its next delete, and returns how many
+/// were dropped. Entities are fetched when their event is applied, so the
+/// first one already writes what the nucleus holds by then and the others
+/// would only write it again. Events up to `applied` are left alone.
+pub fn compact(events: &mut Vec<ChangeEvent>, applied: u64) -> usize {
+    let before = events.len();
+    let mut upserting = HashSet::new();
+    events.retain(|event| {
+        if event.reqnum <= applied {
+            return true;
+        }
+        if event.method == Method::Delete {
+            upserting.remove(&event.key);
+            return true;
+        }
+        upserting.insert(event.key.clone())
+    });
+    before - events.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(reqnum: u64, id: u64) -> ChangeEvent {
+        ChangeEvent {
+            reqnum,
+            method: Method::Update,
+            key: [&b"vear:"[..], &id.to_be_bytes()[..]].concat(),
+            source_time: None,
+        }
+    }
+
+    fn reqnums(events: &[ChangeEvent]) -> Vec<u64> {
+        events.iter().map(|e| e.reqnum).collect()
+    }
+
+    #[test]
+    fn in_order_batch_is_untouched() {
+        let events = vec![event(4, 1), event(5, 2), event(6, 1)];
+        let (normalized, anomalies) = normalize(events.clone(), 3, DuplicatePolicy::Apply).unwrap();
+        assert_eq!(normalized, events);
+        assert!(anomalies.is_empty());
+    }
+
+    #[test]
+    fn out_of_order_batch_is_sorted() {
+        let events = vec![event(5, 2), event(4, 1), event(6, 1)];
+        let (normalized, anomalies) = normalize(events, 3, DuplicatePolicy::Apply).unwrap();
+        assert_eq!(reqnums(&normalized), [4, 5, 6]);
+        assert_eq!(anomalies, [Anomaly::OutOfOrder]);
+    }
+
+    #[test]
+    fn identical_duplicates_are_collapsed() {
+        let events = vec![event(4, 1), event(5, 2), event(4, 1)];
+        let (normalized, anomalies) = normalize(events, 3, DuplicatePolicy::Reject).unwrap();
+        assert_eq!(reqnums(&normalized), [4, 5]);
+        assert_eq!(
+            anomalies,
+            [
+                Anomaly::OutOfOrder,
+                Anomaly::Duplicate {
+                    reqnum: 4,
+                    identical: true
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn differing_duplicates_follow_the_policy() {
+        let events = || vec![event(4, 1), event(4, 2), event(5, 3)];
+        let (applied, _) = normalize(events(), 3, DuplicatePolicy::Apply).unwrap();
+        assert_eq!(applied, events());
+
+        let (first, _) = normalize(events(), 3, DuplicatePolicy::First).unwrap();
+        assert_eq!(first, vec![event(4, 1), event(5, 3)]);
+
+        assert_eq!(
+            normalize(events(), 3, DuplicatePolicy::Reject),
+            Err(DuplicateReqnum(4))
+        );
+    }
+
+    #[test]
+    fn gaps_and_stale_events_are_reported() {
+        let events = vec![event(2, 1), event(4, 1), event(7, 2)];
+        let (normalized, anomalies) = normalize(events, 3, DuplicatePolicy::Apply).unwrap();
+        assert_eq!(reqnums(&normalized), [2, 4, 7]);
+        assert_eq!(
+            anomalies,
+            [Anomaly::Stale(2), Anomaly::Gap { after: 4, next: 7 }]
+        );
+    }
+
+    #[test]
+    fn only_gaps_past_the_tolerance_count() {
+        let anomalies = [
+            Anomaly::Gap { after: 4, next: 6 },
+            Anomaly::Stale(2),
+            Anomaly::Gap {
+                after: 10,
+                next: 20,
+            },
+        ];
+        assert_eq!(gaps(&anomalies, 0), [(4, 6), (10, 20)]);
+        assert_eq!(gaps(&anomalies, 1), [(10, 20)]);
+        assert!(gaps(&anomalies, 9).is_empty());
+    }
+
+    #[test]
+    fn gap_keys_lie_between_the_creates_either_side() {
+        let create = |reqnum, id| ChangeEvent {
+            method: Method::Create,
+            ..event(reqnum, id)
+        };
+        let comment = |reqnum, id| ChangeEvent {
+            key: [&b"veco:"[..], &u64::to_be_bytes(id)[..]].concat(),
+            ..create(reqnum, id)
+        };
+        let mut created = Created::default();
+        created.note(&create(1, 3));
+        created.note(&create(2, 5));
+        created.note(&event(3, 9));
+
+        // articles 6 and 7 may hide in the gap, there were no comments before it
+        let from_gap = [event(10, 9), create(11, 8), create(12, 10), comment(13, 4)];
+        let keys = created.gap_keys(&from_gap);
+        assert_eq!(keys, [event(0, 6).key, event(0, 7).key]);
+        assert!(Created::default().gap_keys(&from_gap).is_empty());
+    }
+
+    #[test]
+    fn compaction_keeps_the_first_upsert_until_a_delete() {
+        let with = |method, event: ChangeEvent| ChangeEvent { method, ..event };
+        let mut events = vec![
+            with(Method::Update, event(1, 7)),
+            with(Method::Create, event(2, 7)),
+            with(Method::Update, event(3, 7)),
+            with(Method::Create, event(4, 8)),
+            with(Method::Update, event(5, 7)),
+            with(Method::Delete, event(6, 7)),
+            with(Method::Create, event(7, 7)),
+            with(Method::Update, event(8, 7)),
+            with(Method::Update, event(9, 8)),
+        ];
+
+        assert_eq!(compact(&mut events, 1), 4);
+        assert_eq!(reqnums(&events), [1, 2, 4, 6, 7]);
+    }
+}
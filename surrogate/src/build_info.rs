@@ -0,0 +1,14 @@
+/// The `vemodel` types this surrogate decodes entities as, see
+/// [`vemodel::VERSION`]. Surrogates of a fleet disagreeing on it is the
+/// first thing to rule out when some of them fail to decode what others
+/// don't.
+pub const VEMODEL_VERSION: &str = vemodel::VERSION;
+
+/// `git describe` of the tree the surrogate was built from, "unknown" when
+/// it wasn't built from a git checkout.
+pub const GIT_DESCRIBE: &str = env!("SURROGATE_GIT_DESCRIBE");
+
+/// Both of them, as they're logged at startup and served on `/healthz`.
+pub fn summary() -> String {
+    format!("vemodel {} build {}", VEMODEL_VERSION, GIT_DESCRIBE)
+}
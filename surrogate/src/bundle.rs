@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Client;
+use tracing::info;
+
+use vemodel::{Method, SubspaceId, VeArticle, VeComment, VeSubspace};
+
+use crate::config::Config;
+use crate::db::{self, Change, Entity};
+use crate::id::SqlId;
+use crate::key::Prefix;
+use crate::model::Model;
+use crate::sink::Sink;
+use crate::{content, query};
+
+/// Bumped whenever the layout of a bundle changes, older bundles are refused
+/// rather than half imported.
+pub const FORMAT: u32 = 1;
+
+/// A subspace with everything in it, as written by `export-subspace` and read
+/// by `import-bundle`.
+///
+/// Rows are taken from the tables rather than the views, hidden ones
+/// included, and nicknames are the raw ones, so that the database a bundle is
+/// imported into ends up with what the exporting one had.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    pub format: u32,
+    pub exported_time: i64,
+    pub subspace: VeSubspace,
+    pub articles: Vec<VeArticle>,
+    /// Comments on any of `articles`.
+    pub comments: Vec<VeComment>,
+}
+
+/// Reads subspace `id` and its articles and comments, `None` if there's no
+/// such subspace.
+pub async fn export(
+    client: &Client,
+    id: SubspaceId,
+) -> Result<Option<Bundle>, Box<dyn std::error::Error>> {
+    let id = SqlId(id.0);
+    let Some(row) = client
+        .query_opt("SELECT * FROM subspaces WHERE id = $1", &[&id])
+        .await?
+    else {
+        return Ok(None);
+    };
+    let subspace = query::subspace_from_row(&row);
+
+    let articles = client
+        .query(
+            "SELECT * FROM articles WHERE subspace_id = $1 ORDER BY id",
+            &[&id],
+        )
+        .await?
+        .iter()
+        .map(|row| {
+            Ok(VeArticle {
+                author_nickname: row.get("author_nickname"),
+                ..query::article_from_row(row)?
+            })
+        })
+        .collect::<Result<Vec<_>, content::ContentError>>()?;
+
+    let comments = client
+        .query(
+            // replies to comments too, however deep
+            "WITH RECURSIVE thread AS (
+                 SELECT c.* FROM comments c
+                 JOIN articles a ON a.id = c.post_id AND c.target_type = 'article'
+                 WHERE a.subspace_id = $1
+                 UNION ALL
+                 SELECT r.* FROM comments r
+                 JOIN thread t ON t.id = r.post_id AND r.target_type = 'comment'
+             )
+             SELECT * FROM thread ORDER BY id",
+            &[&id],
+        )
+        .await?
+        .iter()
+        .map(|row| VeComment {
+            author_nickname: row.get("author_nickname"),
+            ..query::comment_from_row(row)
+        })
+        .collect();
+
+    Ok(Some(Bundle {
+        format: FORMAT,
+        exported_time: db::unix_millis(),
+        subspace,
+        articles,
+        comments,
+    }))
+}
+
+/// Upserts everything in `bundle` the way the writer applies changes from the
+/// nucleus, parents first, in a single transaction, then applies the same
+/// changes to `sinks`, those of [`crate::sink::build_for_command`]. The sinks
+/// only get them once the transaction has committed, and a sink failing
+/// leaves the import in Postgres, to be imported again for the sinks to
+/// catch up.
+///
+/// The sync state is left alone: should the nucleus of the importing database
+/// hold the same entities, its changes still apply over the imported rows.
+pub async fn import(
+    client: &Client,
+    config: &Config,
+    sinks: &[Box<dyn Sink>],
+    bundle: Bundle,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if bundle.format != FORMAT {
+        return Err(format!(
+            "bundle is in format {}, this build reads format {}",
+            bundle.format, FORMAT
+        )
+        .into());
+    }
+    let counts = (bundle.articles.len(), bundle.comments.len());
+    let subspace_id = bundle.subspace.id;
+
+    let changes = std::iter::once(change(
+        Model::Subspace,
+        bundle.subspace.id.0,
+        Entity::Subspace(bundle.subspace),
+    ))
+    .chain(
+        bundle
+            .articles
+            .into_iter()
+            .map(|article| change(Model::Article, article.id.0, Entity::Article(article))),
+    )
+    .chain(
+        bundle
+            .comments
+            .into_iter()
+            .map(|comment| change(Model::Comment, comment.id.0, Entity::Comment(comment))),
+    )
+    .collect::<Vec<_>>();
+
+    client.batch_execute("BEGIN").await?;
+    for change in &changes {
+        let applied = db::handle_database_operation(client, config, change)
+            .await
+            .map_err(|e| format!("importing {}: {}", change.correlation_id, e));
+        if let Err(error) = applied {
+            client.batch_execute("ROLLBACK").await?;
+            return Err(error.into());
+        }
+    }
+    client.batch_execute("COMMIT").await?;
+    for sink in sinks {
+        for change in &changes {
+            sink.apply(change).await.map_err(|e| {
+                format!(
+                    "imported into Postgres, but sink {} failed at {}: {}",
+                    sink.name(),
+                    change.correlation_id,
+                    e
+                )
+            })?;
+        }
+    }
+    info!(
+        "Imported subspace {} with {} articles and {} comments",
+        subspace_id, counts.0, counts.1
+    );
+    Ok(())
+}
+
+// Imported entities don't come from a nucleus request, hence reqnum 0.
+fn change(model: Model, id: u64, entity: Entity) -> Change {
+    let prefix = Prefix::of_model(model);
+    Change {
+        reqnum: 0,
+        key: prefix.key(id),
+        correlation_id: format!("import-{}{}", String::from_utf8_lossy(prefix.bytes), id),
+        method: Method::Update,
+        entity,
+        source_time: None,
+        event: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key;
+
+    #[test]
+    fn imported_changes_are_keyed_like_the_nucleus_would() {
+        let change = change(Model::Article, 7, Entity::Deleted(Model::Article, 7));
+        assert_eq!(key::split_key(&change.key), ("vear:".to_string(), 7));
+        assert_eq!(change.correlation_id, "import-vear:7");
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use vemodel::{ArticleId, CommentId, SubspaceId, VeArticle, VeComment, VeSubspace};
+
+use crate::db::Entity;
+use crate::key::Prefix;
+use crate::metrics;
+use crate::model::Model;
+use crate::nucleus::{Fetched, Nucleus, NucleusError};
+use crate::rpc::{ChangeEvent, EventBatch};
+use crate::sink::BoxFuture;
+
+/// A nucleus that keeps the entities it fetched, least recently used out
+/// first, keyed by their storage key.
+///
+/// A cached entity is served for every change event already observed when it
+/// was fetched: it can only be newer than any of them. Those are the events of
+/// the same batch and events served again after a failed one, so hot entities
+/// changed many times over a catch-up are fetched once per batch. An event
+/// observed later drops it, as does its time to live running out. Missing
+/// entities and errors aren't cached.
+pub struct CachingNucleus<N> {
+    inner: N,
+    ttl: Duration,
+    lru: Mutex<Lru>,
+}
+
+struct Entry {
+    entity: Entity,
+    fetched: Instant,
+    /// The latest reqnum observed when it was fetched.
+    seen: u64,
+    /// Position in `Lru::order`.
+    used: u64,
+}
+
+struct Lru {
+    capacity: usize,
+    entries: HashMap<Vec<u8>, Entry>,
+    // `Entry::used` -> key, least recently used first
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    seen: u64,
+}
+
+impl<N: Nucleus> CachingNucleus<N> {
+    /// Caches up to `capacity` entities, none at all with 0.
+    pub fn new(inner: N, capacity: usize, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            lru: Mutex::new(Lru {
+                capacity,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                seen: 0,
+            }),
+        }
+    }
+
+    async fn cached<T: Clone>(
+        &self,
+        key: Vec<u8>,
+        fetch: BoxFuture<'_, Fetched<T>>,
+        wrap: fn(T) -> Entity,
+        unwrap: fn(Entity) -> Option<T>,
+    ) -> Fetched<T> {
+        let hit = self.lru.lock().unwrap().get(&key, self.ttl);
+        if let Some(entity) = hit.and_then(unwrap) {
+            metrics::ENTITY_CACHE_HITS.inc();
+            return Ok(Ok(Some(entity)));
+        }
+        metrics::ENTITY_CACHE_MISSES.inc();
+        let fetched = fetch.await?;
+        if let Ok(Some(entity)) = &fetched {
+            self.lru.lock().unwrap().insert(key, wrap(entity.clone()));
+        }
+        Ok(fetched)
+    }
+}
+
+impl<N: Nucleus> Nucleus for CachingNucleus<N> {
+    fn get_from_common_key(
+        &self,
+        sentinel: u64,
+    ) -> BoxFuture<'_, Result<Result<EventBatch, String>, NucleusError>> {
+        self.inner.get_from_common_key(sentinel)
+    }
+
+    fn head_reqnum(&self) -> BoxFuture<'_, Result<Result<u64, String>, NucleusError>> {
+        self.inner.head_reqnum()
+    }
+
+    fn get_subspace(&self, id: SubspaceId) -> BoxFuture<'_, Fetched<VeSubspace>> {
+        Box::pin(self.cached(
+            key(Model::Subspace, id.0),
+            self.inner.get_subspace(id),
+            Entity::Subspace,
+            |entity| match entity {
+                Entity::Subspace(subspace) => Some(subspace),
+                _ => None,
+            },
+        ))
+    }
+
+    fn get_article(&self, id: ArticleId) -> BoxFuture<'_, Fetched<VeArticle>> {
+        Box::pin(self.cached(
+            key(Model::Article, id.0),
+            self.inner.get_article(id),
+            Entity::Article,
+            |entity| match entity {
+                Entity::Article(article) => Some(article),
+                _ => None,
+            },
+        ))
+    }
+
+    fn get_comment(&self, id: CommentId) -> BoxFuture<'_, Fetched<VeComment>> {
+        Box::pin(self.cached(
+            key(Model::Comment, id.0),
+            self.inner.get_comment(id),
+            Entity::Comment,
+            |entity| match entity {
+                Entity::Comment(comment) => Some(comment),
+                _ => None,
+            },
+        ))
+    }
+
+    fn observe(&self, events: &[ChangeEvent]) {
+        self.lru.lock().unwrap().observe(events);
+        self.inner.observe(events);
+    }
+}
+
+fn key(model: Model, id: u64) -> Vec<u8> {
+    Prefix::of_model(model).key(id)
+}
+
+impl Lru {
+    fn get(&mut self, key: &[u8], ttl: Duration) -> Option<Entity> {
+        let entry = self.entries.get_mut(key)?;
+        if entry.fetched.elapsed() >= ttl {
+            self.remove(key);
+            return None;
+        }
+        self.tick += 1;
+        self.order.remove(&entry.used);
+        self.order.insert(self.tick, key.to_vec());
+        entry.used = self.tick;
+        Some(entry.entity.clone())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, entity: Entity) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        let entry = Entry {
+            entity,
+            fetched: Instant::now(),
+            seen: self.seen,
+            used: self.tick,
+        };
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+        }
+    }
+
+    fn observe(&mut self, events: &[ChangeEvent]) {
+        for event in events {
+            let stale = self
+                .entries
+                .get(&event.key)
+                .is_some_and(|entry| event.reqnum > entry.seen);
+            if stale {
+                self.remove(&event.key);
+            }
+            self.seen = self.seen.max(event.reqnum);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::fixture;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use vemodel::Method;
+
+    /// Serves every article there's asked for, counting the fetches.
+    #[derive(Default)]
+    struct Counting {
+        fetches: AtomicUsize,
+    }
+
+    impl Nucleus for Counting {
+        fn get_from_common_key(
+            &self,
+            _sentinel: u64,
+        ) -> BoxFuture<'_, Result<Result<EventBatch, String>, NucleusError>> {
+            Box::pin(async { Ok(Ok(EventBatch::default())) })
+        }
+
+        fn head_reqnum(&self) -> BoxFuture<'_, Result<Result<u64, String>, NucleusError>> {
+            Box::pin(async { Ok(Ok(0)) })
+        }
+
+        fn get_subspace(&self, _id: SubspaceId) -> BoxFuture<'_, Fetched<VeSubspace>> {
+            Box::pin(async { Ok(Ok(None)) })
+        }
+
+        fn get_article(&self, id: ArticleId) -> BoxFuture<'_, Fetched<VeArticle>> {
+            Box::pin(async move {
+                let fetch = self.fetches.fetch_add(1, Ordering::SeqCst) + 1;
+                Ok(Ok(Some(VeArticle {
+                    title: format!("fetch {}", fetch),
+                    ..fixture::article(id.0)
+                })))
+            })
+        }
+
+        fn get_comment(&self, _id: CommentId) -> BoxFuture<'_, Fetched<VeComment>> {
+            Box::pin(async { Ok(Ok(None)) })
+        }
+    }
+
+    fn event(reqnum: u64, id: u64) -> ChangeEvent {
+        ChangeEvent {
+            reqnum,
+            method: Method::Update,
+            key: key(Model::Article, id),
+            source_time: None,
+        }
+    }
+
+    async fn title(nucleus: &impl Nucleus, id: u64) -> String {
+        nucleus
+            .get_article(ArticleId(id))
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap()
+            .title
+    }
+
+    #[tokio::test]
+    async fn serves_events_observed_before_the_fetch() {
+        let nucleus = CachingNucleus::new(Counting::default(), 16, Duration::from_secs(60));
+        let batch = [event(1, 7), event(2, 7), event(3, 7)];
+        nucleus.observe(&batch);
+        for _ in &batch {
+            assert_eq!(title(&nucleus, 7).await, "fetch 1");
+        }
+
+        // served again after a failure, still older than the fetch
+        nucleus.observe(&batch[1..]);
+        assert_eq!(title(&nucleus, 7).await, "fetch 1");
+
+        nucleus.observe(&[event(4, 8)]);
+        assert_eq!(title(&nucleus, 7).await, "fetch 1");
+        nucleus.observe(&[event(5, 7)]);
+        assert_eq!(title(&nucleus, 7).await, "fetch 2");
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used() {
+        let nucleus = CachingNucleus::new(Counting::default(), 2, Duration::from_secs(60));
+        title(&nucleus, 1).await;
+        title(&nucleus, 2).await;
+        title(&nucleus, 1).await;
+        title(&nucleus, 3).await;
+        assert_eq!(nucleus.inner.fetches.load(Ordering::SeqCst), 3);
+
+        title(&nucleus, 1).await;
+        assert_eq!(nucleus.inner.fetches.load(Ordering::SeqCst), 3);
+        title(&nucleus, 2).await;
+        assert_eq!(nucleus.inner.fetches.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn expired_and_disabled_caches_fetch_every_time() {
+        let expired = CachingNucleus::new(Counting::default(), 16, Duration::ZERO);
+        let disabled = CachingNucleus::new(Counting::default(), 0, Duration::from_secs(60));
+        for nucleus in [&expired, &disabled] {
+            title(nucleus, 7).await;
+            title(nucleus, 7).await;
+            assert_eq!(nucleus.inner.fetches.load(Ordering::SeqCst), 2);
+        }
+    }
+}
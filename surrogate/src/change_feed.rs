@@ -0,0 +1,119 @@
+use serde_json::{json, Value};
+use tokio_postgres::Client;
+
+use crate::build_info;
+use crate::config::Config;
+use crate::db::Change;
+use crate::id::SqlId;
+
+/// Rows of `table` as they were before and after a change, captured by the
+/// Postgres writer in the transaction applying it.
+///
+/// With `VE_CHANGE_FEED`, every change that did alter a row is recorded in
+/// `change_feed` as a Debezium-like envelope, e.g.
+///
+/// {"op":"u","before":{...},"after":{...},"source":{"avs_id":"5Fs...","table":"articles","reqnum":42,"ts":1760000000000,"vemodel":"0.1.0"}}
+///
+/// Being written along with the row, the feed never disagrees with the
+/// tables, unlike a secondary sink, which only sees the change and not the
+/// row it replaced. Consumers read it in `id` order and delete what they've
+/// read, nothing prunes it otherwise.
+pub struct Capture {
+    table: &'static str,
+    id: u64,
+    before: Option<Value>,
+}
+
+impl Capture {
+    /// Reads the row `change` is about to alter.
+    pub async fn before(client: &Client, change: &Change) -> Result<Self, tokio_postgres::Error> {
+        let table = change.entity.model().table();
+        let id = change.entity.id();
+        let before = row(client, table, id).await?;
+        Ok(Self { table, id, before })
+    }
+
+    /// Reads the row again now that `change` is applied and records both.
+    pub async fn record(
+        self,
+        client: &Client,
+        config: &Config,
+        change: &Change,
+    ) -> Result<(), tokio_postgres::Error> {
+        let after = row(client, self.table, self.id).await?;
+        let ts = change.source_time.unwrap_or_else(crate::db::unix_millis);
+        let source = json!({
+            "avs_id": config.avs_id.as_str(),
+            "table": self.table,
+            "reqnum": change.reqnum,
+            "ts": ts,
+            // the types the row was decoded as, changing with upgrades
+            "vemodel": build_info::VEMODEL_VERSION,
+        });
+        let Some(envelope) = envelope(self.before, after, source) else {
+            return Ok(());
+        };
+        client
+            .execute(
+                "INSERT INTO change_feed (reqnum, envelope, recorded_time)
+                 VALUES ($1, $2::TEXT::JSONB, $3)",
+                &[
+                    &(change.reqnum as i64),
+                    &envelope.to_string(),
+                    &crate::db::unix_millis(),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+async fn row(
+    client: &Client,
+    table: &str,
+    id: u64,
+) -> Result<Option<Value>, tokio_postgres::Error> {
+    let query = format!(
+        "SELECT to_jsonb(t)::TEXT AS row FROM {} t WHERE id = $1",
+        table
+    );
+    let row = client.query_opt(&query, &[&SqlId(id)]).await?;
+    // Postgres wrote it, it's JSON
+    Ok(row.map(|row| serde_json::from_str(row.get("row")).unwrap_or(Value::Null)))
+}
+
+// `None` when there was no row either side, e.g. the delete of one never indexed.
+fn envelope(before: Option<Value>, after: Option<Value>, source: Value) -> Option<Value> {
+    let op = match (&before, &after) {
+        (None, None) => return None,
+        (None, Some(_)) => "c",
+        (Some(_), Some(_)) => "u",
+        (Some(_), None) => "d",
+    };
+    Some(json!({
+        "op": op,
+        "before": before,
+        "after": after,
+        "source": source,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn op_follows_the_rows_either_side() {
+        let row = || Some(json!({ "id": 7, "title": "Weekly update" }));
+        let op = |before, after| envelope(before, after, json!({})).map(|e| e["op"].clone());
+        assert_eq!(op(None, row()), Some(json!("c")));
+        assert_eq!(op(row(), row()), Some(json!("u")));
+        assert_eq!(op(row(), None), Some(json!("d")));
+        assert_eq!(op(None, None), None);
+
+        let deleted = envelope(row(), None, json!({ "reqnum": 42 })).unwrap();
+        assert_eq!(deleted["before"]["id"], 7);
+        assert!(deleted["after"].is_null());
+        assert_eq!(deleted["source"]["reqnum"], 42);
+    }
+}
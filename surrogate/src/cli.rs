@@ -0,0 +1,245 @@
+use std::path::PathBuf;
+
+use crate::integrity::Repair;
+use crate::model::Model;
+
+const USAGE: &str = "usage: surrogate [--validate-schema] [--print-config] [--check] [--change-log] [<command>]
+
+options:
+    --validate-schema             check the schema is up to date instead of migrating it
+    --print-config                print the configuration as resolved, passwords redacted, and exit
+    --check                       check Postgres and the nucleus can be reached, and exit
+    --change-log                  have resync, redrive, repairs and imports append to VE_CHANGE_LOG_PATH too
+
+commands:
+    (none)                        poll the nucleus and index its changes
+    migrate                       apply pending migrations and exit
+    dead-letter list              print the dead-lettered events
+    dead-letter redrive <reqnum>  re-fetch and apply a dead-lettered event
+    resync <model> <id>           re-fetch and apply one entity, printing it before and after
+    content recode                rewrite article content in VE_CONTENT_ENCODING
+    verify-decode [<sample>]      decode the first <sample> ids of every model, read-only
+    export-subspace <id>          print a subspace and its articles and comments as a JSON bundle
+    import-bundle <path>          upsert the JSON bundle at <path>
+    verify-counts [repair]        print the authors whose content counts drifted, or recompute them
+    verify-referential-integrity [refetch|delete]
+                                  print the articles and comments whose parent is missing,
+                                  or re-fetch the parents or delete them
+    verify-shadow [<sample>]      compare the shadow database with this one, <sample> rows per table
+    rebuild-denorm                recompute every denormalized column, counts and stats from the rows";
+
+// ids of each model fetched by `verify-decode` when no sample size is given
+const DEFAULT_VERIFY_SAMPLE: u64 = 20;
+
+// rows of each table compared by `verify-shadow` when no sample size is given
+const DEFAULT_SHADOW_SAMPLE: i64 = 100;
+
+/// What the surrogate was asked to do on the command line.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Poll the nucleus and index its changes, the default.
+    Run,
+    /// Apply pending migrations, then exit.
+    Migrate,
+    /// Print the events parked in the dead-letter table.
+    DeadLetterList,
+    /// Re-fetch and apply a dead-lettered event, removing it on success.
+    DeadLetterRedrive(u64),
+    /// Re-fetch one entity and apply it everywhere, as an update would be.
+    Resync(Model, u64),
+    /// Rewrite stored article content in the configured encoding.
+    ContentRecode,
+    /// Fetch and decode the first ids of every model, without writing anything.
+    VerifyDecode(u64),
+    /// Print a subspace with its articles and comments as a bundle.
+    ExportSubspace(u64),
+    /// Upsert the entities of a bundle file.
+    ImportBundle(PathBuf),
+    /// Compare the per-author content counts with the rows, recomputing
+    /// them with `repair`.
+    VerifyCounts { repair: bool },
+    /// Find the articles and comments whose parent isn't stored, repairing
+    /// them as `repair` says.
+    VerifyIntegrity { repair: Option<Repair> },
+    /// Compare the shadow database with the primary, sampling that many rows
+    /// of each table.
+    VerifyShadow(i64),
+    /// Recompute the denormalized columns, counts and stats.
+    RebuildDenorm,
+}
+
+/// The parsed command line.
+#[derive(Debug, PartialEq)]
+pub struct Cli {
+    pub command: Command,
+    /// Leave the schema alone and only check it's what this build expects,
+    /// for deployments that run `migrate` as a separate step.
+    pub validate_schema: bool,
+    /// Print the resolved configuration as JSON instead of running the command.
+    pub print_config: bool,
+    /// Only check the configuration, Postgres and the nucleus, instead of
+    /// running the command, for deployment smoke tests.
+    pub check: bool,
+    /// Have the commands applying changes append them to the change log as
+    /// well, off by default since the running surrogate writes to it.
+    pub change_log: bool,
+}
+
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Cli, String> {
+    let args: Vec<String> = args.into_iter().collect();
+    let (flags, args): (Vec<&str>, Vec<&str>) = args
+        .iter()
+        .map(String::as_str)
+        .partition(|arg| arg.starts_with("--"));
+
+    let mut validate_schema = false;
+    let mut print_config = false;
+    let mut check = false;
+    let mut change_log = false;
+    for flag in flags {
+        match flag {
+            "--validate-schema" => validate_schema = true,
+            "--print-config" => print_config = true,
+            "--check" => check = true,
+            "--change-log" => change_log = true,
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    Ok(Cli {
+        command: parse_command(&args)?,
+        validate_schema,
+        print_config,
+        check,
+        change_log,
+    })
+}
+
+fn parse_command(args: &[&str]) -> Result<Command, String> {
+    match args {
+        [] => Ok(Command::Run),
+        ["migrate"] => Ok(Command::Migrate),
+        ["dead-letter", "list"] => Ok(Command::DeadLetterList),
+        ["dead-letter", "redrive", reqnum] => reqnum
+            .parse()
+            .map(Command::DeadLetterRedrive)
+            .map_err(|e| format!("invalid reqnum {}: {}", reqnum, e)),
+        ["resync", model, id] => {
+            let model = model.parse()?;
+            id.parse()
+                .map(|id| Command::Resync(model, id))
+                .map_err(|e| format!("invalid {} id {}: {}", model.as_str(), id, e))
+        }
+        ["content", "recode"] => Ok(Command::ContentRecode),
+        ["verify-decode"] => Ok(Command::VerifyDecode(DEFAULT_VERIFY_SAMPLE)),
+        ["verify-decode", sample] => sample
+            .parse()
+            .map(Command::VerifyDecode)
+            .map_err(|e| format!("invalid sample size {}: {}", sample, e)),
+        ["export-subspace", id] => id
+            .parse()
+            .map(Command::ExportSubspace)
+            .map_err(|e| format!("invalid subspace id {}: {}", id, e)),
+        ["import-bundle", path] => Ok(Command::ImportBundle(PathBuf::from(path))),
+        ["verify-counts"] => Ok(Command::VerifyCounts { repair: false }),
+        ["verify-counts", "repair"] => Ok(Command::VerifyCounts { repair: true }),
+        ["verify-referential-integrity"] => Ok(Command::VerifyIntegrity { repair: None }),
+        ["verify-referential-integrity", repair] => repair
+            .parse()
+            .map(|repair| Command::VerifyIntegrity {
+                repair: Some(repair),
+            })
+            .map_err(|_| USAGE.to_string()),
+        ["rebuild-denorm"] => Ok(Command::RebuildDenorm),
+        ["verify-shadow"] => Ok(Command::VerifyShadow(DEFAULT_SHADOW_SAMPLE)),
+        ["verify-shadow", sample] => sample
+            .parse()
+            .map(Command::VerifyShadow)
+            .map_err(|e| format!("invalid sample size {}: {}", sample, e)),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn command(line: &str) -> Result<Command, String> {
+        parse(args(line)).map(|cli| cli.command)
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(command(""), Ok(Command::Run));
+        assert_eq!(command("migrate"), Ok(Command::Migrate));
+        assert_eq!(command("dead-letter list"), Ok(Command::DeadLetterList));
+        assert_eq!(
+            command("dead-letter redrive 42"),
+            Ok(Command::DeadLetterRedrive(42))
+        );
+        assert_eq!(
+            command("resync article 7"),
+            Ok(Command::Resync(Model::Article, 7))
+        );
+        assert_eq!(command("content recode"), Ok(Command::ContentRecode));
+        assert_eq!(command("verify-decode"), Ok(Command::VerifyDecode(20)));
+        assert_eq!(command("verify-decode 5"), Ok(Command::VerifyDecode(5)));
+        assert_eq!(command("export-subspace 3"), Ok(Command::ExportSubspace(3)));
+        assert_eq!(
+            command("import-bundle backup.json"),
+            Ok(Command::ImportBundle(PathBuf::from("backup.json")))
+        );
+        assert_eq!(
+            command("verify-counts"),
+            Ok(Command::VerifyCounts { repair: false })
+        );
+        assert_eq!(
+            command("verify-counts repair"),
+            Ok(Command::VerifyCounts { repair: true })
+        );
+        assert_eq!(
+            command("verify-referential-integrity"),
+            Ok(Command::VerifyIntegrity { repair: None })
+        );
+        assert_eq!(
+            command("verify-referential-integrity delete"),
+            Ok(Command::VerifyIntegrity {
+                repair: Some(Repair::Delete)
+            })
+        );
+        assert_eq!(command("verify-shadow"), Ok(Command::VerifyShadow(100)));
+        assert_eq!(command("verify-shadow 5"), Ok(Command::VerifyShadow(5)));
+        assert_eq!(command("rebuild-denorm"), Ok(Command::RebuildDenorm));
+    }
+
+    #[test]
+    fn parses_flags_anywhere() {
+        let cli = parse(args("dead-letter --validate-schema list")).unwrap();
+        assert_eq!(cli.command, Command::DeadLetterList);
+        assert!(cli.validate_schema);
+        assert!(!parse(args("")).unwrap().validate_schema);
+        let cli = parse(args("--print-config migrate")).unwrap();
+        assert_eq!(cli.command, Command::Migrate);
+        assert!(cli.print_config);
+        assert!(!parse(args("")).unwrap().print_config);
+        assert!(parse(args("--check")).unwrap().check);
+        assert!(!parse(args("")).unwrap().check);
+        assert!(parse(args("--change-log")).unwrap().change_log);
+        assert!(!parse(args("")).unwrap().change_log);
+        assert!(parse(args("--frobnicate")).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        assert!(command("dead-letter redrive x").is_err());
+        assert!(command("export-subspace").is_err());
+        assert!(command("resync user 7").is_err());
+        assert!(command("resync article x").is_err());
+        assert!(command("verify-counts fix").is_err());
+        assert!(command("verify-referential-integrity fix").is_err());
+        assert!(command("frobnicate").is_err());
+    }
+}
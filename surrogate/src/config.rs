@@ -0,0 +1,899 @@
+use serde::{Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use vemodel::{Method, UserId};
+
+use crate::account::AvsId;
+use crate::alert::Webhook;
+use crate::content::ContentEncoding;
+use crate::expiry::{SubspaceTtl, Ttls};
+use crate::file_sink::Compression;
+use crate::html::{self, Tag};
+use crate::id::IdColumnType;
+use crate::leader::Standby;
+use crate::model::Model;
+use crate::nickname;
+use crate::projection::{Field, Projection};
+use crate::query::SubspaceView;
+use crate::required::BlankFields;
+use crate::rpc::EventCodec;
+use crate::sink::{SentinelAdvance, SinkMethods};
+use crate::thread::DeepReplies;
+
+const DEFAULT_POSTGRES_CONFIG: &str =
+    "host=localhost port=5432 user=postgres password=your_password dbname=ve_db";
+const DEFAULT_NUCLEUS_URL: &str = "http://localhost:9944";
+const DEFAULT_EXCERPT_LENGTH: usize = 200;
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_PARENT_GAP_ATTEMPTS: u32 = 30;
+const DEFAULT_CONFLICT_RETRIES: u32 = 3;
+const DEFAULT_PARTITION_MONTHS_AHEAD: u32 = 3;
+const DEFAULT_TRENDING_REFRESH_SECS: u64 = 300;
+const DEFAULT_NICKNAME_MAX_LENGTH: usize = 32;
+const DEFAULT_CHANGE_LOG_ROTATE_BYTES: u64 = 256 * 1024 * 1024;
+const DEFAULT_CHANGE_LOG_FLUSH_MS: u64 = 1000;
+const DEFAULT_ENTITY_CACHE_SIZE: usize = 1024;
+const DEFAULT_ENTITY_CACHE_TTL_SECS: u64 = 300;
+const DEFAULT_EXPIRY_INTERVAL_SECS: u64 = 60;
+const DEFAULT_DECODE_ALARM_THRESHOLD: usize = 10;
+const DEFAULT_DECODE_ALARM_WINDOW_SECS: u64 = 60;
+const DEFAULT_MAX_READY_LAG: u64 = 1000;
+const DEFAULT_SSE_BUFFER: usize = 1024;
+const DEFAULT_NUCLEUS_REQUEST_TIMEOUT_MS: u64 = 60_000;
+const DEFAULT_NUCLEUS_MAX_CONCURRENT_REQUESTS: usize = 256;
+const DEFAULT_NUCLEUS_IDLE_TIMEOUT_MS: u64 = 60_000;
+const DEFAULT_POLL_INTERVAL_MIN_MS: u64 = 1000;
+const DEFAULT_POLL_INTERVAL_MAX_MS: u64 = 5000;
+const DEFAULT_ALERT_INTERVAL_SECS: u64 = 900;
+const DEFAULT_ALERT_AFTER_FAILURES: u32 = 5;
+const DEFAULT_ALERT_STALE_SECS: u64 = 600;
+const DEFAULT_MISSING_ENTITY_RETRIES: u32 = 2;
+const DEFAULT_MISSING_ENTITY_RETRY_MS: u64 = 100;
+const DEFAULT_APPROVED_COMMENT_STATUSES: &[i16] = &[1];
+const DEFAULT_REJECTED_COMMENT_STATUSES: &[i16] = &[2];
+const DEFAULT_AVS_ID: &str = "5FsXfPrUDqq6abYccExCTUxyzjYaaYTr5utLx2wwdBv1m8R8";
+
+/// What to do when a `get_*` fetch that follows a Create/Update event returns
+/// `None`, i.e. the entity vanished between the change event and our fetch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingEntityPolicy {
+    /// Treat it as a delete and remove any stale row.
+    Delete,
+    /// Log it and leave the table untouched.
+    Skip,
+}
+
+impl FromStr for MissingEntityPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delete" => Ok(Self::Delete),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!("unknown missing entity policy: {}", s)),
+        }
+    }
+}
+
+/// Where a database that has never synced starts reading the nucleus from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StartMode {
+    /// From the first change ever, indexing the whole history.
+    Backfill,
+    /// From the latest change at startup, leaving out the history before it.
+    Tail,
+}
+
+impl FromStr for StartMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "backfill" => Ok(Self::Backfill),
+            "tail" => Ok(Self::Tail),
+            _ => Err(format!("unknown start mode: {}", s)),
+        }
+    }
+}
+
+/// What to do when differing events of a `get_from_common_key` batch share a
+/// reqnum. Identical copies of an event are always collapsed into one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    /// Apply all of them, in the order they came in.
+    Apply,
+    /// Apply the first one and drop the rest.
+    First,
+    /// Refuse the batch and poll again next cycle.
+    Reject,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "apply" => Ok(Self::Apply),
+            "first" => Ok(Self::First),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!("unknown duplicate reqnum policy: {}", s)),
+        }
+    }
+}
+
+/// When the database task commits the transaction it applies changes in.
+///
+/// The sentinel is saved in that same transaction and the polling loop only
+/// ever hands a committed sentinel to the nucleus, so a crash never loses
+/// events, the uncommitted ones are fetched and applied again on restart:
+/// - `Cycle` redoes at most the last poll cycle,
+/// - `Events(n)` redoes at most the cycles since `n` events were last committed,
+/// - `Window(d)` redoes at most `d` worth of cycles. The check runs once per
+///   cycle, so the window is effectively rounded up to the poll interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitPolicy {
+    Cycle,
+    Events(usize),
+    Window(Duration),
+}
+
+impl CommitPolicy {
+    /// Whether an open transaction holding `events` changes, started `elapsed`
+    /// ago, should be committed at the end of this cycle.
+    pub fn is_due(&self, events: usize, elapsed: Duration) -> bool {
+        match *self {
+            Self::Cycle => true,
+            Self::Events(n) => events >= n,
+            Self::Window(window) => elapsed >= window,
+        }
+    }
+}
+
+impl FromStr for CommitPolicy {
+    type Err = String;
+
+    // `cycle`, `events:<count>` or `window:<seconds>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "cycle" => Ok(Self::Cycle),
+            Some(("events", n)) => n.parse().map(Self::Events).map_err(|e| e.to_string()),
+            Some(("window", secs)) => secs
+                .parse()
+                .map(|secs| Self::Window(Duration::from_secs(secs)))
+                .map_err(|e| e.to_string()),
+            _ => Err(format!("unknown commit policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for CommitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cycle => f.write_str("cycle"),
+            Self::Events(n) => write!(f, "events:{}", n),
+            Self::Window(window) => write!(f, "window:{}", window.as_secs()),
+        }
+    }
+}
+
+// As `VE_COMMIT_POLICY` has it.
+impl Serialize for CommitPolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Runtime configuration, read from `VE_*` environment variables.
+///
+/// Serializes with the passwords of `postgres_config`, `nucleus_url` and
+/// `shadow_postgres_config` redacted, for `--print-config`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Config {
+    #[serde(serialize_with = "redact")]
+    pub postgres_config: String,
+    #[serde(serialize_with = "redact")]
+    pub nucleus_url: String,
+    /// Database every change is written to as well, to validate a schema
+    /// migration against, `None` for no shadow.
+    #[serde(serialize_with = "redact_shadow")]
+    pub shadow_postgres_config: Option<String>,
+    /// Whether the database of `postgres_config` is created when it doesn't
+    /// exist, for first runs, rather than failing to connect.
+    pub create_database_if_missing: bool,
+    pub avs_id: AvsId,
+    pub missing_entity: MissingEntityPolicy,
+    /// Times a create or update whose entity the nucleus doesn't have is
+    /// fetched again before it's left to `missing_entity`. The nucleus has
+    /// been seen serving a change a moment before the entity can be read.
+    pub missing_entity_retries: u32,
+    /// How long to wait before each of those fetches.
+    pub missing_entity_retry_delay: Duration,
+    /// What becomes of entities with a required field blank.
+    pub blank_fields: BlankFields,
+    /// Maximum length, in graphemes, of the generated `articles.excerpt`.
+    pub excerpt_length: usize,
+    /// Authors whose comments are never indexed. A comment of theirs indexed
+    /// already is deleted with its next create or update event, the others
+    /// stay until then. Unblocking an author doesn't bring back what was
+    /// deleted or passed over while they were blocked: each comes back with
+    /// its next event, or straight away with `resync comment <id>`, the ids
+    /// being in the log lines of the deletions.
+    pub blocked_authors: HashSet<UserId>,
+    /// Failed attempts after which an event is moved to the dead-letter table.
+    pub max_attempts: u32,
+    /// Failed attempts after which an event referencing a row that isn't
+    /// indexed is dead-lettered, usually more than `max_attempts` to let its
+    /// parent arrive.
+    pub parent_gap_attempts: u32,
+    /// Collation of the text columns read back sorted or compared, subspace
+    /// and article titles, `None` for the database's default.
+    pub text_collation: Option<String>,
+    /// Type of the id columns, `BIGINT` by default, `NUMERIC(20)` for ids past
+    /// `i64::MAX`. The columns are converted by `migrate`.
+    pub id_column_type: IdColumnType,
+    /// Times the open transaction is rolled back and its changes applied again,
+    /// backing off, when one fails on a serialization failure or deadlock,
+    /// before the change failing then fails like any other.
+    pub conflict_retries: u32,
+    pub commit_policy: CommitPolicy,
+    /// Subspace statuses meaning hidden, whose content the read paths leave out.
+    /// Empty, the default, turns the filtering off.
+    pub hidden_subspace_statuses: Vec<i16>,
+    /// Subspaces whose comments need approving before they're visible.
+    pub moderated_subspaces: Vec<u64>,
+    /// Subspaces with views of their own, see `query::create_subspace_views`.
+    pub subspace_views: Vec<SubspaceView>,
+    /// Comment statuses meaning approved, in moderated subspaces.
+    pub approved_comment_statuses: Vec<i16>,
+    /// Comment statuses meaning rejected, in moderated subspaces. Those
+    /// neither approved nor rejected are pending.
+    pub rejected_comment_statuses: Vec<i16>,
+    pub sentinel_advance: SentinelAdvance,
+    /// Whether each change is applied by one sink after the other, and by
+    /// all of them before the next, see `sink::Fanout`.
+    pub ordered_sinks: bool,
+    /// The methods of the changes each secondary sink named is sent, e.g.
+    /// `VE_SINK_METHODS=file:create+update,sse:update+delete`. Those not
+    /// named are sent them all.
+    pub sink_methods: HashMap<String, Vec<Method>>,
+    pub content_encoding: ContentEncoding,
+    /// Tags kept in the `content_html` articles and comments are rendered
+    /// to, `None` to not render them, see `html::render`.
+    pub content_html_tags: Option<HashSet<Tag>>,
+    /// Whether `articles` and `comments` are partitioned by month of `created_time`.
+    pub partitioning: bool,
+    /// How many months of partitions to keep ready beyond the current one.
+    pub partition_months_ahead: u32,
+    pub duplicate_reqnums: DuplicatePolicy,
+    /// How often `trending_articles` is refreshed, `None` to never refresh it.
+    pub trending_refresh: Option<Duration>,
+    /// Models indexed, events of the others are passed over.
+    pub models: HashSet<Model>,
+    /// How author nicknames are sanitized for display, `None` to serve them
+    /// as the nucleus has them. The raw nickname is stored either way.
+    pub nickname_rules: Option<nickname::Rules>,
+    /// JSONL file every applied change is appended to, `None` for no such log.
+    pub change_log_path: Option<PathBuf>,
+    /// Size past which the change log is rotated.
+    pub change_log_rotate_bytes: u64,
+    /// How often buffered change log lines are flushed to the file.
+    pub change_log_flush: Duration,
+    /// How change log lines are compressed, if at all.
+    pub change_log_compression: Compression,
+    /// Fields of the entities the change log has, all of them by default.
+    pub change_log_fields: Projection,
+    /// Length, in bytes, strings of an entity are cut down to when Postgres
+    /// rejects its text, `None` to only strip NUL bytes.
+    pub max_text_bytes: Option<usize>,
+    /// Only consulted while there's no sentinel saved yet, and then only
+    /// without `initial_sentinel`.
+    pub start_mode: StartMode,
+    /// The sentinel a database that has never synced starts from, seeding
+    /// `sync_state` with it, e.g. to leave out a known stretch of history.
+    /// A sentinel already saved always goes first, so this never moves that of
+    /// a live index: that's what `VE_SENTINEL_RESET_PATH` is for.
+    pub initial_sentinel: Option<u64>,
+    /// What to do while another surrogate holds the writer lock of the AVS.
+    pub standby: Standby,
+    /// Entities fetched from the nucleus kept for reuse, 0 to cache none.
+    pub entity_cache_size: usize,
+    /// How long a cached entity is reused at most, even with no change
+    /// event for it in between.
+    pub entity_cache_ttl: Duration,
+    /// File a sentinel reset is read from on SIGHUP, see `reset::SentinelReset`.
+    pub sentinel_reset_path: Option<PathBuf>,
+    /// How long an applied event is remembered in `applied_events`, `None`
+    /// to remember all of them. This is how far back a replay, from a
+    /// sentinel reset or an `initial_sentinel` below events applied already,
+    /// still passes over what it has applied: an event older than this is
+    /// applied again.
+    pub applied_events_retention: Option<Duration>,
+    /// Most change events asked of the nucleus per poll, `None` for all of
+    /// them at once. Paging needs a nucleus with `get_page_from_common_key`.
+    pub event_page_size: Option<u32>,
+    /// How the nucleus encodes change events, SCALE unless it's a fake one.
+    pub event_codec: EventCodec,
+    /// Most new change events applied per poll, `None` for no limit. The
+    /// others are left with the nucleus for the polls that follow.
+    pub max_events_per_cycle: Option<usize>,
+    /// How long to wait before the next poll while there are events left
+    /// over, rather than the usual poll interval.
+    pub catch_up_pause: Duration,
+    /// The shortest and longest the poll interval adapts between, see
+    /// `poll::PollInterval`. The same for both fixes it.
+    pub poll_interval_min: Duration,
+    pub poll_interval_max: Duration,
+    /// How long new creates and updates are held back for more of the same
+    /// entities to collapse into, `None` to apply every event as it comes.
+    /// A batch holding a delete is applied straight away, as is one with
+    /// events left over.
+    pub compact_window: Option<Duration>,
+    /// Written to the `source` column of every upserted row, `<VE_SOURCE>/<version>`,
+    /// so rows of tables shared by several instances can be told apart. `None`,
+    /// with `VE_SOURCE` unset, leaves it NULL.
+    pub source: Option<String>,
+    /// Whether the Postgres writer records every change in `change_feed`,
+    /// see `change_feed::Capture`.
+    pub change_feed: bool,
+    /// How long articles are kept before they expire, see `expiry::Ttls`.
+    pub article_ttls: Ttls,
+    /// How often expired articles are looked for, when any can expire.
+    pub expiry_interval: Duration,
+    /// Reqnums a batch can skip over before it's taken for lost events.
+    pub reqnum_gap_tolerance: u64,
+    /// Whether the entities lost events may have created are fetched again,
+    /// see `batch::Created::gap_keys`.
+    pub reconcile_gaps: bool,
+    /// Whether a comment's `post_id` may name another comment rather than an
+    /// article. The foreign key to `articles` is dropped then, see
+    /// `db::reply_target`.
+    pub comment_replies: bool,
+    /// Depth past which replies are rejected or flattened, `None` for no
+    /// limit. Cycles are refused whatever it is, see `thread::place`.
+    pub max_comment_depth: Option<usize>,
+    /// What becomes of replies past `max_comment_depth`.
+    pub deep_replies: DeepReplies,
+    /// Substrings of errors the AVS answers a poll with that stop the
+    /// surrogate, the others are polled again. Empty, the default, never stops.
+    pub fatal_nucleus_errors: Vec<String>,
+    /// Decode failures of one model within `decode_alarm_window` taken for
+    /// a likely schema change on the nucleus, `None` to only log them.
+    pub decode_alarm_threshold: Option<usize>,
+    /// Rolling window the decode failures are counted over.
+    pub decode_alarm_window: Duration,
+    /// Where `/readyz` is served, and ingest paused and resumed, `None` for
+    /// nowhere.
+    pub health_addr: Option<SocketAddr>,
+    /// Reqnums the committed sentinel can be behind the nucleus head with
+    /// the surrogate still ready.
+    pub max_ready_lag: u64,
+    /// Where applied changes are streamed as Server-Sent Events, `None` for
+    /// nowhere, see `sse::EventStream`.
+    pub sse_addr: Option<SocketAddr>,
+    /// Changes each event stream client may fall behind before it misses some.
+    pub sse_buffer: usize,
+    /// How long a call to the nucleus may take before it fails.
+    pub nucleus_request_timeout: Duration,
+    /// Calls to the nucleus in flight at once, at most.
+    pub nucleus_max_concurrent_requests: usize,
+    /// How long the client to the nucleus may sit idle before it's built
+    /// afresh, `None` for ever, see `connection::Connection`.
+    pub nucleus_idle_timeout: Option<Duration>,
+    /// Where alerts of failures are posted, `None` for nowhere, see
+    /// `alert::Condition`.
+    pub alert_webhook: Option<Webhook>,
+    /// How long after an alert another one of the same condition is held
+    /// back for.
+    pub alert_interval: Duration,
+    /// Failures in a row of the nucleus or of Postgres that make for an alert.
+    pub alert_after_failures: u32,
+    /// How long the committed sentinel can stay put with the nucleus ahead
+    /// before it's alerted of, `None` for ever.
+    pub alert_stale_after: Option<Duration>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars(|key| env::var(key).ok())
+    }
+
+    /// The config `var` has, it's given a `VE_*` name and returns the value
+    /// set, `None` for the default.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let var: Vars = &var;
+        let models: HashSet<Model> = parse_list_env(var, "VE_MODELS")?;
+        let content_html_tags = if parse_env(var, "VE_CONTENT_HTML", false)? {
+            Some(
+                parse_list_env_or(var, "VE_CONTENT_HTML_TAGS", &html::default_tags())?
+                    .into_iter()
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        Ok(Self {
+            postgres_config: env_or(var, "VE_POSTGRES_CONFIG", DEFAULT_POSTGRES_CONFIG),
+            nucleus_url: env_or(var, "VE_NUCLEUS_URL", DEFAULT_NUCLEUS_URL),
+            shadow_postgres_config: var("VE_SHADOW_POSTGRES_CONFIG"),
+            create_database_if_missing: parse_env(var, "VE_CREATE_DATABASE_IF_MISSING", false)?,
+            avs_id: parse_env(var, "VE_AVS_ID", DEFAULT_AVS_ID.parse()?)?,
+            missing_entity: parse_env(var, "VE_MISSING_ENTITY", MissingEntityPolicy::Skip)?,
+            missing_entity_retries: parse_env(
+                var,
+                "VE_MISSING_ENTITY_RETRIES",
+                DEFAULT_MISSING_ENTITY_RETRIES,
+            )?,
+            missing_entity_retry_delay: Duration::from_millis(parse_env(
+                var,
+                "VE_MISSING_ENTITY_RETRY_MS",
+                DEFAULT_MISSING_ENTITY_RETRY_MS,
+            )?),
+            blank_fields: parse_env(var, "VE_BLANK_FIELDS", BlankFields::Keep)?,
+            excerpt_length: parse_env(var, "VE_EXCERPT_LENGTH", DEFAULT_EXCERPT_LENGTH)?,
+            blocked_authors: parse_list_env(var, "VE_BLOCKED_AUTHORS")?,
+            max_attempts: parse_env(var, "VE_MAX_ATTEMPTS", DEFAULT_MAX_ATTEMPTS)?,
+            parent_gap_attempts: parse_env(
+                var,
+                "VE_PARENT_GAP_ATTEMPTS",
+                DEFAULT_PARENT_GAP_ATTEMPTS,
+            )?,
+            text_collation: var("VE_TEXT_COLLATION"),
+            id_column_type: parse_env(var, "VE_ID_COLUMN_TYPE", IdColumnType::Bigint)?,
+            conflict_retries: parse_env(var, "VE_CONFLICT_RETRIES", DEFAULT_CONFLICT_RETRIES)?,
+            commit_policy: parse_env(var, "VE_COMMIT_POLICY", CommitPolicy::Cycle)?,
+            hidden_subspace_statuses: parse_list_env(var, "VE_HIDDEN_SUBSPACE_STATUSES")?,
+            moderated_subspaces: parse_list_env(var, "VE_MODERATED_SUBSPACES")?,
+            subspace_views: parse_list_env(var, "VE_SUBSPACE_VIEWS")?,
+            approved_comment_statuses: parse_list_env_or(
+                var,
+                "VE_APPROVED_COMMENT_STATUSES",
+                DEFAULT_APPROVED_COMMENT_STATUSES,
+            )?,
+            rejected_comment_statuses: parse_list_env_or(
+                var,
+                "VE_REJECTED_COMMENT_STATUSES",
+                DEFAULT_REJECTED_COMMENT_STATUSES,
+            )?,
+            sentinel_advance: parse_env(var, "VE_SENTINEL_ADVANCE", SentinelAdvance::Primary)?,
+            ordered_sinks: parse_env(var, "VE_ORDERED_SINKS", false)?,
+            sink_methods: parse_list_env::<SinkMethods, Vec<_>>(var, "VE_SINK_METHODS")?
+                .into_iter()
+                .map(|SinkMethods(sink, methods)| (sink, methods))
+                .collect(),
+            content_encoding: parse_env(var, "VE_CONTENT_ENCODING", ContentEncoding::Plain)?,
+            content_html_tags,
+            partitioning: parse_env(var, "VE_PARTITION_BY_CREATED_TIME", false)?,
+            partition_months_ahead: parse_env(
+                var,
+                "VE_PARTITION_MONTHS_AHEAD",
+                DEFAULT_PARTITION_MONTHS_AHEAD,
+            )?,
+            duplicate_reqnums: parse_env(var, "VE_DUPLICATE_REQNUMS", DuplicatePolicy::Apply)?,
+            // 0 turns refreshing off
+            trending_refresh: Some(parse_env(
+                var,
+                "VE_TRENDING_REFRESH_SECS",
+                DEFAULT_TRENDING_REFRESH_SECS,
+            )?)
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+            // all of them when unset
+            models: if models.is_empty() {
+                HashSet::from(Model::ALL)
+            } else {
+                models
+            },
+            nickname_rules: if parse_env(var, "VE_SANITIZE_NICKNAMES", true)? {
+                Some(nickname::Rules {
+                    max_length: parse_env(
+                        var,
+                        "VE_NICKNAME_MAX_LENGTH",
+                        DEFAULT_NICKNAME_MAX_LENGTH,
+                    )?,
+                    fold_homoglyphs: parse_env(var, "VE_FOLD_HOMOGLYPHS", false)?,
+                })
+            } else {
+                None
+            },
+            change_log_path: var("VE_CHANGE_LOG_PATH").map(PathBuf::from),
+            change_log_rotate_bytes: parse_env(
+                var,
+                "VE_CHANGE_LOG_ROTATE_BYTES",
+                DEFAULT_CHANGE_LOG_ROTATE_BYTES,
+            )?,
+            change_log_flush: Duration::from_millis(parse_env(
+                var,
+                "VE_CHANGE_LOG_FLUSH_MS",
+                DEFAULT_CHANGE_LOG_FLUSH_MS,
+            )?),
+            change_log_compression: parse_env(var, "VE_CHANGE_LOG_COMPRESSION", Compression::None)?,
+            change_log_fields: parse_list_env::<Field, _>(var, "VE_CHANGE_LOG_FIELDS")?,
+            // 0, the default, never truncates
+            max_text_bytes: Some(parse_env(var, "VE_MAX_TEXT_BYTES", 0)?).filter(|&max| max > 0),
+            start_mode: parse_env(var, "VE_START_MODE", StartMode::Backfill)?,
+            initial_sentinel: var("VE_INITIAL_SENTINEL")
+                .map(|raw| {
+                    raw.parse()
+                        .map_err(|e| format!("invalid value for VE_INITIAL_SENTINEL: {}", e))
+                })
+                .transpose()?,
+            standby: parse_env(var, "VE_STANDBY", Standby::Exit)?,
+            entity_cache_size: parse_env(var, "VE_ENTITY_CACHE_SIZE", DEFAULT_ENTITY_CACHE_SIZE)?,
+            entity_cache_ttl: Duration::from_secs(parse_env(
+                var,
+                "VE_ENTITY_CACHE_TTL_SECS",
+                DEFAULT_ENTITY_CACHE_TTL_SECS,
+            )?),
+            sentinel_reset_path: var("VE_SENTINEL_RESET_PATH").map(PathBuf::from),
+            // 0, the default, keeps every applied event
+            applied_events_retention: Some(parse_env(var, "VE_APPLIED_EVENTS_RETENTION_SECS", 0)?)
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            // 0, the default, doesn't page
+            event_page_size: Some(parse_env(var, "VE_EVENT_PAGE_SIZE", 0)?)
+                .filter(|&size| size > 0),
+            event_codec: parse_env(var, "VE_EVENT_CODEC", EventCodec::Scale)?,
+            // 0, the default, doesn't limit
+            max_events_per_cycle: Some(parse_env(var, "VE_MAX_EVENTS_PER_CYCLE", 0)?)
+                .filter(|&max| max > 0),
+            catch_up_pause: Duration::from_millis(parse_env(var, "VE_CATCH_UP_PAUSE_MS", 0)?),
+            poll_interval_min: Duration::from_millis(parse_env(
+                var,
+                "VE_POLL_INTERVAL_MIN_MS",
+                DEFAULT_POLL_INTERVAL_MIN_MS,
+            )?),
+            poll_interval_max: Duration::from_millis(parse_env(
+                var,
+                "VE_POLL_INTERVAL_MAX_MS",
+                DEFAULT_POLL_INTERVAL_MAX_MS,
+            )?),
+            // 0, the default, doesn't compact
+            compact_window: Some(parse_env(var, "VE_COMPACT_WINDOW_MS", 0)?)
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            source: var("VE_SOURCE").map(|name| format!("{}/{}", name, env!("CARGO_PKG_VERSION"))),
+            change_feed: parse_env(var, "VE_CHANGE_FEED", false)?,
+            article_ttls: Ttls {
+                // 0, the default, never expires
+                default: Some(parse_env(var, "VE_ARTICLE_TTL_SECS", 0)?)
+                    .filter(|&secs| secs > 0)
+                    .map(Duration::from_secs),
+                subspaces: parse_list_env::<SubspaceTtl, Vec<_>>(var, "VE_SUBSPACE_ARTICLE_TTLS")?
+                    .into_iter()
+                    .map(|SubspaceTtl(subspace, ttl)| (subspace, ttl))
+                    .collect(),
+            },
+            // `tokio::time::interval` panics on 0
+            expiry_interval: Duration::from_secs(
+                parse_env(var, "VE_EXPIRY_INTERVAL_SECS", DEFAULT_EXPIRY_INTERVAL_SECS)?.max(1),
+            ),
+            reqnum_gap_tolerance: parse_env(var, "VE_REQNUM_GAP_TOLERANCE", 0)?,
+            reconcile_gaps: parse_env(var, "VE_RECONCILE_GAPS", false)?,
+            comment_replies: parse_env(var, "VE_COMMENT_REPLIES", false)?,
+            // 0, the default, doesn't limit it
+            max_comment_depth: Some(parse_env(var, "VE_MAX_COMMENT_DEPTH", 0)?)
+                .filter(|&max| max > 0),
+            deep_replies: parse_env(var, "VE_DEEP_REPLIES", DeepReplies::Reject)?,
+            fatal_nucleus_errors: parse_list_env(var, "VE_FATAL_NUCLEUS_ERRORS")?,
+            // 0 turns the alarm off
+            decode_alarm_threshold: Some(parse_env(
+                var,
+                "VE_DECODE_ALARM_THRESHOLD",
+                DEFAULT_DECODE_ALARM_THRESHOLD,
+            )?)
+            .filter(|&threshold| threshold > 0),
+            decode_alarm_window: Duration::from_secs(parse_env(
+                var,
+                "VE_DECODE_ALARM_WINDOW_SECS",
+                DEFAULT_DECODE_ALARM_WINDOW_SECS,
+            )?),
+            health_addr: var("VE_HEALTH_ADDR")
+                .map(|addr| {
+                    addr.parse()
+                        .map_err(|e| format!("VE_HEALTH_ADDR={}: {}", addr, e))
+                })
+                .transpose()?,
+            max_ready_lag: parse_env(var, "VE_MAX_READY_LAG", DEFAULT_MAX_READY_LAG)?,
+            sse_addr: var("VE_SSE_ADDR")
+                .map(|addr| {
+                    addr.parse()
+                        .map_err(|e| format!("VE_SSE_ADDR={}: {}", addr, e))
+                })
+                .transpose()?,
+            sse_buffer: parse_env(var, "VE_SSE_BUFFER", DEFAULT_SSE_BUFFER)?,
+            nucleus_request_timeout: Duration::from_millis(parse_env(
+                var,
+                "VE_NUCLEUS_REQUEST_TIMEOUT_MS",
+                DEFAULT_NUCLEUS_REQUEST_TIMEOUT_MS,
+            )?),
+            nucleus_max_concurrent_requests: parse_env(
+                var,
+                "VE_NUCLEUS_MAX_CONCURRENT_REQUESTS",
+                DEFAULT_NUCLEUS_MAX_CONCURRENT_REQUESTS,
+            )?,
+            // 0 never rebuilds it
+            nucleus_idle_timeout: Some(parse_env(
+                var,
+                "VE_NUCLEUS_IDLE_TIMEOUT_MS",
+                DEFAULT_NUCLEUS_IDLE_TIMEOUT_MS,
+            )?)
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis),
+            alert_webhook: var("VE_ALERT_WEBHOOK_URL")
+                .map(|url| {
+                    url.parse()
+                        .map_err(|e| format!("invalid value for VE_ALERT_WEBHOOK_URL: {}", e))
+                })
+                .transpose()?,
+            alert_interval: Duration::from_secs(parse_env(
+                var,
+                "VE_ALERT_INTERVAL_SECS",
+                DEFAULT_ALERT_INTERVAL_SECS,
+            )?),
+            // 0 would alert of every failure, as 1 does
+            alert_after_failures: parse_env(
+                var,
+                "VE_ALERT_AFTER_FAILURES",
+                DEFAULT_ALERT_AFTER_FAILURES,
+            )?
+            .max(1),
+            // 0 never alerts of it
+            alert_stale_after: Some(parse_env(
+                var,
+                "VE_ALERT_STALE_SECS",
+                DEFAULT_ALERT_STALE_SECS,
+            )?)
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+        })
+    }
+
+    /// Every setting at its default, whatever the environment has, for tests
+    /// to start from. Public for those of the binary and the benchmark too.
+    pub fn for_tests() -> Self {
+        Self::from_vars(|_| None).expect("the defaults are a valid config")
+    }
+
+    /// Like [`Config::for_tests`], but on the database `VE_POSTGRES_CONFIG`
+    /// names, for tests and benchmarks that need one.
+    pub fn for_database_tests() -> Self {
+        Self::from_vars(|key| match key {
+            "VE_POSTGRES_CONFIG" => env::var(key).ok(),
+            _ => None,
+        })
+        .expect("the defaults are a valid config")
+    }
+
+    pub fn indexes(&self, model: Model) -> bool {
+        self.models.contains(&model)
+    }
+}
+
+fn redact<S: Serializer>(config: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&redacted(config))
+}
+
+fn redact_shadow<S: Serializer>(config: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match config {
+        Some(config) => redact(config, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+// Masks the password of a libpq `key=value` string or of a URL, be it the
+// URL's own or a `password` query parameter.
+fn redacted(config: &str) -> String {
+    const MASK: &str = "***";
+    if let Some((scheme, rest)) = config.split_once("://") {
+        let (authority, tail) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+        let authority = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => match userinfo.split_once(':') {
+                Some((user, _)) => format!("{}:{}@{}", user, MASK, host),
+                None => authority.to_string(),
+            },
+            None => authority.to_string(),
+        };
+        let tail = match tail.split_once('?') {
+            Some((path, query)) => {
+                let query: Vec<String> = query
+                    .split('&')
+                    .map(|param| match param.split_once('=') {
+                        Some(("password", _)) => format!("password={}", MASK),
+                        _ => param.to_string(),
+                    })
+                    .collect();
+                format!("{}?{}", path, query.join("&"))
+            }
+            None => tail.to_string(),
+        };
+        return format!("{}://{}{}", scheme, authority, tail);
+    }
+
+    let mut redacted = String::new();
+    let mut rest = config;
+    while let Some(start) = rest.find("password") {
+        let (before, from) = rest.split_at(start);
+        let key_end = "password".len();
+        let eq = from[key_end..]
+            .find(|c: char| !c.is_whitespace())
+            .map(|i| key_end + i)
+            .filter(|&i| from[i..].starts_with('='));
+        // only a `password` key, not a value or another key containing it
+        let eq = match eq {
+            Some(eq) if before.is_empty() || before.ends_with(char::is_whitespace) => eq,
+            _ => {
+                redacted.push_str(&rest[..start + key_end]);
+                rest = &from[key_end..];
+                continue;
+            }
+        };
+        let value = from[eq + 1..].trim_start();
+        let value_end = if let Some(quoted) = value.strip_prefix('\'') {
+            // quoted values run to the next unescaped quote
+            let mut escaped = false;
+            let close = quoted
+                .char_indices()
+                .find(|&(_, c)| {
+                    let close = c == '\'' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    close
+                })
+                .map_or(quoted.len(), |(i, _)| i + 1);
+            1 + close
+        } else {
+            value.find(char::is_whitespace).unwrap_or(value.len())
+        };
+        redacted.push_str(before);
+        redacted.push_str("password=");
+        redacted.push_str(MASK);
+        rest = &value[value_end..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+// Looks a `VE_*` setting up, `None` when it's unset.
+type Vars<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+fn env_or(var: Vars, key: &str, default: &str) -> String {
+    var(key).unwrap_or_else(|| default.to_string())
+}
+
+fn parse_env<T>(var: Vars, key: &str, default: T) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match var(key) {
+        Some(raw) => raw
+            .parse()
+            .map_err(|e| format!("invalid value for {}: {}", key, e)),
+        None => Ok(default),
+    }
+}
+
+// Parses a comma separated `key`, e.g. `VE_BLOCKED_AUTHORS=3,17`, empty when unset.
+fn parse_list_env<T, C>(var: Vars, key: &str) -> Result<C, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+    C: FromIterator<T>,
+{
+    match var(key) {
+        Some(raw) => parse_list(&raw).map_err(|e| format!("invalid value for {}: {}", key, e)),
+        None => Ok(std::iter::empty().collect()),
+    }
+}
+
+// Like `parse_list_env`, but `default` when unset.
+fn parse_list_env_or<T>(var: Vars, key: &str, default: &[T]) -> Result<Vec<T>, String>
+where
+    T: FromStr + Clone,
+    T::Err: std::fmt::Display,
+{
+    match var(key) {
+        Some(_) => parse_list_env(var, key),
+        None => Ok(default.to_vec()),
+    }
+}
+
+fn parse_list<T, C>(raw: &str) -> Result<C, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+    C: FromIterator<T>,
+{
+    raw.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse::<T>().map_err(|e| format!("{}: {}", item, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_comma_separated_lists() {
+        let ids: HashSet<u64> = parse_list(" 3, 17,,3 ").unwrap();
+        assert_eq!(ids, HashSet::from([3, 17]));
+
+        let empty: HashSet<u64> = parse_list("").unwrap();
+        assert!(empty.is_empty());
+
+        assert!(parse_list::<u64, HashSet<u64>>("3,x").is_err());
+    }
+
+    #[test]
+    fn parses_commit_policies() {
+        assert_eq!("cycle".parse(), Ok(CommitPolicy::Cycle));
+        assert_eq!("events:500".parse(), Ok(CommitPolicy::Events(500)));
+        assert_eq!(
+            "window:30".parse(),
+            Ok(CommitPolicy::Window(Duration::from_secs(30)))
+        );
+        assert!("events".parse::<CommitPolicy>().is_err());
+        assert!("window:soon".parse::<CommitPolicy>().is_err());
+    }
+
+    #[test]
+    fn reads_settings_from_the_vars_given() {
+        let config = |raw: &'static str| {
+            Config::from_vars(|key| (key == "VE_MAX_ATTEMPTS").then(|| raw.to_string()))
+        };
+        assert_eq!(config("7").unwrap().max_attempts, 7);
+        assert!(config("seven").is_err());
+        assert_eq!(Config::for_tests().max_attempts, DEFAULT_MAX_ATTEMPTS);
+    }
+
+    #[test]
+    fn parses_models() {
+        let models: HashSet<Model> = parse_list("article, subspace").unwrap();
+        assert_eq!(models, HashSet::from([Model::Article, Model::Subspace]));
+        assert!("user".parse::<Model>().is_err());
+    }
+
+    #[test]
+    fn commit_policies_print_as_parsed() {
+        for policy in ["cycle", "events:500", "window:30"] {
+            assert_eq!(policy.parse::<CommitPolicy>().unwrap().to_string(), policy);
+        }
+    }
+
+    #[test]
+    fn redacts_passwords() {
+        assert_eq!(
+            redacted("host=localhost password=hunter2 dbname=ve_db"),
+            "host=localhost password=*** dbname=ve_db"
+        );
+        assert_eq!(
+            redacted("password = 'two words\\' here' user=postgres"),
+            "password=*** user=postgres"
+        );
+        assert_eq!(redacted("user=password_admin"), "user=password_admin");
+        assert_eq!(
+            redacted("postgres://postgres:hunter2@db:5432/ve_db?password=hunter2&sslmode=require"),
+            "postgres://postgres:***@db:5432/ve_db?password=***&sslmode=require"
+        );
+        assert_eq!(redacted("http://localhost:9944"), "http://localhost:9944");
+    }
+
+    #[test]
+    fn commit_policy_due() {
+        let second = Duration::from_secs(1);
+        assert!(CommitPolicy::Cycle.is_due(0, Duration::ZERO));
+        assert!(!CommitPolicy::Events(10).is_due(9, second));
+        assert!(CommitPolicy::Events(10).is_due(10, Duration::ZERO));
+        assert!(!CommitPolicy::Window(second * 5).is_due(1000, second));
+        assert!(CommitPolicy::Window(second * 5).is_due(0, second * 5));
+    }
+}
@@ -0,0 +1,110 @@
+use jsonrpsee::core::client::ClientT;
+use jsonrpsee::core::traits::ToRpcParams;
+use jsonrpsee::core::Error;
+use jsonrpsee::http_client::{HttpClient, HttpClientBuilder};
+use serde::de::DeserializeOwned;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::metrics;
+
+/// An HTTP client to the nucleus at `VE_NUCLEUS_URL`, with the request
+/// timeout and concurrency of the config.
+pub fn build(config: &Config) -> Result<HttpClient, Error> {
+    HttpClientBuilder::default()
+        .request_timeout(config.nucleus_request_timeout)
+        .max_concurrent_requests(config.nucleus_max_concurrent_requests)
+        .build(&config.nucleus_url)
+}
+
+/// The client the polling loop calls the nucleus with. Caught up, it sits
+/// idle between polls, and proxies or load balancers on the way tend to drop
+/// idle connections without a word, failing the next call made on one.
+///
+/// So the client is built afresh, with a pool of new connections, once it's
+/// been idle for `VE_NUCLEUS_IDLE_TIMEOUT_MS`, and a call that fails on the
+/// way or times out is made once more on a fresh one. Every call it makes
+/// reads or is safe to repeat: a poll is sent the committed sentinel however
+/// often. Each rebuild counts in `surrogate_nucleus_reconnects_total`.
+pub struct Connection {
+    config: Config,
+    state: Mutex<State>,
+}
+
+struct State {
+    client: HttpClient,
+    last_used: Instant,
+}
+
+impl Connection {
+    pub fn open(config: &Config) -> Result<Self, Error> {
+        Ok(Self {
+            config: config.clone(),
+            state: Mutex::new(State {
+                client: build(config)?,
+                last_used: Instant::now(),
+            }),
+        })
+    }
+
+    /// The client as it stands, for one-off calls.
+    pub fn client(&self) -> HttpClient {
+        self.state.lock().unwrap().client.clone()
+    }
+
+    /// Calls `method` with the params `params` makes, again on a fresh
+    /// client if the connection was lost.
+    pub async fn request<R, P>(&self, method: &str, params: impl Fn() -> P) -> Result<R, Error>
+    where
+        R: DeserializeOwned,
+        P: ToRpcParams + Send,
+    {
+        match self.checkout(false)?.request(method, params()).await {
+            Err(e) if lost(&e) => {
+                warn!("Lost the connection to the nucleus, reconnecting: {}", e);
+                self.checkout(true)?.request(method, params()).await
+            }
+            result => result,
+        }
+    }
+
+    // The client to make a call with, rebuilt first when asked to or idle for
+    // too long.
+    fn checkout(&self, reconnect: bool) -> Result<HttpClient, Error> {
+        let mut state = self.state.lock().unwrap();
+        if reconnect || idle(state.last_used.elapsed(), self.config.nucleus_idle_timeout) {
+            state.client = build(&self.config)?;
+            metrics::NUCLEUS_RECONNECTS.inc();
+        }
+        state.last_used = Instant::now();
+        Ok(state.client.clone())
+    }
+}
+
+// Whether a client unused for `unused` may have had its connections dropped.
+fn idle(unused: Duration, timeout: Option<Duration>) -> bool {
+    timeout.is_some_and(|timeout| unused >= timeout)
+}
+
+// Whether a call failed for the connection rather than in the nucleus.
+fn lost(e: &Error) -> bool {
+    matches!(e, Error::Transport(_) | Error::RequestTimeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnects_when_idle_or_lost() {
+        let minute = Duration::from_secs(60);
+        assert!(!idle(Duration::from_secs(59), Some(minute)));
+        assert!(idle(minute, Some(minute)));
+        assert!(!idle(Duration::from_secs(3600), None));
+
+        assert!(lost(&Error::RequestTimeout));
+        assert!(!lost(&Error::Custom("unknown method".to_string())));
+    }
+}
@@ -0,0 +1,228 @@
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use tokio_postgres::{Client, Row};
+use tracing::info;
+
+use crate::id::SqlId;
+
+/// How article `content` is stored. Rows record their own encoding in
+/// `content_encoding`, so switching modes only affects rows written from then
+/// on and readers handle any mix of both.
+///
+/// Compressed content lives in `content_bytes` with `content` left NULL, so
+/// consumers querying the tables directly have to go through [`decode`] or
+/// keep the default mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentEncoding {
+    /// Plain text in `content`.
+    Plain,
+    /// zstd in `content_bytes`.
+    Zstd,
+}
+
+// zstd's own default, a good trade-off for text
+const ZSTD_LEVEL: i32 = 3;
+
+// rows rewritten per statement by `recode`
+const RECODE_BATCH: i64 = 500;
+
+impl ContentEncoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Plain => "plain",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+impl FromStr for ContentEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Self::Plain),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!("unknown content encoding: {}", s)),
+        }
+    }
+}
+
+/// Stored content that couldn't be turned back into text.
+#[derive(Debug)]
+pub enum ContentError {
+    UnknownEncoding(String),
+    /// The encoding calls for a column the row has left NULL.
+    Missing(ContentEncoding),
+    Zstd(std::io::Error),
+    Utf8(std::string::FromUtf8Error),
+}
+
+impl fmt::Display for ContentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownEncoding(encoding) => write!(f, "unknown content encoding {}", encoding),
+            Self::Missing(encoding) => {
+                write!(f, "no content stored for encoding {}", encoding.as_str())
+            }
+            Self::Zstd(e) => write!(f, "content failed to decompress: {}", e),
+            Self::Utf8(e) => write!(f, "decompressed content isn't UTF-8: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ContentError {}
+
+/// Content as stored, the `content` and `content_bytes` columns.
+#[derive(Debug, PartialEq)]
+pub struct Stored {
+    pub text: Option<String>,
+    pub bytes: Option<Vec<u8>>,
+}
+
+pub fn encode(content: &str, encoding: ContentEncoding) -> std::io::Result<Stored> {
+    Ok(match encoding {
+        ContentEncoding::Plain => Stored {
+            text: Some(content.to_string()),
+            bytes: None,
+        },
+        ContentEncoding::Zstd => Stored {
+            text: None,
+            bytes: Some(zstd::encode_all(content.as_bytes(), ZSTD_LEVEL)?),
+        },
+    })
+}
+
+pub fn decode(stored: Stored, encoding: &str) -> Result<String, ContentError> {
+    match encoding
+        .parse()
+        .map_err(|_| ContentError::UnknownEncoding(encoding.to_string()))?
+    {
+        ContentEncoding::Plain => stored
+            .text
+            .ok_or(ContentError::Missing(ContentEncoding::Plain)),
+        ContentEncoding::Zstd => {
+            let bytes = stored
+                .bytes
+                .ok_or(ContentError::Missing(ContentEncoding::Zstd))?;
+            let raw = zstd::decode_all(&bytes[..]).map_err(ContentError::Zstd)?;
+            String::from_utf8(raw).map_err(ContentError::Utf8)
+        }
+    }
+}
+
+/// Reads the content of an `articles` row, whatever it's stored as.
+pub fn from_row(row: &Row) -> Result<String, ContentError> {
+    let stored = Stored {
+        text: row.get("content"),
+        bytes: row.get("content_bytes"),
+    };
+    decode(stored, row.get("content_encoding"))
+}
+
+/// Rewrites every article not yet stored in `encoding`, e.g. to compress the
+/// content of an existing plain text database. Safe to interrupt and rerun.
+pub async fn recode(
+    client: &Client,
+    encoding: ContentEncoding,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut rows_done, mut before, mut after) = (0usize, 0usize, 0usize);
+    loop {
+        let rows = client
+            .query(
+                "SELECT id, content, content_bytes, content_encoding, indexed_time FROM articles
+                 WHERE content_encoding <> $1
+                 ORDER BY id
+                 LIMIT $2",
+                &[&encoding.as_str(), &RECODE_BATCH],
+            )
+            .await?;
+        if rows.is_empty() {
+            break;
+        }
+
+        for row in &rows {
+            let id: SqlId = row.get("id");
+            before += stored_size(&Stored {
+                text: row.get("content"),
+                bytes: row.get("content_bytes"),
+            });
+            let stored = encode(&from_row(row)?, encoding)?;
+            after += stored_size(&stored);
+            // a row upserted since it was read has newer content, left as it is
+            // and picked up again by the next batch if it isn't in `encoding`
+            client
+                .execute(
+                    "UPDATE articles SET content = $2, content_bytes = $3, content_encoding = $4
+                     WHERE id = $1 AND indexed_time IS NOT DISTINCT FROM $5",
+                    &[
+                        &id,
+                        &stored.text,
+                        &stored.bytes,
+                        &encoding.as_str(),
+                        &row.get::<_, Option<i64>>("indexed_time"),
+                    ],
+                )
+                .await?;
+        }
+        rows_done += rows.len();
+        info!("Recoded {} articles so far", rows_done);
+    }
+
+    info!(
+        "Recoded {} articles to {}, content went from {} to {} bytes",
+        rows_done,
+        encoding.as_str(),
+        before,
+        after
+    );
+    Ok(())
+}
+
+fn stored_size(stored: &Stored) -> usize {
+    stored.text.as_ref().map_or(0, String::len) + stored.bytes.as_ref().map_or(0, Vec::len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARTICLE: &str = "# Weekly update\n\nThis week we shipped the **new** indexer. \
+        The indexer now keeps up with the nucleus, and the indexer's lag is down.\n\n\
+        - faster sync\n- fewer retries\n- smaller tables\n";
+
+    #[test]
+    fn plain_content_round_trips() {
+        let stored = encode(ARTICLE, ContentEncoding::Plain).unwrap();
+        assert_eq!(stored.bytes, None);
+        assert_eq!(decode(stored, "plain").unwrap(), ARTICLE);
+    }
+
+    #[test]
+    fn zstd_content_round_trips() {
+        let stored = encode(ARTICLE, ContentEncoding::Zstd).unwrap();
+        assert_eq!(stored.text, None);
+        assert_eq!(decode(stored, "zstd").unwrap(), ARTICLE);
+    }
+
+    #[test]
+    fn repetitive_content_shrinks() {
+        let content = ARTICLE.repeat(20);
+        let stored = encode(&content, ContentEncoding::Zstd).unwrap();
+        assert!(stored_size(&stored) * 4 < content.len());
+    }
+
+    #[test]
+    fn unknown_or_missing_content_is_an_error() {
+        let stored = || encode(ARTICLE, ContentEncoding::Plain).unwrap();
+        assert!(matches!(
+            decode(stored(), "lz4"),
+            Err(ContentError::UnknownEncoding(_))
+        ));
+        assert!(matches!(
+            decode(stored(), "zstd"),
+            Err(ContentError::Missing(ContentEncoding::Zstd))
+        ));
+    }
+}
@@ -0,0 +1,273 @@
+use std::fmt;
+use tokio_postgres::Client;
+use tracing::info;
+use vemodel::UserId;
+
+use crate::config::Config;
+use crate::id::SqlId;
+use crate::model::Model;
+use crate::query::inline_list;
+
+/// The per-author counts `users` keeps, one per table of authored content.
+///
+/// They're moved along in the transaction of the change that creates,
+/// reassigns or deletes the row, so they only drift when rows are written
+/// some other way. `verify-counts` finds those that did and `verify-counts
+/// repair` recomputes them. In the subspaces of `VE_MODERATED_SUBSPACES`
+/// only approved comments count, see [`comment_counts`], so a comment's
+/// count moves with its status too. Those of an article moved in or out of
+/// one are only recounted by a repair, as are all of them when the setting
+/// changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counted {
+    Articles,
+    Comments,
+}
+
+impl Counted {
+    /// The count rows of `model` feed, `None` for a model without authors.
+    pub fn of(model: Model) -> Option<Self> {
+        match model {
+            Model::Subspace => None,
+            Model::Article => Some(Self::Articles),
+            Model::Comment => Some(Self::Comments),
+        }
+    }
+
+    fn table(self) -> &'static str {
+        match self {
+            Self::Articles => "articles",
+            Self::Comments => "comments",
+        }
+    }
+
+    /// The column counting rows of it, in `users` and `subspace_stats` alike.
+    pub fn column(self) -> &'static str {
+        match self {
+            Self::Articles => "article_count",
+            Self::Comments => "comment_count",
+        }
+    }
+}
+
+/// The condition a comment of `status` in `subspace`, SQL expressions both,
+/// meets when it counts: in the subspaces of `VE_MODERATED_SUBSPACES` only
+/// approved comments do, as in `visible_comments`. One whose subspace isn't
+/// known, its article not being stored, counts.
+pub fn comment_counts(config: &Config, status: &str, subspace: &str) -> String {
+    if config.moderated_subspaces.is_empty() {
+        return "TRUE".to_string();
+    }
+    // ids and statuses are plain integers, inlined like in the views
+    format!(
+        "(NOT COALESCE({} = ANY(ARRAY[{}]::{}[]), FALSE) OR {} = ANY(ARRAY[{}]::SMALLINT[]))",
+        subspace,
+        inline_list(&config.moderated_subspaces),
+        config.id_column_type.as_sql(),
+        status,
+        inline_list(&config.approved_comment_statuses)
+    )
+}
+
+/// Author of row `id` as stored, before a change to it, `None` without one
+/// or when it doesn't count.
+pub async fn author_of(
+    client: &Client,
+    config: &Config,
+    counted: Counted,
+    id: u64,
+) -> Result<Option<UserId>, tokio_postgres::Error> {
+    let query = match counted {
+        Counted::Articles => format!("SELECT author_id FROM {} WHERE id = $1", counted.table()),
+        // the subspace is that of the article the thread hangs off
+        Counted::Comments => format!(
+            "WITH RECURSIVE thread (post_id, target_type) AS (
+                SELECT post_id, target_type FROM comments WHERE id = $1
+                UNION
+                SELECT c.post_id, c.target_type
+                FROM thread JOIN comments c ON c.id = thread.post_id
+                WHERE thread.target_type = 'comment'
+             )
+             SELECT c.author_id FROM comments c
+             LEFT JOIN (
+                SELECT a.subspace_id FROM thread JOIN articles a ON a.id = thread.post_id
+                WHERE thread.target_type = 'article'
+             ) s ON TRUE
+             WHERE c.id = $1 AND {}",
+            comment_counts(config, "c.status", "s.subspace_id")
+        ),
+    };
+    let row = client.query_opt(&query, &[&SqlId(id)]).await?;
+    Ok(row.map(|row| UserId(row.get::<_, SqlId>("author_id").0)))
+}
+
+/// Moves a count from author `from` to author `to`, the authors of a row
+/// before and after a change to it.
+pub async fn shift(
+    client: &Client,
+    counted: Counted,
+    from: Option<UserId>,
+    to: Option<UserId>,
+) -> Result<(), tokio_postgres::Error> {
+    // an author first seen on a delete starts from 0 rather than -1
+    let query = format!(
+        "INSERT INTO users (id, {column}) VALUES ($1, GREATEST($2::BIGINT, 0))
+         ON CONFLICT (id) DO UPDATE SET {column} = users.{column} + $2::BIGINT",
+        column = counted.column()
+    );
+    for (author, delta) in deltas(from, to) {
+        client
+            .execute(&query, &[&SqlId(author.0), &delta])
+            .await?;
+    }
+    Ok(())
+}
+
+/// The `-1` and `+1` moving a count from what a row counted towards before a
+/// change to what it does after. Nothing for a row that stays with its
+/// author, or its subspace, whatever else changed.
+pub fn deltas<T: PartialEq>(from: Option<T>, to: Option<T>) -> Vec<(T, i64)> {
+    if from == to {
+        return Vec::new();
+    }
+    let from = from.map(|author| (author, -1));
+    let to = to.map(|author| (author, 1));
+    from.into_iter().chain(to).collect()
+}
+
+/// An author whose stored counts aren't those of the rows.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Drift {
+    pub author: UserId,
+    /// Stored and actual article count.
+    pub articles: (i64, i64),
+    /// Stored and actual comment count.
+    pub comments: (i64, i64),
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "author={} article_count={} actual={} comment_count={} actual={}",
+            self.author, self.articles.0, self.articles.1, self.comments.0, self.comments.1
+        )
+    }
+}
+
+// Counts of the rows per author, computed from scratch, with the comments
+// that count only.
+fn actual(config: &Config) -> String {
+    format!(
+        "
+        WITH RECURSIVE thread (id, post_id, target_type) AS (
+            SELECT id, post_id, target_type FROM comments
+            UNION
+            SELECT thread.id, c.post_id, c.target_type
+            FROM thread JOIN comments c ON c.id = thread.post_id
+            WHERE thread.target_type = 'comment'
+        ),
+        comment_subspaces AS (
+            SELECT thread.id, a.subspace_id FROM thread JOIN articles a ON a.id = thread.post_id
+            WHERE thread.target_type = 'article'
+        )
+        SELECT id, SUM(articles)::BIGINT AS articles, SUM(comments)::BIGINT AS comments
+        FROM (
+            SELECT author_id AS id, COUNT(*) AS articles, 0 AS comments FROM articles GROUP BY author_id
+            UNION ALL
+            SELECT c.author_id, 0, COUNT(*) FROM comments c
+            LEFT JOIN comment_subspaces s ON s.id = c.id
+            WHERE {}
+            GROUP BY c.author_id
+        ) counted
+        GROUP BY id",
+        comment_counts(config, "c.status", "s.subspace_id")
+    )
+}
+
+/// The authors whose counts drifted, by id.
+pub async fn drift(client: &Client, config: &Config) -> Result<Vec<Drift>, tokio_postgres::Error> {
+    let query = format!(
+        "WITH actual AS ({})
+         SELECT COALESCE(u.id, a.id) AS id,
+                COALESCE(u.article_count, 0) AS article_count, COALESCE(a.articles, 0) AS articles,
+                COALESCE(u.comment_count, 0) AS comment_count, COALESCE(a.comments, 0) AS comments
+         FROM users u FULL JOIN actual a ON a.id = u.id
+         WHERE COALESCE(u.article_count, 0) <> COALESCE(a.articles, 0)
+            OR COALESCE(u.comment_count, 0) <> COALESCE(a.comments, 0)
+         ORDER BY 1",
+        actual(config)
+    );
+    let rows = client.query(&query, &[]).await?;
+    Ok(rows
+        .iter()
+        .map(|row| Drift {
+            author: UserId(row.get::<_, SqlId>("id").0),
+            articles: (row.get("article_count"), row.get("articles")),
+            comments: (row.get("comment_count"), row.get("comments")),
+        })
+        .collect())
+}
+
+/// Recomputes every author's counts, returning how many authors changed.
+/// Best run with ingest stopped, a change applied meanwhile may be counted
+/// twice or not at all.
+pub async fn repair(client: &Client, config: &Config) -> Result<u64, tokio_postgres::Error> {
+    let query = format!(
+        "WITH actual AS ({}),
+         counted AS (
+             INSERT INTO users (id, article_count, comment_count)
+             SELECT id, articles, comments FROM actual
+             ON CONFLICT (id) DO UPDATE SET
+                article_count = EXCLUDED.article_count,
+                comment_count = EXCLUDED.comment_count
+             WHERE (users.article_count, users.comment_count)
+                IS DISTINCT FROM (EXCLUDED.article_count, EXCLUDED.comment_count)
+             RETURNING id
+         ),
+         emptied AS (
+             UPDATE users SET article_count = 0, comment_count = 0
+             WHERE id NOT IN (SELECT id FROM actual)
+               AND (article_count, comment_count) <> (0, 0)
+             RETURNING id
+         )
+         SELECT (SELECT COUNT(*) FROM counted) + (SELECT COUNT(*) FROM emptied) AS repaired",
+        actual(config)
+    );
+    let repaired: i64 = client.query_one(&query, &[]).await?.get("repaired");
+    info!("Recomputed the counts of {} authors", repaired);
+    Ok(repaired as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_move_only_between_differing_authors() {
+        let (alice, bob) = (Some(UserId(1)), Some(UserId(2)));
+        assert_eq!(deltas(None, alice), [(UserId(1), 1)]);
+        assert!(deltas(alice, alice).is_empty());
+        assert_eq!(deltas(alice, bob), [(UserId(1), -1), (UserId(2), 1)]);
+        assert_eq!(deltas(bob, None), [(UserId(2), -1)]);
+        assert!(deltas(None, None).is_empty());
+    }
+
+    #[test]
+    fn only_approved_comments_count_in_moderated_subspaces() {
+        let config = Config {
+            moderated_subspaces: Vec::new(),
+            ..Config::for_tests()
+        };
+        assert_eq!(comment_counts(&config, "c.status", "s.subspace_id"), "TRUE");
+        let config = Config {
+            moderated_subspaces: vec![3, 4],
+            approved_comment_statuses: vec![1],
+            ..config
+        };
+        assert_eq!(
+            comment_counts(&config, "c.status", "s.subspace_id"),
+            "(NOT COALESCE(s.subspace_id = ANY(ARRAY[3, 4]::BIGINT[]), FALSE) OR c.status = ANY(ARRAY[1]::SMALLINT[]))"
+        );
+    }
+}
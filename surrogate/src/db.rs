@@ -0,0 +1,1907 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::{Client, NoTls};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use vemodel::{Method, VeArticle, VeComment, VeSubspace};
+
+use crate::alert::{self, Condition};
+use crate::change_feed;
+use crate::config::{CommitPolicy, Config};
+use crate::counts::{self, Counted};
+use crate::dead_letter::{self, DeadLetter};
+use crate::id::{sql_id, SqlId};
+use crate::model::{Model, Registered};
+use crate::rpc::ChangeEvent;
+use crate::sink::BoxFuture;
+use crate::stats::{self, Contribution};
+use crate::thread::{self, Parent};
+use crate::{
+    content, etag, html, id, metrics, migrations, nickname, partition, query, schema, scrub,
+    text, trending,
+};
+
+/// A change on its way to the sinks, tagged with the request that produced it.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub reqnum: u64,
+    /// Storage key of the entity on the nucleus.
+    pub key: Vec<u8>,
+    pub method: Method,
+    pub entity: Entity,
+    pub correlation_id: String,
+    /// When the nucleus recorded the change, in unix milliseconds, if it said.
+    pub source_time: Option<i64>,
+    /// Whether the change is that of the nucleus event `reqnum`, recorded in
+    /// `applied_events` once applied. Expiry's deletes and the refetches of
+    /// a reqnum gap only borrow a reqnum, imports have none.
+    pub event: bool,
+}
+
+/// The entity a change is about, as fetched for creates and updates and by
+/// id for deletes.
+#[derive(Debug, Clone)]
+pub enum Entity {
+    Subspace(VeSubspace),
+    Article(VeArticle),
+    Comment(VeComment),
+    Deleted(Model, u64),
+}
+
+impl Entity {
+    pub fn model(&self) -> Model {
+        match self {
+            Self::Subspace(_) => Model::Subspace,
+            Self::Article(_) => Model::Article,
+            Self::Comment(_) => Model::Comment,
+            Self::Deleted(model, _) => *model,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        match self {
+            Self::Subspace(subspace) => subspace.id.0,
+            Self::Article(article) => article.id.0,
+            Self::Comment(comment) => comment.id.0,
+            Self::Deleted(_, id) => *id,
+        }
+    }
+
+    /// The entity as JSON, a bare id for deletes.
+    pub fn to_json(&self) -> serde_json::Result<serde_json::Value> {
+        match self {
+            Self::Subspace(subspace) => serde_json::to_value(subspace),
+            Self::Article(article) => serde_json::to_value(article),
+            Self::Comment(comment) => serde_json::to_value(comment),
+            Self::Deleted(_, id) => Ok(serde_json::Value::from(*id)),
+        }
+    }
+
+    /// Reads back what `to_json` gave for an entity of `model`.
+    pub fn from_json(model: Model, value: serde_json::Value) -> serde_json::Result<Self> {
+        (model.info().from_json)(value)
+    }
+
+    /// The subspace the entity is in, or is, when it says. Of deletes, only a
+    /// subspace's does.
+    pub fn subspace_id(&self) -> Option<u64> {
+        match self {
+            Self::Subspace(subspace) => subspace.subspace_id(),
+            Self::Article(article) => article.subspace_id(),
+            Self::Comment(comment) => comment.subspace_id(),
+            Self::Deleted(model, id) => (*model == Model::Subspace).then_some(*id),
+        }
+    }
+}
+
+/// Messages understood by the database task.
+#[derive(Debug)]
+pub enum Message {
+    Change(Change),
+    /// Every change of the current batch has been sent, reply with the reqnums
+    /// (and errors) of those that failed to apply.
+    Flush(oneshot::Sender<Vec<(u64, String)>>),
+    DeadLetter(DeadLetter),
+    /// Changes up to `sentinel` are settled, save it and commit if the policy
+    /// is due.
+    Checkpoint {
+        sentinel: u64,
+        ack: oneshot::Sender<Checkpointed>,
+    },
+    /// An operator moved the sentinel, save it and commit right away.
+    Reset {
+        sentinel: u64,
+        ack: oneshot::Sender<Checkpointed>,
+    },
+    /// Ingest is pausing at `sentinel`, commit right away so nothing is left
+    /// open, holding locks, while it is.
+    Pause {
+        sentinel: u64,
+        ack: oneshot::Sender<Checkpointed>,
+    },
+}
+
+impl Change {
+    pub fn new(event: &ChangeEvent, method: Method, entity: Entity, correlation_id: &str) -> Self {
+        Self {
+            reqnum: event.reqnum,
+            key: event.key.clone(),
+            method,
+            entity,
+            correlation_id: correlation_id.to_string(),
+            source_time: event.source_time,
+            event: true,
+        }
+    }
+
+    /// The delete of article `reqnum` as the change of event `reqnum`, for
+    /// tests that only follow changes through.
+    #[cfg(test)]
+    pub(crate) fn fixture(reqnum: u64) -> Self {
+        Self {
+            reqnum,
+            key: Vec::new(),
+            method: Method::Delete,
+            entity: Entity::Deleted(Model::Article, reqnum),
+            correlation_id: reqnum.to_string(),
+            source_time: None,
+            event: true,
+        }
+    }
+}
+
+/// Entities for tests to start from, with struct update syntax for the fields
+/// a test is about. Not test-only, the binary's tests and the benchmark can't
+/// see the library's.
+pub mod fixture {
+    use vemodel::{
+        ArticleId, CommentId, SubspaceId, UserId, VeArticle, VeComment, VeSubspace, Weight,
+    };
+
+    /// Subspace `id`, titled after it.
+    pub fn subspace(id: u64) -> VeSubspace {
+        VeSubspace {
+            id: SubspaceId(id),
+            title: format!("subspace {}", id),
+            slug: id.to_string(),
+            description: String::new(),
+            banner: String::new(),
+            status: 0,
+            weight: Weight(0),
+            created_time: 0,
+        }
+    }
+
+    /// Article `id` of subspace 1 by user 1, titled after it.
+    pub fn article(id: u64) -> VeArticle {
+        VeArticle {
+            id: ArticleId(id),
+            title: format!("article {}", id),
+            content: String::new(),
+            author_id: UserId(1),
+            author_nickname: "alice".to_string(),
+            subspace_id: SubspaceId(1),
+            ext_link: String::new(),
+            status: 0,
+            weight: Weight(0),
+            created_time: 0,
+            updated_time: 0,
+        }
+    }
+
+    /// Comment `id` on article 1 by user 1, with no content.
+    pub fn comment(id: u64) -> VeComment {
+        VeComment {
+            id: CommentId(id),
+            content: String::new(),
+            author_id: UserId(1),
+            author_nickname: "alice".to_string(),
+            post_id: ArticleId(1),
+            status: 0,
+            weight: Weight(0),
+            created_time: 0,
+        }
+    }
+}
+
+// Where `CREATE DATABASE` is issued from, every server has it.
+const MAINTENANCE_DATABASE: &str = "postgres";
+
+// wait before applying again a change that conflicted with another transaction
+const CONFLICT_BACKOFF: Duration = Duration::from_millis(50);
+
+// text columns read back sorted or compared, kept in `VE_TEXT_COLLATION`
+const COLLATED: [(&str, &str); 2] = [("subspaces", "title"), ("articles", "title")];
+
+/// Connects to `postgres_config`, first creating its database when it's
+/// missing and `VE_CREATE_DATABASE_IF_MISSING` is set.
+pub async fn connect(config: &Config) -> Result<Client, Box<dyn std::error::Error>> {
+    match open(&config.postgres_config.parse()?).await {
+        Err(e) if config.create_database_if_missing && is_missing_database(&e) => {
+            create_database(config).await?;
+            Ok(open(&config.postgres_config.parse()?).await?)
+        }
+        result => Ok(result?),
+    }
+}
+
+async fn open(pg_config: &tokio_postgres::Config) -> Result<Client, tokio_postgres::Error> {
+    let (client, connection) = pg_config.connect(NoTls).await?;
+
+    // Spawn connection handler
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("PostgreSQL connection error: {}", e);
+            alert::raise(
+                Condition::DatabaseFailures,
+                format!("PostgreSQL connection error: {}", e),
+            );
+        }
+    });
+
+    // asked for on startup already, set again so no pooler in between can
+    // have text transcoded, or mangled, on the way
+    client.batch_execute("SET client_encoding = 'UTF8'").await?;
+    let server_encoding: String = client.query_one("SHOW server_encoding", &[]).await?.get(0);
+    if server_encoding == "SQL_ASCII" {
+        warn!("The database encoding is SQL_ASCII, Postgres neither checks text nor sorts or compares non-ASCII text meaningfully");
+    }
+
+    Ok(client)
+}
+
+fn is_missing_database(e: &tokio_postgres::Error) -> bool {
+    e.code()
+        .is_some_and(|code| *code == SqlState::INVALID_CATALOG_NAME)
+}
+
+// Two creating the same database at once can make the loser trip over the
+// catalog's unique index rather than get the usual error.
+fn is_existing_database(e: &tokio_postgres::Error) -> bool {
+    e.code().is_some_and(|code| {
+        *code == SqlState::DUPLICATE_DATABASE || *code == SqlState::UNIQUE_VIOLATION
+    })
+}
+
+// Creates the database of `postgres_config`, from the maintenance database
+// of the same server. Another surrogate creating it first is as good.
+async fn create_database(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut pg_config: tokio_postgres::Config = config.postgres_config.parse()?;
+    let dbname = pg_config
+        .get_dbname()
+        .ok_or("VE_CREATE_DATABASE_IF_MISSING needs a dbname in VE_POSTGRES_CONFIG")?
+        .to_string();
+    pg_config.dbname(MAINTENANCE_DATABASE);
+    let client = open(&pg_config).await?;
+    match client
+        .batch_execute(&format!("CREATE DATABASE {}", quote_ident(&dbname)))
+        .await
+    {
+        Ok(()) => info!("Created database {}", dbname),
+        Err(e) if is_existing_database(&e) => info!("Database {} was created meanwhile", dbname),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// `name` as a Postgres identifier, quoted so any name is taken as is.
+pub fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Gets the database ready for this build. With `migrate`, the schema is
+/// brought up to date, created from scratch on a fresh database. Without, it
+/// is left to a separate `migrate` run and only checked.
+///
+/// It's all one transaction, so readers never find the views missing while
+/// they're recreated, and a setup that fails leaves the database as it was.
+pub async fn setup_database(
+    client: &mut Client,
+    config: &Config,
+    migrate: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut tx = client.transaction().await.map_err(step("begin"))?;
+    let client = &mut tx;
+    trending::drop_view(client)
+        .await
+        .map_err(step("drop the trending view"))?;
+    query::drop_views(client)
+        .await
+        .map_err(step("drop the read views"))?;
+    if migrate {
+        migrations::run_migrations(client)
+            .await
+            .map_err(step("migrate"))?;
+        migrations::validate_deferred(client)
+            .await
+            .map_err(step("validate deferred constraints"))?;
+        id::set_column_type(client, config.id_column_type)
+            .await
+            .map_err(step("set the id column type"))?;
+        partition::setup(client, config)
+            .await
+            .map_err(step("set up partitions"))?;
+        set_collation(client, config.text_collation.as_deref())
+            .await
+            .map_err(step("set the text collation"))?;
+        backfill_description_plain(client)
+            .await
+            .map_err(step("backfill description_plain"))?;
+    } else {
+        schema::validate(client, config.id_column_type)
+            .await
+            .map_err(step("validate the schema"))?;
+    }
+    drop_orphaned_foreign_keys(client, config)
+        .await
+        .map_err(step("drop orphaned foreign keys"))?;
+    // Views depend on config, so they're recreated on every start rather than migrated
+    query::create_views(client, config)
+        .await
+        .map_err(step("create the read views"))?;
+    query::create_subspace_views(client, config)
+        .await
+        .map_err(step("create the subspace views"))?;
+    trending::create_view(client)
+        .await
+        .map_err(step("create the trending view"))?;
+    tx.commit().await.map_err(step("commit"))?;
+    Ok(())
+}
+
+// Says which step of `setup_database` failed.
+fn step<E: std::fmt::Display>(name: &'static str) -> impl FnOnce(E) -> String {
+    move |e| format!("database setup failed to {}: {}", name, e)
+}
+
+// Puts the `COLLATED` columns in `collation`, or back in the database's
+// default without one. Their indexes are rebuilt where it changes, and the
+// views depending on them are dropped by then.
+async fn set_collation<C: tokio_postgres::GenericClient>(
+    client: &C,
+    collation: Option<&str>,
+) -> Result<(), tokio_postgres::Error> {
+    for (table, column) in COLLATED {
+        // NULL for the default
+        let current: Option<String> = client
+            .query_one(
+                "SELECT collation_name FROM information_schema.columns
+                 WHERE table_schema = current_schema() AND table_name = $1 AND column_name = $2",
+                &[&table, &column],
+            )
+            .await?
+            .get(0);
+        if current.as_deref() == collation {
+            continue;
+        }
+        client
+            .batch_execute(&format!(
+                "ALTER TABLE {} ALTER COLUMN {} TYPE VARCHAR COLLATE {}",
+                table,
+                column,
+                quote_ident(collation.unwrap_or("default"))
+            ))
+            .await?;
+        info!(
+            "Collated {}.{} as {}",
+            table,
+            column,
+            collation.unwrap_or("the database default")
+        );
+    }
+    Ok(())
+}
+
+// Fills in `description_plain` for subspaces written before it existed.
+// There are few enough subspaces to do it in one go.
+async fn backfill_description_plain<C: tokio_postgres::GenericClient>(
+    client: &C,
+) -> Result<(), tokio_postgres::Error> {
+    let rows = client
+        .query(
+            "SELECT id, description FROM subspaces
+             WHERE description_plain IS NULL AND description IS NOT NULL",
+            &[],
+        )
+        .await?;
+    for row in &rows {
+        let description: String = row.get("description");
+        client
+            .execute(
+                "UPDATE subspaces SET description_plain = $2 WHERE id = $1",
+                &[&row.get::<_, SqlId>("id"), &text::strip_markup(&description)],
+            )
+            .await?;
+    }
+    if !rows.is_empty() {
+        info!("Filled in description_plain of {} subspaces", rows.len());
+    }
+    Ok(())
+}
+
+// A model indexed without the one its rows reference would have every insert
+// violate the foreign key, the referenced table stays empty. The keys aren't
+// put back when the other model is enabled again, existing rows may well
+// reference entities that were never indexed.
+async fn drop_orphaned_foreign_keys<C: tokio_postgres::GenericClient>(
+    client: &C,
+    config: &Config,
+) -> Result<(), tokio_postgres::Error> {
+    let references = [
+        (
+            Model::Article,
+            Model::Subspace,
+            "articles",
+            "articles_subspace_id_fkey",
+        ),
+        (
+            Model::Comment,
+            Model::Article,
+            "comments",
+            "comments_post_id_fkey",
+        ),
+    ];
+    for (model, referenced, table, constraint) in references {
+        if config.indexes(model) && !config.indexes(referenced) {
+            warn!(
+                "{}s are indexed without {}s, dropping {}",
+                model.as_str(),
+                referenced.as_str(),
+                constraint
+            );
+            client
+                .batch_execute(&format!(
+                    "ALTER TABLE {} DROP CONSTRAINT IF EXISTS {}",
+                    table, constraint
+                ))
+                .await?;
+        }
+    }
+    // a reply references a comment, the writer checks the parent exists instead
+    if config.comment_replies {
+        client
+            .batch_execute("ALTER TABLE comments DROP CONSTRAINT IF EXISTS comments_post_id_fkey")
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn load_sentinel(
+    client: &Client,
+    avs_id: &str,
+) -> Result<Option<u64>, tokio_postgres::Error> {
+    let row = client
+        .query_opt(
+            "SELECT sentinel FROM sync_state WHERE avs_id = $1",
+            &[&avs_id],
+        )
+        .await?;
+    Ok(row.map(|row| row.get::<_, i64>(0) as u64))
+}
+
+/// Saves `sentinel` as the first of the AVS, unless one is saved already,
+/// returning whether it was.
+pub async fn seed_sentinel(
+    client: &Client,
+    avs_id: &str,
+    sentinel: u64,
+) -> Result<bool, tokio_postgres::Error> {
+    let seeded = client
+        .execute(
+            "INSERT INTO sync_state (avs_id, sentinel, updated_time)
+             VALUES ($1, $2, EXTRACT(EPOCH FROM now())::BIGINT)
+             ON CONFLICT (avs_id) DO NOTHING",
+            &[&avs_id, &(sentinel as i64)],
+        )
+        .await?;
+    Ok(seeded == 1)
+}
+
+async fn save_sentinel(
+    client: &Client,
+    avs_id: &str,
+    sentinel: u64,
+) -> Result<(), tokio_postgres::Error> {
+    client
+        .execute(
+            "INSERT INTO sync_state (avs_id, sentinel, updated_time)
+             VALUES ($1, $2, EXTRACT(EPOCH FROM now())::BIGINT)
+             ON CONFLICT (avs_id) DO UPDATE SET
+                sentinel = $2,
+                updated_time = EXTRACT(EPOCH FROM now())::BIGINT",
+            &[&avs_id, &(sentinel as i64)],
+        )
+        .await?;
+    Ok(())
+}
+
+/// What the error of a change starts with when the row it references isn't
+/// there, e.g. a comment on an article not indexed yet. The polling loop
+/// retries those for `VE_PARENT_GAP_ATTEMPTS` cycles, giving the parent time
+/// to arrive, rather than `VE_MAX_ATTEMPTS`.
+pub const PARENT_GAP: &str = "parent not indexed yet: ";
+
+// Whether `error` is Postgres failing a statement for a transaction it ran
+// alongside, which the same statement may well get past once that's done.
+fn conflicts(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<tokio_postgres::Error>()
+        .and_then(tokio_postgres::Error::code)
+        .is_some_and(|code| {
+            *code == SqlState::T_R_SERIALIZATION_FAILURE || *code == SqlState::T_R_DEADLOCK_DETECTED
+        })
+}
+
+// How long to wait before the `retry`th go at a change that conflicted,
+// doubling from `CONFLICT_BACKOFF`.
+fn conflict_backoff(retry: u32) -> Duration {
+    CONFLICT_BACKOFF * 2u32.saturating_pow(retry.saturating_sub(1)).min(64)
+}
+
+fn lacks_parent(error: &(dyn std::error::Error + 'static)) -> bool {
+    error.is::<MissingParent>()
+        || error
+            .downcast_ref::<tokio_postgres::Error>()
+            .and_then(tokio_postgres::Error::code)
+            .is_some_and(|code| *code == SqlState::FOREIGN_KEY_VIOLATION)
+}
+
+/// A comment whose `post_id` is neither an article nor a comment indexed,
+/// what the foreign key would have caught were it not dropped for replies.
+#[derive(Debug)]
+pub struct MissingParent(pub u64);
+
+impl std::fmt::Display for MissingParent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no article or comment {} to reply to", self.0)
+    }
+}
+
+impl std::error::Error for MissingParent {}
+
+/// What a comment replies to, `article` or `comment`, by which of them has
+/// the id of its `post_id`. The nucleus doesn't say, so an id both an
+/// article and a comment have is taken for the article, as it always was.
+pub async fn reply_target(
+    client: &Client,
+    post_id: SqlId,
+) -> Result<&'static str, Box<dyn std::error::Error>> {
+    let row = client
+        .query_one(
+            "SELECT EXISTS (SELECT 1 FROM articles WHERE id = $1),
+                    EXISTS (SELECT 1 FROM comments WHERE id = $1)",
+            &[&post_id],
+        )
+        .await?;
+    match (row.get(0), row.get(1)) {
+        (true, _) => Ok("article"),
+        (false, true) => Ok("comment"),
+        (false, false) => Err(MissingParent(post_id.0).into()),
+    }
+}
+
+/// Where the database task stands after a [`Message::Checkpoint`].
+#[derive(Debug)]
+pub struct Checkpointed {
+    /// Reqnum up to which changes are durably committed.
+    pub committed: u64,
+    /// Whether changes past `committed` are still held in the open transaction.
+    /// When not, the polling loop resumes from `committed`.
+    pub pending: bool,
+}
+
+/// What the writer needs of the database, so its commit boundary can be
+/// exercised without one.
+trait Store: Send + Sync {
+    /// Runs a transaction control statement, `BEGIN`, `COMMIT` and the like.
+    fn execute<'a>(&'a self, statement: &'a str) -> BoxFuture<'a, Result<(), String>>;
+
+    /// Applies `change`, failing with whether it was for a conflict with
+    /// another transaction, which aborts the open one but may well go through
+    /// in a new one.
+    fn apply<'a>(&'a self, change: &'a Change) -> BoxFuture<'a, Result<(), (bool, String)>>;
+
+    fn insert_dead_letter<'a>(
+        &'a self,
+        letter: &'a DeadLetter,
+    ) -> BoxFuture<'a, Result<(), String>>;
+
+    fn save_sentinel(&self, sentinel: u64) -> BoxFuture<'_, Result<(), String>>;
+}
+
+struct PgStore {
+    client: Client,
+    config: Config,
+}
+
+impl Store for PgStore {
+    fn execute<'a>(&'a self, statement: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            self.client
+                .batch_execute(statement)
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    fn apply<'a>(&'a self, change: &'a Change) -> BoxFuture<'a, Result<(), (bool, String)>> {
+        Box::pin(self.try_apply(change))
+    }
+
+    fn insert_dead_letter<'a>(
+        &'a self,
+        letter: &'a DeadLetter,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            dead_letter::insert(&self.client, letter)
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    fn save_sentinel(&self, sentinel: u64) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let avs_id = self.config.avs_id.as_str();
+            save_sentinel(&self.client, avs_id, sentinel)
+                .await
+                .map_err(|e| e.to_string())?;
+            match self.config.applied_events_retention {
+                Some(retention) => prune_applied(&self.client, avs_id, retention)
+                    .await
+                    .map_err(|e| e.to_string()),
+                None => Ok(()),
+            }
+        })
+    }
+}
+
+// Forgets the events applied longer than `retention` ago, in the transaction
+// saving the sentinel. Whatever the sentinel, a replay is only told apart
+// from new events by the rows kept here.
+async fn prune_applied(
+    client: &Client,
+    avs_id: &str,
+    retention: Duration,
+) -> Result<(), tokio_postgres::Error> {
+    let retention = i64::try_from(retention.as_secs()).unwrap_or(i64::MAX);
+    client
+        .execute(
+            "DELETE FROM applied_events
+             WHERE avs_id = $1 AND applied_time < EXTRACT(EPOCH FROM now())::BIGINT - $2",
+            &[&avs_id, &retention],
+        )
+        .await?;
+    Ok(())
+}
+
+impl PgStore {
+    // Applies `change` within the savepoint `apply_change` took, failing with
+    // whether it was for a conflict with another transaction.
+    async fn try_apply(&self, change: &Change) -> Result<(), (bool, String)> {
+        let applied = if change.event {
+            self.record_applied(change).await
+        } else {
+            Ok(true)
+        };
+        if !applied.map_err(|e| (conflicts(e.as_ref()), e.to_string()))? {
+            metrics::REAPPLIED_EVENTS.inc();
+            debug!(correlation_id = %change.correlation_id, "Event {} is applied already, passing over it", change.reqnum);
+            return Ok(());
+        }
+        let Err(e) = handle_database_operation(&self.client, &self.config, change).await else {
+            return Ok(());
+        };
+        let error = if lacks_parent(e.as_ref()) {
+            format!("{}{}", PARENT_GAP, e)
+        } else {
+            e.to_string()
+        };
+        if conflicts(e.as_ref()) {
+            Err((true, error))
+        } else if scrub::rejects_text(e.as_ref()) {
+            self.apply_scrubbed(change, error)
+                .await
+                .map_err(|error| (false, error))
+        } else {
+            Err((false, error))
+        }
+    }
+
+    // Records the event of `change` in `applied_events`, within the savepoint
+    // of the change so it only stays if the change does, returning whether
+    // it wasn't there already. Events are told apart by key and method as
+    // well as reqnum, `VE_DUPLICATE_REQNUMS=apply` has several share one.
+    async fn record_applied(&self, change: &Change) -> Result<bool, Box<dyn std::error::Error>> {
+        let inserted = self
+            .client
+            .execute(
+                "INSERT INTO applied_events (avs_id, reqnum, key, method, applied_time)
+                 VALUES ($1, $2, $3, $4, EXTRACT(EPOCH FROM now())::BIGINT)
+                 ON CONFLICT (avs_id, reqnum, key, method) DO NOTHING",
+                &[
+                    &self.config.avs_id.as_str(),
+                    &sql_id(change.reqnum)?,
+                    &change.key,
+                    &dead_letter::method_name(change.method),
+                ],
+            )
+            .await?;
+        Ok(inserted == 1)
+    }
+
+    // Applies `change` again with its text made acceptable to Postgres, keeping
+    // the original aside. Fails with `error` when there's nothing to fix.
+    async fn apply_scrubbed(&self, change: &Change, error: String) -> Result<(), String> {
+        if matches!(change.entity, Entity::Deleted(..)) {
+            return Err(error);
+        }
+        let mut value = change.entity.to_json().map_err(|e| e.to_string())?;
+        let fixes = scrub::scrub(&mut value, self.config.max_text_bytes);
+        if fixes.is_empty() {
+            return Err(error);
+        }
+        let scrubbed = Change {
+            entity: Entity::from_json(change.entity.model(), value).map_err(|e| e.to_string())?,
+            ..change.clone()
+        };
+        // the failed statement aborted the transaction, back to the savepoint `apply_change` took
+        self.client
+            .batch_execute("ROLLBACK TO SAVEPOINT change")
+            .await
+            .map_err(|e| e.to_string())?;
+        if change.event {
+            self.record_applied(change)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        handle_database_operation(&self.client, &self.config, &scrubbed)
+            .await
+            .map_err(|e| e.to_string())?;
+        scrub::record(&self.client, change, &error, &fixes)
+            .await
+            .map_err(|e| e.to_string())?;
+        warn!(correlation_id = %change.correlation_id, "Postgres rejected the text of the change, stored it scrubbed: {}", error);
+        Ok(())
+    }
+}
+
+// The transaction the writer keeps open until the commit policy is due.
+struct Batch {
+    started: Instant,
+    events: usize,
+    /// A dead letter failed to record, so events it stood for would be
+    /// skipped without a trace were the batch committed.
+    poisoned: bool,
+    /// The changes applied, and when they were received, for
+    /// `surrogate_apply_latency_seconds` once they're committed.
+    applied: Vec<(Model, Method, Instant)>,
+    /// What went into the transaction, in order, to go through again in a new
+    /// one when a conflict has Postgres abort it.
+    written: Vec<Write>,
+}
+
+// A change, with when it was received, or a dead letter the writer was sent.
+enum Write {
+    Change(Change, Instant),
+    DeadLetter(DeadLetter),
+}
+
+/// Applies changes sent by the polling loop until the channel closes.
+///
+/// Changes accumulate in one transaction, each under its own savepoint so a
+/// failing change doesn't take the others down with it, and the transaction
+/// is committed along with the sentinel according to the commit policy.
+///
+/// Delivery to Postgres is at least once: the sentinel is only ever saved in
+/// the transaction holding the changes (and dead letters) it covers, and the
+/// polling loop only ever hands the nucleus a sentinel that has committed. A
+/// crash or failed commit anywhere loses the open transaction as a whole, and
+/// its events are read and applied again, never skipped. Secondary sinks get
+/// the same guarantee only with `VE_SENTINEL_ADVANCE=all`, otherwise changes
+/// still queued for them when the process dies are lost.
+///
+/// Every event applied is recorded in `applied_events`, in the transaction
+/// applying it, and one recorded already is passed over, so events read
+/// again, after an operator's rewind say, or past a failed one the sentinel
+/// stops short of, are applied once all the same. Events compacted away or
+/// dead-lettered aren't recorded, and those recorded are only forgotten with
+/// `VE_APPLIED_EVENTS_RETENTION_SECS`, once older than that.
+pub async fn run_writer(
+    client: Client,
+    config: Config,
+    committed: u64,
+    rx: mpsc::Receiver<Message>,
+) {
+    let (policy, conflict_retries) = (config.commit_policy, config.conflict_retries);
+    let store = PgStore { client, config };
+    write_loop(store, policy, conflict_retries, committed, rx).await
+}
+
+async fn write_loop<S: Store>(
+    store: S,
+    policy: CommitPolicy,
+    conflict_retries: u32,
+    mut committed: u64,
+    mut rx: mpsc::Receiver<Message>,
+) {
+    let mut failed = Vec::new();
+    let mut batch: Option<Batch> = None;
+    while let Some(message) = rx.recv().await {
+        match message {
+            Message::Change(change) => {
+                let write = Write::Change(change, Instant::now());
+                write_batched(&store, &mut batch, &mut failed, conflict_retries, write).await;
+            }
+            Message::Flush(ack) => {
+                // the polling loop only goes away on shutdown, nobody to tell then
+                let _ = ack.send(std::mem::take(&mut failed));
+            }
+            Message::DeadLetter(letter) => {
+                let write = Write::DeadLetter(letter);
+                write_batched(&store, &mut batch, &mut failed, conflict_retries, write).await;
+            }
+            Message::Checkpoint { sentinel, ack } => {
+                let _ =
+                    ack.send(settle(&store, policy, &mut batch, sentinel, &mut committed).await);
+            }
+            // a cycle policy is always due
+            Message::Reset { sentinel, ack } | Message::Pause { sentinel, ack } => {
+                let _ = ack.send(
+                    settle(
+                        &store,
+                        CommitPolicy::Cycle,
+                        &mut batch,
+                        sentinel,
+                        &mut committed,
+                    )
+                    .await,
+                );
+            }
+        }
+    }
+}
+
+// Writes `write` in the open transaction. A change failing on a conflict with
+// another transaction has Postgres abort the transaction as a whole, so it's
+// rolled back and everything it held written again in a new one after a
+// backoff, up to `conflict_retries` times before the change fails like any
+// other.
+async fn write_batched<S: Store>(
+    store: &S,
+    batch: &mut Option<Batch>,
+    failed: &mut Vec<(u64, String)>,
+    conflict_retries: u32,
+    write: Write,
+) {
+    let mut pending = VecDeque::from([write]);
+    let mut retries = 0;
+    while let Some(write) = pending.pop_front() {
+        let (change, received) = match write {
+            Write::Change(change, received) => (change, received),
+            Write::DeadLetter(letter) => {
+                write_dead_letter(store, batch, letter).await;
+                continue;
+            }
+        };
+        let span = info_span!(
+            "apply",
+            correlation_id = %change.correlation_id,
+            model = change.entity.model().as_str()
+        );
+        match apply_change(store, batch, &change, received)
+            .instrument(span)
+            .await
+        {
+            Ok(()) => {
+                if let Some(batch) = batch {
+                    batch.written.push(Write::Change(change, received));
+                }
+            }
+            Err((true, e)) if retries < conflict_retries => {
+                retries += 1;
+                metrics::CONFLICT_RETRIES.inc();
+                let delay = conflict_backoff(retries);
+                warn!(correlation_id = %change.correlation_id, "Conflicting transaction, rolling back to write the batch again in {:?} ({}/{}): {}", delay, retries, conflict_retries, e);
+                if let Err(e) = store.execute("ROLLBACK").await {
+                    error!("Failed to roll back: {}", e);
+                }
+                let written = batch.take().map_or_else(Vec::new, |open| open.written);
+                pending = written
+                    .into_iter()
+                    .chain([Write::Change(change, received)])
+                    .chain(pending)
+                    .collect();
+                tokio::time::sleep(delay).await;
+            }
+            Err((_, e)) => {
+                error!(correlation_id = %change.correlation_id, "Database operation error: {}", e);
+                failed.push((change.reqnum, e));
+            }
+        }
+    }
+}
+
+async fn write_dead_letter<S: Store>(store: &S, batch: &mut Option<Batch>, letter: DeadLetter) {
+    let result = match begin(store, batch).await {
+        Ok(()) => store.insert_dead_letter(&letter).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = &result {
+        error!(
+            reqnum = letter.reqnum,
+            "Failed to record dead letter: {}", e
+        );
+    }
+    if let Some(batch) = batch {
+        batch.poisoned |= result.is_err();
+        // one that failed too, a transaction written again tries it again
+        batch.written.push(Write::DeadLetter(letter));
+    }
+}
+
+async fn begin<S: Store>(store: &S, batch: &mut Option<Batch>) -> Result<(), String> {
+    if batch.is_none() {
+        store.execute("BEGIN").await?;
+        *batch = Some(Batch {
+            started: Instant::now(),
+            events: 0,
+            poisoned: false,
+            applied: Vec::new(),
+            written: Vec::new(),
+        });
+    }
+    Ok(())
+}
+
+async fn apply_change<S: Store>(
+    store: &S,
+    batch: &mut Option<Batch>,
+    change: &Change,
+    received: Instant,
+) -> Result<(), (bool, String)> {
+    let failed = |e| (false, e);
+    begin(store, batch).await.map_err(failed)?;
+    store.execute("SAVEPOINT change").await.map_err(failed)?;
+
+    let result = store.apply(change).await;
+    let end = match &result {
+        Ok(()) => "RELEASE SAVEPOINT change",
+        // the whole transaction goes, see `write_batched`
+        Err((true, _)) => return result,
+        Err((false, _)) => "ROLLBACK TO SAVEPOINT change",
+    };
+    store.execute(end).await.map_err(failed)?;
+
+    if let Some(batch) = batch {
+        batch.events += 1;
+        if result.is_ok() {
+            batch
+                .applied
+                .push((change.entity.model(), change.method, received));
+        }
+    }
+    if let (Ok(()), Some(source_time)) = (&result, change.source_time) {
+        // a nucleus clock ahead of ours would make for negative ages
+        let age = (unix_millis() - source_time).max(0);
+        metrics::EVENT_AGE.observe(Duration::from_millis(age as u64));
+    }
+    result
+}
+
+// Checkpoints at `sentinel`, rolling the open transaction back if that fails.
+async fn settle<S: Store>(
+    store: &S,
+    policy: CommitPolicy,
+    batch: &mut Option<Batch>,
+    sentinel: u64,
+    committed: &mut u64,
+) -> Checkpointed {
+    let pending = match checkpoint(store, policy, batch, sentinel).await {
+        Ok(true) => {
+            *committed = sentinel;
+            alert::recovered(Condition::DatabaseFailures);
+            false
+        }
+        Ok(false) => true,
+        Err(e) => {
+            // whatever the open transaction held is gone, start over from `committed`
+            error!("Failed to commit up to sentinel {}: {}", sentinel, e);
+            alert::failed(
+                Condition::DatabaseFailures,
+                format!("failed to commit up to sentinel {}: {}", sentinel, e),
+            );
+            if let Err(e) = store.execute("ROLLBACK").await {
+                error!("Failed to roll back: {}", e);
+            }
+            *batch = None;
+            false
+        }
+    };
+    Checkpointed {
+        committed: *committed,
+        pending,
+    }
+}
+
+// Saves the sentinel and commits when the policy says so, returning whether it did.
+async fn checkpoint<S: Store>(
+    store: &S,
+    policy: CommitPolicy,
+    batch: &mut Option<Batch>,
+    sentinel: u64,
+) -> Result<bool, String> {
+    if batch.as_ref().is_some_and(|open| open.poisoned) {
+        return Err("a dead letter of the open transaction failed to record".to_string());
+    }
+    begin(store, batch).await?;
+    store.save_sentinel(sentinel).await?;
+
+    let due = batch
+        .as_ref()
+        .is_none_or(|open| policy.is_due(open.events, open.started.elapsed()));
+    if due {
+        store.execute("COMMIT").await?;
+        for (model, method, received) in batch.take().into_iter().flat_map(|open| open.applied) {
+            metrics::APPLY_LATENCY.observe(model, method, received.elapsed());
+        }
+    }
+    Ok(due)
+}
+
+pub async fn handle_database_operation(
+    client: &Client,
+    config: &Config,
+    change: &Change,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !config.change_feed {
+        return write_change(client, config, change).await;
+    }
+    let capture = change_feed::Capture::before(client, change).await?;
+    write_change(client, config, change).await?;
+    capture.record(client, config, change).await?;
+    Ok(())
+}
+
+async fn write_change(
+    client: &Client,
+    config: &Config,
+    change: &Change,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let indexed_time = unix_millis();
+    match &change.entity {
+        Entity::Subspace(subspace) => {
+            let id = SqlId(subspace.id.0);
+            let row = client.query_one(
+                &format!("INSERT INTO {} (id, title, slug, description, banner, status, weight, created_time,
+                                      source_time, indexed_time, description_plain, source, etag)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+                 ON CONFLICT (id) DO UPDATE SET
+                    title = $2,
+                    slug = $3,
+                    description = $4,
+                    banner = $5,
+                    status = $6,
+                    weight = $7,
+                    created_time = $8,
+                    source_time = $9,
+                    indexed_time = $10,
+                    description_plain = $11,
+                    source = $12,
+                    etag = $13
+                 RETURNING (xmax = 0) AS inserted", Model::Subspace.table()),
+                &[
+                    &id,
+                    &subspace.title,
+                    &subspace.slug,
+                    &absent_as_null(&subspace.description),
+                    &absent_as_null(&subspace.banner),
+                    &subspace.status,
+                    &subspace.weight.check()?.0,
+                    &subspace.created_time,
+                    &change.source_time,
+                    &indexed_time,
+                    &absent_as_null(&text::strip_markup(&subspace.description)),
+                    &config.source,
+                    &etag::of(subspace),
+                ],
+            ).await?;
+            count_upsert(row.get("inserted"));
+            // the slug is denormalized onto the subspace's articles, for feeds to build URLs without a join
+            client.execute(
+                "UPDATE articles SET subspace_slug = $2 WHERE subspace_id = $1 AND subspace_slug IS DISTINCT FROM $2",
+                &[&id, &subspace.slug],
+            ).await?;
+            info!("Upserted subspace: {}", subspace.id);
+        }
+        Entity::Article(article) => {
+            let (id, author_id, subspace_id) = (
+                SqlId(article.id.0),
+                SqlId(article.author_id.0),
+                SqlId(article.subspace_id.0),
+            );
+            let excerpt = text::excerpt(&article.content, config.excerpt_length);
+            let stored = content::encode(&article.content, config.content_encoding)?;
+            let content_html = config
+                .content_html_tags
+                .as_ref()
+                .map(|tags| html::render(&article.content, tags));
+            let previous =
+                counts::author_of(client, config, Counted::Articles, article.id.0).await?;
+            let counted_in =
+                stats::contribution_of(client, config, Counted::Articles, article.id.0).await?;
+            move_out_of_partition(
+                client,
+                config,
+                Model::Article.table(),
+                id,
+                article.created_time,
+            )
+            .await?;
+            let row = client.query_one(
+                &format!("INSERT INTO {} (id, title, content, author_id, author_nickname, subspace_id, 
+                                     ext_link, status, weight, created_time, updated_time, excerpt,
+                                     content_bytes, content_encoding, source_time, indexed_time,
+                                     author_nickname_sanitized, source, etag, subspace_slug, content_html, edited)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19,
+                         (SELECT slug FROM subspaces WHERE id = $6), $20, $21)
+                 ON CONFLICT {} DO UPDATE SET
+                    title = $2,
+                    content = $3,
+                    author_id = $4,
+                    author_nickname = $5,
+                    subspace_id = $6,
+                    ext_link = $7,
+                    status = $8,
+                    weight = $9,
+                    created_time = $10,
+                    updated_time = $11,
+                    excerpt = $12,
+                    content_bytes = $13,
+                    content_encoding = $14,
+                    source_time = $15,
+                    indexed_time = $16,
+                    author_nickname_sanitized = $17,
+                    source = $18,
+                    etag = $19,
+                    subspace_slug = EXCLUDED.subspace_slug,
+                    content_html = $20,
+                    edited = $21
+                 RETURNING (xmax = 0) AS inserted", Model::Article.table(), conflict_target(config)),
+                &[
+                    &id,
+                    &article.title,
+                    &stored.text,
+                    &author_id,
+                    &article.author_nickname,
+                    &subspace_id,
+                    &absent_as_null(&article.ext_link),
+                    &article.status,
+                    &article.weight.check()?.0,
+                    &article.created_time,
+                    &article.updated_time,
+                    &excerpt,
+                    &stored.bytes,
+                    &config.content_encoding.as_str(),
+                    &change.source_time,
+                    &indexed_time,
+                    &display_nickname(config, &article.author_nickname),
+                    &config.source,
+                    &etag::of(article),
+                    &content_html,
+                    &edited(article),
+                ],
+            ).await?;
+            count_upsert(row.get("inserted"));
+            counts::shift(client, Counted::Articles, previous, Some(article.author_id)).await?;
+            let contribution = Contribution {
+                subspace_id: article.subspace_id,
+                author_id: article.author_id,
+            };
+            stats::shift(
+                client,
+                config,
+                Counted::Articles,
+                counted_in,
+                Some(contribution),
+                Some(article.created_time),
+            )
+            .await?;
+            info!("Upserted article: {}", article.id);
+        }
+        Entity::Comment(comment) => {
+            let (id, author_id, post_id) = (
+                SqlId(comment.id.0),
+                SqlId(comment.author_id.0),
+                SqlId(comment.post_id.0),
+            );
+            let previous =
+                counts::author_of(client, config, Counted::Comments, comment.id.0).await?;
+            let counted_in =
+                stats::contribution_of(client, config, Counted::Comments, comment.id.0).await?;
+            move_out_of_partition(
+                client,
+                config,
+                Model::Comment.table(),
+                id,
+                comment.created_time,
+            )
+            .await?;
+            let target_type = if config.comment_replies {
+                reply_target(client, post_id).await?
+            } else {
+                "article"
+            };
+            // a reply is checked against its thread before it can loop it or make it too deep
+            let (target_type, post_id) = if target_type == "comment" {
+                let parents = thread::ancestry(client, post_id).await?;
+                let placed = thread::place(
+                    comment.id.0,
+                    comment.post_id.0,
+                    &parents,
+                    config.max_comment_depth,
+                    config.deep_replies,
+                )?;
+                if placed != Parent::Comment(comment.post_id.0) {
+                    warn!(
+                        "Flattened reply {} to comment {} over VE_MAX_COMMENT_DEPTH onto {:?}",
+                        comment.id, comment.post_id, placed
+                    );
+                }
+                match placed {
+                    Parent::Comment(parent) => ("comment", SqlId(parent)),
+                    Parent::Article(article) => ("article", SqlId(article)),
+                }
+            } else {
+                (target_type, post_id)
+            };
+            let content_html = config
+                .content_html_tags
+                .as_ref()
+                .map(|tags| html::render(&comment.content, tags));
+            let row = client
+                .query_one(
+                    &format!("INSERT INTO {} (id, content, author_id, author_nickname, post_id, 
+                                     status, weight, created_time, source_time, indexed_time,
+                                     author_nickname_sanitized, source, etag, target_type, content_html)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+                 ON CONFLICT {} DO UPDATE SET
+                    content = $2,
+                    author_id = $3,
+                    author_nickname = $4,
+                    post_id = $5,
+                    status = $6,
+                    weight = $7,
+                    created_time = $8,
+                    source_time = $9,
+                    indexed_time = $10,
+                    author_nickname_sanitized = $11,
+                    source = $12,
+                    etag = $13,
+                    target_type = $14,
+                    content_html = $15
+                 RETURNING (xmax = 0) AS inserted", Model::Comment.table(), conflict_target(config)),
+                    &[
+                        &id,
+                        &comment.content,
+                        &author_id,
+                        &comment.author_nickname,
+                        &post_id,
+                        &comment.status,
+                        &comment.weight.check()?.0,
+                        &comment.created_time,
+                        &change.source_time,
+                        &indexed_time,
+                        &display_nickname(config, &comment.author_nickname),
+                        &config.source,
+                        &etag::of(comment),
+                        &target_type,
+                        &content_html,
+                    ],
+                )
+                .await?;
+            count_upsert(row.get("inserted"));
+            // read back, a reply counts where its thread does, and only once approved in a moderated subspace
+            let author = counts::author_of(client, config, Counted::Comments, comment.id.0).await?;
+            counts::shift(client, Counted::Comments, previous, author).await?;
+            let contribution =
+                stats::contribution_of(client, config, Counted::Comments, comment.id.0).await?;
+            stats::shift(
+                client,
+                config,
+                Counted::Comments,
+                counted_in,
+                contribution,
+                Some(comment.created_time),
+            )
+            .await?;
+            info!("Upserted comment: {}", comment.id);
+        }
+        Entity::Deleted(model, id) => {
+            let row_id = SqlId(*id);
+            let counted = Counted::of(*model);
+            let (previous, counted_in) = match counted {
+                Some(counted) => (
+                    counts::author_of(client, config, counted, *id).await?,
+                    stats::contribution_of(client, config, counted, *id).await?,
+                ),
+                None => (None, None),
+            };
+            let query = format!("DELETE FROM {} WHERE id = $1", model.table());
+            client.execute(&query, &[&row_id]).await?;
+            if *model == Model::Subspace {
+                client
+                    .execute(
+                        "UPDATE articles SET subspace_slug = NULL WHERE subspace_id = $1",
+                        &[&row_id],
+                    )
+                    .await?;
+            }
+            if let Some(counted) = counted {
+                counts::shift(client, counted, previous, None).await?;
+                stats::shift(client, config, counted, counted_in, None, None).await?;
+            }
+            info!("Deleted {} record: {}", model.table(), id);
+        }
+    }
+
+    Ok(())
+}
+
+// Upserts return `xmax = 0`, true only on a freshly inserted row version and
+// false on the one a conflict-update wrote. A high share of conflict-updates
+// means entities are being applied more than once, e.g. on catch-up.
+fn count_upsert(inserted: bool) {
+    if inserted {
+        metrics::UPSERT_INSERTS.inc();
+    } else {
+        metrics::UPSERT_CONFLICT_UPDATES.inc();
+    }
+}
+
+// Partitioned tables are keyed by `(id, created_time)`, see `partition::TABLES`.
+fn conflict_target(config: &Config) -> &'static str {
+    if config.partitioning {
+        "(id, created_time)"
+    } else {
+        "(id)"
+    }
+}
+
+// With `created_time` in the key, an update that changes it would insert a
+// second row rather than replace the first, so the old one goes beforehand.
+async fn move_out_of_partition(
+    client: &Client,
+    config: &Config,
+    table: &str,
+    id: SqlId,
+    created_time: i64,
+) -> Result<(), tokio_postgres::Error> {
+    if config.partitioning {
+        client
+            .execute(
+                &format!("DELETE FROM {} WHERE id = $1 AND created_time <> $2", table),
+                &[&id, &created_time],
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Whether `article` was edited since it was created, as `articles.edited`
+/// has it. The nucleus may send an update time equal to the creation time
+/// on create, or 0, neither of which is an edit.
+pub fn edited(article: &VeArticle) -> bool {
+    article.updated_time > article.created_time
+}
+
+// The models have no `Option` for text that may be absent, the nucleus leaves
+// it empty instead. It's stored as NULL, so "has a banner" is `banner IS NOT
+// NULL` in SQL, and read back as the empty string.
+fn absent_as_null(text: &str) -> Option<&str> {
+    Some(text).filter(|text| !text.is_empty())
+}
+
+/// The nickname read paths serve, the raw one when sanitizing is turned off.
+pub fn display_nickname(config: &Config, raw: &str) -> String {
+    match &config.nickname_rules {
+        Some(rules) => nickname::sanitize(raw, rules),
+        None => raw.to_string(),
+    }
+}
+
+pub fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::RangeInclusive;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use crate::id::IdOutOfRange;
+
+    #[test]
+    fn entity_json_reads_back_as_its_model() {
+        let json = serde_json::json!({
+            "id": 7,
+            "title": "Weekly update",
+            "content": "hello",
+            "author_id": 3,
+            "author_nickname": "alice",
+            "subspace_id": 1,
+            "ext_link": "",
+            "status": 0,
+            "weight": 0,
+            "created_time": 1700000000,
+            "updated_time": 1700000000,
+        });
+        let article = Entity::from_json(Model::Article, json.clone()).unwrap();
+        assert_eq!(article.model(), Model::Article);
+        assert_eq!(article.to_json().unwrap(), json);
+        assert!(Entity::from_json(Model::Subspace, json).is_err());
+        assert_eq!(article.subspace_id(), Some(1));
+
+        let deleted = Entity::Deleted(Model::Comment, 42);
+        assert_eq!(deleted.to_json().unwrap(), serde_json::json!(42));
+        assert_eq!(deleted.subspace_id(), None);
+        assert_eq!(Entity::Deleted(Model::Subspace, 1).subspace_id(), Some(1));
+    }
+
+    #[test]
+    fn absent_text_is_stored_as_null() {
+        assert_eq!(absent_as_null(""), None);
+        assert_eq!(absent_as_null(" "), Some(" "));
+        assert_eq!(
+            absent_as_null("https://example.com/banner.png"),
+            Some("https://example.com/banner.png")
+        );
+    }
+
+    #[test]
+    fn a_missing_reply_target_is_a_parent_gap() {
+        let missing: Box<dyn std::error::Error> = MissingParent(7).into();
+        assert!(lacks_parent(missing.as_ref()));
+        let other: Box<dyn std::error::Error> = IdOutOfRange(7).into();
+        assert!(!lacks_parent(other.as_ref()));
+        assert!(!conflicts(other.as_ref()));
+    }
+
+    #[test]
+    fn conflicts_back_off_doubling() {
+        assert_eq!(conflict_backoff(1), Duration::from_millis(50));
+        assert_eq!(conflict_backoff(2), Duration::from_millis(100));
+        assert_eq!(conflict_backoff(3), Duration::from_millis(200));
+        assert_eq!(conflict_backoff(40), Duration::from_millis(50 * 64));
+    }
+
+    #[test]
+    fn articles_are_edited_once_updated_after_creation() {
+        let article = |created_time, updated_time| VeArticle {
+            created_time,
+            updated_time,
+            ..fixture::article(1)
+        };
+        assert!(!edited(&article(1_700_000_000, 1_700_000_000)));
+        assert!(!edited(&article(1_700_000_000, 0)));
+        assert!(edited(&article(1_700_000_000, 1_700_000_060)));
+    }
+
+    #[test]
+    fn database_names_are_quoted_as_is() {
+        assert_eq!(quote_ident("ve_db"), "\"ve_db\"");
+        assert_eq!(quote_ident("Ve \"db\""), "\"Ve \"\"db\"\"\"");
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct State {
+        applied: Vec<u64>,
+        dead: Vec<u64>,
+        sentinel: u64,
+    }
+
+    // A database in memory, whose commits and dead letters can be made to
+    // fail, and changes to conflict.
+    #[derive(Default)]
+    struct MemStore {
+        durable: Mutex<State>,
+        open: Mutex<Option<State>>,
+        commits_to_fail: AtomicUsize,
+        fail_dead_letters: AtomicBool,
+        conflicts_to_fail: AtomicUsize,
+    }
+
+    impl MemStore {
+        fn in_open<F: FnOnce(&mut State)>(&self, f: F) -> Result<(), String> {
+            let mut open = self.open.lock().unwrap();
+            open.as_mut()
+                .map(f)
+                .ok_or_else(|| "no transaction".to_string())
+        }
+
+        fn run(&self, statement: &str) -> Result<(), String> {
+            match statement {
+                "BEGIN" => *self.open.lock().unwrap() = Some(self.durable.lock().unwrap().clone()),
+                "COMMIT" => {
+                    let open = self.open.lock().unwrap().take().ok_or("no transaction")?;
+                    if self.commits_to_fail.load(Ordering::SeqCst) > 0 {
+                        self.commits_to_fail.fetch_sub(1, Ordering::SeqCst);
+                        // the connection went down with the transaction
+                        return Err("connection reset".to_string());
+                    }
+                    *self.durable.lock().unwrap() = open;
+                }
+                "ROLLBACK" => *self.open.lock().unwrap() = None,
+                _ => {}
+            }
+            Ok(())
+        }
+
+        // Every reqnum up to the durable sentinel has been applied or dead-lettered.
+        fn assert_nothing_skipped(&self) {
+            let durable = self.durable.lock().unwrap();
+            for reqnum in 1..=durable.sentinel {
+                assert!(
+                    durable.applied.contains(&reqnum) || durable.dead.contains(&reqnum),
+                    "reqnum {} skipped, durable state {:?}",
+                    reqnum,
+                    durable
+                );
+            }
+        }
+    }
+
+    impl Store for Arc<MemStore> {
+        fn execute<'a>(&'a self, statement: &'a str) -> BoxFuture<'a, Result<(), String>> {
+            Box::pin(std::future::ready(self.run(statement)))
+        }
+
+        fn apply<'a>(&'a self, change: &'a Change) -> BoxFuture<'a, Result<(), (bool, String)>> {
+            let result = if self.conflicts_to_fail.load(Ordering::SeqCst) > 0 {
+                self.conflicts_to_fail.fetch_sub(1, Ordering::SeqCst);
+                Err((true, "deadlock detected".to_string()))
+            } else {
+                self.in_open(|open| open.applied.push(change.reqnum))
+                    .map_err(|e| (false, e))
+            };
+            Box::pin(std::future::ready(result))
+        }
+
+        fn insert_dead_letter<'a>(
+            &'a self,
+            letter: &'a DeadLetter,
+        ) -> BoxFuture<'a, Result<(), String>> {
+            let result = if self.fail_dead_letters.load(Ordering::SeqCst) {
+                Err("disk full".to_string())
+            } else {
+                self.in_open(|open| open.dead.push(letter.reqnum))
+            };
+            Box::pin(std::future::ready(result))
+        }
+
+        fn save_sentinel(&self, sentinel: u64) -> BoxFuture<'_, Result<(), String>> {
+            let result = self.in_open(|open| open.sentinel = sentinel);
+            Box::pin(std::future::ready(result))
+        }
+    }
+
+    /// Writes a subspace titled in CJK and emoji and reads it back, removing
+    /// it afterwards. It migrates the database, so use a scratch one:
+    ///
+    ///     VE_POSTGRES_CONFIG="host=localhost user=postgres dbname=scratch" cargo test --lib multilingual -- --ignored
+    #[tokio::test]
+    #[ignore = "needs a scratch database"]
+    async fn multilingual_text_reads_back_unchanged() {
+        let config = Config::for_database_tests();
+        let mut client = connect(&config).await.unwrap();
+        setup_database(&mut client, &config, true).await.unwrap();
+
+        // clear of the ids a real nucleus hands out
+        let id = 1 << 42;
+        let subspace = VeSubspace {
+            title: "週刊ニュース 🦀🎉".to_string(),
+            slug: "weekly".to_string(),
+            description: "한국어 설명, 中文说明 👍🏽".to_string(),
+            ..fixture::subspace(id)
+        };
+        let upsert = Change {
+            method: Method::Update,
+            entity: Entity::Subspace(subspace.clone()),
+            ..change(0)
+        };
+        handle_database_operation(&client, &config, &upsert)
+            .await
+            .unwrap();
+        let row = client
+            .query_one("SELECT * FROM subspaces WHERE id = $1", &[&SqlId(id)])
+            .await
+            .unwrap();
+        let stored = query::subspace_from_row(&row);
+        client
+            .execute("DELETE FROM subspaces WHERE id = $1", &[&SqlId(id)])
+            .await
+            .unwrap();
+        assert_eq!(stored.title, subspace.title);
+        assert_eq!(stored.description, subspace.description);
+    }
+
+    /// Applies a replayed event, an event at the reqnum of a gap's refetch and
+    /// events sharing a reqnum, each through the writer's store, then the
+    /// replay of an event older than the retention, in a transaction that's
+    /// rolled back. Needs a scratch database:
+    ///
+    ///     VE_POSTGRES_CONFIG="host=localhost user=postgres dbname=scratch" cargo test --lib applied_events -- --ignored
+    #[tokio::test]
+    #[ignore = "needs a scratch database"]
+    async fn applied_events_pass_over_replays_only() {
+        let mut store = scratch_store().await;
+        // clear of the ids and reqnums a real nucleus hands out
+        let (id, reqnum) = (1 << 43, 1 << 43);
+
+        store.apply(&upsert(reqnum, id, "first")).await.unwrap();
+        store.apply(&upsert(reqnum, id, "replayed")).await.unwrap();
+        assert_eq!(title(&store, id).await.as_deref(), Some("first"));
+
+        // a refetch for a gap borrows the reqnum of the event past it
+        let refetch = Change {
+            event: false,
+            ..upsert(reqnum + 1, id, "refetched")
+        };
+        store.apply(&refetch).await.unwrap();
+        store
+            .apply(&upsert(reqnum + 1, id, "past the gap"))
+            .await
+            .unwrap();
+        assert_eq!(title(&store, id).await.as_deref(), Some("past the gap"));
+
+        // VE_DUPLICATE_REQNUMS=apply
+        store
+            .apply(&upsert(reqnum + 2, id + 1, "one"))
+            .await
+            .unwrap();
+        store
+            .apply(&upsert(reqnum + 2, id + 2, "another"))
+            .await
+            .unwrap();
+        assert_eq!(title(&store, id + 1).await.as_deref(), Some("one"));
+        assert_eq!(title(&store, id + 2).await.as_deref(), Some("another"));
+        assert_eq!(recorded(&store, reqnum..=reqnum + 2).await, 4);
+
+        // saving a sentinel past them forgets none, a rewind still passes over them
+        store.save_sentinel(reqnum + 2).await.unwrap();
+        assert_eq!(recorded(&store, reqnum..=reqnum + 2).await, 4);
+        store.apply(&upsert(reqnum, id, "rewound")).await.unwrap();
+        assert_eq!(title(&store, id).await.as_deref(), Some("past the gap"));
+
+        store.config.applied_events_retention = Some(Duration::from_secs(60));
+        store
+            .client
+            .execute(
+                "UPDATE applied_events SET applied_time = applied_time - 3600
+                 WHERE avs_id = $1 AND reqnum = $2",
+                &[&store.config.avs_id.as_str(), &(reqnum as i64)],
+            )
+            .await
+            .unwrap();
+        store.save_sentinel(reqnum + 2).await.unwrap();
+        assert_eq!(recorded(&store, reqnum..=reqnum + 2).await, 3);
+        store.apply(&upsert(reqnum, id, "rewound")).await.unwrap();
+        assert_eq!(title(&store, id).await.as_deref(), Some("rewound"));
+        store.client.batch_execute("ROLLBACK").await.unwrap();
+    }
+
+    // The writer's store on the database of `VE_POSTGRES_CONFIG`, with a
+    // transaction open for the test to roll back.
+    async fn scratch_store() -> PgStore {
+        let config = Config::for_database_tests();
+        let mut client = connect(&config).await.unwrap();
+        setup_database(&mut client, &config, true).await.unwrap();
+        client.batch_execute("BEGIN").await.unwrap();
+        PgStore { client, config }
+    }
+
+    fn upsert(reqnum: u64, id: u64, title: &str) -> Change {
+        let subspace = VeSubspace {
+            title: title.to_string(),
+            ..fixture::subspace(id)
+        };
+        Change {
+            key: crate::key::Prefix::of_model(Model::Subspace).key(id),
+            method: Method::Update,
+            entity: Entity::Subspace(subspace),
+            ..change(reqnum)
+        }
+    }
+
+    async fn title(store: &PgStore, id: u64) -> Option<String> {
+        store
+            .client
+            .query_opt("SELECT title FROM subspaces WHERE id = $1", &[&SqlId(id)])
+            .await
+            .unwrap()
+            .map(|row| row.get(0))
+    }
+
+    async fn recorded(store: &PgStore, reqnums: RangeInclusive<u64>) -> i64 {
+        store
+            .client
+            .query_one(
+                "SELECT COUNT(*) FROM applied_events
+                 WHERE avs_id = $1 AND reqnum BETWEEN $2 AND $3",
+                &[
+                    &store.config.avs_id.as_str(),
+                    &(*reqnums.start() as i64),
+                    &(*reqnums.end() as i64),
+                ],
+            )
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    // The changes that failed since the last flush, once every change sent
+    // before has gone through the writer.
+    async fn flush(tx: &mpsc::Sender<Message>) -> Vec<(u64, String)> {
+        let (ack, flushed) = oneshot::channel();
+        tx.send(Message::Flush(ack)).await.unwrap();
+        flushed.await.unwrap()
+    }
+
+    async fn checkpoint_at(tx: &mpsc::Sender<Message>, sentinel: u64) -> Checkpointed {
+        flush(tx).await;
+        let (ack, checkpointed) = oneshot::channel();
+        tx.send(Message::Checkpoint { sentinel, ack })
+            .await
+            .unwrap();
+        checkpointed.await.unwrap()
+    }
+
+    // Sends a poll cycle's worth of changes and checkpoints past them.
+    async fn cycle(tx: &mpsc::Sender<Message>, reqnums: RangeInclusive<u64>) -> Checkpointed {
+        for reqnum in reqnums.clone() {
+            tx.send(Message::Change(Change::fixture(reqnum)))
+                .await
+                .unwrap();
+        }
+        checkpoint_at(tx, *reqnums.end()).await
+    }
+
+    #[tokio::test]
+    async fn failed_commit_rereads_rather_than_skips() {
+        let store = Arc::new(MemStore::default());
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(write_loop(store.clone(), CommitPolicy::Cycle, 3, 0, rx));
+
+        let checkpointed = cycle(&tx, 1..=3).await;
+        assert_eq!((checkpointed.committed, checkpointed.pending), (3, false));
+
+        store.commits_to_fail.store(1, Ordering::SeqCst);
+        let checkpointed = cycle(&tx, 4..=6).await;
+        assert_eq!((checkpointed.committed, checkpointed.pending), (3, false));
+        assert_eq!(store.durable.lock().unwrap().sentinel, 3);
+        store.assert_nothing_skipped();
+
+        // the polling loop resumes from `committed`, serving 4..=6 again
+        let checkpointed = cycle(&tx, 4..=6).await;
+        assert_eq!(checkpointed.committed, 6);
+        assert_eq!(store.durable.lock().unwrap().applied, [1, 2, 3, 4, 5, 6]);
+        store.assert_nothing_skipped();
+    }
+
+    #[tokio::test]
+    async fn reset_commits_whatever_the_policy() {
+        let store = Arc::new(MemStore::default());
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(write_loop(
+            store.clone(),
+            CommitPolicy::Events(100),
+            3,
+            0,
+            rx,
+        ));
+
+        let checkpointed = cycle(&tx, 1..=3).await;
+        assert_eq!((checkpointed.committed, checkpointed.pending), (0, true));
+
+        let (ack, reset) = oneshot::channel();
+        tx.send(Message::Reset { sentinel: 10, ack }).await.unwrap();
+        let checkpointed = reset.await.unwrap();
+        assert_eq!((checkpointed.committed, checkpointed.pending), (10, false));
+        let durable = store.durable.lock().unwrap();
+        assert_eq!(
+            (durable.sentinel, &durable.applied[..]),
+            (10, &[1, 2, 3][..])
+        );
+    }
+
+    #[tokio::test]
+    async fn crash_loses_the_open_transaction_whole() {
+        let store = Arc::new(MemStore::default());
+        let (tx, rx) = mpsc::channel(100);
+        let writer = tokio::spawn(write_loop(
+            store.clone(),
+            CommitPolicy::Events(100),
+            3,
+            0,
+            rx,
+        ));
+
+        let checkpointed = cycle(&tx, 1..=3).await;
+        assert_eq!((checkpointed.committed, checkpointed.pending), (0, true));
+        // the process goes away before the policy is due
+        drop(tx);
+        writer.await.unwrap();
+        assert_eq!(store.durable.lock().unwrap().sentinel, 0);
+        store.assert_nothing_skipped();
+
+        // on restart everything is read again from the durable sentinel
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(write_loop(store.clone(), CommitPolicy::Cycle, 3, 0, rx));
+        assert_eq!(cycle(&tx, 1..=3).await.committed, 3);
+        store.assert_nothing_skipped();
+    }
+
+    #[tokio::test]
+    async fn failed_dead_letter_holds_the_sentinel_back() {
+        let store = Arc::new(MemStore::default());
+        store.fail_dead_letters.store(true, Ordering::SeqCst);
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(write_loop(store.clone(), CommitPolicy::Cycle, 3, 0, rx));
+
+        tx.send(Message::Change(Change::fixture(1))).await.unwrap();
+        let letter = DeadLetter::for_change(&Change::fixture(2), "postgres", "boom".to_string(), 5);
+        tx.send(Message::DeadLetter(letter)).await.unwrap();
+        tx.send(Message::Change(Change::fixture(3))).await.unwrap();
+
+        let checkpointed = checkpoint_at(&tx, 3).await;
+        assert_eq!((checkpointed.committed, checkpointed.pending), (0, false));
+        assert_eq!(store.durable.lock().unwrap().sentinel, 0);
+        store.assert_nothing_skipped();
+    }
+
+    #[tokio::test]
+    async fn conflict_writes_the_whole_batch_again() {
+        let store = Arc::new(MemStore::default());
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(write_loop(store.clone(), CommitPolicy::Cycle, 3, 0, rx));
+
+        tx.send(Message::Change(Change::fixture(1))).await.unwrap();
+        let letter = DeadLetter::for_change(&Change::fixture(2), "postgres", "boom".to_string(), 5);
+        tx.send(Message::DeadLetter(letter)).await.unwrap();
+        // both in the open transaction before 3 conflicts
+        assert!(flush(&tx).await.is_empty());
+        store.conflicts_to_fail.store(2, Ordering::SeqCst);
+        tx.send(Message::Change(Change::fixture(3))).await.unwrap();
+
+        let checkpointed = checkpoint_at(&tx, 3).await;
+        assert_eq!((checkpointed.committed, checkpointed.pending), (3, false));
+        let durable = store.durable.lock().unwrap();
+        assert_eq!(
+            (&durable.applied[..], &durable.dead[..]),
+            (&[1, 3][..], &[2][..])
+        );
+    }
+
+    #[tokio::test]
+    async fn conflicts_past_the_retries_fail_the_change() {
+        let store = Arc::new(MemStore::default());
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(write_loop(store.clone(), CommitPolicy::Cycle, 3, 0, rx));
+
+        store.conflicts_to_fail.store(4, Ordering::SeqCst);
+        tx.send(Message::Change(Change::fixture(1))).await.unwrap();
+        let failed = flush(&tx).await;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, 1);
+
+        tx.send(Message::Change(Change::fixture(2))).await.unwrap();
+        checkpoint_at(&tx, 2).await;
+        assert_eq!(store.durable.lock().unwrap().applied, [2]);
+    }
+}
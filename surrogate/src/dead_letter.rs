@@ -0,0 +1,146 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_postgres::{Client, Row};
+
+use vemodel::Method;
+
+use crate::db::Change;
+use crate::id::SqlId;
+use crate::key;
+
+/// An event that kept failing to apply and was set aside, so that it no
+/// longer holds the sentinel back.
+#[derive(Debug)]
+pub struct DeadLetter {
+    pub reqnum: u64,
+    /// The sink that gave up on the event. Re-driving a letter of the primary
+    /// sink goes through all sinks, that of a secondary one through it alone.
+    pub sink: String,
+    pub prefix: String,
+    pub id: u64,
+    pub method: Method,
+    /// The undecodable nucleus response, when decoding is what failed.
+    pub raw_bytes: Option<Vec<u8>>,
+    pub error: String,
+    /// Unix time, in seconds, of the first failed attempt.
+    pub first_seen: i64,
+    pub attempts: u32,
+}
+
+impl DeadLetter {
+    /// A dead letter for a change `sink` gave up on after `attempts` tries.
+    pub fn for_change(change: &Change, sink: &str, error: String, attempts: u32) -> Self {
+        let (prefix, id) = key::split_key(&change.key);
+        Self {
+            reqnum: change.reqnum,
+            sink: sink.to_string(),
+            prefix,
+            id,
+            method: change.method,
+            raw_bytes: None,
+            error,
+            first_seen: unix_now(),
+            attempts,
+        }
+    }
+}
+
+pub async fn insert(client: &Client, letter: &DeadLetter) -> Result<(), tokio_postgres::Error> {
+    // a re-driven event that fails again keeps its history
+    client
+        .execute(
+            "INSERT INTO dead_letter (reqnum, prefix, id, method, raw_bytes, error, first_seen, attempts, sink)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+             ON CONFLICT (reqnum, sink) DO UPDATE SET
+                raw_bytes = $5,
+                error = $6,
+                attempts = dead_letter.attempts + $8",
+            &[
+                &(letter.reqnum as i64),
+                &letter.prefix,
+                &SqlId(letter.id),
+                &method_name(letter.method),
+                &letter.raw_bytes,
+                &letter.error,
+                &letter.first_seen,
+                &(letter.attempts as i32),
+                &letter.sink,
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn list(client: &Client) -> Result<Vec<DeadLetter>, Box<dyn std::error::Error>> {
+    let rows = client
+        .query(
+            "SELECT reqnum, sink, prefix, id, method, raw_bytes, error, first_seen, attempts
+             FROM dead_letter ORDER BY reqnum, sink",
+            &[],
+        )
+        .await?;
+    rows.iter().map(from_row).collect()
+}
+
+/// The dead letters of one event, one per sink that gave up on it.
+pub async fn get(
+    client: &Client,
+    reqnum: u64,
+) -> Result<Vec<DeadLetter>, Box<dyn std::error::Error>> {
+    let rows = client
+        .query(
+            "SELECT reqnum, sink, prefix, id, method, raw_bytes, error, first_seen, attempts
+             FROM dead_letter WHERE reqnum = $1 ORDER BY sink",
+            &[&(reqnum as i64)],
+        )
+        .await?;
+    rows.iter().map(from_row).collect()
+}
+
+pub async fn remove(client: &Client, reqnum: u64, sink: &str) -> Result<(), tokio_postgres::Error> {
+    client
+        .execute(
+            "DELETE FROM dead_letter WHERE reqnum = $1 AND sink = $2",
+            &[&(reqnum as i64), &sink],
+        )
+        .await?;
+    Ok(())
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn from_row(row: &Row) -> Result<DeadLetter, Box<dyn std::error::Error>> {
+    Ok(DeadLetter {
+        reqnum: row.get::<_, i64>("reqnum") as u64,
+        sink: row.get("sink"),
+        prefix: row.get("prefix"),
+        id: row.get::<_, SqlId>("id").0,
+        method: parse_method(row.get("method"))?,
+        raw_bytes: row.get("raw_bytes"),
+        error: row.get("error"),
+        first_seen: row.get("first_seen"),
+        attempts: row.get::<_, i32>("attempts") as u32,
+    })
+}
+
+/// How `method` is stored, here and in `applied_events`.
+pub fn method_name(method: Method) -> &'static str {
+    match method {
+        Method::Create => "create",
+        Method::Update => "update",
+        Method::Delete => "delete",
+    }
+}
+
+fn parse_method(name: &str) -> Result<Method, String> {
+    match name {
+        "create" => Ok(Method::Create),
+        "update" => Ok(Method::Update),
+        "delete" => Ok(Method::Delete),
+        _ => Err(format!("unknown method in dead letter: {}", name)),
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+use crate::model::Model;
+
+/// Tells a burst of decode failures, what a nucleus upgrading its types
+/// under a running surrogate looks like, from the odd bad entity.
+///
+/// Failures are counted per model, i.e. per key prefix, over a rolling
+/// `window`. Once `threshold` of them fall within it the model is alerting
+/// and stays so until a whole window goes by without one, so a burst is
+/// reported once however long it lasts.
+#[derive(Debug, Default)]
+pub struct DecodeAlarm {
+    failures: HashMap<Model, VecDeque<Instant>>,
+    alerting: HashSet<Model>,
+}
+
+impl DecodeAlarm {
+    /// Counts a failure to decode an entity of `model`, returning the
+    /// failures within the window when that starts an alert.
+    pub fn record(
+        &mut self,
+        model: Model,
+        now: Instant,
+        window: Duration,
+        threshold: usize,
+    ) -> Option<usize> {
+        let failures = self.failures.entry(model).or_default();
+        evict(failures, now, window);
+        failures.push_back(now);
+        metrics::DECODE_FAILURES.inc();
+        (failures.len() >= threshold && self.alerting.insert(model)).then_some(failures.len())
+    }
+
+    /// Whether failures of `model` are part of an alert already, and not
+    /// worth reporting one by one.
+    pub fn is_alerting(&self, model: Model) -> bool {
+        self.alerting.contains(&model)
+    }
+
+    /// Forgets the failures that have fallen out of the window, ending the
+    /// alerts with none left, and updates the window gauge.
+    pub fn refresh(&mut self, now: Instant, window: Duration) {
+        for (model, failures) in &mut self.failures {
+            evict(failures, now, window);
+            if failures.is_empty() {
+                self.alerting.remove(model);
+            }
+        }
+        self.failures.retain(|_, failures| !failures.is_empty());
+        let total = self.failures.values().map(VecDeque::len).sum::<usize>();
+        metrics::DECODE_FAILURES_IN_WINDOW.set(total as u64);
+    }
+}
+
+fn evict(failures: &mut VecDeque<Instant>, now: Instant, window: Duration) {
+    while failures
+        .front()
+        .is_some_and(|&at| now.duration_since(at) >= window)
+    {
+        failures.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alerts_once_per_burst() {
+        let window = Duration::from_secs(60);
+        let mut alarm = DecodeAlarm::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(alarm.record(Model::Article, at(0), window, 3), None);
+        assert_eq!(alarm.record(Model::Article, at(1), window, 3), None);
+        // another prefix doesn't count towards it
+        assert_eq!(alarm.record(Model::Comment, at(2), window, 3), None);
+        assert_eq!(alarm.record(Model::Article, at(3), window, 3), Some(3));
+        assert!(alarm.is_alerting(Model::Article));
+        assert!(!alarm.is_alerting(Model::Comment));
+        assert_eq!(alarm.record(Model::Article, at(4), window, 3), None);
+
+        // still failing within the window, so still the same alert
+        alarm.refresh(at(62), window);
+        assert!(alarm.is_alerting(Model::Article));
+        alarm.refresh(at(64), window);
+        assert!(!alarm.is_alerting(Model::Article));
+
+        assert_eq!(alarm.record(Model::Article, at(100), window, 3), None);
+    }
+
+    #[test]
+    fn failures_spread_out_never_alert() {
+        let window = Duration::from_secs(10);
+        let mut alarm = DecodeAlarm::default();
+        let start = Instant::now();
+        for i in 0..5 {
+            let now = start + Duration::from_secs(i * 10);
+            assert_eq!(alarm.record(Model::Subspace, now, window, 2), None);
+            alarm.refresh(now, window);
+        }
+    }
+}
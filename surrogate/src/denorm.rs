@@ -0,0 +1,277 @@
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, Row};
+use tracing::info;
+
+use vemodel::SubspaceId;
+
+use crate::config::Config;
+use crate::db::{self, MissingParent};
+use crate::id::SqlId;
+use crate::model::Model;
+use crate::{counts, etag, html, query, stats, text};
+
+// rows read and rewritten at a time
+const BATCH: i64 = 500;
+
+// subspaces between progress reports while their stats are recomputed
+const STATS_PROGRESS: usize = 100;
+
+/// Recomputes every denormalized column from the base columns and tables,
+/// as the upserts would write them with this build and config: excerpts,
+/// plain descriptions, sanitized nicknames, ETags, rendered HTML, subspace
+/// slugs and reply targets, then the per-author counts and per-subspace
+/// stats. For data written before one of them existed or changed, without
+/// syncing everything from the nucleus again.
+///
+/// Safe to run alongside ingest. Rows are rewritten one at a time and only
+/// when they haven't been upserted since they were read, `indexed_time`
+/// tells, an upsert writes fresh columns itself. Counts and stats are
+/// recomputed with their tables locked, see `rebuild_counts`.
+pub async fn rebuild(client: &Client, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    for model in Model::ALL {
+        rebuild_table(client, config, model).await?;
+    }
+    rebuild_counts(client, config).await?;
+    rebuild_stats(client, config).await?;
+    Ok(())
+}
+
+async fn rebuild_table(
+    client: &Client,
+    config: &Config,
+    model: Model,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let table = model.table();
+    let total: i64 = client
+        .query_one(&format!("SELECT COUNT(*) FROM {}", table), &[])
+        .await?
+        .get(0);
+    let select = format!(
+        "SELECT * FROM {} WHERE (id > $1 OR $1 IS NULL) ORDER BY id LIMIT $2",
+        table
+    );
+    let (mut after, mut done, mut stale) = (None::<SqlId>, 0, 0);
+    loop {
+        let rows = client.query(&select, &[&after, &BATCH]).await?;
+        let Some(last) = rows.last() else {
+            break;
+        };
+        after = Some(last.get("id"));
+        for row in &rows {
+            stale += usize::from(rewrite(client, config, model, row).await?);
+        }
+        done += rows.len();
+        info!("Rebuilt {} of {} {}, {} stale", done, total, table, stale);
+    }
+    info!("Rebuilt the {} {}, rewrote {}", done, table, stale);
+    Ok(())
+}
+
+// Rewrites the derived columns of `row` that don't read as they'd be
+// written now, returning whether there were any.
+async fn rewrite(
+    client: &Client,
+    config: &Config,
+    model: Model,
+    row: &Row,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let derived: Vec<_> = derive(client, config, model, row)
+        .await?
+        .into_iter()
+        .filter(|(column, value)| row.get::<_, Option<String>>(*column) != *value)
+        .collect();
+    if derived.is_empty() {
+        return Ok(false);
+    }
+    let (id, indexed_time): (SqlId, Option<i64>) = (row.get("id"), row.get("indexed_time"));
+    let columns: Vec<&str> = derived.iter().map(|(column, _)| *column).collect();
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&id, &indexed_time];
+    params.extend(
+        derived
+            .iter()
+            .map(|(_, value)| value as &(dyn ToSql + Sync)),
+    );
+    client
+        .execute(&update(model.table(), &columns), &params)
+        .await?;
+    Ok(true)
+}
+
+// The statement setting `columns` of a row from $3 on, unless it was upserted
+// since its `indexed_time`, $2, was read.
+fn update(table: &str, columns: &[&str]) -> String {
+    let set: Vec<String> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| format!("{} = ${}", column, i + 3))
+        .collect();
+    format!(
+        "UPDATE {} SET {} WHERE id = $1 AND indexed_time IS NOT DISTINCT FROM $2",
+        table,
+        set.join(", ")
+    )
+}
+
+// The derived columns of `row` as an upsert of its entity would write them.
+async fn derive(
+    client: &Client,
+    config: &Config,
+    model: Model,
+    row: &Row,
+) -> Result<Vec<(&'static str, Option<String>)>, Box<dyn std::error::Error>> {
+    let render = |content: &str| {
+        config
+            .content_html_tags
+            .as_ref()
+            .map(|tags| html::render(content, tags))
+    };
+    Ok(match model {
+        Model::Subspace => {
+            let subspace = query::subspace_from_row(row);
+            let plain = text::strip_markup(&subspace.description);
+            vec![
+                (
+                    "description_plain",
+                    Some(plain).filter(|plain| !plain.is_empty()),
+                ),
+                ("etag", Some(etag::of(&subspace))),
+            ]
+        }
+        Model::Article => {
+            // ETags are of the entity as the nucleus has it, with the raw nickname
+            let article = vemodel::VeArticle {
+                author_nickname: row.get("author_nickname"),
+                ..query::article_from_row(row)?
+            };
+            let slug = client
+                .query_opt(
+                    "SELECT slug FROM subspaces WHERE id = $1",
+                    &[&SqlId(article.subspace_id.0)],
+                )
+                .await?
+                .map(|row| row.get(0));
+            vec![
+                (
+                    "excerpt",
+                    Some(text::excerpt(&article.content, config.excerpt_length)),
+                ),
+                (
+                    "author_nickname_sanitized",
+                    Some(db::display_nickname(config, &article.author_nickname)),
+                ),
+                ("etag", Some(etag::of(&article))),
+                ("content_html", render(&article.content)),
+                ("subspace_slug", slug),
+            ]
+        }
+        Model::Comment => {
+            let comment = vemodel::VeComment {
+                author_nickname: row.get("author_nickname"),
+                ..query::comment_from_row(row)
+            };
+            let target_type = if !config.comment_replies {
+                Some("article")
+            } else {
+                match db::reply_target(client, SqlId(comment.post_id.0)).await {
+                    Ok(target) => Some(target),
+                    // the parent is gone, the reply keeps what it had
+                    Err(e) if e.is::<MissingParent>() => None,
+                    Err(e) => return Err(e),
+                }
+            };
+            let mut derived = vec![
+                (
+                    "author_nickname_sanitized",
+                    Some(db::display_nickname(config, &comment.author_nickname)),
+                ),
+                ("etag", Some(etag::of(&comment))),
+                ("content_html", render(&comment.content)),
+            ];
+            if let Some(target_type) = target_type {
+                derived.push(("target_type", Some(target_type.to_string())));
+            }
+            derived
+        }
+    })
+}
+
+// Recomputes the per-author counts. Every write shifts them, so they're
+// recomputed with `users` locked: a writer that got to it first is counted,
+// having committed, and one that gets to it meanwhile waits and shifts the
+// recomputed counts by its change, which they don't have.
+async fn rebuild_counts(
+    client: &Client,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    client
+        .batch_execute("BEGIN; LOCK TABLE users IN EXCLUSIVE MODE")
+        .await?;
+    finish(client, counts::repair(client, config).await).await?;
+    Ok(())
+}
+
+// Recomputes the stats of every subspace with content or stats, one at a
+// time and locked like the counts, so ingest is held up a subspace at most.
+async fn rebuild_stats(client: &Client, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let ids: Vec<SqlId> = client
+        .query(
+            "SELECT id FROM subspaces
+             UNION SELECT subspace_id FROM articles
+             UNION SELECT subspace_id FROM subspace_stats
+             ORDER BY 1",
+            &[],
+        )
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    for (done, &id) in ids.iter().enumerate() {
+        client
+            .batch_execute("BEGIN; LOCK TABLE subspace_authors, subspace_stats IN EXCLUSIVE MODE")
+            .await?;
+        finish(
+            client,
+            stats::recompute(client, config, SubspaceId(id.0)).await,
+        )
+        .await?;
+        if (done + 1) % STATS_PROGRESS == 0 {
+            info!(
+                "Recomputed the stats of {} of {} subspaces",
+                done + 1,
+                ids.len()
+            );
+        }
+    }
+    info!("Recomputed the stats of {} subspaces", ids.len());
+    Ok(())
+}
+
+// Commits the transaction `result` was had in, or rolls it back if it failed.
+async fn finish<T>(
+    client: &Client,
+    result: Result<T, tokio_postgres::Error>,
+) -> Result<T, tokio_postgres::Error> {
+    match result {
+        Ok(value) => {
+            client.batch_execute("COMMIT").await?;
+            Ok(value)
+        }
+        Err(e) => {
+            client.batch_execute("ROLLBACK").await?;
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_only_rows_not_upserted_since() {
+        assert_eq!(
+            update("articles", &["excerpt", "etag"]),
+            "UPDATE articles SET excerpt = $3, etag = $4 WHERE id = $1 AND indexed_time IS NOT DISTINCT FROM $2"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::{info, warn};
+
+use vemodel::Method;
+
+use crate::db::{Change, Entity};
+use crate::dead_letter::unix_now;
+use crate::id::SqlId;
+use crate::key::Prefix;
+use crate::metrics;
+use crate::model::Model;
+use crate::sink::Fanout;
+
+// articles expired per sweep, the rest wait for the next one
+const SWEEP_BATCH: i64 = 500;
+
+/// How long articles are kept past their `created_time` before [`sweep`]
+/// deletes them, along with their comments. Nothing expires by default.
+///
+/// Only articles of a subspace with a TTL, its own or the default one, are
+/// ever looked at. A subspace can opt out of the default with a TTL of 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Ttls {
+    /// TTL of the articles of subspaces without one of their own, from
+    /// `VE_ARTICLE_TTL_SECS`.
+    pub default: Option<Duration>,
+    /// Subspace id -> TTL of its articles, `None` for them to never expire,
+    /// from `VE_SUBSPACE_ARTICLE_TTLS`.
+    pub subspaces: HashMap<u64, Option<Duration>>,
+}
+
+impl Ttls {
+    /// Whether any article can expire at all.
+    pub fn is_on(&self) -> bool {
+        self.default.is_some() || self.subspaces.values().any(Option::is_some)
+    }
+}
+
+/// A `<subspace>:<seconds>` item of `VE_SUBSPACE_ARTICLE_TTLS`, 0 seconds
+/// for the articles of the subspace to never expire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubspaceTtl(pub u64, pub Option<Duration>);
+
+impl FromStr for SubspaceTtl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (subspace, secs) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <subspace>:<seconds>, got {}", s))?;
+        let subspace = subspace
+            .parse()
+            .map_err(|e| format!("{}: {}", subspace, e))?;
+        let secs: u64 = secs.parse().map_err(|e| format!("{}: {}", secs, e))?;
+        Ok(Self(
+            subspace,
+            Some(secs).filter(|&secs| secs > 0).map(Duration::from_secs),
+        ))
+    }
+}
+
+/// Deletes up to a batch of expired articles and their comments, returning
+/// how many articles expired.
+///
+/// The deletes go through `fanout` like those of the nucleus, comments
+/// first, so every sink sees them. There's no reqnum of their own to give
+/// them, they carry `reqnum`, that of the last change applied, and are
+/// committed with the next checkpoint. Any that fail are logged and tried
+/// again by the next sweep, the rows are still there.
+///
+/// Expiry is local to the surrogate, the nucleus keeps the articles. An
+/// update to an expired one brings it back until the following sweep.
+pub async fn sweep(
+    client: &Client,
+    ttls: &Ttls,
+    fanout: &Fanout,
+    reqnum: u64,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let (subspaces, subspace_ttls): (Vec<SqlId>, Vec<Option<i64>>) = ttls
+        .subspaces
+        .iter()
+        .map(|(&subspace, ttl)| (SqlId(subspace), ttl.map(|ttl| ttl.as_secs() as i64)))
+        .unzip();
+    let default_ttl = ttls.default.map(|ttl| ttl.as_secs() as i64);
+    // a NULL TTL never compares true, so articles that don't expire are never selected,
+    // and the subspaces are NUMERIC to compare with ids of either VE_ID_COLUMN_TYPE
+    let rows = client
+        .query(
+            "SELECT a.id AS article_id, c.id AS comment_id
+             FROM (
+                 SELECT a.id FROM articles a
+                 LEFT JOIN unnest($1::NUMERIC[], $2::BIGINT[]) AS t(subspace_id, ttl)
+                     ON t.subspace_id = a.subspace_id
+                 WHERE a.created_time < $4::BIGINT
+                     - CASE WHEN t.subspace_id IS NULL THEN $3::BIGINT ELSE t.ttl END
+                 ORDER BY a.id
+                 LIMIT $5
+             ) a
+             LEFT JOIN comments c ON c.post_id = a.id AND c.target_type = 'article'
+             ORDER BY a.id, c.id",
+            &[
+                &subspaces,
+                &subspace_ttls,
+                &default_ttl,
+                &unix_now(),
+                &SWEEP_BATCH,
+            ],
+        )
+        .await?;
+    let rows: Vec<(u64, Option<u64>)> = rows
+        .iter()
+        .map(|row| {
+            (
+                row.get::<_, SqlId>("article_id").0,
+                row.get::<_, Option<SqlId>>("comment_id").map(|id| id.0),
+            )
+        })
+        .collect();
+
+    let deletes = deletes(&rows);
+    let expired = deletes
+        .iter()
+        .filter(|(model, _)| *model == Model::Article)
+        .count();
+    for (model, id) in deletes {
+        let change = Change {
+            reqnum,
+            key: Prefix::of_model(model).key(id),
+            method: Method::Delete,
+            entity: Entity::Deleted(model, id),
+            correlation_id: format!("expiry-{}{}", model.as_str(), id),
+            source_time: None,
+            event: false,
+        };
+        fanout.send(change).await?;
+    }
+    for (_, sink, error) in fanout.flush().await? {
+        warn!(sink = %sink, "Failed to delete expired content, retrying next sweep: {}", error);
+    }
+    if expired > 0 {
+        metrics::EXPIRED_ARTICLES.add(expired as u64);
+        info!("Expired {} articles", expired);
+    }
+    Ok(expired)
+}
+
+// Rows are `(article, comment)` in article order, the comments of an article
+// deleted before it as they reference it.
+fn deletes(rows: &[(u64, Option<u64>)]) -> Vec<(Model, u64)> {
+    let mut deletes = Vec::new();
+    for (i, &(article, comment)) in rows.iter().enumerate() {
+        if let Some(comment) = comment {
+            deletes.push((Model::Comment, comment));
+        }
+        if !matches!(rows.get(i + 1), Some(&(next, _)) if next == article) {
+            deletes.push((Model::Article, article));
+        }
+    }
+    deletes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry_is_off_without_a_ttl() {
+        let mut ttls = Ttls::default();
+        assert!(!ttls.is_on());
+        ttls.subspaces.insert(3, None);
+        assert!(!ttls.is_on());
+        ttls.subspaces.insert(7, Some(Duration::from_secs(3600)));
+        assert!(ttls.is_on());
+        assert!(Ttls {
+            default: Some(Duration::from_secs(60)),
+            ..Ttls::default()
+        }
+        .is_on());
+    }
+
+    #[test]
+    fn parses_subspace_ttls() {
+        assert_eq!(
+            "7:3600".parse(),
+            Ok(SubspaceTtl(7, Some(Duration::from_secs(3600))))
+        );
+        assert_eq!("3:0".parse(), Ok(SubspaceTtl(3, None)));
+        assert!("7".parse::<SubspaceTtl>().is_err());
+        assert!("7:soon".parse::<SubspaceTtl>().is_err());
+    }
+
+    #[test]
+    fn comments_are_deleted_before_their_article() {
+        let rows = [(1, Some(10)), (1, Some(11)), (2, None), (3, Some(12))];
+        assert_eq!(
+            deletes(&rows),
+            [
+                (Model::Comment, 10),
+                (Model::Comment, 11),
+                (Model::Article, 1),
+                (Model::Article, 2),
+                (Model::Comment, 12),
+                (Model::Article, 3),
+            ]
+        );
+    }
+}
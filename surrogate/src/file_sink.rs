@@ -0,0 +1,305 @@
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use vemodel::Method;
+
+use crate::db::{self, Change, Entity};
+use crate::metrics;
+use crate::projection::Projection;
+use crate::sink::{BoxFuture, Sink};
+
+/// Appends every applied change to a JSONL file, one object per line:
+///
+/// ```text
+/// {"reqnum":42,"model":"article","method":"Update","entity":{...},"correlation_id":"42-article7","source_time":null,"logged_time":1760000000000}
+/// ```
+///
+/// `entity` is the entity as fetched from the nucleus, less the fields the
+/// projection leaves out, or the id of a delete.
+/// Once the file would grow past the rotation size it's renamed to
+/// `<path>.<unix millis>` and a new one started, so every file but the
+/// current one is complete and never written again.
+///
+/// Lines are buffered and flushed every flush interval. Files are fsynced
+/// when rotated and on shutdown; a crash can still lose the last interval,
+/// which the at-least-once delivery of the sink then writes again, so
+/// consumers should expect the odd repeated reqnum.
+///
+/// With [`Compression::Zstd`] each line is written as a zstd frame of its
+/// own. A file is then a valid zstd stream, `zstd -dc` turns it back into
+/// JSONL, and the rotation size counts compressed bytes.
+pub struct FileSink {
+    path: PathBuf,
+    rotate_bytes: u64,
+    compression: Compression,
+    projection: Projection,
+    log: Arc<Mutex<Log>>,
+}
+
+/// How the lines of the change log are written, from
+/// `VE_CHANGE_LOG_COMPRESSION`. Uncompressed by default, for consumers
+/// tailing the file as it's written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+// the level of `content`, entities are mostly article text too
+const ZSTD_LEVEL: i32 = 3;
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!("unknown change log compression: {}", s)),
+        }
+    }
+}
+
+struct Log {
+    file: BufWriter<File>,
+    // bytes in the current file, buffered ones included
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    reqnum: u64,
+    model: &'a str,
+    method: Method,
+    entity: serde_json::Value,
+    correlation_id: &'a str,
+    source_time: Option<i64>,
+    logged_time: i64,
+}
+
+impl FileSink {
+    /// Opens the log at `path` for appending, creating it if need be, and
+    /// starts flushing it every `flush_interval`.
+    pub fn open(
+        path: PathBuf,
+        rotate_bytes: u64,
+        flush_interval: Duration,
+        compression: Compression,
+        projection: Projection,
+    ) -> std::io::Result<Self> {
+        let (file, size) = open_file(&path)?;
+        let log = Arc::new(Mutex::new(Log { file, size }));
+        tokio::spawn(flush_periodically(Arc::downgrade(&log), flush_interval));
+        info!("Logging applied changes to {}", path.display());
+        Ok(Self {
+            path,
+            rotate_bytes,
+            compression,
+            projection,
+            log,
+        })
+    }
+
+    async fn rotate(&self, log: &mut Log) -> std::io::Result<()> {
+        sync(&mut log.file).await?;
+        let rotated = format!("{}.{}", self.path.display(), db::unix_millis());
+        tokio::fs::rename(&self.path, &rotated).await?;
+        let (file, size) = open_file(&self.path)?;
+        *log = Log { file, size };
+        info!("Rotated change log to {}", rotated);
+        Ok(())
+    }
+
+    async fn append(&self, change: &Change) -> std::io::Result<()> {
+        let model = change.entity.model();
+        let mut entity = change.entity.to_json()?;
+        self.projection.apply(model, &mut entity);
+        let mut line = serde_json::to_vec(&Record {
+            reqnum: change.reqnum,
+            model: model.as_str(),
+            method: change.method,
+            entity,
+            correlation_id: &change.correlation_id,
+            source_time: change.source_time,
+            logged_time: db::unix_millis(),
+        })?;
+        line.push(b'\n');
+        metrics::CHANGE_LOG_LINE_BYTES.add(line.len() as u64);
+        if self.compression == Compression::Zstd {
+            line = zstd::encode_all(&line[..], ZSTD_LEVEL)?;
+        }
+        metrics::CHANGE_LOG_WRITTEN_BYTES.add(line.len() as u64);
+
+        let mut log = self.log.lock().await;
+        // a line longer than the rotation size still goes in a file of its own
+        if log.size > 0 && log.size + line.len() as u64 > self.rotate_bytes {
+            self.rotate(&mut log).await?;
+        }
+        log.file.write_all(&line).await?;
+        log.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Sink for FileSink {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn apply<'a>(&'a self, change: &'a Change) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move { self.append(change).await.map_err(|e| e.to_string()) })
+    }
+
+    fn close(&self) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let mut log = self.log.lock().await;
+            sync(&mut log.file).await.map_err(|e| e.to_string())
+        })
+    }
+}
+
+fn open_file(path: &Path) -> std::io::Result<(BufWriter<File>, u64)> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let size = file.metadata()?.len();
+    Ok((BufWriter::new(File::from_std(file)), size))
+}
+
+async fn sync(file: &mut BufWriter<File>) -> std::io::Result<()> {
+    file.flush().await?;
+    file.get_ref().sync_all().await
+}
+
+// Flushes the buffered lines every `interval` until the sink is dropped.
+async fn flush_periodically(log: Weak<Mutex<Log>>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(log) = log.upgrade() else {
+            return;
+        };
+        if let Err(e) = log.lock().await.file.flush().await {
+            error!("Failed to flush the change log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("surrogate-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn writes_a_line_per_change() {
+        let path = temp_dir("jsonl").join("changes.jsonl");
+        let sink = FileSink::open(
+            path.clone(),
+            1 << 20,
+            Duration::from_secs(60),
+            Compression::None,
+            Projection::default(),
+        )
+        .unwrap();
+        for reqnum in 1..=3 {
+            sink.apply(&Change::fixture(reqnum)).await.unwrap();
+        }
+        sink.close().await.unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["reqnum"], 2);
+        assert_eq!(lines[1]["model"], "article");
+        assert_eq!(lines[1]["method"], "Delete");
+        assert_eq!(lines[1]["entity"], 2);
+    }
+
+    #[tokio::test]
+    async fn rotates_past_the_size_limit() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("changes.jsonl");
+        // room for two lines per file
+        let line_len = serde_json::to_vec(&Record {
+            reqnum: 1,
+            model: "article",
+            method: Method::Delete,
+            entity: serde_json::json!(1),
+            correlation_id: "1",
+            source_time: None,
+            logged_time: db::unix_millis(),
+        })
+        .unwrap()
+        .len() as u64
+            + 1;
+        let sink = FileSink::open(
+            path.clone(),
+            line_len * 2,
+            Duration::from_secs(60),
+            Compression::None,
+            Projection::default(),
+        )
+        .unwrap();
+        for reqnum in 1..=5 {
+            sink.apply(&Change::fixture(reqnum)).await.unwrap();
+            // rotated names are by the millisecond
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        sink.close().await.unwrap();
+
+        let mut lines = 0;
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let content = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            assert!(content.lines().count() <= 2);
+            lines += content.lines().count();
+        }
+        assert_eq!(lines, 5);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn compressed_log_decompresses_to_the_lines() {
+        let path = temp_dir("zstd").join("changes.jsonl.zst");
+        let sink = FileSink::open(
+            path.clone(),
+            1 << 20,
+            Duration::from_secs(60),
+            Compression::Zstd,
+            Projection::default(),
+        )
+        .unwrap();
+        for reqnum in 1..=3 {
+            sink.apply(&Change::fixture(reqnum)).await.unwrap();
+        }
+        sink.close().await.unwrap();
+
+        let log = zstd::decode_all(&std::fs::read(&path).unwrap()[..]).unwrap();
+        let reqnums: Vec<u64> = String::from_utf8(log)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["reqnum"]
+                    .as_u64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(reqnums, [1, 2, 3]);
+    }
+}
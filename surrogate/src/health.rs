@@ -0,0 +1,166 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+use crate::{build_info, metrics};
+
+// what `LAG` holds until the head has been asked for once
+const UNKNOWN: u64 = u64::MAX;
+
+// requests are a line or two, anything past this is cut off
+const MAX_REQUEST: usize = 4096;
+
+static LAG: AtomicU64 = AtomicU64::new(UNKNOWN);
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Records how far the committed sentinel is behind the head of the nucleus,
+/// in reqnums, for `/readyz` and `surrogate_replication_lag_reqnums`.
+pub fn set_lag(head: u64, committed: u64) {
+    let lag = head.saturating_sub(committed);
+    LAG.store(lag, Ordering::Relaxed);
+    metrics::REPLICATION_LAG.set(lag);
+}
+
+/// The lag last recorded, `None` until the head has been asked for.
+pub fn lag() -> Option<u64> {
+    Some(LAG.load(Ordering::Relaxed)).filter(|&lag| lag != UNKNOWN)
+}
+
+/// Whether the surrogate is ready to serve reads, caught up to within
+/// `max_lag` reqnums of the nucleus, and the body `/readyz` says so with.
+/// It isn't before the lag has been measured, nor ever when the nucleus
+/// predates `get_head_reqnum`.
+pub fn readiness(max_lag: u64) -> (bool, String) {
+    match LAG.load(Ordering::Relaxed) {
+        UNKNOWN => (false, "lag unknown".to_string()),
+        lag => (lag <= max_lag, format!("lag {} max {}", lag, max_lag)),
+    }
+}
+
+/// Whether an operator asked for ingest to pause, with `POST /pause`. The
+/// polling loop stops at the end of the cycle it's in, and goes on from the
+/// committed sentinel after `POST /resume`.
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// Answers `GET /readyz` on `addr` until the process exits: 200 when ready,
+/// 503 when not, see [`readiness`]. `GET /healthz` is always 200, with the
+/// build so fleets can be checked for surrogates decoding differently, and
+/// says `paused` while ingest is. `POST /pause` and `POST /resume` pause and
+/// resume it, see [`is_paused`], so `addr` is for operators only.
+pub async fn serve(addr: SocketAddr, max_lag: u64) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Can't listen on {} for health checks: {}", addr, e);
+            return;
+        }
+    };
+    info!("Serving health checks on {}", addr);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream, max_lag).await {
+                        warn!("Failed to answer a health check: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept a health check: {}", e),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, max_lag: u64) -> std::io::Result<()> {
+    let mut request = vec![0; MAX_REQUEST];
+    let len = stream.read(&mut request).await?;
+    let (status, body) = route(&request[..len], max_lag);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// The status line and body answering the request starting with `request`.
+fn route(request: &[u8], max_lag: u64) -> (&'static str, String) {
+    let line = request.split(|&b| b == b'\n').next().unwrap_or_default();
+    let mut parts = std::str::from_utf8(line)
+        .unwrap_or_default()
+        .split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/readyz")) => match readiness(max_lag) {
+            (true, body) => ("200 OK", body),
+            (false, body) => ("503 Service Unavailable", body),
+        },
+        (Some("GET"), Some("/healthz")) => {
+            let paused = if is_paused() { " paused" } else { "" };
+            ("200 OK", format!("ok{} {}", paused, build_info::summary()))
+        }
+        (Some("POST"), Some("/pause")) => {
+            PAUSED.store(true, Ordering::Relaxed);
+            ("200 OK", "pausing after this cycle".to_string())
+        }
+        (Some("POST"), Some("/resume")) => {
+            PAUSED.store(false, Ordering::Relaxed);
+            ("200 OK", "resuming".to_string())
+        }
+        (Some("GET"), _) => ("404 Not Found", "not found".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one test, `LAG` is process-wide
+    #[test]
+    fn ready_once_caught_up() {
+        assert_eq!(
+            route(b"GET /readyz HTTP/1.1\r\n", 10).0,
+            "503 Service Unavailable"
+        );
+
+        set_lag(120, 100);
+        assert_eq!(readiness(10), (false, "lag 20 max 10".to_string()));
+        assert_eq!(
+            route(b"GET /readyz HTTP/1.1\r\n", 10).0,
+            "503 Service Unavailable"
+        );
+        set_lag(105, 100);
+        assert_eq!(route(b"GET /readyz HTTP/1.1\r\n", 10).0, "200 OK");
+        // a nucleus that restarted behind us isn't any lag
+        set_lag(50, 100);
+        assert!(readiness(0).0);
+
+        let (status, body) = route(b"GET /healthz HTTP/1.1\r\n", 10);
+        assert_eq!(status, "200 OK");
+        assert!(body.contains(vemodel::VERSION));
+        assert_eq!(route(b"GET /metrics HTTP/1.1\r\n", 10).0, "404 Not Found");
+        assert_eq!(
+            route(b"POST /readyz HTTP/1.1\r\n", 10).0,
+            "405 Method Not Allowed"
+        );
+    }
+
+    #[test]
+    fn pauses_and_resumes() {
+        assert!(!is_paused());
+        assert_eq!(route(b"POST /pause HTTP/1.1\r\n", 10).0, "200 OK");
+        assert!(is_paused());
+        let (_, body) = route(b"GET /healthz HTTP/1.1\r\n", 10);
+        assert!(body.starts_with("ok paused "));
+        assert_eq!(route(b"GET /pause HTTP/1.1\r\n", 10).0, "404 Not Found");
+
+        assert_eq!(route(b"POST /resume HTTP/1.1\r\n", 10).0, "200 OK");
+        assert!(!is_paused());
+    }
+}
@@ -0,0 +1,121 @@
+use ammonia::Builder;
+use pulldown_cmark::{html, Options, Parser};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// The tags `content_html` keeps unless `VE_CONTENT_HTML_TAGS` says
+/// otherwise, those markdown renders to.
+pub const DEFAULT_TAGS: &[&str] = &[
+    "a",
+    "blockquote",
+    "br",
+    "code",
+    "del",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "img",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "strong",
+    "table",
+    "tbody",
+    "td",
+    "th",
+    "thead",
+    "tr",
+    "ul",
+];
+
+// dropped with everything in them whatever the allowlist, ammonia won't have them in both
+const NEVER_ALLOWED: [&str; 2] = ["script", "style"];
+
+/// A tag rendered content may keep, an item of `VE_CONTENT_HTML_TAGS`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Tag(String);
+
+impl FromStr for Tag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tag = s.to_ascii_lowercase();
+        if tag.is_empty() || !tag.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(format!("not a tag name: {}", s));
+        }
+        if NEVER_ALLOWED.contains(&tag.as_str()) {
+            return Err(format!("{} can't be allowed", tag));
+        }
+        Ok(Self(tag))
+    }
+}
+
+/// [`DEFAULT_TAGS`], as the allowlist.
+pub fn default_tags() -> Vec<Tag> {
+    DEFAULT_TAGS
+        .iter()
+        .map(|&tag| Tag(tag.to_string()))
+        .collect()
+}
+
+/// Renders the markdown `content` of an article or comment to HTML that's
+/// safe to serve as is: only `tags` are kept, the text of the others is,
+/// and of their attributes only those ammonia deems harmless, so no event
+/// handlers or `javascript:` links. HTML in the markdown goes the same way.
+pub fn render(content: &str, tags: &HashSet<Tag>) -> String {
+    let mut rendered = String::with_capacity(content.len() * 3 / 2);
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    html::push_html(&mut rendered, Parser::new_ext(content, options));
+    Builder::default()
+        .tags(tags.iter().map(|tag| tag.0.as_str()).collect())
+        .clean(&rendered)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_markdown_without_anything_executable() {
+        let tags: HashSet<Tag> = default_tags().into_iter().collect();
+        let html = render(
+            "# Title\n\nSome *text*, [a link](https://example.com)",
+            &tags,
+        );
+        assert!(html.contains("<h1>Title</h1>"));
+        assert!(html.contains("<em>text</em>"));
+        assert!(html.contains("href=\"https://example.com\""));
+
+        for content in [
+            "<script>alert(1)</script>",
+            "[x](javascript:alert(1))",
+            "<img src=\"x.png\" onerror=\"alert(1)\">",
+        ] {
+            assert!(!render(content, &tags).contains("alert"), "{}", content);
+        }
+    }
+
+    #[test]
+    fn keeps_the_text_of_tags_not_allowed() {
+        let tags: HashSet<Tag> = ["p".parse().unwrap()].into_iter().collect();
+        let html = render("# Title\n\nbody", &tags);
+        assert!(!html.contains("<h1>"));
+        assert!(html.contains("Title"));
+        assert!(html.contains("<p>body</p>"));
+    }
+
+    #[test]
+    fn parses_tags() {
+        assert_eq!("EM".parse(), Ok(Tag("em".to_string())));
+        assert!("script".parse::<Tag>().is_err());
+        assert!("a href".parse::<Tag>().is_err());
+    }
+}
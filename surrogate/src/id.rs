@@ -0,0 +1,333 @@
+use bytes::BytesMut;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use tokio_postgres::types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+use tokio_postgres::GenericClient;
+use tracing::info;
+
+/// The type of the columns holding entity and user ids, from
+/// `VE_ID_COLUMN_TYPE`.
+///
+/// `BIGINT`, the default, makes for smaller rows and indexes but only holds
+/// ids up to `i64::MAX`: the change of an entity past that fails, to be
+/// retried and dead-lettered. `NUMERIC(20)` holds every `u64`. The columns
+/// are converted on the next start with migrations on, which rewrites the
+/// tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdColumnType {
+    Bigint,
+    Numeric20,
+}
+
+impl IdColumnType {
+    /// As it's written in DDL.
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            Self::Bigint => "BIGINT",
+            Self::Numeric20 => "NUMERIC(20)",
+        }
+    }
+
+    /// As `format_type` gives it for a column of this type.
+    fn formatted(self) -> &'static str {
+        match self {
+            Self::Bigint => "bigint",
+            Self::Numeric20 => "numeric(20,0)",
+        }
+    }
+
+    /// As `information_schema.columns` has it for a column of this type.
+    pub fn data_type(self) -> &'static str {
+        match self {
+            Self::Bigint => "bigint",
+            Self::Numeric20 => "numeric",
+        }
+    }
+}
+
+impl FromStr for IdColumnType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bigint" => Ok(Self::Bigint),
+            "numeric20" => Ok(Self::Numeric20),
+            _ => Err(format!("unknown id column type: {}", s)),
+        }
+    }
+}
+
+/// Every column holding an entity or user id, as table and column. Keep in
+/// step with the migrations, which create them `BIGINT`.
+pub const ID_COLUMNS: &[(&str, &str)] = &[
+    ("subspaces", "id"),
+    ("articles", "id"),
+    ("articles", "author_id"),
+    ("articles", "subspace_id"),
+    ("comments", "id"),
+    ("comments", "author_id"),
+    ("comments", "post_id"),
+    ("users", "id"),
+    ("subspace_authors", "subspace_id"),
+    ("subspace_authors", "author_id"),
+    ("subspace_stats", "subspace_id"),
+    ("dead_letter", "id"),
+    ("scrubbed_changes", "id"),
+];
+
+/// Converts the columns of [`ID_COLUMNS`] not of type `id_type` yet. The
+/// foreign keys between them can't reference a column of another type, so
+/// those of their tables are dropped for the conversion and added back as
+/// they were. Going back to `BIGINT` fails on an id past `i64::MAX`.
+pub async fn set_column_type<C: GenericClient>(
+    client: &C,
+    id_type: IdColumnType,
+) -> Result<(), tokio_postgres::Error> {
+    let mut converting = Vec::new();
+    for &(table, column) in ID_COLUMNS {
+        let current: String = client
+            .query_one(
+                "SELECT format_type(atttypid, atttypmod) FROM pg_attribute
+                 WHERE attrelid = $1::TEXT::regclass AND attname = $2",
+                &[&table, &column],
+            )
+            .await?
+            .get(0);
+        if current != id_type.formatted() {
+            converting.push((table, column));
+        }
+    }
+    if converting.is_empty() {
+        return Ok(());
+    }
+
+    let tables: Vec<&str> = ID_COLUMNS.iter().map(|&(table, _)| table).collect();
+    let foreign_keys = client
+        .query(
+            "SELECT conrelid::regclass::TEXT, conname::TEXT, pg_get_constraintdef(oid)
+             FROM pg_constraint
+             WHERE contype = 'f' AND conparentid = 0 AND conrelid::regclass::TEXT = ANY($1)",
+            &[&tables],
+        )
+        .await?;
+    for key in &foreign_keys {
+        client
+            .batch_execute(&format!(
+                "ALTER TABLE {} DROP CONSTRAINT {}",
+                key.get::<_, String>(0),
+                key.get::<_, String>(1)
+            ))
+            .await?;
+    }
+    for (table, column) in converting {
+        client
+            .batch_execute(&format!(
+                "ALTER TABLE {} ALTER COLUMN {} TYPE {}",
+                table,
+                column,
+                id_type.as_sql()
+            ))
+            .await?;
+        info!("Converted {}.{} to {}", table, column, id_type.as_sql());
+    }
+    for key in &foreign_keys {
+        client
+            .batch_execute(&format!(
+                "ALTER TABLE {} ADD CONSTRAINT {} {}",
+                key.get::<_, String>(0),
+                key.get::<_, String>(1),
+                key.get::<_, String>(2)
+            ))
+            .await?;
+    }
+    Ok(())
+}
+
+/// An id as it's bound to and read from a column of [`ID_COLUMNS`], whichever
+/// [`IdColumnType`] it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SqlId(pub u64);
+
+/// An id past `i64::MAX`, which the `BIGINT` columns can't hold.
+#[derive(Debug)]
+pub struct IdOutOfRange(pub u64);
+
+impl fmt::Display for IdOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id {} is out of range for a BIGINT, see VE_ID_COLUMN_TYPE",
+            self.0
+        )
+    }
+}
+
+impl Error for IdOutOfRange {}
+
+/// What an id column held that's no id: a negative number, a fraction, or
+/// one past `u64::MAX`.
+#[derive(Debug)]
+pub struct NotAnId;
+
+impl fmt::Display for NotAnId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "id column holds no u64")
+    }
+}
+
+impl Error for NotAnId {}
+
+// Ids are `u64` on the nucleus. An `as` cast to `BIGINT` would store one past
+// `i64::MAX` as a negative id no lookup by the real one finds, so binding it
+// fails instead, and the change with it, to be retried and dead-lettered.
+pub(crate) fn sql_id(id: u64) -> Result<i64, IdOutOfRange> {
+    i64::try_from(id).map_err(|_| IdOutOfRange(id))
+}
+
+impl ToSql for SqlId {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        if *ty == Type::NUMERIC {
+            out.extend_from_slice(&numeric(self.0));
+            Ok(IsNull::No)
+        } else {
+            sql_id(self.0)?.to_sql(ty, out)
+        }
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::INT8 || *ty == Type::NUMERIC
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for SqlId {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let id = if *ty == Type::NUMERIC {
+            from_numeric(raw)
+        } else {
+            u64::try_from(i64::from_sql(ty, raw)?).ok()
+        };
+        Ok(Self(id.ok_or(NotAnId)?))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::INT8 || *ty == Type::NUMERIC
+    }
+}
+
+// The binary form of NUMERIC: the number of digits, the weight of the first,
+// the sign and the display scale, then the digits, base 10000 and most
+// significant first. Postgres leaves out trailing zero digits.
+fn numeric(id: u64) -> Vec<u8> {
+    let mut digits = Vec::new();
+    let mut rest = id;
+    while rest > 0 {
+        digits.push((rest % 10_000) as u16);
+        rest /= 10_000;
+    }
+    let weight = digits.len().saturating_sub(1) as u16;
+    digits.reverse();
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    let header = [digits.len() as u16, weight, 0, 0];
+    header
+        .iter()
+        .chain(&digits)
+        .flat_map(|field| field.to_be_bytes())
+        .collect()
+}
+
+// `None` for a NUMERIC that's negative, not a number or infinite, has a
+// fraction, or is past `u64::MAX`.
+fn from_numeric(raw: &[u8]) -> Option<u64> {
+    let field = |i: usize| {
+        raw.get(2 * i..2 * i + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let (digits, weight, sign) = (field(0)?, field(1)? as i16, field(2)?);
+    if sign != 0 {
+        return None;
+    }
+    let mut id: u64 = 0;
+    for i in 0..usize::from(digits) {
+        let digit = field(4 + i)?;
+        let power = i32::from(weight) - i as i32;
+        if power < 0 {
+            if digit != 0 {
+                return None;
+            }
+            continue;
+        }
+        let value = 10_000u64
+            .checked_pow(power as u32)
+            .and_then(|scale| u64::from(digit).checked_mul(scale))?;
+        id = id.checked_add(value)?;
+    }
+    Some(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_past_i64_max_are_refused_for_bigint() {
+        assert_eq!(sql_id(0).unwrap(), 0);
+        assert_eq!(sql_id(i64::MAX as u64).unwrap(), i64::MAX);
+        assert_eq!(sql_id(i64::MAX as u64 + 1).unwrap_err().0, 1 << 63);
+        assert!(sql_id(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn numeric_ids_are_encoded_like_postgres_does() {
+        assert_eq!(numeric(0), [0, 0, 0, 0, 0, 0, 0, 0]);
+        // 1 0000 0000: one digit of weight 2, the zero ones left out
+        assert_eq!(numeric(100_000_000), [0, 1, 0, 2, 0, 0, 0, 0, 0, 1]);
+        // 12 3456
+        assert_eq!(
+            numeric(123_456),
+            [0, 2, 0, 1, 0, 0, 0, 0, 0, 12, 0x0d, 0x80]
+        );
+    }
+
+    #[test]
+    fn numeric_ids_round_trip_over_the_whole_range() {
+        for id in [0, 1, 9_999, 10_000, 123_456, 1 << 63, u64::MAX] {
+            assert_eq!(from_numeric(&numeric(id)), Some(id), "{}", id);
+        }
+    }
+
+    #[test]
+    fn numerics_that_are_no_id_are_refused() {
+        // -1
+        assert_eq!(from_numeric(&[0, 1, 0, 0, 0x40, 0, 0, 0, 0, 1]), None);
+        // NaN
+        assert_eq!(from_numeric(&[0, 0, 0, 0, 0xc0, 0, 0, 0]), None);
+        // 1.5
+        assert_eq!(
+            from_numeric(&[0, 2, 0, 0, 0, 0, 0, 1, 0, 1, 0x13, 0x88]),
+            None
+        );
+        // 1.0 is 1 all the same
+        assert_eq!(from_numeric(&[0, 2, 0, 0, 0, 0, 0, 1, 0, 1, 0, 0]), Some(1));
+        // 10^20
+        assert_eq!(from_numeric(&[0, 1, 0, 5, 0, 0, 0, 0, 0, 1]), None);
+        assert_eq!(from_numeric(&[0, 1]), None);
+    }
+
+    #[test]
+    fn types_parse_as_configured() {
+        assert_eq!("bigint".parse(), Ok(IdColumnType::Bigint));
+        assert_eq!("numeric20".parse(), Ok(IdColumnType::Numeric20));
+        assert!("int".parse::<IdColumnType>().is_err());
+    }
+}
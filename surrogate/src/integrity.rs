@@ -0,0 +1,204 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use tokio_postgres::Client;
+use tracing::info;
+
+use crate::config::Config;
+use crate::id::SqlId;
+use crate::model::Model;
+
+/// What `verify-referential-integrity` does with the orphans it finds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repair {
+    /// Fetch the missing parents from the nucleus and apply them, the orphans
+    /// of a parent the nucleus doesn't have either stay as they are.
+    Refetch,
+    /// Delete the orphans, as a delete event would.
+    Delete,
+}
+
+impl FromStr for Repair {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refetch" => Ok(Self::Refetch),
+            "delete" => Ok(Self::Delete),
+            _ => Err(format!("unknown repair: {}", s)),
+        }
+    }
+}
+
+/// A row whose parent isn't stored: an article of a subspace or a comment on
+/// an article that isn't there. Nothing holds the tables to each other once
+/// they're partitioned, and deleting a subspace or an article leaves what's
+/// in it behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Orphan {
+    pub model: Model,
+    pub id: u64,
+    pub parent: u64,
+}
+
+impl Orphan {
+    /// The model of the missing parent.
+    pub fn parent_model(&self) -> Model {
+        parent_of(self.model)
+    }
+}
+
+fn parent_of(model: Model) -> Model {
+    match model {
+        Model::Comment => Model::Article,
+        _ => Model::Subspace,
+    }
+}
+
+/// Whether rows of `model` are looked at for orphans: only when it's indexed
+/// along with its parent's model, as the read views have it. Every row would
+/// look orphaned otherwise, and repairing them would delete them all.
+pub fn checks(config: &Config, model: Model) -> bool {
+    config.indexes(model) && config.indexes(parent_of(model))
+}
+
+impl fmt::Display for Orphan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} has no {} {}",
+            self.model.as_str(),
+            self.id,
+            self.parent_model().as_str(),
+            self.parent
+        )
+    }
+}
+
+/// The articles with no stored subspace, by id, none unless [`checks`] has
+/// them looked at.
+pub async fn orphaned_articles(
+    client: &Client,
+    config: &Config,
+) -> Result<Vec<Orphan>, tokio_postgres::Error> {
+    orphans(
+        client,
+        config,
+        Model::Article,
+        "SELECT a.id, a.subspace_id AS parent FROM articles a
+         WHERE NOT EXISTS (SELECT 1 FROM subspaces s WHERE s.id = a.subspace_id)
+         ORDER BY a.id",
+    )
+    .await
+}
+
+/// The comments on an article that isn't stored, by id, none unless
+/// [`checks`] has them looked at. Replies to another comment aren't looked
+/// at, their thread is.
+pub async fn orphaned_comments(
+    client: &Client,
+    config: &Config,
+) -> Result<Vec<Orphan>, tokio_postgres::Error> {
+    orphans(
+        client,
+        config,
+        Model::Comment,
+        "SELECT c.id, c.post_id AS parent FROM comments c
+         WHERE c.target_type = 'article'
+           AND NOT EXISTS (SELECT 1 FROM articles a WHERE a.id = c.post_id)
+         ORDER BY c.id",
+    )
+    .await
+}
+
+async fn orphans(
+    client: &Client,
+    config: &Config,
+    model: Model,
+    query: &str,
+) -> Result<Vec<Orphan>, tokio_postgres::Error> {
+    if !checks(config, model) {
+        info!(
+            "{}s or {}s aren't indexed, not looking for orphaned {}s",
+            model.as_str(),
+            parent_of(model).as_str(),
+            model.as_str()
+        );
+        return Ok(Vec::new());
+    }
+    let rows = client.query(query, &[]).await?;
+    Ok(rows
+        .iter()
+        .map(|row| Orphan {
+            model,
+            id: row.get::<_, SqlId>("id").0,
+            parent: row.get::<_, SqlId>("parent").0,
+        })
+        .collect())
+}
+
+/// The parents `orphans` are missing, each once, in the order first missed.
+pub fn missing_parents(orphans: &[Orphan]) -> Vec<(Model, u64)> {
+    let mut seen = HashSet::new();
+    orphans
+        .iter()
+        .map(|orphan| (orphan.parent_model(), orphan.parent))
+        .filter(|&parent| seen.insert(parent))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orphans_name_their_missing_parent() {
+        let orphans = [
+            Orphan {
+                model: Model::Article,
+                id: 7,
+                parent: 3,
+            },
+            Orphan {
+                model: Model::Comment,
+                id: 9,
+                parent: 7,
+            },
+            Orphan {
+                model: Model::Comment,
+                id: 10,
+                parent: 7,
+            },
+        ];
+        assert_eq!(orphans[0].to_string(), "article 7 has no subspace 3");
+        assert_eq!(orphans[1].to_string(), "comment 9 has no article 7");
+        assert_eq!(
+            missing_parents(&orphans),
+            [(Model::Subspace, 3), (Model::Article, 7)]
+        );
+    }
+
+    #[test]
+    fn checks_only_models_indexed_with_their_parent() {
+        let config = |models: [Model; 2]| Config {
+            models: models.into_iter().collect(),
+            ..Config::for_tests()
+        };
+        let without_subspaces = config([Model::Article, Model::Comment]);
+        assert!(!checks(&without_subspaces, Model::Article));
+        assert!(checks(&without_subspaces, Model::Comment));
+        let without_comments = config([Model::Subspace, Model::Article]);
+        assert!(checks(&without_comments, Model::Article));
+        assert!(!checks(&without_comments, Model::Comment));
+        let without_articles = config([Model::Subspace, Model::Comment]);
+        assert!(!checks(&without_articles, Model::Article));
+        assert!(!checks(&without_articles, Model::Comment));
+    }
+
+    #[test]
+    fn parses_repairs() {
+        assert_eq!("refetch".parse(), Ok(Repair::Refetch));
+        assert_eq!("delete".parse(), Ok(Repair::Delete));
+        assert!("fix".parse::<Repair>().is_err());
+    }
+}
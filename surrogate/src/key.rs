@@ -0,0 +1,193 @@
+use parity_scale_codec::{Compact, Decode, Encode};
+
+use crate::model::{Model, MODELS};
+
+/// How an id is encoded in a storage key.
+#[derive(Debug, Clone, Copy)]
+pub struct IdCodec {
+    /// Reads an id off the front of the bytes and moves past it, `None` if
+    /// they don't start with one.
+    pub read: fn(&mut &[u8]) -> Option<u64>,
+    pub encode: fn(u64) -> Vec<u8>,
+}
+
+impl IdCodec {
+    /// The id `bytes` stand for, `None` if they aren't one and nothing else.
+    pub fn decode(&self, mut bytes: &[u8]) -> Option<u64> {
+        let id = (self.read)(&mut bytes)?;
+        bytes.is_empty().then_some(id)
+    }
+}
+
+/// A `u64` in 8 big-endian bytes, as the nucleus keys its entities so they
+/// sort by id.
+pub const BIG_ENDIAN: IdCodec = IdCodec {
+    read: |bytes| {
+        let (id, rest) = bytes.split_first_chunk::<8>()?;
+        *bytes = rest;
+        Some(u64::from_be_bytes(*id))
+    },
+    encode: |id| id.to_be_bytes().to_vec(),
+};
+
+/// A SCALE compact `u64`, for keys written with `Compact(id).encode()`.
+pub const SCALE_COMPACT: IdCodec = IdCodec {
+    read: |bytes| Compact::<u64>::decode(bytes).ok().map(|id| id.0),
+    encode: |id| Compact(id).encode(),
+};
+
+/// One field of a storage key past its prefix.
+#[derive(Debug, Clone, Copy)]
+pub struct Field {
+    pub name: &'static str,
+    pub codec: IdCodec,
+}
+
+/// The key layout of the entities the nucleus has now: the prefix, then the
+/// id, big-endian.
+pub const SIMPLE: &[Field] = &[Field {
+    name: "id",
+    codec: BIG_ENDIAN,
+}];
+
+/// A storage key prefix of the nucleus, with the model of the entities keyed
+/// under it and the layout of their keys. Each model's is in [`MODELS`].
+///
+/// A layout is the fields following the prefix, in order, the entity's own
+/// id last: a nested entity keyed under its parent would be laid out as
+/// `&[Field { name: "parent_id", .. }, Field { name: "id", .. }]`. New key
+/// shapes are new layouts, nothing else reads the bytes.
+#[derive(Debug)]
+pub struct Prefix {
+    pub bytes: &'static [u8; 5],
+    pub model: Model,
+    pub layout: &'static [Field],
+}
+
+impl Prefix {
+    /// The prefix `key` starts with, `None` if it's of no indexed model.
+    pub fn of(key: &[u8]) -> Option<&'static Self> {
+        MODELS
+            .iter()
+            .map(|info| &info.prefix)
+            .find(|prefix| key.starts_with(prefix.bytes))
+    }
+
+    pub fn of_model(model: Model) -> &'static Self {
+        &model.info().prefix
+    }
+
+    /// The fields of `key`, a key with this prefix, in layout order. `None`
+    /// if it doesn't have every one of them or has bytes past the last.
+    pub fn decode(&self, key: &[u8]) -> Option<Vec<u64>> {
+        let mut bytes = key.strip_prefix(&self.bytes[..])?;
+        let fields = self
+            .layout
+            .iter()
+            .map(|field| (field.codec.read)(&mut bytes))
+            .collect::<Option<Vec<_>>>()?;
+        bytes.is_empty().then_some(fields)
+    }
+
+    /// The id of `key`, a key with this prefix.
+    pub fn decode_id(&self, key: &[u8]) -> Option<u64> {
+        self.decode(key)?.last().copied()
+    }
+
+    /// The key with fields `fields`, in layout order, `None` if there are
+    /// more or fewer than the layout has.
+    pub fn encode(&self, fields: &[u64]) -> Option<Vec<u8>> {
+        if fields.len() != self.layout.len() {
+            return None;
+        }
+        let mut key = self.bytes.to_vec();
+        for (field, &value) in self.layout.iter().zip(fields) {
+            key.extend((field.codec.encode)(value));
+        }
+        Some(key)
+    }
+
+    /// The key of entity `id`, under a prefix whose keys have only the id.
+    pub fn key(&self, id: u64) -> Vec<u8> {
+        self.encode(&[id])
+            .expect("the key of an entity nested under another needs its parent")
+    }
+}
+
+/// Splits a storage key into its printable prefix and its id. Ids of
+/// prefixes other than the known ones are read as big-endian.
+pub fn split_key(key: &[u8]) -> (String, u64) {
+    let prefix = String::from_utf8_lossy(&key[..key.len().min(5)]).into_owned();
+    let id = Prefix::of(key)
+        .and_then(|prefix| prefix.decode_id(key))
+        .or_else(|| key.get(5..).map(vec_to_u64))
+        .unwrap_or_default();
+    (prefix, id)
+}
+
+pub fn vec_to_u64(v: &[u8]) -> u64 {
+    let mut array = [0u8; 8];
+    let len = std::cmp::min(v.len(), 8);
+    array[..len].copy_from_slice(&v[..len]);
+    u64::from_be_bytes(array)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_round_trip_through_their_prefix() {
+        for model in Model::ALL {
+            let prefix = Prefix::of_model(model);
+            let key = prefix.key(7);
+            assert_eq!(Prefix::of(&key).map(|prefix| prefix.model), Some(model));
+            assert_eq!(prefix.decode_id(&key), Some(7));
+        }
+        let article = Prefix::of_model(Model::Article);
+        assert_eq!(
+            article.key(7),
+            [&b"vear:"[..], &7u64.to_be_bytes()].concat()
+        );
+        assert_eq!(article.decode_id(b"vear:\x07"), None);
+        assert!(Prefix::of(b"_reqnum").is_none());
+    }
+
+    #[test]
+    fn id_codecs_round_trip() {
+        for codec in [BIG_ENDIAN, SCALE_COMPACT] {
+            for id in [0, 7, 1 << 40, u64::MAX] {
+                assert_eq!(codec.decode(&(codec.encode)(id)), Some(id));
+            }
+        }
+        assert_eq!((SCALE_COMPACT.encode)(7), [28]);
+        assert_eq!(SCALE_COMPACT.decode(&[28, 0]), None);
+        assert_eq!(BIG_ENDIAN.decode(&[7]), None);
+    }
+
+    #[test]
+    fn composite_keys_decode_field_by_field() {
+        let nested = Prefix {
+            bytes: b"vecr:",
+            model: Model::Comment,
+            layout: &[
+                Field {
+                    name: "post_id",
+                    codec: SCALE_COMPACT,
+                },
+                Field {
+                    name: "id",
+                    codec: BIG_ENDIAN,
+                },
+            ],
+        };
+        let key = nested.encode(&[7, 9]).unwrap();
+        assert_eq!(key, [&b"vecr:\x1c"[..], &9u64.to_be_bytes()].concat());
+        assert_eq!(nested.decode(&key), Some(vec![7, 9]));
+        assert_eq!(nested.decode_id(&key), Some(9));
+        assert_eq!(nested.encode(&[9]), None);
+        // short of a field, or with bytes to spare
+        assert_eq!(nested.decode(&key[..key.len() - 1]), None);
+        assert_eq!(nested.decode(&[&key[..], &[0]].concat()), None);
+    }
+}
@@ -0,0 +1,88 @@
+use serde::Serialize;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::info;
+
+use crate::account::AvsId;
+
+// how often a standby tries for the lock again
+const STANDBY_RETRY: Duration = Duration::from_secs(5);
+
+/// What a surrogate does when another one already writes for its AVS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Standby {
+    /// Wait as a hot standby and take over once the other one goes away.
+    Wait,
+    /// Exit with an error.
+    Exit,
+}
+
+impl FromStr for Standby {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wait" => Ok(Self::Wait),
+            "exit" => Ok(Self::Exit),
+            _ => Err(format!("unknown standby policy: {}", s)),
+        }
+    }
+}
+
+/// Makes this the only surrogate writing for `avs_id` to the database of
+/// `client`, with a session-level advisory lock keyed by the AVS id.
+///
+/// The lock has to be taken on the connection the writer goes on to use: it
+/// lasts as long as that session, so a surrogate that dies or loses its
+/// connection lets a standby take over, and one that has lost it can't write
+/// anymore either. Surrogates of different AVSs sharing a database don't
+/// hold each other up, short of their ids hashing alike.
+pub async fn acquire(
+    client: &Client,
+    avs_id: &AvsId,
+    standby: Standby,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut waiting = false;
+    loop {
+        let locked: bool = client
+            .query_one(
+                "SELECT pg_try_advisory_lock(hashtextextended($1, 0))",
+                &[&avs_id.as_str()],
+            )
+            .await?
+            .get(0);
+        if locked {
+            info!("Holding the writer lock for AVS {}", avs_id.as_str());
+            return Ok(());
+        }
+        if standby == Standby::Exit {
+            return Err(format!(
+                "another surrogate is writing for AVS {}, set VE_STANDBY=wait to wait for it",
+                avs_id.as_str()
+            )
+            .into());
+        }
+        if !waiting {
+            info!(
+                "Another surrogate is writing for AVS {}, waiting as a standby",
+                avs_id.as_str()
+            );
+            waiting = true;
+        }
+        tokio::time::sleep(STANDBY_RETRY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_standby() {
+        assert_eq!("wait".parse(), Ok(Standby::Wait));
+        assert_eq!("exit".parse(), Ok(Standby::Exit));
+        assert!("fight".parse::<Standby>().is_err());
+    }
+}
@@ -0,0 +1,50 @@
+pub mod account;
+pub mod alert;
+pub mod batch;
+pub mod build_info;
+pub mod bundle;
+pub mod cache;
+pub mod change_feed;
+pub mod cli;
+pub mod config;
+pub mod connection;
+pub mod content;
+pub mod counts;
+pub mod db;
+pub mod dead_letter;
+pub mod decode_alarm;
+pub mod denorm;
+pub mod etag;
+pub mod expiry;
+pub mod file_sink;
+pub mod health;
+pub mod html;
+pub mod id;
+pub mod integrity;
+pub mod key;
+pub mod leader;
+pub mod logging;
+pub mod metrics;
+pub mod migrations;
+pub mod model;
+pub mod nickname;
+pub mod nucleus;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod partition;
+pub mod poll;
+pub mod projection;
+pub mod query;
+pub mod required;
+pub mod reset;
+pub mod rpc;
+pub mod schema;
+pub mod scrub;
+pub mod shadow;
+pub mod sink;
+pub mod sse;
+pub mod stats;
+pub mod text;
+pub mod thread;
+pub mod trending;
+pub mod verify;
@@ -0,0 +1,99 @@
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_FILTER: &str = "info";
+
+/// Installs the global subscriber, filtering events as `VE_LOG` says, or
+/// `RUST_LOG` when it's unset, at `info` for everything by default.
+///
+/// Filters are comma separated `target=level` directives, where the most
+/// specific target wins, e.g. `VE_LOG=info,surrogate::db=warn` to drop the
+/// line logged per upsert. Targets are module paths:
+///
+/// - `surrogate`: the polling loop and the commands. Being a prefix of all
+///   the others, it also sets the level of those not given one.
+/// - `surrogate::db`: the writer, an info line per upsert and delete.
+/// - `surrogate::rpc`: decoding nucleus responses.
+/// - `surrogate::sink`, `surrogate::file_sink`: secondary sinks.
+/// - `surrogate::migrations`, `surrogate::partition`, `surrogate::content`,
+///   `surrogate::trending`: schema upkeep and the background tasks.
+///
+/// Built with the `otel` feature, spans and metrics are exported to
+/// `VE_OTLP_ENDPOINT` as well when it's set, see [`crate::otel::layer`]. The
+/// filter applies to the spans exported too.
+pub fn init() -> Result<(), String> {
+    let directives = std::env::var("VE_LOG")
+        .or_else(|_| std::env::var("RUST_LOG"))
+        .unwrap_or_else(|_| DEFAULT_FILTER.to_string());
+    let registry = tracing_subscriber::registry()
+        .with(filter(&directives)?)
+        .with(tracing_subscriber::fmt::layer());
+    #[cfg(feature = "otel")]
+    let registry = registry.with(crate::otel::layer()?);
+    registry.init();
+    #[cfg(not(feature = "otel"))]
+    if std::env::var_os("VE_OTLP_ENDPOINT").is_some() {
+        tracing::warn!(
+            "VE_OTLP_ENDPOINT is set, but this build has no otel feature to export with"
+        );
+    }
+    Ok(())
+}
+
+/// Exports what's still buffered for `VE_OTLP_ENDPOINT`, for before exiting.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    crate::otel::shutdown();
+}
+
+/// Parses filter directives, refusing malformed ones rather than quietly
+/// logging everything at the default level.
+pub fn filter(directives: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(directives).map_err(|e| format!("invalid log filter {}: {}", directives, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn honors_per_target_levels() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(filter("info,surrogate::db=warn").unwrap())
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(target: "surrogate::db", "upserted article");
+            tracing::warn!(target: "surrogate::db", "slow upsert");
+            tracing::info!(target: "surrogate::rpc", "decoded response");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(!output.contains("upserted article"));
+        assert!(output.contains("slow upsert"));
+        assert!(output.contains("decoded response"));
+    }
+
+    #[test]
+    fn rejects_malformed_filters() {
+        assert!(filter("surrogate::db=loud").is_err());
+    }
+}
@@ -1,297 +1,2166 @@
-use jsonrpsee::core::client::ClientT;
-use jsonrpsee::http_client::HttpClientBuilder;
-use jsonrpsee::rpc_params;
-use parity_scale_codec::{Decode, Encode};
-use tokio_postgres::{Client, NoTls};
-use tokio::sync::mpsc;
-use tokio::time::{sleep, Duration};
-
-use vemodel::{
-    Method, VeArticle, VeComment, VeSubspace, PREFIX_ARTICLE_KEY, PREFIX_COMMENT_KEY,
-    PREFIX_SUBSPACE_KEY,
-};
-
-async fn setup_database(client: &Client) -> Result<(), Box<dyn std::error::Error>> {
-    // Create tables with proper schema matching the Rust structs
-    client.batch_execute("
-        CREATE TABLE IF NOT EXISTS subspaces (
-            id BIGINT PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            slug VARCHAR NOT NULL,
-            description TEXT,
-            banner VARCHAR,
-            status SMALLINT NOT NULL,
-            weight SMALLINT NOT NULL,
-            created_time BIGINT NOT NULL
+use jsonrpsee::http_client::HttpClient;
+use std::collections::HashMap;
+use std::future::Future;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep, Duration, Instant};
+use tokio_postgres::Client;
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument};
+
+use surrogate::alert::{self, Condition, Staleness};
+use surrogate::batch;
+use surrogate::build_info;
+use surrogate::bundle;
+use surrogate::cache::CachingNucleus;
+use surrogate::cli::{self, Command};
+use surrogate::config::{Config, MissingEntityPolicy, StartMode};
+use surrogate::connection::Connection;
+use surrogate::content;
+use surrogate::counts;
+use surrogate::db::{self, Change, Entity, Message};
+use surrogate::dead_letter::{self, unix_now, DeadLetter};
+use surrogate::decode_alarm::DecodeAlarm;
+use surrogate::denorm;
+use surrogate::expiry;
+use surrogate::health;
+use surrogate::id::SqlId;
+use surrogate::integrity::{self, Orphan, Repair};
+use surrogate::key::{split_key, Prefix};
+use surrogate::leader;
+use surrogate::logging;
+use surrogate::metrics;
+use surrogate::model::Model;
+use surrogate::nucleus::{self, AvsErrorClass, Fetched, Nucleus, NucleusError, RpcNucleus};
+use surrogate::partition;
+use surrogate::poll::PollInterval;
+use surrogate::required::{self, BlankField, Required};
+use surrogate::reset::SentinelReset;
+use surrogate::rpc::{ChangeEvent, EventBatch, ResponseError, UndecodableEvent};
+use surrogate::shadow;
+use surrogate::sink::{self, Fanout, PRIMARY};
+use surrogate::trending;
+use surrogate::verify;
+
+use vemodel::{Method, SubspaceId, VeArticle, VeComment, VeSubspace};
+
+// how often the lag behind the nucleus head is measured, at most
+const LAG_INTERVAL: Duration = Duration::from_secs(5);
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    logging::init()?;
+    info!("Surrogate starting, {}", build_info::summary());
+
+    let cli::Cli {
+        command,
+        validate_schema,
+        print_config,
+        check,
+        change_log,
+    } = cli::parse(std::env::args().skip(1))?;
+    let config = Config::from_env()?;
+    if print_config {
+        println!("{}", serde_json::to_string_pretty(&config)?);
+        return Ok(());
+    }
+    let connection = Connection::open(&config)?;
+    if check {
+        return check_connectivity(connection, &config).await;
+    }
+
+    // read-only, so it's done before the database is touched at all
+    if let Command::VerifyDecode(sample) = command {
+        return verify_decode(&connection.client(), &config, sample).await;
+    }
+
+    // PostgreSQL connection
+    let mut client = db::connect(&config).await?;
+    if command == Command::Run {
+        // before setup, so a standby leaves the schema and views alone until it takes over.
+        // On the writer's connection, the lock goes with its session
+        leader::acquire(&client, &config.avs_id, config.standby).await?;
+    }
+
+    // Set up database tables
+    let migrate = command == Command::Migrate || !validate_schema;
+    db::setup_database(&mut client, &config, migrate).await?;
+
+    let result = match command {
+        Command::Run => {
+            let nucleus = RpcNucleus::new(
+                connection,
+                config.avs_id.clone(),
+                config.event_page_size,
+                config.event_codec,
+            );
+            let nucleus =
+                CachingNucleus::new(nucleus, config.entity_cache_size, config.entity_cache_ttl);
+            let result = run(client, nucleus, config).await;
+            if let Err(e) = &result {
+                alert::stopped(e.to_string()).await;
+            }
+            result
+        }
+        Command::Migrate => Ok(()),
+        Command::DeadLetterList => {
+            for letter in dead_letter::list(&client).await? {
+                println!(
+                    "reqnum={} sink={} key={}{} method={:?} attempts={} first_seen={} error={}",
+                    letter.reqnum,
+                    letter.sink,
+                    letter.prefix,
+                    letter.id,
+                    letter.method,
+                    letter.attempts,
+                    letter.first_seen,
+                    letter.error
+                );
+            }
+            Ok(())
+        }
+        Command::DeadLetterRedrive(reqnum) => {
+            let nucleus = RpcNucleus::new(
+                connection,
+                config.avs_id.clone(),
+                config.event_page_size,
+                config.event_codec,
+            );
+            redrive(&client, &nucleus, &config, reqnum, change_log).await
+        }
+        Command::Resync(model, id) => {
+            let nucleus = RpcNucleus::new(
+                connection,
+                config.avs_id.clone(),
+                config.event_page_size,
+                config.event_codec,
+            );
+            resync(&client, &nucleus, &config, model, id, change_log).await
+        }
+        Command::ContentRecode => content::recode(&client, config.content_encoding).await,
+        Command::ExportSubspace(id) => {
+            let bundle = bundle::export(&client, SubspaceId(id))
+                .await?
+                .ok_or_else(|| format!("no subspace with id {}", id))?;
+            println!("{}", serde_json::to_string_pretty(&bundle)?);
+            Ok(())
+        }
+        Command::ImportBundle(path) => {
+            let bundle = serde_json::from_slice(&std::fs::read(&path)?)?;
+            let sinks = sink::build_for_command(&config, change_log).await?;
+            let imported = bundle::import(&client, &config, &sinks, bundle).await;
+            for sink in &sinks {
+                sink.close().await?;
+            }
+            imported
+        }
+        Command::VerifyCounts { repair: false } => {
+            let drifted = counts::drift(&client, &config).await?;
+            for drift in &drifted {
+                println!("{}", drift);
+            }
+            info!("{} authors with drifted counts", drifted.len());
+            Ok(())
+        }
+        Command::VerifyCounts { repair: true } => {
+            counts::repair(&client, &config).await?;
+            Ok(())
+        }
+        Command::VerifyIntegrity { repair } => {
+            let nucleus = RpcNucleus::new(
+                connection,
+                config.avs_id.clone(),
+                config.event_page_size,
+                config.event_codec,
+            );
+            verify_integrity(&client, &nucleus, &config, repair, change_log).await
+        }
+        Command::VerifyShadow(sample) => verify_shadow(&client, &config, sample).await,
+        Command::RebuildDenorm => denorm::rebuild(&client, &config).await,
+        Command::VerifyDecode(_) => unreachable!("handled before connecting"),
+    };
+    logging::shutdown();
+    result
+}
+
+async fn run(
+    client: Client,
+    nucleus: impl Nucleus,
+    config: Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    alert::spawn(&config);
+    let committed = match db::load_sentinel(&client, config.avs_id.as_str()).await? {
+        Some(sentinel) => sentinel,
+        None => {
+            let sentinel =
+                first_sentinel(&nucleus, config.initial_sentinel, config.start_mode).await?;
+            // so a restart before the first checkpoint starts from it again, not from a later head
+            db::seed_sentinel(&client, config.avs_id.as_str(), sentinel).await?;
+            sentinel
+        }
+    };
+    let mut progress = Progress::new(committed);
+
+    let (tx, rx) = mpsc::channel(100);
+
+    // Spawn a task for PostgreSQL operations
+    tokio::spawn(db::run_writer(client, config.clone(), committed, rx));
+    let fanout = Fanout::spawn(tx, sink::build(&config).await?, &config);
+    if config.partitioning {
+        tokio::spawn(partition::run_maintenance(config.clone()));
+    }
+    if let Some(interval) = config.trending_refresh {
+        tokio::spawn(trending::run_refresh(config.clone(), interval));
+    }
+    if let Some(addr) = config.health_addr {
+        tokio::spawn(health::serve(addr, config.max_ready_lag));
+    }
+    // measured between cycles, at most once per `LAG_INTERVAL` while catching up
+    let mut lag_measured: Option<Instant> = None;
+    // swept from the loop, so expired content goes through the sinks between cycles; the
+    // connection of its own only reads, the writer's holds the open transaction
+    let expiry_client = if config.article_ttls.is_on() {
+        Some(db::connect(&config).await?)
+    } else {
+        None
+    };
+    let mut sweeps = tokio::time::interval(config.expiry_interval);
+    sweeps.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    // checked between cycles, so a cycle is never cut short half applied
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut hangup = signal(SignalKind::hangup())?;
+    // ingest as the loop last left it, it only looks at `/pause` between cycles
+    let mut paused = false;
+    let mut staleness = Staleness::new(progress.committed, Instant::now());
+    let mut poll_interval = PollInterval::new(config.poll_interval_min, config.poll_interval_max);
+
+    loop {
+        let more = if health::is_paused() {
+            if !paused {
+                paused = true;
+                pause_ingest(&fanout, &mut progress).await?;
+            }
+            false
+        } else {
+            if paused {
+                paused = false;
+                metrics::INGEST_PAUSED.set(0);
+                info!("Ingest resumed from sentinel {}", progress.committed);
+            }
+            let from = progress.sentinel;
+            let span = info_span!("poll", committed = progress.committed);
+            let more = poll_cycle(&nucleus, &config, &fanout, &mut progress)
+                .instrument(span)
+                .await?;
+            poll_interval.observe(progress.sentinel > from);
+            more
+        };
+        if lag_measured.is_none_or(|at| at.elapsed() >= LAG_INTERVAL) {
+            lag_measured = Some(Instant::now());
+            match nucleus.head_reqnum().await {
+                Ok(Ok(head)) => health::set_lag(head, progress.committed),
+                Ok(Err(e)) => debug!("The nucleus failed get_head_reqnum, lag unknown: {}", e),
+                Err(e) => debug!("Failed to ask the nucleus for its head, lag unknown: {}", e),
+            }
+        }
+        if let Some(after) = config.alert_stale_after {
+            // paused, it's meant to stay put; without a head, what was served but not applied tells
+            let behind = !paused && health::lag().map_or(metrics::BACKLOG.get() > 0, |lag| lag > 0);
+            if let Some(stuck) = staleness.check(progress.committed, behind, Instant::now(), after)
+            {
+                alert::raise(
+                    Condition::StaleSentinel,
+                    format!(
+                        "committed sentinel {} hasn't moved for {}s with the nucleus ahead",
+                        progress.committed,
+                        stuck.as_secs()
+                    ),
+                );
+            }
+        }
+        let pause = if more {
+            config.catch_up_pause
+        } else if let (Some(since), Some(window)) = (progress.held_since, config.compact_window) {
+            // back once the held events are due, or sooner to pick up a delete
+            window
+                .saturating_sub(since.elapsed())
+                .min(poll_interval.current())
+        } else {
+            poll_interval.current()
+        };
+        tokio::select! {
+            _ = sleep(pause) => {}
+            _ = hangup.recv() => reset_sentinel(&config, &fanout, &mut progress).await?,
+            _ = sweeps.tick(), if expiry_client.is_some() && !paused => {
+                if let Some(client) = &expiry_client {
+                    if let Err(e) = expiry::sweep(client, &config.article_ttls, &fanout, progress.sentinel).await {
+                        error!("Expiry sweep failed, trying again next time: {}", e);
+                    }
+                }
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    // what the open transaction holds is read again on the next start
+    info!("Shutting down, closing sinks");
+    fanout.shutdown().await;
+    Ok(())
+}
+
+// Where a database that has never synced starts reading from,
+// `VE_INITIAL_SENTINEL` or else where `VE_START_MODE` says.
+async fn first_sentinel(
+    nucleus: &impl Nucleus,
+    initial: Option<u64>,
+    mode: StartMode,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    if let Some(sentinel) = initial {
+        info!(
+            "Starting from VE_INITIAL_SENTINEL {}, leaving out the history up to it",
+            sentinel
         );
-        
-        CREATE TABLE IF NOT EXISTS articles (
-            id BIGINT PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            content TEXT NOT NULL,
-            author_id BIGINT NOT NULL,
-            author_nickname VARCHAR NOT NULL,
-            subspace_id BIGINT NOT NULL,
-            ext_link VARCHAR,
-            status SMALLINT NOT NULL,
-            weight SMALLINT NOT NULL,
-            created_time BIGINT NOT NULL,
-            updated_time BIGINT NOT NULL,
-            FOREIGN KEY (subspace_id) REFERENCES subspaces(id)
+        return Ok(sentinel);
+    }
+    match mode {
+        StartMode::Backfill => Ok(0),
+        StartMode::Tail => {
+            let head = nucleus.head_reqnum().await??;
+            info!(
+                "Tailing the nucleus from reqnum {}, leaving out the history before it",
+                head
+            );
+            Ok(head)
+        }
+    }
+}
+
+/// Where the polling loop stands between cycles.
+struct Progress {
+    /// Durable in the database.
+    committed: u64,
+    /// How far the open transaction of the database task has got.
+    sentinel: u64,
+    /// reqnum -> (failed attempts so far, unix time of the first one)
+    attempts: HashMap<u64, (u32, i64)>,
+    /// Since when new events are held back for `VE_COMPACT_WINDOW_MS`.
+    held_since: Option<Instant>,
+    /// Highest ids created so far, for reconciling reqnum gaps.
+    created: batch::Created,
+    /// The reqnum following the last gap reported, gaps are served again
+    /// until the events past them are committed but only reported once.
+    gap_reported: u64,
+    /// Recent decode failures, for telling a schema change from a bad entity.
+    decode_alarm: DecodeAlarm,
+}
+
+impl Progress {
+    fn new(committed: u64) -> Self {
+        Self {
+            committed,
+            sentinel: committed,
+            attempts: HashMap::new(),
+            held_since: None,
+            created: batch::Created::default(),
+            gap_reported: committed,
+            decode_alarm: DecodeAlarm::default(),
+        }
+    }
+}
+
+/// Fetches one batch of change events, hands them to the sinks and checkpoints past those settled.
+/// Returns whether there are events left over, a full page or those past `VE_MAX_EVENTS_PER_CYCLE`,
+/// in which case the next cycle only waits out `VE_CATCH_UP_PAUSE_MS`.
+async fn poll_cycle(
+    nucleus: &impl Nucleus,
+    config: &Config,
+    fanout: &Fanout,
+    progress: &mut Progress,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let Progress {
+        committed,
+        sentinel,
+        attempts,
+        held_since,
+        created,
+        gap_reported,
+        decode_alarm,
+    } = progress;
+    debug!("==> sentinel: {}, committed: {}", sentinel, committed);
+    // the nucleus forgets everything up to the sentinel it is sent, so it
+    // must only ever see one that has been committed
+    let res = match nucleus.get_from_common_key(*committed).await {
+        Ok(res) => res,
+        Err(e @ NucleusError::Response(ResponseError::NotAString(_))) => {
+            warn!("Retrying cycle: {}", e);
+            alert::failed(Condition::NucleusFailures, e.to_string());
+            return Ok(false);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            metrics::NUCLEUS_POLL_ERRORS.inc();
+            return match nucleus::classify(&e, &config.fatal_nucleus_errors) {
+                AvsErrorClass::Transient => {
+                    warn!(
+                        "The nucleus failed the poll, polling again next cycle: {}",
+                        e
+                    );
+                    alert::failed(
+                        Condition::NucleusFailures,
+                        format!("the nucleus failed the poll: {}", e),
+                    );
+                    Ok(false)
+                }
+                AvsErrorClass::Fatal => Err(format!("the nucleus failed the poll: {}", e).into()),
+            };
+        }
+    };
+    alert::recovered(Condition::NucleusFailures);
+    let EventBatch {
+        events: res,
+        undecodable,
+    } = res;
+    // failed like events that don't apply, their stand-ins in the batch hold the sentinel up
+    let undecodable: HashMap<u64, UndecodableEvent> = undecodable
+        .into_iter()
+        .map(|event| (event.reqnum, event))
+        .collect();
+    let full_page = config
+        .event_page_size
+        .is_some_and(|size| res.len() >= size as usize);
+    // the walk below relies on ascending reqnums to only ever move the sentinel forward
+    let (mut events, anomalies) = match batch::normalize(res, *committed, config.duplicate_reqnums)
+    {
+        Ok(normalized) => normalized,
+        Err(e) => {
+            error!("Refusing batch, polling again next cycle: {}", e);
+            return Ok(false);
+        }
+    };
+    for anomaly in &anomalies {
+        warn!("Odd batch from the nucleus: {}", anomaly);
+    }
+    let gaps = batch::gaps(&anomalies, config.reqnum_gap_tolerance);
+    for &(after, next) in &gaps {
+        if next <= *gap_reported {
+            continue;
+        }
+        error!(
+            first = after + 1,
+            last = next - 1,
+            "Reqnums missing from the nucleus, events may have been lost"
         );
-        
-        CREATE TABLE IF NOT EXISTS comments (
-            id BIGINT PRIMARY KEY,
-            content TEXT NOT NULL,
-            author_id BIGINT NOT NULL,
-            author_nickname VARCHAR NOT NULL,
-            post_id BIGINT NOT NULL,
-            status SMALLINT NOT NULL,
-            weight SMALLINT NOT NULL,
-            created_time BIGINT NOT NULL,
-            FOREIGN KEY (post_id) REFERENCES articles(id)
+        metrics::REQNUM_GAPS.inc();
+        metrics::MISSED_REQNUMS.add(next - after - 1);
+        *gap_reported = next;
+    }
+    let newest = events.last().map_or(*sentinel, |event| event.reqnum);
+    // the events past the window are served again next cycle
+    let cut = config.max_events_per_cycle.and_then(|max| {
+        events
+            .iter()
+            .filter(|event| event.reqnum > *sentinel)
+            .nth(max)
+            .map(|event| event.reqnum)
+    });
+    if let Some(cut) = cut {
+        events.retain(|event| event.reqnum < cut);
+    }
+    if let Some(window) = config.compact_window {
+        let mut new = events
+            .iter()
+            .filter(|event| event.reqnum > *sentinel)
+            .peekable();
+        if new.peek().is_some() {
+            let since = *held_since.get_or_insert_with(Instant::now);
+            let due = full_page || cut.is_some() || since.elapsed() >= window;
+            if !due && new.all(|event| event.method != Method::Delete) {
+                // served again, along with whatever came in meanwhile
+                return Ok(false);
+            }
+            *held_since = None;
+            // stand-ins are no upserts, neither to compact away nor to compact others with
+            let (stand_ins, mut decoded): (Vec<_>, Vec<_>) = events
+                .into_iter()
+                .partition(|event| undecodable.contains_key(&event.reqnum));
+            metrics::COMPACTED_EVENTS.add(batch::compact(&mut decoded, *sentinel) as u64);
+            events = decoded;
+            events.extend(stand_ins);
+            events.sort_by_key(|event| event.reqnum);
+        }
+    }
+    nucleus.observe(&events);
+
+    // reqnum -> (sink, error, raw bytes) for every sink the event failed in
+    let mut failures: HashMap<u64, Vec<(String, String, Option<Vec<u8>>)>> = HashMap::new();
+    for (i, event) in events.iter().enumerate() {
+        if event.reqnum <= *sentinel {
+            // already applied, waiting in the open transaction
+            continue;
+        }
+        if config.reconcile_gaps && gaps.iter().any(|&(_, next)| next == event.reqnum) {
+            // where the lost events would have been applied, a failure holds up the event past the gap
+            for key in created.gap_keys(&events[i..]) {
+                let lost = ChangeEvent {
+                    reqnum: event.reqnum,
+                    method: Method::Update,
+                    key,
+                    source_time: None,
+                };
+                let correlation_id = correlation_id(event.reqnum, &lost.key);
+                info!(%correlation_id, "Fetching again an entity a reqnum gap may have created");
+                if let Err(e) =
+                    process_event(nucleus, config, fanout, &lost, &correlation_id, false).await
+                {
+                    error!(%correlation_id, "Failed to reconcile gap: {}", e);
+                    failures.entry(event.reqnum).or_default().push((
+                        PRIMARY.to_string(),
+                        format!("reconciling {}: {}", correlation_id, e),
+                        None,
+                    ));
+                }
+            }
+        }
+        let correlation_id = correlation_id(event.reqnum, &event.key);
+        if let Some(undecodable) = undecodable.get(&event.reqnum) {
+            error!(%correlation_id, "Failed to process event: {}", undecodable.error);
+            failures.entry(event.reqnum).or_default().push((
+                PRIMARY.to_string(),
+                undecodable.error.clone(),
+                Some(undecodable.raw.clone()),
+            ));
+            continue;
+        }
+        if let Err(e) = process_event(nucleus, config, fanout, event, &correlation_id, true).await {
+            let decode = e
+                .downcast_ref::<NucleusError>()
+                .is_some_and(NucleusError::is_decode);
+            match (
+                decode,
+                Prefix::of(&event.key),
+                config.decode_alarm_threshold,
+            ) {
+                (true, Some(prefix), Some(threshold)) => {
+                    if let Some(failures) = decode_alarm.record(
+                        prefix.model,
+                        std::time::Instant::now(),
+                        config.decode_alarm_window,
+                        threshold,
+                    ) {
+                        metrics::SCHEMA_CHANGE_ALERTS.inc();
+                        alert::raise(Condition::DecodeFailures, format!("{} {} entities failed to decode within {}s, likely a schema change on the nucleus", failures, prefix.model.as_str(), config.decode_alarm_window.as_secs()));
+                        error!(
+                            model = prefix.model.as_str(),
+                            failures,
+                            "Likely schema change on the nucleus: {} {} entities failed to decode within {}s, check this build's vemodel against it with verify-decode",
+                            failures,
+                            prefix.model.as_str(),
+                            config.decode_alarm_window.as_secs()
+                        );
+                    }
+                    // the alert stands for them, one by one they're only noise
+                    if decode_alarm.is_alerting(prefix.model) {
+                        debug!(%correlation_id, "Failed to process event: {}", e);
+                    } else {
+                        error!(%correlation_id, "Failed to process event: {}", e);
+                    }
+                }
+                _ => error!(%correlation_id, "Failed to process event: {}", e),
+            }
+            let raw_bytes = e
+                .downcast_ref::<NucleusError>()
+                .and_then(|e| e.raw())
+                .map(<[u8]>::to_vec);
+            // nothing was sent anywhere, so it's down to the primary sink
+            failures.entry(event.reqnum).or_default().push((
+                PRIMARY.to_string(),
+                e.to_string(),
+                raw_bytes,
+            ));
+        }
+        created.note(event);
+    }
+
+    decode_alarm.refresh(std::time::Instant::now(), config.decode_alarm_window);
+
+    // wait for the sinks the sentinel depends on, so we know which changes actually landed
+    for (reqnum, sink, error) in fanout.flush().await? {
+        failures
+            .entry(reqnum)
+            .or_default()
+            .push((sink, error, None));
+    }
+
+    metrics::PENDING_PARENTS.set(
+        failures
+            .values()
+            .filter(|failed| awaits_parent(failed))
+            .count() as u64,
+    );
+
+    // stop short of a failed event that still has attempts left, so that
+    // it's served again in the next cycle
+    let last = events.last().map(|event| event.reqnum);
+    for ChangeEvent {
+        reqnum,
+        method,
+        key,
+        ..
+    } in events
+    {
+        if reqnum <= *sentinel {
+            continue;
+        }
+        if let Some(failed) = failures.remove(&reqnum) {
+            let (count, first_seen) = attempts.entry(reqnum).or_insert((0, unix_now()));
+            *count += 1;
+            let max_attempts = if awaits_parent(&failed) {
+                config.parent_gap_attempts
+            } else {
+                config.max_attempts
+            };
+            if *count < max_attempts {
+                warn!(
+                    reqnum,
+                    "Event failed (attempt {}/{}), retrying next cycle", count, max_attempts
+                );
+                break;
+            }
+
+            let (prefix, id) = split_key(&key);
+            let (count, first_seen) = (*count, *first_seen);
+            attempts.remove(&reqnum);
+            warn!(
+                reqnum,
+                "Event failed {} times, moving it to the dead-letter table", count
+            );
+            for (sink, error, raw_bytes) in failed {
+                let letter = DeadLetter {
+                    reqnum,
+                    sink,
+                    prefix: prefix.clone(),
+                    id,
+                    method,
+                    raw_bytes,
+                    error,
+                    first_seen,
+                    attempts: count,
+                };
+                fanout.primary().send(Message::DeadLetter(letter)).await?;
+            }
+        }
+        *sentinel = reqnum;
+    }
+
+    let (ack_tx, ack_rx) = oneshot::channel();
+    fanout
+        .primary()
+        .send(Message::Checkpoint {
+            sentinel: *sentinel,
+            ack: ack_tx,
+        })
+        .await?;
+    let checkpointed = ack_rx.await?;
+    *committed = checkpointed.committed;
+    if !checkpointed.pending {
+        // a failed commit drops the open transaction, redo it from `committed`
+        *sentinel = *committed;
+    }
+    // a lower bound when paging, the nucleus may hold more than it served
+    metrics::BACKLOG.set(newest.saturating_sub(*sentinel));
+    // unless an event failed and is to be served again anyway
+    Ok((full_page || cut.is_some()) && last == Some(*sentinel))
+}
+
+/// Commits what the open transaction holds, for ingest to stay paused with
+/// nothing left open. A failed commit loses it, like any other, and ingest
+/// goes on from the committed sentinel once resumed.
+async fn pause_ingest(
+    fanout: &Fanout,
+    progress: &mut Progress,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (ack_tx, ack_rx) = oneshot::channel();
+    fanout
+        .primary()
+        .send(Message::Pause {
+            sentinel: progress.sentinel,
+            ack: ack_tx,
+        })
+        .await?;
+    let checkpointed = ack_rx.await?;
+    if checkpointed.committed != progress.sentinel {
+        error!(
+            "Failed to commit up to sentinel {} on pausing, staying at {}",
+            progress.sentinel, checkpointed.committed
         );
-    ").await?;
-    
+        *progress = Progress::new(checkpointed.committed);
+    }
+    metrics::INGEST_PAUSED.set(1);
+    info!("Ingest paused at sentinel {}", progress.committed);
     Ok(())
 }
 
-async fn handle_database_operation(
-    client: &Client,
-    model: &str,
-    method: Method,
-    value: &serde_json::Value,
+/// Moves the sentinel where the operator asked in `VE_SENTINEL_RESET_PATH`,
+/// removing the file once done. A reset that can't be read or would move the
+/// sentinel back without saying so is refused, and the file left for a look.
+async fn reset_sentinel(
+    config: &Config,
+    fanout: &Fanout,
+    progress: &mut Progress,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    match (model, method) {
-        ("subspace", Method::Create | Method::Update) => {
-            let subspace: VeSubspace = serde_json::from_value(value.clone())?;
-            client.execute(
-                "INSERT INTO subspaces (id, title, slug, description, banner, status, weight, created_time)
-                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-                 ON CONFLICT (id) DO UPDATE SET
-                    title = $2,
-                    slug = $3,
-                    description = $4,
-                    banner = $5,
-                    status = $6,
-                    weight = $7,
-                    created_time = $8",
-                &[
-                    &(subspace.id as i64),
-                    &subspace.title,
-                    &subspace.slug,
-                    &subspace.description,
-                    &subspace.banner,
-                    &(subspace.status as i16),
-                    &(subspace.weight as i16),
-                    &(subspace.created_time as i64),
-                ],
-            ).await?;
-            println!("Upserted subspace: {}", subspace.id);
-        },
-        ("article", Method::Create | Method::Update) => {
-            let article: VeArticle = serde_json::from_value(value.clone())?;
-            client.execute(
-                "INSERT INTO articles (id, title, content, author_id, author_nickname, subspace_id, 
-                                     ext_link, status, weight, created_time, updated_time)
-                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
-                 ON CONFLICT (id) DO UPDATE SET
-                    title = $2,
-                    content = $3,
-                    author_id = $4,
-                    author_nickname = $5,
-                    subspace_id = $6,
-                    ext_link = $7,
-                    status = $8,
-                    weight = $9,
-                    created_time = $10,
-                    updated_time = $11",
-                &[
-                    &(article.id as i64),
-                    &article.title,
-                    &article.content,
-                    &(article.author_id as i64),
-                    &article.author_nickname,
-                    &(article.subspace_id as i64),
-                    &article.ext_link,
-                    &(article.status as i16),
-                    &(article.weight as i16),
-                    &(article.created_time as i64),
-                    &(article.updated_time as i64),
-                ],
-            ).await?;
-            println!("Upserted article: {}", article.id);
-        },
-        ("comment", Method::Create | Method::Update) => {
-            let comment: VeComment = serde_json::from_value(value.clone())?;
-            client.execute(
-                "INSERT INTO comments (id, content, author_id, author_nickname, post_id, 
-                                     status, weight, created_time)
-                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-                 ON CONFLICT (id) DO UPDATE SET
-                    content = $2,
-                    author_id = $3,
-                    author_nickname = $4,
-                    post_id = $5,
-                    status = $6,
-                    weight = $7,
-                    created_time = $8",
-                &[
-                    &(comment.id as i64),
-                    &comment.content,
-                    &(comment.author_id as i64),
-                    &comment.author_nickname,
-                    &(comment.post_id as i64),
-                    &(comment.status as i16),
-                    &(comment.weight as i16),
-                    &(comment.created_time as i64),
-                ],
-            ).await?;
-            println!("Upserted comment: {}", comment.id);
-        },
-        (model, Method::Delete) => {
-            let id = value.as_i64().unwrap();
-            let table_name = match model {
-                "subspace" => "subspaces",
-                "article" => "articles",
-                "comment" => "comments",
-                _ => return Err("Invalid model type".into()),
-            };
-            let query = format!("DELETE FROM {} WHERE id = $1", table_name);
-            client.execute(&query, &[&id]).await?;
-            println!("Deleted {} record: {}", table_name, id);
-        },
-        _ => return Err("Invalid operation".into()),
+    let Some(path) = &config.sentinel_reset_path else {
+        warn!("SIGHUP received but VE_SENTINEL_RESET_PATH is unset, ignoring it");
+        return Ok(());
+    };
+    let reset = match std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|raw| raw.parse::<SentinelReset>())
+    {
+        Ok(reset) => reset,
+        Err(e) => {
+            error!("Refusing sentinel reset from {}: {}", path.display(), e);
+            return Ok(());
+        }
+    };
+    if let Err(e) = reset.check(progress.committed) {
+        error!("Refusing sentinel reset from {}: {}", path.display(), e);
+        return Ok(());
     }
 
+    let (ack_tx, ack_rx) = oneshot::channel();
+    fanout
+        .primary()
+        .send(Message::Reset {
+            sentinel: reset.sentinel,
+            ack: ack_tx,
+        })
+        .await?;
+    let checkpointed = ack_rx.await?;
+    if checkpointed.committed != reset.sentinel {
+        // the open transaction failed to commit, nothing moved
+        error!(
+            "Sentinel reset to {} failed, staying at {}",
+            reset.sentinel, checkpointed.committed
+        );
+        *progress = Progress::new(checkpointed.committed);
+        return Ok(());
+    }
+    if reset.sentinel < progress.committed {
+        warn!(
+            "Sentinel rewound by operator from {} to {}",
+            progress.committed, reset.sentinel
+        );
+    } else {
+        info!(
+            "Sentinel moved by operator from {} to {}",
+            progress.committed, reset.sentinel
+        );
+    }
+    *progress = Progress::new(reset.sentinel);
+    std::fs::remove_file(path)?;
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let (tx, mut rx) = mpsc::channel(100);
+// Resolves on ctrl-c or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
 
-    // PostgreSQL connection
-    let postgres_config = "host=localhost port=5432 user=postgres password=your_password dbname=ve_db";
-    let (client, connection) = tokio_postgres::connect(postgres_config, NoTls).await?;
+/// Reaches Postgres and the nucleus once each, without writing to either,
+/// and prints how that went. Fails if either can't be reached, and exits
+/// non-zero with it. The config is good by then: it would have failed to load.
+async fn check_connectivity(
+    connection: Connection,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("config: ok");
+    // a check creates nothing
+    let postgres = match db::connect(&Config {
+        create_database_if_missing: false,
+        ..config.clone()
+    })
+    .await
+    {
+        Ok(client) => client
+            .query_one("SELECT 1", &[])
+            .await
+            .map(|_| ())
+            .map_err(Into::into),
+        Err(e) => Err(e),
+    };
+    let nucleus = RpcNucleus::new(
+        connection,
+        config.avs_id.clone(),
+        config.event_page_size,
+        config.event_codec,
+    );
+    // the cheapest read there is, whether or not there's such a subspace
+    let nucleus: Result<(), Box<dyn std::error::Error>> =
+        match nucleus.get_subspace(SubspaceId(0)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(format!("the AVS failed the call: {}", e).into()),
+            Err(e) => Err(e.into()),
+        };
+    let mut ok = true;
+    for (name, result) in [("postgres", postgres), ("nucleus", nucleus)] {
+        match result {
+            Ok(()) => println!("{}: ok", name),
+            Err(e) => {
+                println!("{}: {}", name, e);
+                ok = false;
+            }
+        }
+    }
+    if !ok {
+        return Err("the check failed".into());
+    }
+    Ok(())
+}
+
+async fn verify_decode(
+    http_client: &HttpClient,
+    config: &Config,
+    sample: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let reports = verify::verify_decode(http_client, config.avs_id.as_str(), sample).await?;
+    for report in &reports {
+        println!(
+            "{}: decoded={} missing={} failed={}",
+            report.model, report.decoded, report.missing, report.failed
+        );
+        for (id, error) in &report.errors {
+            println!("  id={} error={}", id, error);
+        }
+    }
+    if reports.iter().any(|report| report.failed > 0) {
+        return Err("some entities failed to decode".into());
+    }
+    Ok(())
+}
 
-    // Spawn connection handler
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("PostgreSQL connection error: {}", e);
+async fn verify_shadow(
+    client: &Client,
+    config: &Config,
+    sample: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let shadow = config
+        .shadow_postgres_config
+        .as_deref()
+        .ok_or("VE_SHADOW_POSTGRES_CONFIG is not set")?;
+    let shadow = shadow::connect(config, shadow).await?;
+    let reports = shadow::compare(client, &shadow, sample).await?;
+    for report in &reports {
+        println!(
+            "{}: primary_rows={} shadow_rows={} sampled={} missing={} differing={}",
+            report.table,
+            report.primary_rows,
+            report.shadow_rows,
+            report.sampled,
+            report.missing,
+            report.differing
+        );
+        if !report.ids.is_empty() {
+            println!("  ids={:?}", report.ids);
         }
-    });
+    }
+    if !reports.iter().all(shadow::TableReport::matches) {
+        return Err("the shadow database differs".into());
+    }
+    Ok(())
+}
 
-    // Set up database tables
-    setup_database(&client).await?;
+/// Re-fetches and applies a dead-lettered event, dropping its letters once it lands.
+///
+/// A letter of the primary sink re-applies the event everywhere, one of a
+/// secondary sink re-applies it to that sink only. Everywhere is the sinks of
+/// [`sink::build_for_command`], the event stream's letters can't be re-driven.
+async fn redrive(
+    client: &Client,
+    nucleus: &impl Nucleus,
+    config: &Config,
+    reqnum: u64,
+    change_log: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let letters = dead_letter::get(client, reqnum).await?;
+    let letter = letters
+        .first()
+        .ok_or_else(|| format!("no dead letter with reqnum {}", reqnum))?;
+    let prefix = Prefix::of(letter.prefix.as_bytes())
+        .ok_or_else(|| format!("unknown key prefix {}", letter.prefix))?;
+    let key = prefix.key(letter.id);
+    let correlation_id = correlation_id(reqnum, &key);
 
-    // Spawn a task for PostgreSQL operations
-    let db_client = client.clone();
-    tokio::spawn(async move {
-        while let Some((model, method, value)) = rx.recv().await {
-            if let Err(e) = handle_database_operation(&db_client, &model, method, &value).await {
-                eprintln!("Database operation error: {}", e);
+    let everywhere = letters.iter().any(|l| l.sink == PRIMARY);
+    let sinks: Vec<_> = sink::build_for_command(config, change_log)
+        .await?
+        .into_iter()
+        .filter(|s| everywhere || letters.iter().any(|l| l.sink == s.name()))
+        .collect();
+    if let Some(letter) = letters
+        .iter()
+        .find(|l| l.sink != PRIMARY && !sinks.iter().any(|s| s.name() == l.sink))
+    {
+        return Err(format!(
+            "sink {} is not enabled, or not for commands (the change log takes --change-log)",
+            letter.sink
+        )
+        .into());
+    }
+
+    let event = ChangeEvent {
+        reqnum,
+        method: letter.method,
+        key,
+        source_time: None,
+    };
+    let (tx, mut rx) = mpsc::channel(100);
+    process_event(
+        nucleus,
+        config,
+        &Fanout::primary_only(tx),
+        &event,
+        &correlation_id,
+        true,
+    )
+    .await?;
+    while let Some(message) = rx.recv().await {
+        if let Message::Change(change) = message {
+            if everywhere {
+                db::handle_database_operation(client, config, &change).await?;
+            }
+            for sink in &sinks {
+                sink.apply(&change).await?;
             }
         }
-    });
+    }
 
-    // Main task for RPC querying
-    let http_client = HttpClientBuilder::default().build("http://localhost:9944")?;
+    for letter in &letters {
+        dead_letter::remove(client, reqnum, &letter.sink).await?;
+    }
+    info!(%correlation_id, "Re-drove dead letter");
+    Ok(())
+}
 
-    let avs_id = "5FsXfPrUDqq6abYccExCTUxyzjYaaYTr5utLx2wwdBv1m8R8";
-    let mut sentinel: u64 = 0;
-    loop {
-        println!("==> sentinel: {}", sentinel);
-        let params = rpc_params![
-            avs_id,
-            "get_from_common_key",
-            hex::encode(sentinel.encode())
-        ];
+/// Fetches one entity from the nucleus and applies it to the database and the sinks of
+/// [`sink::build_for_command`], as an update event would, printing the row before and after. It's no event, so it has no reqnum:
+/// its changes carry 0, and the sentinel isn't touched.
+async fn resync(
+    client: &Client,
+    nucleus: &impl Nucleus,
+    config: &Config,
+    model: Model,
+    id: u64,
+    change_log: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = Prefix::of_model(model).key(id);
+    let correlation_id = correlation_id(0, &key);
+    let before = stored(client, model, id).await?;
 
-        let res: serde_json::Value = http_client.request("nucleus_post", params).await?;
-        let res = res.as_str().expect("a str res");
-        let bytes = hex::decode(res).expect("Invalid hex string");
-        let res = <Result<Vec<(u64, Method, Vec<u8>)>, String>>::decode(&mut &bytes[..]).unwrap();
-
-        for (reqnum, method, key) in res? {
-            match slice_to_array(&key[..5]).unwrap() {
-                PREFIX_SUBSPACE_KEY => {
-                    let id = vec_to_u64(&key[5..]);
-                    match method {
-                        Method::Create | Method::Update => {
-                            let params = rpc_params![avs_id, "get_subspace", hex::encode(id.encode())];
-                            let res: serde_json::Value = http_client.request("nucleus_get", params).await?;
-                            let res = res.as_str().expect("a str res");
-                            let bytes = hex::decode(res).expect("Invalid hex string");
-                            let result = <Result<Option<VeSubspace>, String>>::decode(&mut &bytes[..]).unwrap();
-                            if let Ok(Some(sb)) = result {
-                                let json_value = serde_json::to_value(&sb)?;
-                                tx.send(("subspace", method, json_value)).await?;
-                            }
-                        }
-                        Method::Delete => {
-                            let json_value = serde_json::to_value(&id)?;
-                            tx.send(("subspace", method, json_value)).await?;
-                        }
-                    }
+    let sinks = sink::build_for_command(config, change_log).await?;
+    let applied = refetch(client, nucleus, config, &sinks, model, id).await?;
+    for sink in &sinks {
+        sink.close().await?;
+    }
+    if !applied {
+        return Err(format!(
+            "nothing to apply for {} {}, see the log for why",
+            model.as_str(),
+            id
+        )
+        .into());
+    }
+
+    let after = stored(client, model, id).await?;
+    println!(
+        "before: {}",
+        before
+            .as_ref()
+            .map_or("none".to_string(), ToString::to_string)
+    );
+    if after == before {
+        println!("after: unchanged");
+    } else {
+        println!(
+            "after: {}",
+            after
+                .as_ref()
+                .map_or("none".to_string(), ToString::to_string)
+        );
+    }
+    info!(%correlation_id, "Resynced {} {}", model.as_str(), id);
+    Ok(())
+}
+
+// Fetches `model` `id` and applies whatever it comes to, returning whether there was anything.
+async fn refetch(
+    client: &Client,
+    nucleus: &impl Nucleus,
+    config: &Config,
+    sinks: &[Box<dyn sink::Sink>],
+    model: Model,
+    id: u64,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let key = Prefix::of_model(model).key(id);
+    let correlation_id = correlation_id(0, &key);
+    let event = ChangeEvent {
+        reqnum: 0,
+        method: Method::Update,
+        key,
+        source_time: None,
+    };
+    let (tx, mut rx) = mpsc::channel(100);
+    process_event(
+        nucleus,
+        config,
+        &Fanout::primary_only(tx),
+        &event,
+        &correlation_id,
+        false,
+    )
+    .await?;
+    let mut applied = false;
+    while let Some(message) = rx.recv().await {
+        if let Message::Change(change) = message {
+            apply_everywhere(client, config, sinks, &change).await?;
+            applied = true;
+        }
+    }
+    Ok(applied)
+}
+
+async fn apply_everywhere(
+    client: &Client,
+    config: &Config,
+    sinks: &[Box<dyn sink::Sink>],
+    change: &Change,
+) -> Result<(), Box<dyn std::error::Error>> {
+    db::handle_database_operation(client, config, change).await?;
+    for sink in sinks {
+        sink.apply(change).await?;
+    }
+    Ok(())
+}
+
+/// Prints the articles of a missing subspace and the comments on a missing article, then
+/// repairs them if asked to. Refetching applies the parents the nucleus has; deleting goes
+/// through the database and the sinks of [`sink::build_for_command`] as a delete event would, articles first so their
+/// comments are found orphaned in turn. What's left orphaned is printed and counted at the end.
+/// A model indexed without its parent's isn't looked at, all of it would be orphaned.
+async fn verify_integrity(
+    client: &Client,
+    nucleus: &impl Nucleus,
+    config: &Config,
+    repair: Option<Repair>,
+    change_log: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let articles = integrity::orphaned_articles(client, config).await?;
+    let comments = integrity::orphaned_comments(client, config).await?;
+    let Some(repair) = repair else {
+        for orphan in articles.iter().chain(&comments) {
+            println!("{}", orphan);
+        }
+        info!(
+            "{} orphaned articles, {} orphaned comments",
+            articles.len(),
+            comments.len()
+        );
+        return Ok(());
+    };
+
+    let sinks = sink::build_for_command(config, change_log).await?;
+    match repair {
+        Repair::Refetch => {
+            let orphans: Vec<Orphan> = articles.into_iter().chain(comments).collect();
+            for (model, id) in integrity::missing_parents(&orphans) {
+                if !refetch(client, nucleus, config, &sinks, model, id).await? {
+                    warn!(
+                        "The nucleus has no {} {} either, leaving its orphans",
+                        model.as_str(),
+                        id
+                    );
                 }
-                PREFIX_ARTICLE_KEY => {
-                    let id = vec_to_u64(&key[5..]);
-                    match method {
-                        Method::Create | Method::Update => {
-                            let params = rpc_params![avs_id, "get_article", hex::encode(id.encode())];
-                            let res: serde_json::Value = http_client.request("nucleus_get", params).await?;
-                            let res = res.as_str().expect("a str res");
-                            let bytes = hex::decode(res).expect("Invalid hex string");
-                            let result = <Result<Option<VeArticle>, String>>::decode(&mut &bytes[..]).unwrap();
-                            if let Ok(Some(article)) = result {
-                                let json_value = serde_json::to_value(&article)?;
-                                tx.send(("article", method, json_value)).await?;
-                            }
-                        }
-                        Method::Delete => {
-                            let json_value = serde_json::to_value(&id)?;
-                            tx.send(("article", method, json_value)).await?;
+            }
+        }
+        Repair::Delete => {
+            delete_orphans(client, config, &sinks, &articles).await?;
+            // including the comments of the articles just deleted
+            delete_orphans(
+                client,
+                config,
+                &sinks,
+                &integrity::orphaned_comments(client, config).await?,
+            )
+            .await?;
+        }
+    }
+    for sink in &sinks {
+        sink.close().await?;
+    }
+
+    let left: Vec<Orphan> = integrity::orphaned_articles(client, config)
+        .await?
+        .into_iter()
+        .chain(integrity::orphaned_comments(client, config).await?)
+        .collect();
+    for orphan in &left {
+        println!("{}", orphan);
+    }
+    info!("{} orphans left after the repair", left.len());
+    Ok(())
+}
+
+async fn delete_orphans(
+    client: &Client,
+    config: &Config,
+    sinks: &[Box<dyn sink::Sink>],
+    orphans: &[Orphan],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for orphan in orphans {
+        let change = Change {
+            reqnum: 0,
+            key: Prefix::of_model(orphan.model).key(orphan.id),
+            method: Method::Delete,
+            entity: Entity::Deleted(orphan.model, orphan.id),
+            correlation_id: format!("integrity-{}{}", orphan.model.as_str(), orphan.id),
+            source_time: None,
+            event: false,
+        };
+        apply_everywhere(client, config, sinks, &change).await?;
+    }
+    Ok(())
+}
+
+// The row of `model` `id` as the entity it stores, for showing.
+async fn stored(
+    client: &Client,
+    model: Model,
+    id: u64,
+) -> Result<Option<serde_json::Value>, Box<dyn std::error::Error>> {
+    let select = format!("SELECT * FROM {} WHERE id = $1", model.table());
+    let Some(row) = client.query_opt(&select, &[&SqlId(id)]).await? else {
+        return Ok(None);
+    };
+    let entity = (model.info().from_row)(&row)?;
+    Ok(Some(entity.to_json()?))
+}
+
+// Holds an entity to `VE_BLANK_FIELDS`, failing the event if it's rejected.
+fn require<T: Required>(
+    entity: &mut T,
+    config: &Config,
+    correlation_id: &str,
+) -> Result<(), BlankField> {
+    for field in required::check(entity, config.blank_fields)? {
+        warn!(%correlation_id, "Filled in the blank {} of {} {} with a placeholder", field, T::MODEL.as_str(), entity.id());
+    }
+    Ok(())
+}
+
+/// Fetches the entity behind one change event and hands it to the database task.
+///
+/// `recorded` says whether the changes are those of the nucleus event, to be
+/// recorded in `applied_events`. Those of a refetch or a gap's lost events
+/// only borrow a reqnum, and the event that has it is still to be applied.
+#[instrument(name = "event", skip_all, fields(correlation_id = %correlation_id))]
+async fn process_event(
+    nucleus: &impl Nucleus,
+    config: &Config,
+    fanout: &Fanout,
+    event: &ChangeEvent,
+    correlation_id: &str,
+    recorded: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let change = |method, entity| Change {
+        event: recorded,
+        ..Change::new(event, method, entity, correlation_id)
+    };
+    let (method, key) = (event.method, &event.key[..]);
+    let Some(prefix) = Prefix::of(key) else {
+        return Ok(());
+    };
+    // served again and dead-lettered in the end, rather than read as some other id
+    let id = prefix.decode_id(key).ok_or_else(|| {
+        format!(
+            "key {} has no {} id",
+            hex::encode(key),
+            prefix.model.as_str()
+        )
+    })?;
+    match prefix.model {
+        Model::Subspace => {
+            process::<VeSubspace>(nucleus, config, fanout, method, id, change, correlation_id).await
+        }
+        Model::Article => {
+            process::<VeArticle>(nucleus, config, fanout, method, id, change, correlation_id).await
+        }
+        Model::Comment => {
+            process::<VeComment>(nucleus, config, fanout, method, id, change, correlation_id).await
+        }
+    }
+}
+
+// What `process_event` does with the change event of entity `id` once it's known to be a `T`.
+async fn process<T: Required>(
+    nucleus: &impl Nucleus,
+    config: &Config,
+    fanout: &Fanout,
+    method: Method,
+    id: u64,
+    change: impl Fn(Method, Entity) -> Change,
+    correlation_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let model = T::MODEL.as_str();
+    if !config.indexes(T::MODEL) {
+        debug!("{}s aren't indexed, passing over {} {}", model, model, id);
+        return Ok(());
+    }
+    match method {
+        Method::Create | Method::Update => {
+            if let Ok(fetched) = fetch_settled(config, || T::fetch(nucleus, id)).await? {
+                match resolve_fetched(fetched, config.missing_entity) {
+                    FetchOutcome::Upsert(mut entity) => {
+                        // the author is only known once the entity has been fetched, and
+                        // whatever of it was indexed before they were blocked goes
+                        if let Some(author) = entity
+                            .blockable_author()
+                            .filter(|author| config.blocked_authors.contains(author))
+                        {
+                            info!(
+                                "{} {} is by blocked author {}, deleting it",
+                                model, id, author
+                            );
+                            fanout
+                                .send(change(Method::Delete, Entity::Deleted(T::MODEL, id)))
+                                .instrument(info_span!("send"))
+                                .await?;
+                            return Ok(());
                         }
+                        require(&mut entity, config, correlation_id)?;
+                        fanout
+                            .send(change(method, entity.into_entity()))
+                            .instrument(info_span!("send"))
+                            .await?;
                     }
-                }
-                PREFIX_COMMENT_KEY => {
-                    let id = vec_to_u64(&key[5..]);
-                    match method {
-                        Method::Create | Method::Update => {
-                            let params = rpc_params![avs_id, "get_comment", hex::encode(id.encode())];
-                            let res: serde_json::Value = http_client.request("nucleus_get", params).await?;
-                            let res = res.as_str().expect("a str res");
-                            let bytes = hex::decode(res).expect("Invalid hex string");
-                            let result = <Result<Option<VeComment>, String>>::decode(&mut &bytes[..]).unwrap();
-                            if let Ok(Some(comment)) = result {
-                                let json_value = serde_json::to_value(&comment)?;
-                                tx.send(("comment", method, json_value)).await?;
-                            }
-                        }
-                        Method::Delete => {
-                            let json_value = serde_json::to_value(&id)?;
-                            tx.send(("comment", method, json_value)).await?;
-                        }
+                    FetchOutcome::Delete => {
+                        warn!("{} {} vanished before fetch, deleting stale row", model, id);
+                        fanout
+                            .send(change(Method::Delete, Entity::Deleted(T::MODEL, id)))
+                            .instrument(info_span!("send"))
+                            .await?;
                     }
+                    FetchOutcome::Skip => warn!("{} {} vanished before fetch, skipping", model, id),
                 }
-                _ => {}
             }
-            sentinel = reqnum;
         }
+        Method::Delete => {
+            fanout
+                .send(change(method, Entity::Deleted(T::MODEL, id)))
+                .instrument(info_span!("send"))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
 
-        sleep(Duration::from_secs(5)).await;
+// Fetches an entity with `fetch`, again up to `VE_MISSING_ENTITY_RETRIES` times
+// `VE_MISSING_ENTITY_RETRY_MS` apart while the nucleus has none, so one it serves the change of
+// before the entity can be read isn't taken for deleted.
+async fn fetch_settled<T, F: Future<Output = Fetched<T>>>(
+    config: &Config,
+    fetch: impl Fn() -> F,
+) -> Fetched<T> {
+    let mut fetched = fetch().await;
+    for _ in 0..config.missing_entity_retries {
+        if !matches!(fetched, Ok(Ok(None))) {
+            break;
+        }
+        sleep(config.missing_entity_retry_delay).await;
+        fetched = fetch().await;
     }
+    fetched
 }
 
-fn vec_to_u64(v: &[u8]) -> u64 {
-    let mut array = [0u8; 8];
-    let len = std::cmp::min(v.len(), 8);
-    array[..len].copy_from_slice(&v[..len]);
-    u64::from_be_bytes(array)
+/// What to do with the result of a `get_*` fetch that followed a Create/Update event.
+#[derive(Debug, PartialEq)]
+enum FetchOutcome<T> {
+    Upsert(T),
+    Delete,
+    Skip,
 }
 
-fn slice_to_array(slice: &[u8]) -> Result<&[u8; 5], &str> {
-    slice.try_into().map_err(|_| "Slice must be 5 bytes long")
+fn resolve_fetched<T>(fetched: Option<T>, policy: MissingEntityPolicy) -> FetchOutcome<T> {
+    match (fetched, policy) {
+        (Some(entity), _) => FetchOutcome::Upsert(entity),
+        (None, MissingEntityPolicy::Delete) => FetchOutcome::Delete,
+        (None, MissingEntityPolicy::Skip) => FetchOutcome::Skip,
+    }
 }
 
+// Whether every sink that failed an event did so for want of the row it references.
+fn awaits_parent(failed: &[(String, String, Option<Vec<u8>>)]) -> bool {
+    failed
+        .iter()
+        .all(|(_, error, _)| error.starts_with(db::PARENT_GAP))
+}
+
+/// Identifies one entity's change event across fetch, decode, send and apply,
+/// e.g. `42-vear:7` for the 42nd request touching article 7.
+fn correlation_id(reqnum: u64, key: &[u8]) -> String {
+    let (prefix, id) = split_key(key);
+    format!("{}-{}{}", reqnum, prefix, id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use surrogate::db::{fixture, Checkpointed};
+    use surrogate::sink::BoxFuture;
+    use vemodel::{ArticleId, CommentId, UserId, PREFIX_ARTICLE_KEY, PREFIX_COMMENT_KEY};
+
+    #[test]
+    fn created_then_deleted_entity_is_removed_under_delete_policy() {
+        // the create event arrives, but by the time we fetch, the entity is gone
+        let fetched: Option<u64> = None;
+        assert_eq!(
+            resolve_fetched(fetched, MissingEntityPolicy::Delete),
+            FetchOutcome::Delete
+        );
+    }
+
+    #[test]
+    fn created_then_deleted_entity_is_ignored_under_skip_policy() {
+        let fetched: Option<u64> = None;
+        assert_eq!(
+            resolve_fetched(fetched, MissingEntityPolicy::Skip),
+            FetchOutcome::Skip
+        );
+    }
+
+    #[test]
+    fn fetched_entity_is_upserted_regardless_of_policy() {
+        assert_eq!(
+            resolve_fetched(Some(7u64), MissingEntityPolicy::Delete),
+            FetchOutcome::Upsert(7)
+        );
+        assert_eq!(
+            resolve_fetched(Some(7u64), MissingEntityPolicy::Skip),
+            FetchOutcome::Upsert(7)
+        );
+    }
+
+    /// A nucleus answering from a script: a batch per poll, articles by id as they are at the time.
+    #[derive(Default)]
+    struct FakeNucleus {
+        batches: Mutex<VecDeque<Vec<ChangeEvent>>>,
+        /// Errors the AVS answers polls with, before serving any batch.
+        failures: Mutex<VecDeque<String>>,
+        articles: Mutex<HashMap<u64, VeArticle>>,
+        comments: HashMap<u64, VeComment>,
+        /// Ids of articles fetched as none that many times before they're there.
+        unsettled: Mutex<HashMap<u64, u32>>,
+        /// Ids of articles whose responses don't decode.
+        broken: Vec<u64>,
+        /// Entries of the batches that don't decode, their stand-ins in the batches.
+        undecodable: Vec<UndecodableEvent>,
+        /// The sentinels polled with, in order.
+        polled: Mutex<Vec<u64>>,
+        head: u64,
+    }
+
+    impl Nucleus for FakeNucleus {
+        fn get_from_common_key(
+            &self,
+            sentinel: u64,
+        ) -> BoxFuture<'_, Result<Result<EventBatch, String>, NucleusError>> {
+            self.polled.lock().unwrap().push(sentinel);
+            if let Some(error) = self.failures.lock().unwrap().pop_front() {
+                return Box::pin(std::future::ready(Ok(Err(error))));
+            }
+            let events = self.batches.lock().unwrap().pop_front().unwrap_or_default();
+            // whatever of them the batch has
+            let undecodable = self
+                .undecodable
+                .iter()
+                .filter(|undecodable| events.contains(&undecodable.stand_in()))
+                .cloned()
+                .collect();
+            Box::pin(std::future::ready(Ok(Ok(EventBatch {
+                events,
+                undecodable,
+            }))))
+        }
+
+        fn head_reqnum(&self) -> BoxFuture<'_, Result<Result<u64, String>, NucleusError>> {
+            Box::pin(std::future::ready(Ok(Ok(self.head))))
+        }
+
+        fn get_subspace(&self, _: SubspaceId) -> BoxFuture<'_, Fetched<VeSubspace>> {
+            Box::pin(std::future::ready(Ok(Ok(None))))
+        }
+
+        fn get_article(&self, id: ArticleId) -> BoxFuture<'_, Fetched<VeArticle>> {
+            let mut unsettled = self.unsettled.lock().unwrap();
+            let fetched = if self.broken.contains(&id.0) {
+                Err(NucleusError::Response(ResponseError::Hex(
+                    hex::FromHexError::OddLength,
+                )))
+            } else if let Some(misses) = unsettled.get_mut(&id.0).filter(|misses| **misses > 0) {
+                *misses -= 1;
+                Ok(Ok(None))
+            } else {
+                Ok(Ok(self.articles.lock().unwrap().get(&id.0).cloned()))
+            };
+            Box::pin(std::future::ready(fetched))
+        }
+
+        fn get_comment(&self, id: CommentId) -> BoxFuture<'_, Fetched<VeComment>> {
+            let fetched = self.comments.get(&id.0).cloned();
+            Box::pin(std::future::ready(Ok(Ok(fetched))))
+        }
+    }
+
+    fn article_event(reqnum: u64, method: Method, id: u64) -> ChangeEvent {
+        ChangeEvent {
+            reqnum,
+            method,
+            key: [&PREFIX_ARTICLE_KEY[..], &id.to_be_bytes()].concat(),
+            source_time: None,
+        }
+    }
+
+    type Applied = Arc<Mutex<Vec<(u64, Method, Entity)>>>;
+
+    // Stands in for the database task, recording the changes it's sent and committing every checkpoint.
+    fn fake_writer() -> (Fanout, Applied) {
+        failing_writer(HashMap::new())
+    }
+
+    // Like `fake_writer`, but failing the changes of the reqnums in `failing` with their error.
+    fn failing_writer(failing: HashMap<u64, String>) -> (Fanout, Applied) {
+        let (tx, mut rx) = mpsc::channel(100);
+        let applied = Applied::default();
+        let recorded = applied.clone();
+        tokio::spawn(async move {
+            let mut failed = Vec::new();
+            while let Some(message) = rx.recv().await {
+                match message {
+                    Message::Change(change) => match failing.get(&change.reqnum) {
+                        Some(error) => failed.push((change.reqnum, error.clone())),
+                        None => recorded.lock().unwrap().push((
+                            change.reqnum,
+                            change.method,
+                            change.entity,
+                        )),
+                    },
+                    Message::Flush(ack) => {
+                        let _ = ack.send(std::mem::take(&mut failed));
+                    }
+                    Message::DeadLetter(_) => {}
+                    Message::Checkpoint { sentinel, ack }
+                    | Message::Reset { sentinel, ack }
+                    | Message::Pause { sentinel, ack } => {
+                        let _ = ack.send(Checkpointed {
+                            committed: sentinel,
+                            pending: false,
+                        });
+                    }
+                }
+            }
+        });
+        (Fanout::primary_only(tx), applied)
+    }
+
+    fn reqnums_and_methods(applied: &Applied) -> Vec<(u64, Method)> {
+        applied
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(reqnum, method, _)| (*reqnum, *method))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn cycle_applies_the_batch_and_polls_from_the_committed_sentinel() {
+        let config = Config::for_tests();
+        let nucleus = FakeNucleus {
+            batches: Mutex::new(VecDeque::from([vec![
+                article_event(1, Method::Create, 7),
+                article_event(2, Method::Create, 8),
+                article_event(3, Method::Delete, 7),
+            ]])),
+            articles: Mutex::new(HashMap::from([(7, article(7))])),
+            ..Default::default()
+        };
+        let (fanout, applied) = fake_writer();
+        let mut progress = Progress::new(0);
+
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+
+        // article 8 is gone by the time it's fetched, and skipped by default
+        assert_eq!(
+            reqnums_and_methods(&applied),
+            [(1, Method::Create), (3, Method::Delete)]
+        );
+        assert!(
+            matches!(&applied.lock().unwrap()[0].2, Entity::Article(article) if article.title == "article 7")
+        );
+        assert_eq!(*nucleus.polled.lock().unwrap(), [0, 3]);
+        assert_eq!(progress.committed, 3);
+    }
+
+    #[tokio::test]
+    async fn comments_of_blocked_authors_are_deleted() {
+        let config = Config {
+            blocked_authors: HashSet::from([UserId(1)]),
+            ..Config::for_tests()
+        };
+        let comment_event = |reqnum, id: u64| ChangeEvent {
+            reqnum,
+            method: Method::Update,
+            key: [&PREFIX_COMMENT_KEY[..], &id.to_be_bytes()].concat(),
+            source_time: None,
+        };
+        let nucleus = FakeNucleus {
+            batches: Mutex::new(VecDeque::from([vec![
+                comment_event(1, 9),
+                comment_event(2, 10),
+            ]])),
+            comments: HashMap::from([
+                (9, fixture::comment(9)),
+                (
+                    10,
+                    VeComment {
+                        author_id: UserId(2),
+                        content: "hi".to_string(),
+                        ..fixture::comment(10)
+                    },
+                ),
+            ]),
+            ..Default::default()
+        };
+        let (fanout, applied) = fake_writer();
+        let mut progress = Progress::new(0);
+
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            reqnums_and_methods(&applied),
+            [(1, Method::Delete), (2, Method::Update)]
+        );
+        assert!(matches!(
+            applied.lock().unwrap()[0].2,
+            Entity::Deleted(Model::Comment, 9)
+        ));
+        assert_eq!(progress.committed, 2);
+    }
+
+    #[tokio::test]
+    async fn full_page_polls_again_straight_away() {
+        let config = Config {
+            event_page_size: Some(2),
+            ..Config::for_tests()
+        };
+        let nucleus = FakeNucleus {
+            batches: Mutex::new(VecDeque::from([
+                vec![
+                    article_event(1, Method::Create, 7),
+                    article_event(2, Method::Update, 7),
+                ],
+                vec![article_event(3, Method::Update, 7)],
+            ])),
+            articles: Mutex::new(HashMap::from([(7, article(7))])),
+            ..Default::default()
+        };
+        let (fanout, _) = fake_writer();
+        let mut progress = Progress::new(0);
+
+        assert!(poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap());
+        assert!(!poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap());
+        assert_eq!(progress.committed, 3);
+    }
+
+    #[tokio::test]
+    async fn window_leaves_the_rest_for_the_next_cycle() {
+        let config = Config {
+            max_events_per_cycle: Some(2),
+            ..Config::for_tests()
+        };
+        let batch: Vec<_> = (1..=5)
+            .map(|reqnum| article_event(reqnum, Method::Update, 7))
+            .collect();
+        let nucleus = FakeNucleus {
+            batches: Mutex::new(VecDeque::from([
+                batch.clone(),
+                batch[2..].to_vec(),
+                batch[4..].to_vec(),
+            ])),
+            articles: Mutex::new(HashMap::from([(7, article(7))])),
+            ..Default::default()
+        };
+        let (fanout, applied) = fake_writer();
+        let mut progress = Progress::new(0);
+
+        assert!(poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap());
+        assert_eq!(progress.committed, 2);
+        assert!(poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap());
+        assert!(!poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap());
+        assert_eq!(*nucleus.polled.lock().unwrap(), [0, 2, 4]);
+        assert_eq!(applied.lock().unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn compaction_holds_updates_and_flushes_on_a_delete() {
+        let config = Config {
+            compact_window: Some(Duration::from_secs(60)),
+            ..Config::for_tests()
+        };
+        let updates: Vec<_> = [Method::Create, Method::Update, Method::Update]
+            .into_iter()
+            .zip(1..)
+            .map(|(method, reqnum)| article_event(reqnum, method, 7))
+            .collect();
+        let deleted = [updates.clone(), vec![article_event(4, Method::Delete, 7)]].concat();
+        let nucleus = FakeNucleus {
+            batches: Mutex::new(VecDeque::from([updates, deleted])),
+            articles: Mutex::new(HashMap::from([(7, article(7))])),
+            ..Default::default()
+        };
+        let (fanout, applied) = fake_writer();
+        let mut progress = Progress::new(0);
+
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+        assert!(applied.lock().unwrap().is_empty());
+        assert!(progress.held_since.is_some());
+
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+        assert_eq!(
+            reqnums_and_methods(&applied),
+            [(1, Method::Create), (4, Method::Delete)]
+        );
+        assert_eq!(*nucleus.polled.lock().unwrap(), [0, 0]);
+        assert_eq!(progress.committed, 4);
+        assert!(progress.held_since.is_none());
+    }
+
+    #[tokio::test]
+    async fn an_undecodable_event_fails_alone() {
+        let config = Config {
+            max_attempts: 2,
+            ..Config::for_tests()
+        };
+        let corrupt = UndecodableEvent {
+            reqnum: 2,
+            key: article_event(2, Method::Update, 8).key,
+            raw: vec![0xff],
+            error: "failed to decode change event".to_string(),
+        };
+        let batch = vec![
+            article_event(1, Method::Create, 7),
+            corrupt.stand_in(),
+            article_event(3, Method::Create, 9),
+        ];
+        let nucleus = FakeNucleus {
+            batches: Mutex::new(VecDeque::from([batch.clone(), batch])),
+            articles: Mutex::new(HashMap::from([(7, article(7)), (9, article(9))])),
+            undecodable: vec![corrupt],
+            ..Default::default()
+        };
+        let (fanout, applied) = fake_writer();
+        let mut progress = Progress::new(0);
+
+        // the others land, only the corrupt one is retried
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+        assert_eq!(
+            reqnums_and_methods(&applied),
+            [(1, Method::Create), (3, Method::Create)]
+        );
+        assert_eq!((progress.committed, progress.attempts[&2].0), (1, 1));
+
+        // and dead-lettered once out of attempts
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+        assert_eq!(progress.committed, 3);
+        assert!(progress.attempts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_parent_gets_its_own_attempts() {
+        let config = Config {
+            max_attempts: 1,
+            parent_gap_attempts: 3,
+            ..Config::for_tests()
+        };
+        let batch = vec![article_event(1, Method::Create, 7)];
+        let nucleus = FakeNucleus {
+            batches: Mutex::new(VecDeque::from([batch.clone(), batch.clone(), batch])),
+            articles: Mutex::new(HashMap::from([(7, article(7))])),
+            ..Default::default()
+        };
+        let error = format!("{}subspace 1 is missing", db::PARENT_GAP);
+        let (fanout, _) = failing_writer(HashMap::from([(1, error)]));
+        let mut progress = Progress::new(0);
+
+        for attempt in 1..3 {
+            poll_cycle(&nucleus, &config, &fanout, &mut progress)
+                .await
+                .unwrap();
+            assert_eq!((progress.committed, progress.attempts[&1].0), (0, attempt));
+        }
+        // dead-lettered on the last one
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+        assert_eq!(progress.committed, 1);
+        assert!(progress.attempts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn gap_reconciliation_fetches_the_ids_the_gap_hides() {
+        let config = Config {
+            reconcile_gaps: true,
+            ..Config::for_tests()
+        };
+        let nucleus = FakeNucleus {
+            batches: Mutex::new(VecDeque::from([
+                vec![article_event(1, Method::Create, 7)],
+                vec![article_event(5, Method::Create, 10)],
+            ])),
+            articles: Mutex::new(HashMap::from([
+                (7, article(7)),
+                (8, article(8)),
+                (10, article(10)),
+            ])),
+            ..Default::default()
+        };
+        let (fanout, applied) = fake_writer();
+        let mut progress = Progress::new(0);
+
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+
+        // article 9, which the nucleus doesn't have, is skipped like any vanished entity
+        let ids: Vec<_> = applied
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(reqnum, method, entity)| (*reqnum, *method, entity.id()))
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                (1, Method::Create, 7),
+                (5, Method::Update, 8),
+                (5, Method::Create, 10)
+            ]
+        );
+        assert_eq!(progress.gap_reported, 5);
+        assert_eq!(progress.committed, 5);
+    }
+
+    #[tokio::test]
+    async fn entities_missing_at_first_are_fetched_again() {
+        let config = Config {
+            missing_entity_retries: 2,
+            missing_entity_retry_delay: Duration::from_millis(1),
+            ..Config::for_tests()
+        };
+        let nucleus = FakeNucleus {
+            batches: Mutex::new(VecDeque::from([vec![
+                article_event(1, Method::Create, 7),
+                article_event(2, Method::Create, 8),
+            ]])),
+            articles: Mutex::new(HashMap::from([(7, article(7)), (8, article(8))])),
+            // 7 is there by the second fetch, 8 not by the last
+            unsettled: Mutex::new(HashMap::from([(7, 1), (8, 3)])),
+            ..Default::default()
+        };
+        let (fanout, applied) = fake_writer();
+        let mut progress = Progress::new(0);
+
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+
+        assert_eq!(reqnums_and_methods(&applied), [(1, Method::Create)]);
+        assert_eq!(nucleus.unsettled.lock().unwrap()[&8], 0);
+        assert_eq!(progress.committed, 2);
+    }
+
+    #[tokio::test]
+    async fn avs_errors_are_retried_unless_fatal() {
+        let config = Config {
+            fatal_nucleus_errors: vec!["unknown method".to_string()],
+            ..Config::for_tests()
+        };
+        let nucleus = FakeNucleus {
+            batches: Mutex::new(VecDeque::from([vec![article_event(1, Method::Create, 7)]])),
+            failures: Mutex::new(VecDeque::from([
+                "storage busy".to_string(),
+                "unknown method".to_string(),
+            ])),
+            articles: Mutex::new(HashMap::from([(7, article(7))])),
+            ..Default::default()
+        };
+        let (fanout, applied) = fake_writer();
+        let mut progress = Progress::new(0);
+
+        assert!(!poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap());
+        let fatal = poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap_err();
+        assert!(fatal.to_string().contains("unknown method"));
+        assert!(applied.lock().unwrap().is_empty());
+
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+        assert_eq!(progress.committed, 1);
+    }
+
+    #[tokio::test]
+    async fn first_sentinel_depends_on_start_mode() {
+        let nucleus = FakeNucleus {
+            head: 41,
+            ..Default::default()
+        };
+        assert_eq!(
+            first_sentinel(&nucleus, None, StartMode::Backfill)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            first_sentinel(&nucleus, None, StartMode::Tail)
+                .await
+                .unwrap(),
+            41
+        );
+        // an initial sentinel goes before either
+        assert_eq!(
+            first_sentinel(&nucleus, Some(7), StartMode::Backfill)
+                .await
+                .unwrap(),
+            7
+        );
+        assert_eq!(
+            first_sentinel(&nucleus, Some(7), StartMode::Tail)
+                .await
+                .unwrap(),
+            7
+        );
+    }
+
+    #[tokio::test]
+    async fn reset_moves_back_only_when_asked_to_rewind() {
+        let path = std::env::temp_dir().join(format!("surrogate-reset-{}", std::process::id()));
+        let config = Config {
+            sentinel_reset_path: Some(path.clone()),
+            ..Config::for_tests()
+        };
+        let (fanout, _) = fake_writer();
+        let mut progress = Progress::new(100);
+
+        std::fs::write(&path, "40").unwrap();
+        reset_sentinel(&config, &fanout, &mut progress)
+            .await
+            .unwrap();
+        assert_eq!(progress.committed, 100);
+        assert!(path.exists());
+
+        std::fs::write(&path, "40 rewind").unwrap();
+        reset_sentinel(&config, &fanout, &mut progress)
+            .await
+            .unwrap();
+        assert_eq!((progress.committed, progress.sentinel), (40, 40));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn failed_event_holds_the_sentinel_back() {
+        let config = Config::for_tests();
+        let batch = vec![
+            article_event(1, Method::Create, 7),
+            article_event(2, Method::Create, 9),
+            article_event(3, Method::Update, 7),
+        ];
+        let nucleus = FakeNucleus {
+            batches: Mutex::new(VecDeque::from([batch.clone(), batch[1..].to_vec()])),
+            articles: Mutex::new(HashMap::from([(7, article(7))])),
+            broken: vec![9],
+            ..Default::default()
+        };
+        let (fanout, applied) = fake_writer();
+        let mut progress = Progress::new(0);
+
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+        assert_eq!(progress.committed, 1);
+        assert_eq!(progress.attempts[&2].0, 1);
+
+        // the events past the failed one are served, and sent, again
+        poll_cycle(&nucleus, &config, &fanout, &mut progress)
+            .await
+            .unwrap();
+        assert_eq!(*nucleus.polled.lock().unwrap(), [0, 1]);
+        assert_eq!(progress.committed, 1);
+        assert_eq!(
+            reqnums_and_methods(&applied),
+            [
+                (1, Method::Create),
+                (3, Method::Update),
+                (3, Method::Update)
+            ]
+        );
+    }
+
+    // Ids and account of the soak, clear of those a real nucleus hands out.
+    const SOAK_FIRST_ID: u64 = 1 << 41;
+    const SOAK_AVS_ID: &str = "5FHneW46xGXgs5mUiveU4sbTyGBzmstUspZC92UhjJM694ty";
+
+    /// Runs the polling loop against the database `VE_POSTGRES_CONFIG` points at, with a fake nucleus
+    /// producing `SOAK_RATE` (default 1000) mixed creates, updates and deletes a second for `SOAK_SECS`
+    /// (default 60), then checks the articles left are exactly those the nucleus holds, that nothing was
+    /// left retrying and that resident memory grew by no more than `SOAK_RSS_SLACK_MB` (default 64)
+    /// once warmed up. It migrates the database, so use a scratch one:
+    ///
+    ///     VE_POSTGRES_CONFIG="host=localhost user=postgres dbname=soak" cargo test --bin surrogate soak -- --ignored --nocapture
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs a scratch database and runs for SOAK_SECS"]
+    async fn soak() {
+        let env = |key: &str, default: u64| {
+            std::env::var(key).map_or(default, |value| value.parse().unwrap())
+        };
+        let (secs, rate, slack_mb) = (
+            env("SOAK_SECS", 60),
+            env("SOAK_RATE", 1000),
+            env("SOAK_RSS_SLACK_MB", 64),
+        );
+        // articles deleted in the batch that creates them are none for good
+        let config = Config {
+            avs_id: SOAK_AVS_ID.parse().unwrap(),
+            missing_entity_retries: 0,
+            ..Config::for_database_tests()
+        };
+
+        let mut client = db::connect(&config).await.unwrap();
+        db::setup_database(&mut client, &config, true)
+            .await
+            .unwrap();
+        client
+            .execute(
+                "DELETE FROM articles WHERE subspace_id = $1",
+                &[&SqlId(SOAK_FIRST_ID)],
+            )
+            .await
+            .unwrap();
+        let (tx, rx) = mpsc::channel(100);
+        let writer = tokio::spawn(db::run_writer(client, config.clone(), 0, rx));
+        let fanout = Fanout::primary_only(tx);
+        let subspace = VeSubspace {
+            title: "Soak".to_string(),
+            slug: "soak".to_string(),
+            ..fixture::subspace(SOAK_FIRST_ID)
+        };
+        let event = ChangeEvent {
+            reqnum: 0,
+            method: Method::Create,
+            key: Prefix::of_model(Model::Subspace).key(SOAK_FIRST_ID),
+            source_time: None,
+        };
+        fanout
+            .send(Change::new(
+                &event,
+                Method::Create,
+                Entity::Subspace(subspace),
+                "soak-subspace",
+            ))
+            .await
+            .unwrap();
+
+        // ids are drawn from a pool, so that entities go through creates, updates and deletes alike
+        let pool = (rate * 10).max(100);
+        let nucleus = FakeNucleus::default();
+        let mut progress = Progress::new(0);
+        let mut rng =
+            std::env::var("SOAK_SEED").map_or(0x9e37_79b9_7f4a_7c15, |seed| seed.parse().unwrap());
+        let mut reqnum = 0;
+        let tick = Duration::from_millis(100);
+        let started = Instant::now();
+        let mut warm_rss = None;
+        while started.elapsed() < Duration::from_secs(secs) {
+            let tick_started = Instant::now();
+            let mut batch = Vec::new();
+            {
+                let mut articles = nucleus.articles.lock().unwrap();
+                for _ in 0..(rate / 10).max(1) {
+                    // xorshift, reproducible with SOAK_SEED
+                    rng ^= rng << 13;
+                    rng ^= rng >> 7;
+                    rng ^= rng << 17;
+                    let id = SOAK_FIRST_ID + rng % pool;
+                    reqnum += 1;
+                    let method = match articles.get(&id) {
+                        None => Method::Create,
+                        Some(_) if (rng >> 32) % 4 == 0 => Method::Delete,
+                        Some(_) => Method::Update,
+                    };
+                    if method == Method::Delete {
+                        articles.remove(&id);
+                    } else {
+                        let article = VeArticle {
+                            title: format!("article {} at {}", id, reqnum),
+                            subspace_id: SubspaceId(SOAK_FIRST_ID),
+                            created_time: unix_now(),
+                            updated_time: reqnum as i64,
+                            ..fixture::article(id)
+                        };
+                        articles.insert(id, article);
+                    }
+                    batch.push(ChangeEvent {
+                        reqnum,
+                        method,
+                        key: Prefix::of_model(Model::Article).key(id),
+                        source_time: None,
+                    });
+                }
+            }
+            nucleus.batches.lock().unwrap().push_back(batch);
+            poll_cycle(&nucleus, &config, &fanout, &mut progress)
+                .await
+                .unwrap();
+            if warm_rss.is_none() && started.elapsed() >= Duration::from_secs(secs) / 10 {
+                warm_rss = rss_kb();
+            }
+            sleep(tick.saturating_sub(tick_started.elapsed())).await;
+        }
+        // whatever the last ticks left behind
+        while !nucleus.batches.lock().unwrap().is_empty() {
+            poll_cycle(&nucleus, &config, &fanout, &mut progress)
+                .await
+                .unwrap();
+        }
+        let elapsed = started.elapsed();
+
+        assert_eq!(progress.committed, reqnum, "events left unapplied");
+        assert!(
+            progress.attempts.is_empty(),
+            "events left retrying: {:?}",
+            progress.attempts
+        );
+        if let (Some(warm), Some(now)) = (warm_rss, rss_kb()) {
+            println!(
+                "resident memory {} kB once warm, {} kB at the end",
+                warm, now
+            );
+            assert!(
+                now <= warm + slack_mb * 1024,
+                "resident memory grew from {} kB to {} kB",
+                warm,
+                now
+            );
+        }
+        // closing the channel lets the writer finish up, it must do so promptly even under load
+        drop(fanout);
+        tokio::time::timeout(Duration::from_secs(10), writer)
+            .await
+            .expect("writer hung on shutdown")
+            .unwrap();
+
+        let client = db::connect(&config).await.unwrap();
+        let rows = client
+            .query(
+                "SELECT id, title, updated_time FROM articles WHERE subspace_id = $1",
+                &[&SqlId(SOAK_FIRST_ID)],
+            )
+            .await
+            .unwrap();
+        let indexed: HashMap<u64, (String, i64)> = rows
+            .iter()
+            .map(|row| {
+                (
+                    row.get::<_, SqlId>("id").0,
+                    (row.get("title"), row.get("updated_time")),
+                )
+            })
+            .collect();
+        let truth: HashMap<u64, (String, i64)> = nucleus
+            .articles
+            .lock()
+            .unwrap()
+            .values()
+            .map(|article| (article.id.0, (article.title.clone(), article.updated_time)))
+            .collect();
+        println!(
+            "{} events in {:.2?}, {} articles left",
+            reqnum,
+            elapsed,
+            truth.len()
+        );
+        assert_eq!(indexed, truth);
+
+        client
+            .execute(
+                "DELETE FROM articles WHERE subspace_id = $1",
+                &[&SqlId(SOAK_FIRST_ID)],
+            )
+            .await
+            .unwrap();
+        client
+            .execute(
+                "DELETE FROM subspaces WHERE id = $1",
+                &[&SqlId(SOAK_FIRST_ID)],
+            )
+            .await
+            .unwrap();
+    }
+
+    // Resident set size of the test process, where /proc has it.
+    fn rss_kb() -> Option<u64> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+        line.split_whitespace().nth(1)?.parse().ok()
+    }
+}
@@ -0,0 +1,462 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use vemodel::Method;
+
+use crate::build_info;
+use crate::model::Model;
+
+/// A monotonically increasing count, named as it's exported.
+pub struct Counter {
+    pub name: &'static str,
+    pub help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub static RPC_NON_STRING_RESPONSES: Counter = Counter::new(
+    "surrogate_rpc_non_string_responses_total",
+    "Nucleus JSON-RPC results that were not a hex string",
+);
+
+pub static UPSERT_INSERTS: Counter = Counter::new(
+    "surrogate_upsert_inserts_total",
+    "Upserts that inserted a new row",
+);
+
+pub static UPSERT_CONFLICT_UPDATES: Counter = Counter::new(
+    "surrogate_upsert_conflict_updates_total",
+    "Upserts that hit an existing row and updated it",
+);
+
+pub static ENTITY_CACHE_HITS: Counter = Counter::new(
+    "surrogate_entity_cache_hits_total",
+    "Entity fetches answered from the cache rather than the nucleus",
+);
+
+pub static ENTITY_CACHE_MISSES: Counter = Counter::new(
+    "surrogate_entity_cache_misses_total",
+    "Entity fetches that went to the nucleus",
+);
+
+pub static COMPACTED_EVENTS: Counter = Counter::new(
+    "surrogate_compacted_events_total",
+    "Change events passed over for an earlier one of the same entity in the batch",
+);
+
+pub static REAPPLIED_EVENTS: Counter = Counter::new(
+    "surrogate_reapplied_events_total",
+    "Change events passed over for being in applied_events already, e.g. read again past a failed one",
+);
+
+pub static SSE_DROPPED_EVENTS: Counter = Counter::new(
+    "surrogate_sse_dropped_events_total",
+    "Changes event stream clients missed for falling more than VE_SSE_BUFFER behind",
+);
+
+pub static EXPIRED_ARTICLES: Counter = Counter::new(
+    "surrogate_expired_articles_total",
+    "Articles deleted for outliving their TTL, not counting their comments",
+);
+
+pub static REQNUM_GAPS: Counter = Counter::new(
+    "surrogate_reqnum_gaps_total",
+    "Gaps in the reqnums served past VE_REQNUM_GAP_TOLERANCE, each possibly lost events",
+);
+
+pub static MISSED_REQNUMS: Counter = Counter::new(
+    "surrogate_missed_reqnums_total",
+    "Reqnums missing from the gaps counted in surrogate_reqnum_gaps_total",
+);
+
+pub static NUCLEUS_POLL_ERRORS: Counter = Counter::new(
+    "surrogate_nucleus_poll_errors_total",
+    "Polls the AVS answered with an error rather than change events",
+);
+
+pub static CONFLICT_RETRIES: Counter = Counter::new(
+    "surrogate_conflict_retries_total",
+    "Open transactions rolled back and written again after a change failed on a serialization failure or deadlock",
+);
+
+pub static NUCLEUS_RECONNECTS: Counter = Counter::new(
+    "surrogate_nucleus_reconnects_total",
+    "Times the client to the nucleus was built afresh, after idling or losing its connection",
+);
+
+pub static CHANGE_LOG_LINE_BYTES: Counter = Counter::new(
+    "surrogate_change_log_line_bytes_total",
+    "Bytes of the change log lines before compression",
+);
+
+pub static CHANGE_LOG_WRITTEN_BYTES: Counter = Counter::new(
+    "surrogate_change_log_written_bytes_total",
+    "Bytes of change log lines written, compressed if VE_CHANGE_LOG_COMPRESSION says so",
+);
+
+pub static DECODE_FAILURES: Counter = Counter::new(
+    "surrogate_decode_failures_total",
+    "Entities fetched from the nucleus that failed to decode",
+);
+
+pub static BLANK_FIELDS: Counter = Counter::new(
+    "surrogate_blank_fields_total",
+    "Required fields of entities from the nucleus found blank, see VE_BLANK_FIELDS",
+);
+
+pub static ALERTS: Counter = Counter::new(
+    "surrogate_alerts_total",
+    "Alerts posted to VE_ALERT_WEBHOOK_URL",
+);
+
+pub static ALERT_FAILURES: Counter = Counter::new(
+    "surrogate_alert_failures_total",
+    "Alerts that failed to post, or were dropped with too many waiting",
+);
+
+pub static SCHEMA_CHANGE_ALERTS: Counter = Counter::new(
+    "surrogate_schema_change_alerts_total",
+    "Bursts of decode failures past VE_DECODE_ALARM_THRESHOLD, each a likely schema change on the nucleus",
+);
+
+/// Every counter, for exporters to walk.
+pub static COUNTERS: [&Counter; 21] = [
+    &RPC_NON_STRING_RESPONSES,
+    &UPSERT_INSERTS,
+    &UPSERT_CONFLICT_UPDATES,
+    &ENTITY_CACHE_HITS,
+    &ENTITY_CACHE_MISSES,
+    &COMPACTED_EVENTS,
+    &REAPPLIED_EVENTS,
+    &SSE_DROPPED_EVENTS,
+    &EXPIRED_ARTICLES,
+    &REQNUM_GAPS,
+    &MISSED_REQNUMS,
+    &NUCLEUS_POLL_ERRORS,
+    &CONFLICT_RETRIES,
+    &NUCLEUS_RECONNECTS,
+    &CHANGE_LOG_LINE_BYTES,
+    &CHANGE_LOG_WRITTEN_BYTES,
+    &DECODE_FAILURES,
+    &BLANK_FIELDS,
+    &ALERTS,
+    &ALERT_FAILURES,
+    &SCHEMA_CHANGE_ALERTS,
+];
+
+/// A value that goes up and down, named as it's exported.
+pub struct Gauge {
+    pub name: &'static str,
+    pub help: &'static str,
+    value: AtomicU64,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set(&self, value: u64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub static SSE_CLIENTS: Gauge = Gauge::new(
+    "surrogate_sse_clients",
+    "Clients connected to the event stream",
+);
+
+pub static POLL_INTERVAL: Gauge = Gauge::new(
+    "surrogate_poll_interval_milliseconds",
+    "How long the polling loop waits between cycles that leave nothing over, as of the last cycle",
+);
+
+pub static BACKLOG: Gauge = Gauge::new(
+    "surrogate_backlog_events",
+    "Reqnums the nucleus has served past the last one applied, as of the last poll",
+);
+
+pub static PENDING_PARENTS: Gauge = Gauge::new(
+    "surrogate_pending_parent_events",
+    "Events retried because the row they reference isn't indexed yet",
+);
+
+pub static DECODE_FAILURES_IN_WINDOW: Gauge = Gauge::new(
+    "surrogate_decode_failures_in_window",
+    "Decode failures within the last VE_DECODE_ALARM_WINDOW_SECS, as of the last poll",
+);
+
+pub static REPLICATION_LAG: Gauge = Gauge::new(
+    "surrogate_replication_lag_reqnums",
+    "Reqnums between the nucleus head and the committed sentinel, as last measured",
+);
+
+pub static INGEST_PAUSED: Gauge = Gauge::new(
+    "surrogate_ingest_paused",
+    "1 while an operator has ingest paused, 0 otherwise",
+);
+
+/// Every gauge, for exporters to walk.
+pub static GAUGES: [&Gauge; 7] = [
+    &SSE_CLIENTS,
+    &POLL_INTERVAL,
+    &BACKLOG,
+    &PENDING_PARENTS,
+    &DECODE_FAILURES_IN_WINDOW,
+    &REPLICATION_LAG,
+    &INGEST_PAUSED,
+];
+
+/// A constant 1 whose labels carry the information, the usual way of
+/// exporting something like a version so it can be joined onto other series.
+pub struct Info {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub labels: &'static [(&'static str, &'static str)],
+}
+
+impl Info {
+    pub const fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [(&'static str, &'static str)],
+    ) -> Self {
+        Self { name, help, labels }
+    }
+}
+
+pub static BUILD_INFO: Info = Info::new(
+    "surrogate_build_info",
+    "The vemodel version and git describe the surrogate was built with",
+    &[
+        ("vemodel_version", build_info::VEMODEL_VERSION),
+        ("git_describe", build_info::GIT_DESCRIBE),
+    ],
+);
+
+/// The largest value observed so far, named as it's exported.
+pub struct Max {
+    pub name: &'static str,
+    pub help: &'static str,
+    value: AtomicU64,
+}
+
+impl Max {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        self.value.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub static RPC_LARGEST_RESPONSE: Max = Max::new(
+    "surrogate_rpc_largest_response_bytes",
+    "Largest decoded nucleus JSON-RPC result, in bytes",
+);
+
+/// A distribution of durations over fixed buckets, named as it's exported.
+pub struct Histogram<const N: usize> {
+    pub name: &'static str,
+    pub help: &'static str,
+    /// Upper bounds of the buckets in seconds, ascending.
+    pub bounds: [f64; N],
+    // observations per bucket, those above the last bound only make up `count`
+    counts: [AtomicU64; N],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    pub const fn new(name: &'static str, help: &'static str, bounds: [f64; N]) -> Self {
+        Self {
+            name,
+            help,
+            bounds,
+            counts: [const { AtomicU64::new(0) }; N],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        if let Some(i) = self.bounds.iter().position(|&bound| secs <= bound) {
+            self.counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Cumulative counts per bucket bound, as Prometheus has them.
+    pub fn buckets(&self) -> [(f64, u64); N] {
+        let mut total = 0;
+        std::array::from_fn(|i| {
+            total += self.counts[i].load(Ordering::Relaxed);
+            (self.bounds[i], total)
+        })
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Sum of all observations in seconds.
+    pub fn sum(&self) -> f64 {
+        self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+    }
+}
+
+pub static EVENT_AGE: Histogram<8> = Histogram::new(
+    "surrogate_event_age_seconds",
+    "Time from the nucleus recording a change to it being applied, for nuclei that timestamp their events",
+    [0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0],
+);
+
+// every model and method, in the order of `ByChange::histograms`
+const CHANGES: [(Model, Method); 9] = [
+    (Model::Subspace, Method::Create),
+    (Model::Subspace, Method::Update),
+    (Model::Subspace, Method::Delete),
+    (Model::Article, Method::Create),
+    (Model::Article, Method::Update),
+    (Model::Article, Method::Delete),
+    (Model::Comment, Method::Create),
+    (Model::Comment, Method::Update),
+    (Model::Comment, Method::Delete),
+];
+
+/// A histogram per model and method of change, exported as one labeled by
+/// `model` and `method`.
+pub struct ByChange<const N: usize> {
+    pub name: &'static str,
+    pub help: &'static str,
+    histograms: [Histogram<N>; 9],
+}
+
+impl<const N: usize> ByChange<N> {
+    pub const fn new(name: &'static str, help: &'static str, bounds: [f64; N]) -> Self {
+        Self {
+            name,
+            help,
+            // `Histogram` isn't `Copy`, and a const block can't take `bounds`
+            histograms: [
+                Histogram::new(name, help, bounds),
+                Histogram::new(name, help, bounds),
+                Histogram::new(name, help, bounds),
+                Histogram::new(name, help, bounds),
+                Histogram::new(name, help, bounds),
+                Histogram::new(name, help, bounds),
+                Histogram::new(name, help, bounds),
+                Histogram::new(name, help, bounds),
+                Histogram::new(name, help, bounds),
+            ],
+        }
+    }
+
+    pub fn observe(&self, model: Model, method: Method, value: Duration) {
+        self.get(model, method).observe(value);
+    }
+
+    pub fn get(&self, model: Model, method: Method) -> &Histogram<N> {
+        let i = CHANGES
+            .iter()
+            .position(|&change| change == (model, method))
+            .expect("every model and method is listed");
+        &self.histograms[i]
+    }
+
+    /// The `model` and `method` labels of each histogram, with it.
+    pub fn series(
+        &self,
+    ) -> impl Iterator<Item = ([(&'static str, &'static str); 2], &Histogram<N>)> {
+        CHANGES
+            .iter()
+            .zip(&self.histograms)
+            .map(|(&(model, method), histogram)| {
+                let method = match method {
+                    Method::Create => "create",
+                    Method::Update => "update",
+                    Method::Delete => "delete",
+                };
+                ([("model", model.as_str()), ("method", method)], histogram)
+            })
+    }
+}
+
+pub static APPLY_LATENCY: ByChange<9> = ByChange::new(
+    "surrogate_apply_latency_seconds",
+    "Time from the writer receiving a change to the transaction applying it committing",
+    [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0],
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new("test", "test", [1.0, 5.0]);
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(3));
+        histogram.observe(Duration::from_secs(60));
+
+        assert_eq!(histogram.buckets(), [(1.0, 1), (5.0, 2)]);
+        assert_eq!(histogram.count(), 3);
+        assert!((histogram.sum() - 63.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn observes_each_change_apart() {
+        let latency = ByChange::new("test", "test", [1.0]);
+        latency.observe(Model::Comment, Method::Update, Duration::from_millis(2));
+        assert_eq!(latency.get(Model::Comment, Method::Update).count(), 1);
+        assert_eq!(latency.get(Model::Article, Method::Update).count(), 0);
+
+        let observed: Vec<_> = latency
+            .series()
+            .filter(|(_, histogram)| histogram.count() > 0)
+            .map(|(labels, _)| labels)
+            .collect();
+        assert_eq!(observed, [[("model", "comment"), ("method", "update")]]);
+    }
+}
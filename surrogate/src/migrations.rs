@@ -0,0 +1,539 @@
+use std::collections::HashSet;
+use std::fmt;
+use tokio_postgres::GenericClient;
+use tracing::{info, warn};
+
+/// A single, numbered schema change. Migrations are applied in ascending
+/// `version` order and each one is recorded in `schema_migrations` once it
+/// has been committed, so it never runs twice against the same database.
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// A statement of a migration that failed. The migration is rolled back as a
+/// whole, so a rerun starts it over.
+#[derive(Debug)]
+pub struct MigrationError {
+    pub version: i32,
+    pub name: &'static str,
+    /// The first line of the failing statement, naming what it creates or alters.
+    pub statement: String,
+    pub source: tokio_postgres::Error,
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "migration {} ({}) failed at `{}`: {}",
+            self.version, self.name, self.statement, self.source
+        )
+    }
+}
+
+impl std::error::Error for MigrationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl Migration {
+    /// The statements of the migration, in order. Migrations are kept to
+    /// plain DDL and DML, no `;` within a statement, for them to split cleanly.
+    pub fn statements(&self) -> impl Iterator<Item = &'static str> {
+        self.sql.split(';').map(str::trim).filter(|s| !s.is_empty())
+    }
+}
+
+// NOTE: never edit or renumber a migration that has shipped, append a new one instead
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        // `IF NOT EXISTS` keeps this a no-op on databases created before migrations existed
+        sql: "
+            CREATE TABLE IF NOT EXISTS subspaces (
+                id BIGINT PRIMARY KEY,
+                title VARCHAR NOT NULL,
+                slug VARCHAR NOT NULL,
+                description TEXT,
+                banner VARCHAR,
+                status SMALLINT NOT NULL,
+                weight SMALLINT NOT NULL,
+                created_time BIGINT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS articles (
+                id BIGINT PRIMARY KEY,
+                title VARCHAR NOT NULL,
+                content TEXT NOT NULL,
+                author_id BIGINT NOT NULL,
+                author_nickname VARCHAR NOT NULL,
+                subspace_id BIGINT NOT NULL,
+                ext_link VARCHAR,
+                status SMALLINT NOT NULL,
+                weight SMALLINT NOT NULL,
+                created_time BIGINT NOT NULL,
+                updated_time BIGINT NOT NULL,
+                FOREIGN KEY (subspace_id) REFERENCES subspaces(id)
+            );
+
+            CREATE TABLE IF NOT EXISTS comments (
+                id BIGINT PRIMARY KEY,
+                content TEXT NOT NULL,
+                author_id BIGINT NOT NULL,
+                author_nickname VARCHAR NOT NULL,
+                post_id BIGINT NOT NULL,
+                status SMALLINT NOT NULL,
+                weight SMALLINT NOT NULL,
+                created_time BIGINT NOT NULL,
+                FOREIGN KEY (post_id) REFERENCES articles(id)
+            );
+        ",
+    },
+    Migration {
+        version: 2,
+        name: "articles_excerpt",
+        sql: "ALTER TABLE articles ADD COLUMN IF NOT EXISTS excerpt TEXT;",
+    },
+    Migration {
+        version: 3,
+        name: "dead_letter",
+        sql: "
+            CREATE TABLE IF NOT EXISTS dead_letter (
+                reqnum BIGINT PRIMARY KEY,
+                prefix VARCHAR NOT NULL,
+                id BIGINT NOT NULL,
+                method VARCHAR NOT NULL,
+                raw_bytes BYTEA,
+                error TEXT NOT NULL,
+                first_seen BIGINT NOT NULL,
+                attempts INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 4,
+        name: "sync_state",
+        sql: "
+            CREATE TABLE IF NOT EXISTS sync_state (
+                avs_id VARCHAR PRIMARY KEY,
+                sentinel BIGINT NOT NULL,
+                updated_time BIGINT NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 5,
+        name: "dead_letter_per_sink",
+        sql: "
+            ALTER TABLE dead_letter ADD COLUMN IF NOT EXISTS sink VARCHAR NOT NULL DEFAULT 'postgres';
+            ALTER TABLE dead_letter DROP CONSTRAINT IF EXISTS dead_letter_pkey;
+            ALTER TABLE dead_letter ADD PRIMARY KEY (reqnum, sink);
+        ",
+    },
+    Migration {
+        version: 6,
+        name: "articles_content_encoding",
+        // existing rows are plain text, `content recode` compresses them if wanted
+        sql: "
+            ALTER TABLE articles ALTER COLUMN content DROP NOT NULL;
+            ALTER TABLE articles ADD COLUMN IF NOT EXISTS content_bytes BYTEA;
+            ALTER TABLE articles ADD COLUMN IF NOT EXISTS content_encoding VARCHAR NOT NULL DEFAULT 'plain';
+        ",
+    },
+    Migration {
+        version: 7,
+        name: "source_and_indexed_time",
+        // unix milliseconds, `source_time` stays NULL for nuclei that don't timestamp events
+        sql: "
+            ALTER TABLE subspaces ADD COLUMN IF NOT EXISTS source_time BIGINT;
+            ALTER TABLE subspaces ADD COLUMN IF NOT EXISTS indexed_time BIGINT;
+            ALTER TABLE articles ADD COLUMN IF NOT EXISTS source_time BIGINT;
+            ALTER TABLE articles ADD COLUMN IF NOT EXISTS indexed_time BIGINT;
+            ALTER TABLE comments ADD COLUMN IF NOT EXISTS source_time BIGINT;
+            ALTER TABLE comments ADD COLUMN IF NOT EXISTS indexed_time BIGINT;
+        ",
+    },
+    Migration {
+        version: 8,
+        name: "author_nickname_sanitized",
+        // NULL for rows written before, readers fall back to the raw `author_nickname`
+        sql: "
+            ALTER TABLE articles ADD COLUMN IF NOT EXISTS author_nickname_sanitized VARCHAR;
+            ALTER TABLE comments ADD COLUMN IF NOT EXISTS author_nickname_sanitized VARCHAR;
+        ",
+    },
+    Migration {
+        version: 9,
+        name: "scrubbed_changes",
+        sql: "
+            CREATE TABLE IF NOT EXISTS scrubbed_changes (
+                reqnum BIGINT PRIMARY KEY,
+                prefix VARCHAR NOT NULL,
+                id BIGINT NOT NULL,
+                original BYTEA NOT NULL,
+                fixes TEXT NOT NULL,
+                error TEXT NOT NULL,
+                scrubbed_time BIGINT NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 10,
+        name: "subspaces_description_plain",
+        // filled in for existing rows by `setup_database`
+        sql: "ALTER TABLE subspaces ADD COLUMN IF NOT EXISTS description_plain TEXT;",
+    },
+    Migration {
+        version: 11,
+        name: "absent_text_as_null",
+        // empty strings stood for absent text until now, new rows store NULL instead
+        sql: "
+            UPDATE subspaces SET description = NULL WHERE description = '';
+            UPDATE subspaces SET banner = NULL WHERE banner = '';
+            UPDATE subspaces SET description_plain = NULL WHERE description_plain = '';
+            UPDATE articles SET ext_link = NULL WHERE ext_link = '';
+        ",
+    },
+    Migration {
+        version: 12,
+        name: "row_source",
+        // NULL unless `VE_SOURCE` names the instance writing the row
+        sql: "
+            ALTER TABLE subspaces ADD COLUMN IF NOT EXISTS source VARCHAR;
+            ALTER TABLE articles ADD COLUMN IF NOT EXISTS source VARCHAR;
+            ALTER TABLE comments ADD COLUMN IF NOT EXISTS source VARCHAR;
+        ",
+    },
+    Migration {
+        version: 13,
+        name: "users_content_counts",
+        // only the counts for now, counted from the rows there already are
+        sql: "
+            CREATE TABLE IF NOT EXISTS users (
+                id BIGINT PRIMARY KEY,
+                article_count BIGINT NOT NULL DEFAULT 0,
+                comment_count BIGINT NOT NULL DEFAULT 0
+            );
+            INSERT INTO users (id, article_count)
+                SELECT author_id, COUNT(*) FROM articles GROUP BY author_id
+                ON CONFLICT (id) DO UPDATE SET article_count = EXCLUDED.article_count;
+            INSERT INTO users (id, comment_count)
+                SELECT author_id, COUNT(*) FROM comments GROUP BY author_id
+                ON CONFLICT (id) DO UPDATE SET comment_count = EXCLUDED.comment_count;
+        ",
+    },
+    Migration {
+        version: 14,
+        name: "change_feed",
+        sql: "
+            CREATE TABLE IF NOT EXISTS change_feed (
+                id BIGSERIAL PRIMARY KEY,
+                reqnum BIGINT NOT NULL,
+                envelope JSONB NOT NULL,
+                recorded_time BIGINT NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 15,
+        name: "articles_created_time_id",
+        // the order of feeds, `id` breaking ties between articles created the same second
+        sql: "
+            CREATE INDEX IF NOT EXISTS articles_created_time_id ON articles (created_time DESC, id DESC);
+        ",
+    },
+    Migration {
+        version: 16,
+        name: "etags",
+        // NULL until the row is next written, there's no computing them in SQL
+        sql: "
+            ALTER TABLE subspaces ADD COLUMN IF NOT EXISTS etag VARCHAR;
+            ALTER TABLE articles ADD COLUMN IF NOT EXISTS etag VARCHAR;
+            ALTER TABLE comments ADD COLUMN IF NOT EXISTS etag VARCHAR;
+        ",
+    },
+    Migration {
+        version: 17,
+        name: "articles_subspace_slug",
+        // kept up to date by the upserts of both, the index is for renaming a subspace
+        sql: "
+            ALTER TABLE articles ADD COLUMN IF NOT EXISTS subspace_slug VARCHAR;
+            UPDATE articles a SET subspace_slug = s.slug FROM subspaces s WHERE s.id = a.subspace_id;
+            CREATE INDEX IF NOT EXISTS articles_subspace_id ON articles (subspace_id);
+        ",
+    },
+    Migration {
+        version: 18,
+        name: "comments_target_type",
+        // what `post_id` references, only ever a comment with `VE_COMMENT_REPLIES`
+        sql: "
+            ALTER TABLE comments ADD COLUMN IF NOT EXISTS target_type VARCHAR NOT NULL DEFAULT 'article';
+        ",
+    },
+    Migration {
+        version: 19,
+        name: "subspace_stats",
+        // counted from the rows there already are, replies in the subspace their thread is in
+        sql: "
+            CREATE TABLE IF NOT EXISTS subspace_authors (
+                subspace_id BIGINT NOT NULL,
+                author_id BIGINT NOT NULL,
+                items BIGINT NOT NULL DEFAULT 0,
+                PRIMARY KEY (subspace_id, author_id)
+            );
+            CREATE TABLE IF NOT EXISTS subspace_stats (
+                subspace_id BIGINT PRIMARY KEY,
+                article_count BIGINT NOT NULL DEFAULT 0,
+                comment_count BIGINT NOT NULL DEFAULT 0,
+                active_authors BIGINT NOT NULL DEFAULT 0,
+                last_activity_time BIGINT
+            );
+            CREATE TEMP TABLE subspace_content ON COMMIT DROP AS
+                WITH RECURSIVE thread (id, author_id, created_time, post_id, target_type) AS (
+                    SELECT id, author_id, created_time, post_id, target_type FROM comments
+                    UNION
+                    SELECT thread.id, thread.author_id, thread.created_time, c.post_id, c.target_type
+                    FROM thread JOIN comments c ON c.id = thread.post_id
+                    WHERE thread.target_type = 'comment'
+                )
+                SELECT subspace_id, author_id, created_time, 'article' AS kind FROM articles
+                UNION ALL
+                SELECT a.subspace_id, thread.author_id, thread.created_time, 'comment'
+                FROM thread JOIN articles a ON a.id = thread.post_id
+                WHERE thread.target_type = 'article';
+            INSERT INTO subspace_authors (subspace_id, author_id, items)
+                SELECT subspace_id, author_id, COUNT(*) FROM subspace_content GROUP BY subspace_id, author_id
+                ON CONFLICT (subspace_id, author_id) DO UPDATE SET items = EXCLUDED.items;
+            INSERT INTO subspace_stats (subspace_id, article_count, comment_count, active_authors, last_activity_time)
+                SELECT subspace_id,
+                       COUNT(*) FILTER (WHERE kind = 'article'),
+                       COUNT(*) FILTER (WHERE kind = 'comment'),
+                       COUNT(DISTINCT author_id),
+                       MAX(created_time)
+                FROM subspace_content GROUP BY subspace_id
+                ON CONFLICT (subspace_id) DO UPDATE SET
+                    article_count = EXCLUDED.article_count,
+                    comment_count = EXCLUDED.comment_count,
+                    active_authors = EXCLUDED.active_authors,
+                    last_activity_time = EXCLUDED.last_activity_time;
+        ",
+    },
+    Migration {
+        version: 20,
+        name: "applied_events",
+        // events applied before it existed have only the sentinel to show for it
+        sql: "
+            CREATE TABLE IF NOT EXISTS applied_events (
+                avs_id VARCHAR NOT NULL,
+                reqnum BIGINT NOT NULL,
+                applied_time BIGINT NOT NULL,
+                PRIMARY KEY (avs_id, reqnum)
+            );
+        ",
+    },
+    Migration {
+        version: 21,
+        name: "content_html",
+        // null until the row is next written with VE_CONTENT_HTML on
+        sql: "
+            ALTER TABLE articles ADD COLUMN IF NOT EXISTS content_html TEXT;
+            ALTER TABLE comments ADD COLUMN IF NOT EXISTS content_html TEXT;
+        ",
+    },
+    Migration {
+        version: 22,
+        name: "weight_range",
+        // 0 is vemodel's `Weight::MIN`. Not valid for rows stored with a negative
+        // weight before, ingest dead-letters those from now on, see `DEFERRED`
+        sql: "
+            ALTER TABLE subspaces ADD CONSTRAINT subspaces_weight_range CHECK (weight >= 0) NOT VALID;
+            ALTER TABLE articles ADD CONSTRAINT articles_weight_range CHECK (weight >= 0) NOT VALID;
+            ALTER TABLE comments ADD CONSTRAINT comments_weight_range CHECK (weight >= 0) NOT VALID;
+        ",
+    },
+    Migration {
+        version: 23,
+        name: "articles_edited",
+        sql: "
+            ALTER TABLE articles ADD COLUMN IF NOT EXISTS edited BOOLEAN NOT NULL DEFAULT FALSE;
+            UPDATE articles SET edited = TRUE WHERE updated_time > created_time;
+        ",
+    },
+    Migration {
+        version: 24,
+        name: "applied_events_by_change",
+        // rows from before match no event again, only VE_APPLIED_EVENTS_RETENTION_SECS prunes them
+        sql: "
+            ALTER TABLE applied_events ADD COLUMN IF NOT EXISTS key BYTEA NOT NULL DEFAULT '';
+            ALTER TABLE applied_events ADD COLUMN IF NOT EXISTS method VARCHAR NOT NULL DEFAULT '';
+            ALTER TABLE applied_events DROP CONSTRAINT IF EXISTS applied_events_pkey;
+            ALTER TABLE applied_events ADD PRIMARY KEY (avs_id, reqnum, key, method);
+        ",
+    },
+    Migration {
+        version: 25,
+        name: "applied_events_applied_time",
+        sql: "
+            CREATE INDEX IF NOT EXISTS applied_events_applied_time ON applied_events (avs_id, applied_time);
+        ",
+    },
+];
+
+/// Constraints migrations add `NOT VALID`, holding for the rows written from
+/// then on without failing the migration over those stored before: table,
+/// constraint, and the condition of the rows breaking it.
+const DEFERRED: &[(&str, &str, &str)] = &[
+    ("subspaces", "subspaces_weight_range", "weight < 0"),
+    ("articles", "articles_weight_range", "weight < 0"),
+    ("comments", "comments_weight_range", "weight < 0"),
+];
+
+/// Validates each constraint of [`DEFERRED`] no row breaks any more, and warns
+/// of the rows still breaking the others, to be fixed or deleted by hand.
+pub async fn validate_deferred<C: GenericClient>(client: &C) -> Result<(), tokio_postgres::Error> {
+    for (table, constraint, breaking) in DEFERRED {
+        let validated: Option<bool> = client
+            .query_opt(
+                "SELECT convalidated FROM pg_constraint
+                 WHERE conname = $1 AND conrelid = $2::TEXT::regclass",
+                &[constraint, table],
+            )
+            .await?
+            .map(|row| row.get(0));
+        if validated != Some(false) {
+            continue;
+        }
+        let rows: i64 = client
+            .query_one(
+                &format!("SELECT COUNT(*) FROM {} WHERE {}", table, breaking),
+                &[],
+            )
+            .await?
+            .get(0);
+        if rows == 0 {
+            client
+                .batch_execute(&format!(
+                    "ALTER TABLE {} VALIDATE CONSTRAINT {}",
+                    table, constraint
+                ))
+                .await?;
+            info!("Validated {} of {}", constraint, table);
+        } else {
+            warn!(
+                "{} rows of {} break {}, which only holds for rows written since; fix or delete them to have it validated: SELECT id FROM {} WHERE {}",
+                rows, table, constraint, table, breaking
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn run_migrations<C: GenericClient>(
+    client: &mut C,
+) -> Result<(), Box<dyn std::error::Error>> {
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version INTEGER PRIMARY KEY,
+                name VARCHAR NOT NULL,
+                applied_time BIGINT NOT NULL
+            )",
+        )
+        .await?;
+
+    let rows = client
+        .query("SELECT version FROM schema_migrations", &[])
+        .await?;
+    let applied: HashSet<i32> = rows.iter().map(|row| row.get(0)).collect();
+
+    for migration in MIGRATIONS {
+        if applied.contains(&migration.version) {
+            continue;
+        }
+
+        // apply the change and record it atomically, a failure leaves no trace
+        let tx = client.transaction().await?;
+        for statement in migration.statements() {
+            tx.batch_execute(statement)
+                .await
+                .map_err(|source| MigrationError {
+                    version: migration.version,
+                    name: migration.name,
+                    statement: statement.lines().next().unwrap_or_default().to_string(),
+                    source,
+                })?;
+        }
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name, applied_time)
+             VALUES ($1, $2, EXTRACT(EPOCH FROM now())::BIGINT)",
+            &[&migration.version, &migration.name],
+        )
+        .await?;
+        tx.commit().await?;
+        info!(
+            "Applied migration {}: {}",
+            migration.version, migration.name
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_strictly_increasing() {
+        for pair in MIGRATIONS.windows(2) {
+            assert!(pair[0].version < pair[1].version);
+        }
+        assert_eq!(MIGRATIONS[0].version, 1);
+    }
+
+    #[test]
+    fn weight_ranges_start_at_the_models_minimum() {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.name == "weight_range")
+            .unwrap();
+        let check = format!("CHECK (weight >= {}) NOT VALID", vemodel::Weight::MIN.0);
+        assert_eq!(migration.statements().count(), DEFERRED.len());
+        for ((table, constraint, breaking), statement) in
+            DEFERRED.iter().zip(migration.statements())
+        {
+            assert!(statement.starts_with(&format!(
+                "ALTER TABLE {} ADD CONSTRAINT {} ",
+                table, constraint
+            )));
+            assert!(statement.contains(&check), "{}", statement);
+            assert_eq!(*breaking, format!("weight < {}", vemodel::Weight::MIN.0));
+        }
+    }
+
+    #[test]
+    fn migrations_split_into_whole_statements() {
+        let initial: Vec<_> = MIGRATIONS[0].statements().collect();
+        assert_eq!(initial.len(), 3);
+        assert_eq!(
+            initial[2].lines().next(),
+            Some("CREATE TABLE IF NOT EXISTS comments (")
+        );
+        for migration in MIGRATIONS {
+            assert!(!migration.sql.contains("$$"), "{}", migration.name);
+            for statement in migration.statements() {
+                let verb = statement.split_whitespace().next().unwrap();
+                assert!(
+                    ["CREATE", "ALTER", "INSERT", "UPDATE", "DROP"].contains(&verb),
+                    "{}: {}",
+                    migration.name,
+                    statement
+                );
+            }
+        }
+    }
+}
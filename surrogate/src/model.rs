@@ -0,0 +1,222 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::str::FromStr;
+use tokio_postgres::Row;
+use vemodel::{
+    ArticleId, CommentId, SubspaceId, UserId, VeArticle, VeComment, VeSubspace, PREFIX_ARTICLE_KEY,
+    PREFIX_COMMENT_KEY, PREFIX_SUBSPACE_KEY,
+};
+
+use crate::content::ContentError;
+use crate::db::Entity;
+use crate::key::{self, Prefix};
+use crate::nucleus::{Fetched, Nucleus};
+use crate::query;
+use crate::sink::BoxFuture;
+
+/// The kinds of entity the nucleus holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Model {
+    Subspace,
+    Article,
+    Comment,
+}
+
+impl Model {
+    pub const ALL: [Model; 3] = [Self::Subspace, Self::Article, Self::Comment];
+
+    /// What this model goes by, from [`MODELS`].
+    pub fn info(self) -> &'static ModelInfo {
+        &MODELS[self as usize]
+    }
+
+    pub fn as_str(self) -> &'static str {
+        self.info().name
+    }
+
+    /// The table rows of this model are kept in.
+    pub fn table(self) -> &'static str {
+        self.info().table
+    }
+}
+
+/// The names a model goes by on the nucleus and in the database, and how its
+/// entities are read back from either.
+#[derive(Debug)]
+pub struct ModelInfo {
+    pub model: Model,
+    /// As it's written in config, on the command line and in logs.
+    pub name: &'static str,
+    pub table: &'static str,
+    /// The storage key prefix of its entities and the layout of their keys.
+    pub prefix: Prefix,
+    /// The nucleus method fetching one by id.
+    pub fetch: &'static str,
+    /// Reads back what [`Entity::to_json`] gave for one.
+    pub from_json: fn(serde_json::Value) -> serde_json::Result<Entity>,
+    /// Reads one back from its row of `table`.
+    pub from_row: fn(&Row) -> Result<Entity, ContentError>,
+}
+
+/// Every model, in [`Model::ALL`] order. Naming a model anywhere else goes
+/// through here, the `Ve*` type of each says which it is with [`Registered`].
+pub static MODELS: [ModelInfo; 3] = [
+    ModelInfo {
+        model: Model::Subspace,
+        name: "subspace",
+        table: "subspaces",
+        prefix: Prefix {
+            bytes: PREFIX_SUBSPACE_KEY,
+            model: Model::Subspace,
+            layout: key::SIMPLE,
+        },
+        fetch: "get_subspace",
+        from_json: from_json::<VeSubspace>,
+        from_row: |row| Ok(Entity::Subspace(query::subspace_from_row(row))),
+    },
+    ModelInfo {
+        model: Model::Article,
+        name: "article",
+        table: "articles",
+        prefix: Prefix {
+            bytes: PREFIX_ARTICLE_KEY,
+            model: Model::Article,
+            layout: key::SIMPLE,
+        },
+        fetch: "get_article",
+        from_json: from_json::<VeArticle>,
+        from_row: |row| query::article_from_row(row).map(Entity::Article),
+    },
+    ModelInfo {
+        model: Model::Comment,
+        name: "comment",
+        table: "comments",
+        prefix: Prefix {
+            bytes: PREFIX_COMMENT_KEY,
+            model: Model::Comment,
+            layout: key::SIMPLE,
+        },
+        fetch: "get_comment",
+        from_json: from_json::<VeComment>,
+        from_row: |row| Ok(Entity::Comment(query::comment_from_row(row))),
+    },
+];
+
+fn from_json<T: Registered + DeserializeOwned>(
+    value: serde_json::Value,
+) -> serde_json::Result<Entity> {
+    serde_json::from_value(value).map(T::into_entity)
+}
+
+/// An entity type of `vemodel`, with the model it's an entity of and what
+/// the processing of its change events needs of it.
+pub trait Registered: Sized {
+    const MODEL: Model;
+
+    fn id(&self) -> u64;
+
+    /// The subspace it's in, or is, `None` if it doesn't say.
+    fn subspace_id(&self) -> Option<u64>;
+
+    /// The author `VE_BLOCKED_AUTHORS` is checked against, only a comment's
+    /// is.
+    fn blockable_author(&self) -> Option<UserId> {
+        None
+    }
+
+    fn into_entity(self) -> Entity;
+
+    /// Fetches the one with id `id` from `nucleus`.
+    fn fetch<N: Nucleus>(nucleus: &N, id: u64) -> BoxFuture<'_, Fetched<Self>>;
+}
+
+impl Registered for VeSubspace {
+    const MODEL: Model = Model::Subspace;
+
+    fn id(&self) -> u64 {
+        self.id.0
+    }
+
+    fn subspace_id(&self) -> Option<u64> {
+        Some(self.id.0)
+    }
+
+    fn into_entity(self) -> Entity {
+        Entity::Subspace(self)
+    }
+
+    fn fetch<N: Nucleus>(nucleus: &N, id: u64) -> BoxFuture<'_, Fetched<Self>> {
+        nucleus.get_subspace(SubspaceId(id))
+    }
+}
+
+impl Registered for VeArticle {
+    const MODEL: Model = Model::Article;
+
+    fn id(&self) -> u64 {
+        self.id.0
+    }
+
+    fn subspace_id(&self) -> Option<u64> {
+        Some(self.subspace_id.0)
+    }
+
+    fn into_entity(self) -> Entity {
+        Entity::Article(self)
+    }
+
+    fn fetch<N: Nucleus>(nucleus: &N, id: u64) -> BoxFuture<'_, Fetched<Self>> {
+        nucleus.get_article(ArticleId(id))
+    }
+}
+
+impl Registered for VeComment {
+    const MODEL: Model = Model::Comment;
+
+    fn id(&self) -> u64 {
+        self.id.0
+    }
+
+    // only that of its thread's article, which isn't at hand
+    fn subspace_id(&self) -> Option<u64> {
+        None
+    }
+
+    fn blockable_author(&self) -> Option<UserId> {
+        Some(self.author_id)
+    }
+
+    fn into_entity(self) -> Entity {
+        Entity::Comment(self)
+    }
+
+    fn fetch<N: Nucleus>(nucleus: &N, id: u64) -> BoxFuture<'_, Fetched<Self>> {
+        nucleus.get_comment(CommentId(id))
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|model| model.as_str() == s)
+            .ok_or_else(|| format!("unknown model: {}", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_model_is_registered_in_order() {
+        for (info, model) in MODELS.iter().zip(Model::ALL) {
+            assert_eq!(info.model, model);
+            assert_eq!(info.prefix.model, model);
+            assert_eq!(model.as_str().parse(), Ok(model));
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use serde::Serialize;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::text;
+
+/// How author nicknames are cleaned up for display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Rules {
+    /// Longest kept nickname, in graphemes.
+    pub max_length: usize,
+    /// Whether lookalikes of latin letters are replaced by the letters they
+    /// imitate, e.g. a cyrillic `а` by `a`.
+    pub fold_homoglyphs: bool,
+}
+
+// Cyrillic and greek letters that render like a latin one in most fonts.
+// Not the whole Unicode confusables table, only the usual impersonation
+// suspects; fullwidth forms are folded separately.
+const HOMOGLYPHS: &[(char, char)] = &[
+    ('а', 'a'),
+    ('е', 'e'),
+    ('һ', 'h'),
+    ('і', 'i'),
+    ('ј', 'j'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('с', 'c'),
+    ('у', 'y'),
+    ('х', 'x'),
+    ('ѕ', 's'),
+    ('ԁ', 'd'),
+    ('ԛ', 'q'),
+    ('ԝ', 'w'),
+    ('А', 'A'),
+    ('В', 'B'),
+    ('Е', 'E'),
+    ('І', 'I'),
+    ('Ј', 'J'),
+    ('К', 'K'),
+    ('М', 'M'),
+    ('Н', 'H'),
+    ('О', 'O'),
+    ('Р', 'P'),
+    ('С', 'C'),
+    ('Ѕ', 'S'),
+    ('Т', 'T'),
+    ('Х', 'X'),
+    ('Α', 'A'),
+    ('Β', 'B'),
+    ('Ε', 'E'),
+    ('Ζ', 'Z'),
+    ('Η', 'H'),
+    ('Ι', 'I'),
+    ('Κ', 'K'),
+    ('Μ', 'M'),
+    ('Ν', 'N'),
+    ('Ο', 'O'),
+    ('Ρ', 'P'),
+    ('Τ', 'T'),
+    ('Υ', 'Y'),
+    ('Χ', 'X'),
+    ('ο', 'o'),
+    ('ν', 'v'),
+];
+
+/// Turns a raw nickname into one safe to display: control, zero-width and
+/// bidi override characters are dropped, markup is stripped as for excerpts,
+/// whitespace is collapsed and the result cut to `rules.max_length`
+/// graphemes. Can come out empty.
+///
+/// Zero-width joiners go too, which splits emoji sequences such as 👨‍👩‍👧
+/// into their parts.
+pub fn sanitize(raw: &str, rules: &Rules) -> String {
+    let visible: String = raw.chars().filter(|&c| !is_invisible(c)).collect();
+    let mut plain = text::strip_markup(&visible);
+    if rules.fold_homoglyphs {
+        plain = plain.chars().map(fold).collect();
+    }
+    plain
+        .graphemes(true)
+        .take(rules.max_length)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+fn is_invisible(c: char) -> bool {
+    // whitespace controls separate words, strip_markup collapses them to spaces
+    (c.is_control() && !c.is_whitespace())
+        || matches!(
+            c,
+            // soft hyphen, mongolian vowel separator
+            '\u{ad}' | '\u{180e}'
+            // zero-width space, non-joiner and joiner, direction marks
+            | '\u{200b}'..='\u{200f}'
+            // bidi embeddings and overrides, which can reverse how a name reads
+            | '\u{202a}'..='\u{202e}'
+            // word joiner, invisible operators, bidi isolates
+            | '\u{2060}'..='\u{2069}'
+            | '\u{feff}'
+        )
+}
+
+fn fold(c: char) -> char {
+    // fullwidth ASCII, `ａｄｍｉｎ`
+    if ('\u{ff01}'..='\u{ff5e}').contains(&c) {
+        return char::from_u32(c as u32 - 0xfee0).unwrap_or(c);
+    }
+    HOMOGLYPHS
+        .iter()
+        .find(|&&(glyph, _)| glyph == c)
+        .map_or(c, |&(_, latin)| latin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: Rules = Rules {
+        max_length: 32,
+        fold_homoglyphs: false,
+    };
+
+    const FOLDING: Rules = Rules {
+        max_length: 32,
+        fold_homoglyphs: true,
+    };
+
+    #[test]
+    fn drops_invisible_characters() {
+        assert_eq!(sanitize("ad\u{200b}min\u{feff}", &RULES), "admin");
+        assert_eq!(sanitize("bob\u{0}\u{7}\nsmith", &RULES), "bob smith");
+        // "alice" spelled backwards behind a right-to-left override
+        assert_eq!(sanitize("\u{202e}ecila", &RULES), "ecila");
+    }
+
+    #[test]
+    fn strips_markup_and_collapses_whitespace() {
+        assert_eq!(sanitize("<b>**big**</b>   name", &RULES), "big name");
+        assert_eq!(sanitize("snake_case", &RULES), "snake_case");
+    }
+
+    #[test]
+    fn caps_length_in_graphemes() {
+        let rules = Rules {
+            max_length: 3,
+            ..RULES
+        };
+        assert_eq!(sanitize("🇯🇵🇯🇵🇯🇵🇯🇵", &rules), "🇯🇵🇯🇵🇯🇵");
+        assert_eq!(sanitize("ab cd", &rules), "ab");
+    }
+
+    #[test]
+    fn folds_homoglyphs_only_when_asked() {
+        // cyrillic а and о
+        let lookalike = "аdmin_bоb";
+        assert_eq!(sanitize(lookalike, &RULES), lookalike);
+        assert_eq!(sanitize(lookalike, &FOLDING), "admin_bob");
+        assert_eq!(sanitize("ＡＤＭＩＮ", &FOLDING), "ADMIN");
+    }
+
+    #[test]
+    fn can_come_out_empty() {
+        assert_eq!(sanitize("\u{200b}\u{200d}", &RULES), "");
+    }
+}
@@ -0,0 +1,218 @@
+use jsonrpsee::rpc_params;
+use parity_scale_codec::{Decode, Encode};
+use std::fmt;
+use tracing::{info_span, Instrument};
+
+use vemodel::{ArticleId, CommentId, SubspaceId, VeArticle, VeComment, VeSubspace};
+
+use crate::account::AvsId;
+use crate::connection::Connection;
+use crate::model::Registered;
+use crate::rpc::{self, ChangeEvent, EventBatch, EventCodec, ResponseError};
+use crate::sink::BoxFuture;
+
+/// A call to the nucleus that failed, either on the way or in decoding.
+#[derive(Debug)]
+pub enum NucleusError {
+    Rpc(jsonrpsee::core::Error),
+    Response(ResponseError),
+}
+
+impl NucleusError {
+    /// Whether the nucleus answered with something this build can't decode,
+    /// rather than failing the call.
+    pub fn is_decode(&self) -> bool {
+        matches!(
+            self,
+            Self::Response(ResponseError::Decode { .. } | ResponseError::Json(_))
+        )
+    }
+
+    /// The raw bytes of a response that failed to decode.
+    pub fn raw(&self) -> Option<&[u8]> {
+        match self {
+            Self::Rpc(_) => None,
+            Self::Response(e) => e.raw(),
+        }
+    }
+}
+
+impl fmt::Display for NucleusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rpc(e) => write!(f, "nucleus call failed: {}", e),
+            Self::Response(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for NucleusError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Rpc(e) => Some(e),
+            Self::Response(e) => Some(e),
+        }
+    }
+}
+
+/// How the polling loop takes an error the AVS answered a poll with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AvsErrorClass {
+    /// Logged, and polled again next cycle.
+    Transient,
+    /// Stops the surrogate, for errors no amount of polling again will fix.
+    Fatal,
+}
+
+/// Classifies an error the AVS answered a poll with, fatal if it contains
+/// any of `fatal_patterns`, from `VE_FATAL_NUCLEUS_ERRORS`.
+pub fn classify(error: &str, fatal_patterns: &[String]) -> AvsErrorClass {
+    if fatal_patterns
+        .iter()
+        .any(|pattern| error.contains(pattern.as_str()))
+    {
+        AvsErrorClass::Fatal
+    } else {
+        AvsErrorClass::Transient
+    }
+}
+
+/// What the nucleus answers a fetch with: the entity if there is one, or the
+/// error the AVS itself returned.
+pub type Fetched<T> = Result<Result<Option<T>, String>, NucleusError>;
+
+/// The calls the surrogate makes to the nucleus, so the polling loop can be
+/// run against a scripted one.
+pub trait Nucleus: Send + Sync {
+    /// The change events after `sentinel`, which the nucleus forgets from
+    /// then on. Possibly only the first page of them, see
+    /// `Config::event_page_size`.
+    fn get_from_common_key(
+        &self,
+        sentinel: u64,
+    ) -> BoxFuture<'_, Result<Result<EventBatch, String>, NucleusError>>;
+
+    /// The reqnum of the latest change the nucleus has recorded, 0 when
+    /// there's none yet. Nuclei older than `get_head_reqnum` fail this.
+    fn head_reqnum(&self) -> BoxFuture<'_, Result<Result<u64, String>, NucleusError>>;
+
+    fn get_subspace(&self, id: SubspaceId) -> BoxFuture<'_, Fetched<VeSubspace>>;
+
+    fn get_article(&self, id: ArticleId) -> BoxFuture<'_, Fetched<VeArticle>>;
+
+    fn get_comment(&self, id: CommentId) -> BoxFuture<'_, Fetched<VeComment>>;
+
+    /// Told of every batch of change events before they're processed, for
+    /// nuclei that cache to drop what the events change.
+    fn observe(&self, _events: &[ChangeEvent]) {}
+}
+
+/// The nucleus over JSON-RPC.
+pub struct RpcNucleus {
+    client: Connection,
+    avs_id: AvsId,
+    page_size: Option<u32>,
+    event_codec: EventCodec,
+}
+
+impl RpcNucleus {
+    /// Asks for at most `page_size` change events at a time, given one, and
+    /// reads them as `event_codec` has them.
+    pub fn new(
+        client: Connection,
+        avs_id: AvsId,
+        page_size: Option<u32>,
+        event_codec: EventCodec,
+    ) -> Self {
+        Self {
+            client,
+            avs_id,
+            page_size,
+            event_codec,
+        }
+    }
+
+    async fn get<T: Decode>(
+        &self,
+        method: &str,
+        args: impl Encode,
+    ) -> Result<Result<T, String>, NucleusError> {
+        let args = hex::encode(args.encode());
+        let params = || rpc_params![self.avs_id.as_str(), method, &args];
+        let res: serde_json::Value = self
+            .client
+            .request("nucleus_get", params)
+            .instrument(info_span!("fetch", method))
+            .await
+            .map_err(NucleusError::Rpc)?;
+        info_span!("decode")
+            .in_scope(|| rpc::decode_response(&res))
+            .map_err(NucleusError::Response)
+    }
+
+    // The entity `id` of `T`, by the method its model is fetched with.
+    async fn fetch<T: Decode + Registered>(&self, id: impl Encode) -> Fetched<T> {
+        self.get(T::MODEL.info().fetch, id).await
+    }
+}
+
+impl Nucleus for RpcNucleus {
+    fn get_from_common_key(
+        &self,
+        sentinel: u64,
+    ) -> BoxFuture<'_, Result<Result<EventBatch, String>, NucleusError>> {
+        Box::pin(async move {
+            let (method, args) = match self.page_size {
+                Some(limit) => ("get_page_from_common_key", (sentinel, limit).encode()),
+                None => ("get_from_common_key", sentinel.encode()),
+            };
+            let args = hex::encode(args);
+            let params = || rpc_params![self.avs_id.as_str(), method, &args];
+            let res: serde_json::Value = self
+                .client
+                .request("nucleus_post", params)
+                .await
+                .map_err(NucleusError::Rpc)?;
+            match self.event_codec {
+                EventCodec::Scale => rpc::decode_events(&res),
+                EventCodec::Json => rpc::decode_json_events(&res),
+            }
+            .map_err(NucleusError::Response)
+        })
+    }
+
+    fn head_reqnum(&self) -> BoxFuture<'_, Result<Result<u64, String>, NucleusError>> {
+        Box::pin(self.get("get_head_reqnum", ()))
+    }
+
+    fn get_subspace(&self, id: SubspaceId) -> BoxFuture<'_, Fetched<VeSubspace>> {
+        Box::pin(self.fetch::<VeSubspace>(id))
+    }
+
+    fn get_article(&self, id: ArticleId) -> BoxFuture<'_, Fetched<VeArticle>> {
+        Box::pin(self.fetch::<VeArticle>(id))
+    }
+
+    fn get_comment(&self, id: CommentId) -> BoxFuture<'_, Fetched<VeComment>> {
+        Box::pin(self.fetch::<VeComment>(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn avs_errors_are_fatal_only_when_they_match() {
+        let patterns = ["unknown method".to_string(), "permission".to_string()];
+        assert_eq!(
+            classify("unknown method get_page_from_common_key", &patterns),
+            AvsErrorClass::Fatal
+        );
+        assert_eq!(
+            classify("storage busy, try again", &patterns),
+            AvsErrorClass::Transient
+        );
+        assert_eq!(classify("unknown method", &[]), AvsErrorClass::Transient);
+    }
+}
@@ -0,0 +1,122 @@
+use opentelemetry::metrics::{Meter, MeterProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::metrics::MeterProvider;
+use opentelemetry_sdk::trace::{self, Tracer};
+use opentelemetry_sdk::{runtime, Resource};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::build_info;
+use crate::metrics;
+
+// how often metrics are pushed to the collector without VE_OTLP_METRICS_INTERVAL_SECS
+const DEFAULT_METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+// kept so `shutdown` can push what's left
+static METER_PROVIDER: OnceLock<MeterProvider> = OnceLock::new();
+
+/// Exports spans and metrics over OTLP/gRPC to the collector at
+/// `VE_OTLP_ENDPOINT`, e.g. `http://collector:4317`, `None` if it's unset.
+///
+/// Spans are those of the log, a trace per poll cycle: `poll`, `event` with
+/// the correlation id, and `fetch` with the RPC method and `decode` under
+/// it. Database writes are `apply` spans of the writer's own, with the
+/// correlation id again to find them by. The
+/// counters and gauges of [`metrics`] are pushed every
+/// `VE_OTLP_METRICS_INTERVAL_SECS`, 60 by default, under the names they
+/// have there. Histograms aren't, OTel has no instrument to observe a
+/// distribution with after the fact.
+pub fn layer<S>() -> Result<Option<OpenTelemetryLayer<S, Tracer>>, String>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let Ok(endpoint) = std::env::var("VE_OTLP_ENDPOINT") else {
+        return Ok(None);
+    };
+    let interval = match std::env::var("VE_OTLP_METRICS_INTERVAL_SECS") {
+        Ok(raw) => Duration::from_secs(
+            raw.parse()
+                .map_err(|e| format!("invalid value for VE_OTLP_METRICS_INTERVAL_SECS: {}", e))?,
+        ),
+        Err(_) => DEFAULT_METRICS_INTERVAL,
+    };
+    let failed = |e: &dyn std::fmt::Display| format!("failed to export to {}: {}", endpoint, e);
+    let resource = Resource::new([
+        KeyValue::new("service.name", "surrogate"),
+        KeyValue::new("service.version", build_info::GIT_DESCRIBE),
+    ]);
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint.clone()),
+        )
+        .with_trace_config(trace::config().with_resource(resource.clone()))
+        .install_batch(runtime::Tokio)
+        .map_err(|e| failed(&e))?;
+    let provider = opentelemetry_otlp::new_pipeline()
+        .metrics(runtime::Tokio)
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint.clone()),
+        )
+        .with_resource(resource)
+        .with_period(interval)
+        .build()
+        .map_err(|e| failed(&e))?;
+    observe(&provider.meter("surrogate"));
+    let _ = METER_PROVIDER.set(provider);
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+// Registers every counter and gauge to be read at each export.
+fn observe(meter: &Meter) {
+    for counter in metrics::COUNTERS {
+        meter
+            .u64_observable_counter(counter.name)
+            .with_description(counter.help)
+            .with_callback(move |observer| observer.observe(counter.get(), &[]))
+            .init();
+    }
+    for gauge in metrics::GAUGES {
+        meter
+            .u64_observable_gauge(gauge.name)
+            .with_description(gauge.help)
+            .with_callback(move |observer| observer.observe(gauge.get(), &[]))
+            .init();
+    }
+    let largest = &metrics::RPC_LARGEST_RESPONSE;
+    meter
+        .u64_observable_gauge(largest.name)
+        .with_description(largest.help)
+        .with_callback(move |observer| observer.observe(largest.get(), &[]))
+        .init();
+    let info = &metrics::BUILD_INFO;
+    let labels: Vec<_> = info
+        .labels
+        .iter()
+        .map(|&(key, value)| KeyValue::new(key, value))
+        .collect();
+    meter
+        .u64_observable_gauge(info.name)
+        .with_description(info.help)
+        .with_callback(move |observer| observer.observe(1, &labels))
+        .init();
+}
+
+/// Pushes the spans and metrics not exported yet, for before exiting.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+    if let Some(provider) = METER_PROVIDER.get() {
+        // exiting anyway, nothing to do about a collector that's gone
+        let _ = provider.shutdown();
+    }
+}
@@ -0,0 +1,224 @@
+use std::time::Duration;
+use tokio_postgres::GenericClient;
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::db;
+
+/// Tables range-partitioned by `created_time`, one partition per month.
+///
+/// A partitioned table's primary key has to include the partition key, so
+/// these are keyed by `(id, created_time)` rather than `id` once
+/// partitioned. Rows whose month has no partition land in a `_default`
+/// partition, which the maintenance task keeps empty by creating partitions
+/// ahead of time.
+///
+/// Foreign keys don't survive the conversion: `comments.post_id` can't
+/// reference an `articles` keyed by more than `id`, and the one of
+/// `articles.subspace_id` isn't recreated either. The conversion logs each
+/// one it drops.
+pub const TABLES: &[&str] = &["articles", "comments"];
+
+// Index, table, columns of the secondary indexes of the partitioned tables,
+// recreated on the partitioned copy. Kept in step with the migrations.
+const INDEXES: &[(&str, &str, &str)] = &[
+    (
+        "articles_created_time_id",
+        "articles",
+        "created_time DESC, id DESC",
+    ),
+    ("articles_subspace_id", "articles", "subspace_id"),
+];
+
+// how often the maintenance task checks for partitions to create
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Whether `table` is partitioned already.
+async fn is_partitioned<C: GenericClient>(
+    client: &C,
+    table: &str,
+) -> Result<bool, tokio_postgres::Error> {
+    let row = client
+        .query_opt(
+            "SELECT c.relkind::TEXT FROM pg_class c
+             JOIN pg_namespace n ON n.oid = c.relnamespace
+             WHERE c.relname = $1 AND n.nspname = current_schema()",
+            &[&table],
+        )
+        .await?;
+    Ok(row.is_some_and(|row| row.get::<_, String>(0) == "p"))
+}
+
+/// Brings the partitioned tables in line with the config: converts them when
+/// partitioning is on and refuses to start when it's been turned off on a
+/// database that's partitioned already, since there's no way back short of
+/// copying the data out by hand.
+pub async fn setup<C: GenericClient>(
+    client: &mut C,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for &table in TABLES {
+        match (is_partitioned(client, table).await?, config.partitioning) {
+            (false, true) => convert(client, table, config.partition_months_ahead).await?,
+            (true, true) => ensure_partitions(client, table, config.partition_months_ahead).await?,
+            (true, false) => {
+                return Err(format!(
+                    "{} is partitioned, set VE_PARTITION_BY_CREATED_TIME=true",
+                    table
+                )
+                .into())
+            }
+            (false, false) => {}
+        }
+    }
+    Ok(())
+}
+
+// Swaps `table` for a partitioned copy holding the same rows, in one transaction.
+async fn convert<C: GenericClient>(
+    client: &mut C,
+    table: &str,
+    months_ahead: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(
+        "Partitioning {} by created_time, this copies every row",
+        table
+    );
+    let tx = client.transaction().await?;
+    tx.batch_execute(&format!(
+        "
+        ALTER TABLE {table} RENAME TO {table}_unpartitioned;
+        ALTER TABLE {table}_unpartitioned RENAME CONSTRAINT {table}_pkey TO {table}_unpartitioned_pkey;
+        CREATE TABLE {table} (LIKE {table}_unpartitioned INCLUDING DEFAULTS INCLUDING CONSTRAINTS)
+            PARTITION BY RANGE (created_time);
+        ALTER TABLE {table} ADD CONSTRAINT {table}_pkey PRIMARY KEY (id, created_time);
+        CREATE TABLE {table}_default PARTITION OF {table} DEFAULT;
+        ",
+        table = table
+    ))
+    .await?;
+    // the indexes of the migrations, which the copy doesn't take along
+    for (index, index_table, columns) in INDEXES {
+        if *index_table == table {
+            tx.batch_execute(&format!(
+                "
+                ALTER INDEX IF EXISTS {index} RENAME TO {index}_unpartitioned;
+                CREATE INDEX {index} ON {table} ({columns});
+                ",
+                index = index,
+                table = table,
+                columns = columns
+            ))
+            .await?;
+        }
+    }
+
+    // partitions for the months the existing rows span, so they don't pile up in the default one
+    let since: Option<i64> = tx
+        .query_one(
+            &format!("SELECT MIN(created_time) FROM {}_unpartitioned", table),
+            &[],
+        )
+        .await?
+        .get(0);
+    create_partitions(&tx, table, since, months_ahead).await?;
+
+    // the foreign keys on the old table and those referencing it, which go with it
+    let dropped = tx
+        .query(
+            "SELECT conname::TEXT, conrelid::regclass::TEXT FROM pg_constraint
+             WHERE contype = 'f' AND (conrelid = $1::TEXT::regclass OR confrelid = $1::TEXT::regclass)
+             ORDER BY conname",
+            &[&format!("{}_unpartitioned", table)],
+        )
+        .await?;
+    for row in &dropped {
+        let (constraint, on): (String, String) = (row.get(0), row.get(1));
+        warn!(
+            "Partitioning {} drops the foreign key {} on {}",
+            table, constraint, on
+        );
+    }
+
+    tx.batch_execute(&format!(
+        "
+        INSERT INTO {table} SELECT * FROM {table}_unpartitioned;
+        DROP TABLE {table}_unpartitioned CASCADE;
+        ",
+        table = table
+    ))
+    .await?;
+    tx.commit().await?;
+    info!("Partitioned {}", table);
+    Ok(())
+}
+
+async fn ensure_partitions<C: GenericClient>(
+    client: &C,
+    table: &str,
+    months_ahead: u32,
+) -> Result<(), tokio_postgres::Error> {
+    create_partitions(client, table, None, months_ahead).await
+}
+
+// Creates the monthly partitions from the month of `since` (the current one
+// when `None`) through `months_ahead` months from now, skipping existing ones.
+async fn create_partitions<C: GenericClient>(
+    client: &C,
+    table: &str,
+    since: Option<i64>,
+    months_ahead: u32,
+) -> Result<(), tokio_postgres::Error> {
+    // months are UTC calendar months of `created_time`, in unix seconds
+    let months = client
+        .query(
+            "SELECT to_char(m, 'YYYYMM'),
+                    EXTRACT(EPOCH FROM m AT TIME ZONE 'UTC')::BIGINT,
+                    EXTRACT(EPOCH FROM (m + INTERVAL '1 month') AT TIME ZONE 'UTC')::BIGINT
+             FROM generate_series(
+                date_trunc('month', to_timestamp(LEAST(COALESCE($1, EXTRACT(EPOCH FROM now())::BIGINT),
+                                                       EXTRACT(EPOCH FROM now())::BIGINT)) AT TIME ZONE 'UTC'),
+                date_trunc('month', now() AT TIME ZONE 'UTC') + make_interval(months => $2),
+                INTERVAL '1 month'
+             ) AS m",
+            &[&since, &(months_ahead as i32)],
+        )
+        .await?;
+
+    for month in months {
+        let (suffix, from, to): (String, i64, i64) = (month.get(0), month.get(1), month.get(2));
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {table}_p{suffix} PARTITION OF {table}
+                 FOR VALUES FROM ({from}) TO ({to})",
+                table = table,
+                suffix = suffix,
+                from = from,
+                to = to
+            ))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Keeps `months_ahead` months of partitions ready for as long as the
+/// surrogate runs. Uses a connection of its own, the writer's sits in an open
+/// transaction most of the time.
+pub async fn run_maintenance(config: Config) {
+    let client = match db::connect(&config).await {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Partition maintenance can't connect, not running: {}", e);
+            return;
+        }
+    };
+    loop {
+        tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+        for &table in TABLES {
+            // a row in the default partition for the new month makes this fail until it's moved out
+            if let Err(e) = ensure_partitions(&client, table, config.partition_months_ahead).await {
+                error!("Failed to create partitions of {}: {}", table, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use crate::metrics;
+
+/// How long the polling loop waits for the nucleus between cycles that
+/// leave nothing over, between `VE_POLL_INTERVAL_MIN_MS` and
+/// `VE_POLL_INTERVAL_MAX_MS`.
+///
+/// A cycle that applied something halves it, more is likely on the way, and
+/// one that found nothing doubles it, so a quiet nucleus is asked less and
+/// less often and a busy one is kept up with. It's exported as
+/// `surrogate_poll_interval_milliseconds`.
+#[derive(Debug)]
+pub struct PollInterval {
+    current: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl PollInterval {
+    /// Starts at `max`, a surrogate starting up may well be caught up.
+    pub fn new(min: Duration, max: Duration) -> Self {
+        // doubled, 0 would stay 0
+        let max = max.max(Duration::from_millis(1));
+        let interval = Self {
+            current: max,
+            min: min.clamp(Duration::from_millis(1), max),
+            max,
+        };
+        interval.export();
+        interval
+    }
+
+    pub fn current(&self) -> Duration {
+        self.current
+    }
+
+    /// Adjusts the interval to a cycle that `applied` something or didn't.
+    pub fn observe(&mut self, applied: bool) {
+        self.current = if applied {
+            (self.current / 2).max(self.min)
+        } else {
+            (self.current * 2).min(self.max)
+        };
+        self.export();
+    }
+
+    fn export(&self) {
+        metrics::POLL_INTERVAL.set(self.current.as_millis() as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shrinks_while_busy_and_grows_while_quiet() {
+        let ms = Duration::from_millis;
+        let mut interval = PollInterval::new(ms(500), ms(4000));
+        assert_eq!(interval.current(), ms(4000));
+
+        for expected in [2000, 1000, 500, 500] {
+            interval.observe(true);
+            assert_eq!(interval.current(), ms(expected));
+        }
+        for expected in [1000, 2000, 4000, 4000] {
+            interval.observe(false);
+            assert_eq!(interval.current(), ms(expected));
+        }
+        // a minimum past the maximum doesn't go over it
+        let mut fixed = PollInterval::new(ms(9000), ms(5000));
+        fixed.observe(true);
+        assert_eq!(fixed.current(), ms(5000));
+        let mut eager = PollInterval::new(ms(0), ms(2));
+        eager.observe(true);
+        eager.observe(true);
+        eager.observe(false);
+        assert_eq!(eager.current(), ms(2));
+    }
+}
@@ -0,0 +1,147 @@
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+
+use crate::model::Model;
+
+/// The fields each model's entity has, as it serializes.
+pub fn fields(model: Model) -> &'static [&'static str] {
+    match model {
+        Model::Subspace => &[
+            "id",
+            "title",
+            "slug",
+            "description",
+            "banner",
+            "status",
+            "weight",
+            "created_time",
+        ],
+        Model::Article => &[
+            "id",
+            "title",
+            "content",
+            "author_id",
+            "author_nickname",
+            "subspace_id",
+            "ext_link",
+            "status",
+            "weight",
+            "created_time",
+            "updated_time",
+        ],
+        Model::Comment => &[
+            "id",
+            "content",
+            "author_id",
+            "author_nickname",
+            "post_id",
+            "status",
+            "weight",
+            "created_time",
+        ],
+    }
+}
+
+/// A `<model>.<field>` item of a sink's field list, e.g. `article.title`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field(pub Model, pub &'static str);
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (model, field) = s
+            .split_once('.')
+            .ok_or_else(|| format!("expected <model>.<field>, got {}", s))?;
+        let model: Model = model.parse()?;
+        fields(model)
+            .iter()
+            .find(|&&known| known == field)
+            .map(|&field| Self(model, field))
+            .ok_or_else(|| format!("{} has no field {}", model.as_str(), field))
+    }
+}
+
+/// Which fields of the entities a sink emits, from its field list, e.g.
+/// `VE_CHANGE_LOG_FIELDS`. Models the list doesn't mention keep all of
+/// theirs, so the empty list, the default, leaves every entity whole.
+///
+/// `id` is always kept, a consumer couldn't tell changes apart without it.
+/// Deletes, carrying only the id, aren't touched.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Projection {
+    fields: HashMap<Model, BTreeSet<&'static str>>,
+}
+
+impl Projection {
+    /// Drops the fields of the `model` entity `value` the sink doesn't emit.
+    pub fn apply(&self, model: Model, value: &mut serde_json::Value) {
+        let (Some(kept), Some(object)) = (self.fields.get(&model), value.as_object_mut()) else {
+            return;
+        };
+        object.retain(|field, _| field == "id" || kept.contains(field.as_str()));
+    }
+}
+
+impl FromIterator<Field> for Projection {
+    fn from_iter<I: IntoIterator<Item = Field>>(fields: I) -> Self {
+        let mut projection = Self::default();
+        for Field(model, field) in fields {
+            projection.fields.entry(model).or_default().insert(field);
+        }
+        projection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{fixture, Entity};
+
+    #[test]
+    fn fields_are_those_the_entities_serialize() {
+        let entities = [
+            Entity::Subspace(fixture::subspace(1)),
+            Entity::Article(fixture::article(2)),
+            Entity::Comment(fixture::comment(4)),
+        ];
+        for entity in entities {
+            let json = entity.to_json().unwrap();
+            let serialized: BTreeSet<&str> = json
+                .as_object()
+                .unwrap()
+                .keys()
+                .map(String::as_str)
+                .collect();
+            let known: BTreeSet<&str> = fields(entity.model()).iter().copied().collect();
+            assert_eq!(serialized, known);
+        }
+    }
+
+    #[test]
+    fn projects_only_the_models_listed() {
+        let projection: Projection = ["article.title", "article.content"]
+            .into_iter()
+            .map(|field| field.parse::<Field>().unwrap())
+            .collect();
+        let mut article = serde_json::json!({"id": 2, "title": "t", "content": "c", "weight": 1});
+        projection.apply(Model::Article, &mut article);
+        assert_eq!(
+            article,
+            serde_json::json!({"id": 2, "title": "t", "content": "c"})
+        );
+
+        let mut comment = serde_json::json!({"id": 4, "content": "c", "weight": 1});
+        projection.apply(Model::Comment, &mut comment);
+        assert_eq!(comment["weight"], 1);
+        let mut deleted = serde_json::json!(2);
+        projection.apply(Model::Article, &mut deleted);
+        assert_eq!(deleted, 2);
+
+        assert!("article.weight".parse::<Field>().is_ok());
+        assert!("article.wieght".parse::<Field>().is_err());
+        assert!("user.title".parse::<Field>().is_err());
+        assert!("title".parse::<Field>().is_err());
+    }
+}
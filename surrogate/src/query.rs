@@ -0,0 +1,431 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tokio_postgres::{Client, Row};
+
+use vemodel::{ArticleId, CommentId, SubspaceId, UserId, VeArticle, VeComment, VeSubspace, Weight};
+
+use crate::config::Config;
+use crate::db::quote_ident;
+use crate::id::SqlId;
+use crate::model::Model;
+use crate::{content, etag};
+
+/// Creates the views every read path goes through, so visibility rules are
+/// applied the same way everywhere:
+/// - `visible_subspaces` drops subspaces whose `status` is one of the
+///   configured hidden statuses (none by default),
+/// - `visible_articles` drops articles under a hidden subspace,
+/// - `visible_comments` drops comments on an article that isn't visible, and
+///   those of moderated subspaces that aren't approved.
+///
+/// When subspaces (articles) aren't indexed, there's nothing to tell visible
+/// from hidden by and all articles (comments) are visible.
+///
+/// Comments of the subspaces in `VE_MODERATED_SUBSPACES` are approved or
+/// rejected by their `status`, see `VE_APPROVED_COMMENT_STATUSES` and
+/// `VE_REJECTED_COMMENT_STATUSES`, and pending approval otherwise. Pending
+/// ones are listed in `moderation_queue`, along with their subspace.
+///
+/// Replies to comments, see `VE_COMMENT_REPLIES`, aren't in the views yet,
+/// only in `comments` with a `target_type` of `comment`.
+///
+/// Deleted rows are removed outright, there's nothing soft-deleted to filter
+/// out yet. Consumers querying the database directly should use these views
+/// rather than the tables.
+pub async fn create_views<C: tokio_postgres::GenericClient>(
+    client: &C,
+    config: &Config,
+) -> Result<(), tokio_postgres::Error> {
+    // views can't take parameters, ids and statuses are plain integers so inlining them is safe
+    let hidden = inline_list(&config.hidden_subspace_statuses);
+    let articles = if config.indexes(Model::Subspace) {
+        "SELECT a.* FROM articles a JOIN visible_subspaces s ON s.id = a.subspace_id"
+    } else {
+        "SELECT * FROM articles"
+    };
+    let moderated = inline_list(&config.moderated_subspaces);
+    let approved = inline_list(&config.approved_comment_statuses);
+    let rejected = inline_list(&config.rejected_comment_statuses);
+    let comments = if config.indexes(Model::Article) {
+        format!(
+            "SELECT c.* FROM comments c
+                JOIN visible_articles a ON a.id = c.post_id AND c.target_type = 'article'
+             WHERE NOT (a.subspace_id = ANY(ARRAY[{}]::{}[]))
+                OR c.status = ANY(ARRAY[{}]::SMALLINT[])",
+            moderated,
+            config.id_column_type.as_sql(),
+            approved
+        )
+    } else {
+        "SELECT * FROM comments".to_string()
+    };
+    client
+        .batch_execute(&format!(
+            "
+            CREATE OR REPLACE VIEW visible_subspaces AS
+                SELECT * FROM subspaces
+                WHERE NOT (status = ANY(ARRAY[{}]::SMALLINT[]));
+
+            CREATE OR REPLACE VIEW visible_articles AS {};
+
+            CREATE OR REPLACE VIEW visible_comments AS {};
+
+            CREATE OR REPLACE VIEW moderation_queue AS
+                SELECT c.*, a.subspace_id FROM comments c
+                    JOIN articles a ON a.id = c.post_id AND c.target_type = 'article'
+                WHERE a.subspace_id = ANY(ARRAY[{}]::{}[])
+                  AND NOT (c.status = ANY(ARRAY[{}]::SMALLINT[] || ARRAY[{}]::SMALLINT[]));
+            ",
+            hidden,
+            articles,
+            comments,
+            moderated,
+            config.id_column_type.as_sql(),
+            approved,
+            rejected
+        ))
+        .await
+}
+
+/// `items` as the elements of an SQL array literal, for lists of plain
+/// integers only.
+pub fn inline_list<T: ToString>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(T::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A `<subspace>[:<role>]` item of `VE_SUBSPACE_VIEWS`, a subspace with
+/// views of its own, which `role` is granted reads of when there's one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SubspaceView {
+    pub subspace: u64,
+    pub role: Option<String>,
+}
+
+impl FromStr for SubspaceView {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (subspace, role) = match s.split_once(':') {
+            Some((_, "")) => return Err(format!("no role after the subspace in {}", s)),
+            Some((subspace, role)) => (subspace, Some(role.to_string())),
+            None => (s, None),
+        };
+        Ok(Self {
+            subspace: subspace
+                .parse()
+                .map_err(|e| format!("{}: {}", subspace, e))?,
+            role,
+        })
+    }
+}
+
+/// Creates `subspace_<id>_articles` and `subspace_<id>_comments` for every
+/// subspace of `VE_SUBSPACE_VIEWS`, its articles and their comments as
+/// `visible_articles` and `visible_comments` have them, and grants reads of
+/// them to its role. They're read through `articles_subspace_id`, without
+/// scanning the shared tables, and are security barriers: a role granted
+/// only them sees nothing of other subspaces, whatever it queries them with.
+///
+/// Like the other views they're recreated on every start, grants included,
+/// so grants to them are made through `VE_SUBSPACE_VIEWS` and not by hand.
+pub async fn create_subspace_views<C: tokio_postgres::GenericClient>(
+    client: &C,
+    config: &Config,
+) -> Result<(), tokio_postgres::Error> {
+    for view in &config.subspace_views {
+        let id = view.subspace;
+        let mut sql = format!(
+            "
+            CREATE VIEW subspace_{id}_articles WITH (security_barrier) AS
+                SELECT * FROM visible_articles WHERE subspace_id = {id};
+
+            CREATE VIEW subspace_{id}_comments WITH (security_barrier) AS
+                SELECT c.* FROM visible_comments c
+                    JOIN articles a ON a.id = c.post_id AND c.target_type = 'article'
+                WHERE a.subspace_id = {id};
+            "
+        );
+        if let Some(role) = &view.role {
+            sql.push_str(&format!(
+                "GRANT SELECT ON subspace_{id}_articles, subspace_{id}_comments TO {};",
+                quote_ident(role)
+            ));
+        }
+        client.batch_execute(&sql).await?;
+    }
+    Ok(())
+}
+
+/// Drops the views, which would otherwise block migrations altering the tables below them.
+pub async fn drop_views<C: tokio_postgres::GenericClient>(
+    client: &C,
+) -> Result<(), tokio_postgres::Error> {
+    // those of subspaces since taken out of `VE_SUBSPACE_VIEWS` too
+    let subspace_views = client
+        .query(
+            "SELECT table_name FROM information_schema.views
+             WHERE table_schema = current_schema()
+               AND table_name ~ '^subspace_[0-9]+_(articles|comments)$'",
+            &[],
+        )
+        .await?;
+    for row in &subspace_views {
+        let view: &str = row.get(0);
+        client
+            .batch_execute(&format!("DROP VIEW IF EXISTS {}", view))
+            .await?;
+    }
+    client
+        .batch_execute(
+            "DROP VIEW IF EXISTS moderation_queue, visible_comments, visible_articles, visible_subspaces",
+        )
+        .await
+}
+
+/// Lists the visible subspaces, heaviest first.
+pub async fn list_subspaces(client: &Client) -> Result<Vec<VeSubspace>, tokio_postgres::Error> {
+    let rows = client
+        .query(
+            "SELECT * FROM visible_subspaces ORDER BY weight DESC, id",
+            &[],
+        )
+        .await?;
+    Ok(rows.iter().map(subspace_from_row).collect())
+}
+
+/// Lists the newest visible articles, optionally only those in one subspace.
+///
+/// Articles are listed by `created_time` and then `id`, both descending, as
+/// every read helper listing articles must: `created_time` is in seconds and
+/// ties are common, ordering by it alone lists them differently from one
+/// query to the next. The `articles_created_time_id` index has that order.
+pub async fn list_articles(
+    client: &Client,
+    subspace_id: Option<SubspaceId>,
+    limit: i64,
+) -> Result<Vec<VeArticle>, Box<dyn std::error::Error>> {
+    articles_before(client, subspace_id, None, limit).await
+}
+
+/// Where a page of articles ended, the position of its last article in the
+/// order of [`list_articles`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub created_time: i64,
+    pub id: ArticleId,
+}
+
+impl Cursor {
+    /// The cursor to pass for the page following that ending with `article`.
+    pub fn after(article: &VeArticle) -> Self {
+        Self {
+            created_time: article.created_time,
+            id: article.id,
+        }
+    }
+}
+
+/// Lists the visible articles older than `cursor` in the order of
+/// [`list_articles`], the newest ones without a cursor.
+///
+/// Pages don't overlap or skip articles however many share a
+/// `created_time`, and unlike with an offset, articles created after the
+/// first page was read don't shift the later pages.
+pub async fn articles_before(
+    client: &Client,
+    subspace_id: Option<SubspaceId>,
+    cursor: Option<Cursor>,
+    limit: i64,
+) -> Result<Vec<VeArticle>, Box<dyn std::error::Error>> {
+    let subspace_id = subspace_id.map(|id| SqlId(id.0));
+    let (created_time, id) = match cursor {
+        Some(cursor) => (Some(cursor.created_time), Some(SqlId(cursor.id.0))),
+        None => (None, None),
+    };
+    // the ids compared first, for Postgres to type them as the columns are
+    let rows = client
+        .query(
+            "SELECT * FROM visible_articles
+             WHERE (subspace_id = $1 OR $1 IS NULL)
+               AND ($2::BIGINT IS NULL OR (created_time, id) < ($2, $3))
+             ORDER BY created_time DESC, id DESC
+             LIMIT $4",
+            &[&subspace_id, &created_time, &id, &limit],
+        )
+        .await?;
+    rows.iter().map(article_from_row).collect()
+}
+
+/// Lists the visible comments on an article, oldest first.
+pub async fn list_comments(
+    client: &Client,
+    post_id: ArticleId,
+) -> Result<Vec<VeComment>, tokio_postgres::Error> {
+    let rows = comment_rows(client, post_id).await?;
+    Ok(rows.iter().map(comment_from_row).collect())
+}
+
+async fn comment_rows(
+    client: &Client,
+    post_id: ArticleId,
+) -> Result<Vec<Row>, tokio_postgres::Error> {
+    client
+        .query(
+            "SELECT * FROM visible_comments WHERE post_id = $1 ORDER BY created_time, id",
+            &[&SqlId(post_id.0)],
+        )
+        .await
+}
+
+/// The author of an article or comment. `users` only keeps counts, so this is
+/// what the content rows themselves carry about their author.
+///
+/// The nickname is the sanitized one, like everywhere on the read paths. The
+/// raw one is left in the tables' `author_nickname` for moderation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Author {
+    pub id: UserId,
+    pub nickname: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PageComment {
+    pub comment: VeComment,
+    pub author: Author,
+}
+
+/// Everything a frontend needs to render an article.
+#[derive(Debug, Clone, Serialize)]
+pub struct ArticlePage {
+    pub article: VeArticle,
+    pub author: Author,
+    /// Oldest first. Comments aren't threaded, they all reply to the article.
+    pub comments: Vec<PageComment>,
+    /// Changes whenever the article or any of its comments do, or a comment
+    /// comes or goes. `None` while any of them was written before ETags were
+    /// stored, see [`etag::not_modified`] for answering `If-None-Match`.
+    pub etag: Option<String>,
+}
+
+/// Builds the page of a visible article, `None` if there's no such article or
+/// it's hidden. Takes two queries however many comments there are.
+pub async fn get_article_page(
+    client: &Client,
+    id: ArticleId,
+) -> Result<Option<ArticlePage>, Box<dyn std::error::Error>> {
+    let Some(row) = client
+        .query_opt(
+            "SELECT * FROM visible_articles WHERE id = $1",
+            &[&SqlId(id.0)],
+        )
+        .await?
+    else {
+        return Ok(None);
+    };
+    let article = article_from_row(&row)?;
+    let comment_rows = comment_rows(client, id).await?;
+    let etag = etag::combine(
+        std::iter::once(&row)
+            .chain(&comment_rows)
+            .map(|row| row.get::<_, Option<&str>>("etag")),
+    );
+    let comments = comment_rows
+        .iter()
+        .map(comment_from_row)
+        .map(|comment| PageComment {
+            author: Author {
+                id: comment.author_id,
+                nickname: comment.author_nickname.clone(),
+            },
+            comment,
+        })
+        .collect();
+    Ok(Some(ArticlePage {
+        author: Author {
+            id: article.author_id,
+            nickname: article.author_nickname.clone(),
+        },
+        article,
+        comments,
+        etag,
+    }))
+}
+
+pub fn subspace_from_row(row: &Row) -> VeSubspace {
+    VeSubspace {
+        id: SubspaceId(row.get::<_, SqlId>("id").0),
+        title: row.get("title"),
+        slug: row.get("slug"),
+        description: row
+            .get::<_, Option<String>>("description")
+            .unwrap_or_default(),
+        banner: row.get::<_, Option<String>>("banner").unwrap_or_default(),
+        status: row.get("status"),
+        weight: Weight(row.get("weight")),
+        created_time: row.get("created_time"),
+    }
+}
+
+pub fn article_from_row(row: &Row) -> Result<VeArticle, content::ContentError> {
+    Ok(VeArticle {
+        id: ArticleId(row.get::<_, SqlId>("id").0),
+        title: row.get("title"),
+        content: content::from_row(row)?,
+        author_id: UserId(row.get::<_, SqlId>("author_id").0),
+        author_nickname: nickname_from_row(row),
+        subspace_id: SubspaceId(row.get::<_, SqlId>("subspace_id").0),
+        ext_link: row.get::<_, Option<String>>("ext_link").unwrap_or_default(),
+        status: row.get("status"),
+        weight: Weight(row.get("weight")),
+        created_time: row.get("created_time"),
+        updated_time: row.get("updated_time"),
+    })
+}
+
+pub fn comment_from_row(row: &Row) -> VeComment {
+    VeComment {
+        id: CommentId(row.get::<_, SqlId>("id").0),
+        content: row.get("content"),
+        author_id: UserId(row.get::<_, SqlId>("author_id").0),
+        author_nickname: nickname_from_row(row),
+        post_id: ArticleId(row.get::<_, SqlId>("post_id").0),
+        status: row.get("status"),
+        weight: Weight(row.get("weight")),
+        created_time: row.get("created_time"),
+    }
+}
+
+// The sanitized nickname, the raw one for rows written before it was stored.
+fn nickname_from_row(row: &Row) -> String {
+    row.get::<_, Option<String>>("author_nickname_sanitized")
+        .unwrap_or_else(|| row.get("author_nickname"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_subspace_views() {
+        assert_eq!(
+            "3".parse(),
+            Ok(SubspaceView {
+                subspace: 3,
+                role: None,
+            })
+        );
+        assert_eq!(
+            "3:tenant_acme".parse(),
+            Ok(SubspaceView {
+                subspace: 3,
+                role: Some("tenant_acme".to_string()),
+            })
+        );
+        assert!("3:".parse::<SubspaceView>().is_err());
+        assert!("acme".parse::<SubspaceView>().is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+use vemodel::{VeArticle, VeComment, VeSubspace};
+
+use crate::metrics;
+use crate::model::{Model, Registered};
+
+/// What a blank title goes by with [`BlankFields::Placeholder`].
+pub const UNTITLED: &str = "[untitled]";
+
+/// What blank content goes by with [`BlankFields::Placeholder`].
+pub const EMPTY: &str = "[empty]";
+
+/// What becomes of an entity from the nucleus with a required field blank,
+/// empty or only whitespace: the title and slug of a subspace, the title and
+/// content of an article, the content of a comment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlankFields {
+    /// Store it as it is.
+    Keep,
+    /// Fail it, so it's dead-lettered once out of attempts.
+    Reject,
+    /// Fill the field in: [`UNTITLED`] for a title, [`EMPTY`] for content,
+    /// `subspace-<id>` for a slug.
+    Placeholder,
+}
+
+impl FromStr for BlankFields {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "reject" => Ok(Self::Reject),
+            "placeholder" => Ok(Self::Placeholder),
+            _ => Err(format!("unknown blank field policy: {}", s)),
+        }
+    }
+}
+
+/// A required field an entity has blank, rejected with [`BlankFields::Reject`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlankField {
+    pub model: Model,
+    pub id: u64,
+    pub field: &'static str,
+}
+
+impl fmt::Display for BlankField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} has a blank {}",
+            self.model.as_str(),
+            self.id,
+            self.field
+        )
+    }
+}
+
+impl std::error::Error for BlankField {}
+
+/// An entity with fields it can't do without.
+pub trait Required: Registered {
+    /// The required fields, by name, with the placeholder of each.
+    fn required(&mut self) -> Vec<(&'static str, &mut String, String)>;
+}
+
+impl Required for VeSubspace {
+    fn required(&mut self) -> Vec<(&'static str, &mut String, String)> {
+        let slug = format!("subspace-{}", self.id.0);
+        vec![
+            ("title", &mut self.title, UNTITLED.to_string()),
+            ("slug", &mut self.slug, slug),
+        ]
+    }
+}
+
+impl Required for VeArticle {
+    fn required(&mut self) -> Vec<(&'static str, &mut String, String)> {
+        vec![
+            ("title", &mut self.title, UNTITLED.to_string()),
+            ("content", &mut self.content, EMPTY.to_string()),
+        ]
+    }
+}
+
+impl Required for VeComment {
+    fn required(&mut self) -> Vec<(&'static str, &mut String, String)> {
+        vec![("content", &mut self.content, EMPTY.to_string())]
+    }
+}
+
+/// Holds `entity` to `policy`, returning the fields it filled in. SCALE has
+/// no absent fields, a string the nucleus left out arrives empty.
+pub fn check<T: Required>(
+    entity: &mut T,
+    policy: BlankFields,
+) -> Result<Vec<&'static str>, BlankField> {
+    let id = entity.id();
+    let mut filled = Vec::new();
+    for (field, value, placeholder) in entity.required() {
+        if !value.trim().is_empty() {
+            continue;
+        }
+        metrics::BLANK_FIELDS.inc();
+        match policy {
+            BlankFields::Keep => {}
+            BlankFields::Reject => {
+                return Err(BlankField {
+                    model: T::MODEL,
+                    id,
+                    field,
+                })
+            }
+            BlankFields::Placeholder => {
+                *value = placeholder;
+                filled.push(field);
+            }
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::fixture;
+    use parity_scale_codec::{Decode, Encode};
+    use vemodel::{SubspaceId, Weight};
+
+    fn article(title: &str, content: &str) -> VeArticle {
+        VeArticle {
+            title: title.to_string(),
+            content: content.to_string(),
+            ..fixture::article(7)
+        }
+    }
+
+    #[test]
+    fn empty_fields_are_kept_rejected_or_filled_in() {
+        let mut kept = article("", "body");
+        assert_eq!(check(&mut kept, BlankFields::Keep), Ok(vec![]));
+        assert_eq!(kept.title, "");
+
+        assert_eq!(
+            check(&mut article("", "body"), BlankFields::Reject),
+            Err(BlankField {
+                model: Model::Article,
+                id: 7,
+                field: "title",
+            })
+        );
+
+        let mut filled = article(" \n", "");
+        assert_eq!(
+            check(&mut filled, BlankFields::Placeholder),
+            Ok(vec!["title", "content"])
+        );
+        assert_eq!((&filled.title[..], &filled.content[..]), (UNTITLED, EMPTY));
+
+        let mut fine = article("Title", "body");
+        assert_eq!(check(&mut fine, BlankFields::Reject), Ok(vec![]));
+    }
+
+    #[test]
+    fn absent_fields_arrive_empty() {
+        // a subspace as a nucleus leaving out its title and slug encodes it
+        let mut bytes = SubspaceId(3).encode();
+        for field in ["", "", "description", "banner"] {
+            bytes.extend(field.to_string().encode());
+        }
+        bytes.extend((0i16, Weight(0), 0i64).encode());
+        let mut subspace = VeSubspace::decode(&mut &bytes[..]).unwrap();
+        assert!(check(&mut subspace, BlankFields::Reject).is_err());
+        assert_eq!(
+            check(&mut subspace, BlankFields::Placeholder),
+            Ok(vec!["title", "slug"])
+        );
+        assert_eq!(subspace.slug, "subspace-3");
+
+        let mut comment = fixture::comment(9);
+        assert_eq!(
+            check(&mut comment, BlankFields::Reject)
+                .unwrap_err()
+                .to_string(),
+            "comment 9 has a blank content"
+        );
+    }
+
+    #[test]
+    fn parses_blank_fields() {
+        assert_eq!("keep".parse(), Ok(BlankFields::Keep));
+        assert_eq!("reject".parse(), Ok(BlankFields::Reject));
+        assert_eq!("placeholder".parse(), Ok(BlankFields::Placeholder));
+        assert!("drop".parse::<BlankFields>().is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::str::FromStr;
+
+/// An operator's request to move the sentinel, read from
+/// `VE_SENTINEL_RESET_PATH` on SIGHUP: the sentinel, followed by `rewind`
+/// when it's behind the committed one.
+///
+/// Forward, the events up to the new sentinel are skipped, the nucleus
+/// forgets them as soon as it's sent. Backward only re-reads events the
+/// nucleus still holds, since it dropped everything up to the committed
+/// sentinel already, e.g. after it was restored from a backup.
+#[derive(Debug, PartialEq)]
+pub struct SentinelReset {
+    pub sentinel: u64,
+    pub rewind: bool,
+}
+
+impl SentinelReset {
+    /// Refuses to move a `committed` sentinel backward unless asked to.
+    pub fn check(&self, committed: u64) -> Result<(), String> {
+        if self.sentinel < committed && !self.rewind {
+            return Err(format!(
+                "{} is behind the committed sentinel {}, add `rewind` to move it back",
+                self.sentinel, committed
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SentinelReset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (sentinel, rewind) = match s.split_whitespace().collect::<Vec<_>>()[..] {
+            [sentinel] => (sentinel, false),
+            [sentinel, "rewind"] => (sentinel, true),
+            _ => {
+                return Err(format!(
+                    "expected `<sentinel> [rewind]`, got {:?}",
+                    s.trim()
+                ))
+            }
+        };
+        let sentinel = sentinel
+            .parse()
+            .map_err(|e| format!("invalid sentinel {}: {}", sentinel, e))?;
+        Ok(Self { sentinel, rewind })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_forward_and_rewinding_resets() {
+        assert_eq!(
+            "1200\n".parse(),
+            Ok(SentinelReset {
+                sentinel: 1200,
+                rewind: false
+            })
+        );
+        assert_eq!(
+            "800 rewind".parse(),
+            Ok(SentinelReset {
+                sentinel: 800,
+                rewind: true
+            })
+        );
+        assert!("".parse::<SentinelReset>().is_err());
+        assert!("800 back".parse::<SentinelReset>().is_err());
+        assert!("-1".parse::<SentinelReset>().is_err());
+    }
+
+    #[test]
+    fn moves_back_only_when_rewinding() {
+        let reset: SentinelReset = "800".parse().unwrap();
+        assert!(reset.check(1000).is_err());
+        assert!(reset.check(800).is_ok());
+        assert!(reset.check(500).is_ok());
+        assert!("800 rewind"
+            .parse::<SentinelReset>()
+            .unwrap()
+            .check(1000)
+            .is_ok());
+    }
+}
@@ -0,0 +1,484 @@
+use parity_scale_codec::{Compact, Decode, DecodeAll};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tracing::debug;
+
+use vemodel::Method;
+
+use crate::metrics;
+
+/// A nucleus JSON-RPC result that couldn't be turned into the expected value.
+#[derive(Debug)]
+pub enum ResponseError {
+    /// The result wasn't a hex string, e.g. an error envelope or null.
+    NotAString(serde_json::Value),
+    Hex(hex::FromHexError),
+    /// A JSON result wasn't in the expected shape.
+    Json(serde_json::Error),
+    /// The SCALE bytes didn't decode, kept along with the error.
+    Decode {
+        raw: Vec<u8>,
+        source: parity_scale_codec::Error,
+    },
+}
+
+impl ResponseError {
+    /// The raw bytes of a response that failed to decode.
+    pub fn raw(&self) -> Option<&[u8]> {
+        match self {
+            Self::Decode { raw, .. } => Some(raw),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotAString(value) => write!(f, "expected a hex string result, got {}", value),
+            Self::Hex(e) => write!(f, "invalid hex string: {}", e),
+            Self::Json(e) => write!(f, "unexpected JSON nucleus response: {}", e),
+            Self::Decode { source, .. } => {
+                write!(f, "failed to decode nucleus response: {}", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResponseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::NotAString(_) => None,
+            Self::Hex(e) => Some(e),
+            Self::Json(e) => Some(e),
+            Self::Decode { source, .. } => Some(source),
+        }
+    }
+}
+
+/// One change event out of `get_from_common_key`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub reqnum: u64,
+    pub method: Method,
+    pub key: Vec<u8>,
+    /// Unix time in milliseconds at which the nucleus recorded the change,
+    /// for nuclei that send it.
+    pub source_time: Option<i64>,
+}
+
+/// An entry of a `get_from_common_key` batch that didn't decode, though its
+/// reqnum did, e.g. one with a method this build doesn't know.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndecodableEvent {
+    pub reqnum: u64,
+    /// The key, when that much decoded, empty otherwise.
+    pub key: Vec<u8>,
+    /// The entry as the nucleus sent it.
+    pub raw: Vec<u8>,
+    pub error: String,
+}
+
+impl UndecodableEvent {
+    /// What the entry stands as in [`EventBatch::events`]: an update of its
+    /// key, which is what re-driving its dead letter then fetches.
+    pub fn stand_in(&self) -> ChangeEvent {
+        ChangeEvent {
+            reqnum: self.reqnum,
+            method: Method::Update,
+            key: self.key.clone(),
+            source_time: None,
+        }
+    }
+}
+
+/// The result of `get_from_common_key`, decoded entry by entry so one the
+/// build can't read doesn't cost it the others.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventBatch {
+    /// The events in the order they were served, with a
+    /// [stand-in](UndecodableEvent::stand_in) for each undecodable one, so
+    /// the batch keeps all of its reqnums.
+    pub events: Vec<ChangeEvent>,
+    /// The entries that didn't decode, to fail like events that don't apply.
+    pub undecodable: Vec<UndecodableEvent>,
+}
+
+impl From<Vec<ChangeEvent>> for EventBatch {
+    fn from(events: Vec<ChangeEvent>) -> Self {
+        Self {
+            events,
+            undecodable: Vec::new(),
+        }
+    }
+}
+
+type Events = Result<Vec<(u64, Method, Vec<u8>)>, String>;
+type TimedEvents = Result<Vec<(u64, Method, Vec<u8>, i64)>, String>;
+
+/// How the nucleus encodes the result of `get_from_common_key`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventCodec {
+    /// Hex-encoded SCALE, as the nucleus has it.
+    Scale,
+    /// A plain JSON array of `[reqnum, method, key_hex]`, optionally followed
+    /// by the source time, e.g. `[[3, "Create", "766561723a0000000000000007"]]`.
+    /// Handy for a fake nucleus in tests or when debugging.
+    Json,
+}
+
+impl FromStr for EventCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scale" => Ok(Self::Scale),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown event codec: {}", s)),
+        }
+    }
+}
+
+/// Decodes the result of a nucleus call, a hex string of SCALE bytes, into `T`.
+///
+/// Decoding a `String` checks it's UTF-8, so entities that decode are safe to
+/// hand to serde and Postgres. Those that don't fail like any undecodable
+/// response, and end up dead-lettered with their bytes.
+pub fn decode_response<T: Decode>(value: &serde_json::Value) -> Result<T, ResponseError> {
+    let raw = response_bytes(value)?;
+    let decoded = T::decode(&mut &raw[..]);
+    decoded.map_err(|source| ResponseError::Decode { raw, source })
+}
+
+/// Decodes the result of `get_from_common_key`, whose entries either carry a
+/// timestamp or, from older nuclei, don't.
+///
+/// A batch that doesn't decode as a whole is read again entry by entry, the
+/// only part of an entry whose layout can't be known being its method, so
+/// those with a method this build doesn't know end up undecodable by
+/// themselves. Anything else wrong with the bytes fails the batch.
+pub fn decode_events(
+    value: &serde_json::Value,
+) -> Result<Result<EventBatch, String>, ResponseError> {
+    let raw = response_bytes(value)?;
+    // the timed shape has to account for every byte, so the untimed one can't pass for it
+    if let Ok(events) = TimedEvents::decode_all(&mut &raw[..]) {
+        return Ok(events.map(|events| {
+            to_events(
+                events
+                    .into_iter()
+                    .map(|(reqnum, method, key, source_time)| {
+                        (reqnum, method, key, Some(source_time))
+                    }),
+            )
+            .into()
+        }));
+    }
+
+    let decoded = Events::decode(&mut &raw[..]);
+    match decoded {
+        Ok(events) => Ok(events.map(|events| {
+            to_events(
+                events
+                    .into_iter()
+                    .map(|(reqnum, method, key)| (reqnum, method, key, None)),
+            )
+            .into()
+        })),
+        Err(source) => match decode_entries(&raw, true).or_else(|| decode_entries(&raw, false)) {
+            Some(batch) => Ok(Ok(batch)),
+            None => Err(ResponseError::Decode { raw, source }),
+        },
+    }
+}
+
+// Reads `raw` as an `Ok` batch, field by field, `None` unless every byte is
+// accounted for like that.
+fn decode_entries(raw: &[u8], timed: bool) -> Option<EventBatch> {
+    let input = &mut &raw[..];
+    // an error from the AVS would have decoded as a whole
+    if u8::decode(input).ok()? != 0 {
+        return None;
+    }
+    let mut batch = EventBatch::default();
+    for _ in 0..Compact::<u32>::decode(input).ok()?.0 {
+        let entry = *input;
+        let reqnum = u64::decode(input).ok()?;
+        let method = u8::decode(input).ok()?;
+        let key = Vec::<u8>::decode(input).ok()?;
+        let source_time = if timed {
+            Some(i64::decode(input).ok()?)
+        } else {
+            None
+        };
+        match Method::decode(&mut &[method][..]) {
+            Ok(method) => batch.events.push(ChangeEvent {
+                reqnum,
+                method,
+                key,
+                source_time,
+            }),
+            Err(e) => {
+                let undecodable = UndecodableEvent {
+                    reqnum,
+                    key,
+                    raw: entry[..entry.len() - input.len()].to_vec(),
+                    error: format!("failed to decode change event: {}", e),
+                };
+                batch.events.push(undecodable.stand_in());
+                batch.undecodable.push(undecodable);
+            }
+        }
+    }
+    input.is_empty().then_some(batch)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonEvent {
+    Timed(u64, Method, String, i64),
+    Untimed(u64, Method, String),
+}
+
+/// Decodes the result of `get_from_common_key` in the shape of
+/// [`EventCodec::Json`], entry by entry: those that don't decode but have a
+/// reqnum are undecodable by themselves, one without fails the batch.
+pub fn decode_json_events(
+    value: &serde_json::Value,
+) -> Result<Result<EventBatch, String>, ResponseError> {
+    let entries = Vec::<serde_json::Value>::deserialize(value).map_err(ResponseError::Json)?;
+    let mut batch = EventBatch::default();
+    for entry in entries {
+        match decode_json_event(&entry) {
+            Ok(event) => batch.events.push(event),
+            Err(error) => {
+                let Some(reqnum) = entry[0].as_u64() else {
+                    return Err(error);
+                };
+                let key = entry[2].as_str().and_then(|key| decode_hex(key).ok());
+                let undecodable = UndecodableEvent {
+                    reqnum,
+                    key: key.unwrap_or_default(),
+                    raw: entry.to_string().into_bytes(),
+                    error: format!("failed to decode change event: {}", error),
+                };
+                batch.events.push(undecodable.stand_in());
+                batch.undecodable.push(undecodable);
+            }
+        }
+    }
+    Ok(Ok(batch))
+}
+
+fn decode_json_event(entry: &serde_json::Value) -> Result<ChangeEvent, ResponseError> {
+    let (reqnum, method, key, source_time) = match JsonEvent::deserialize(entry) {
+        Ok(JsonEvent::Timed(reqnum, method, key, time)) => (reqnum, method, key, Some(time)),
+        Ok(JsonEvent::Untimed(reqnum, method, key)) => (reqnum, method, key, None),
+        Err(e) => return Err(ResponseError::Json(e)),
+    };
+    Ok(ChangeEvent {
+        reqnum,
+        method,
+        key: decode_hex(&key).map_err(ResponseError::Hex)?,
+        source_time,
+    })
+}
+
+fn to_events(
+    entries: impl IntoIterator<Item = (u64, Method, Vec<u8>, Option<i64>)>,
+) -> Vec<ChangeEvent> {
+    entries
+        .into_iter()
+        .map(|(reqnum, method, key, source_time)| ChangeEvent {
+            reqnum,
+            method,
+            key,
+            source_time,
+        })
+        .collect()
+}
+
+/// Decodes hex as the nucleus sends it, with or without a `0x` prefix and in
+/// either case, so a change in its formatting doesn't stop ingest.
+pub fn decode_hex(hex_str: &str) -> Result<Vec<u8>, hex::FromHexError> {
+    let digits = hex_str
+        .strip_prefix("0x")
+        .or_else(|| hex_str.strip_prefix("0X"))
+        .unwrap_or(hex_str);
+    hex::decode(digits)
+}
+
+fn response_bytes(value: &serde_json::Value) -> Result<Vec<u8>, ResponseError> {
+    let Some(hex_str) = value.as_str() else {
+        debug!(response = %value, "Nucleus result is not a string");
+        metrics::RPC_NON_STRING_RESPONSES.inc();
+        return Err(ResponseError::NotAString(value.clone()));
+    };
+    let raw = decode_hex(hex_str).map_err(ResponseError::Hex)?;
+    metrics::RPC_LARGEST_RESPONSE.observe(raw.len() as u64);
+    Ok(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::fixture;
+    use parity_scale_codec::Encode;
+    use vemodel::VeArticle;
+
+    #[test]
+    fn decodes_hex_encoded_scale() {
+        let value = serde_json::json!(hex::encode(42u64.encode()));
+        assert_eq!(decode_response::<u64>(&value).unwrap(), 42);
+    }
+
+    #[test]
+    fn hex_may_be_prefixed_and_in_either_case() {
+        assert_eq!(decode_hex("0aff").unwrap(), [0x0a, 0xff]);
+        assert_eq!(decode_hex("0x0aff").unwrap(), [0x0a, 0xff]);
+        assert_eq!(decode_hex("0X0AfF").unwrap(), [0x0a, 0xff]);
+        assert!(decode_hex("0x").unwrap().is_empty());
+        assert!(decode_hex("0x0x0a").is_err());
+
+        let value = serde_json::json!(format!("0x{}", hex::encode(42u64.encode()).to_uppercase()));
+        assert_eq!(decode_response::<u64>(&value).unwrap(), 42);
+    }
+
+    #[test]
+    fn non_string_result_is_an_error_and_counted() {
+        let before = metrics::RPC_NON_STRING_RESPONSES.get();
+        let envelope = serde_json::json!({ "code": -32000, "message": "nucleus not found" });
+
+        match decode_response::<u64>(&envelope) {
+            Err(ResponseError::NotAString(value)) => assert_eq!(value, envelope),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(decode_response::<u64>(&serde_json::Value::Null).is_err());
+        assert!(metrics::RPC_NON_STRING_RESPONSES.get() >= before + 2);
+    }
+
+    #[test]
+    fn decodes_events_with_and_without_timestamps() {
+        let key = [&b"vear:"[..], &7u64.to_be_bytes()[..]].concat();
+        let untimed: Events = Ok(vec![(3, Method::Create, key.clone())]);
+        let timed: TimedEvents = Ok(vec![(3, Method::Create, key.clone(), 1_700_000_000_000)]);
+
+        let event = |source_time| ChangeEvent {
+            reqnum: 3,
+            method: Method::Create,
+            key: key.clone(),
+            source_time,
+        };
+        let decode = |events: Vec<u8>| decode_events(&serde_json::json!(hex::encode(events)));
+        assert_eq!(
+            decode(untimed.encode()).unwrap(),
+            Ok(vec![event(None)].into())
+        );
+        assert_eq!(
+            decode(timed.encode()).unwrap(),
+            Ok(vec![event(Some(1_700_000_000_000))].into())
+        );
+    }
+
+    #[test]
+    fn an_unknown_method_only_costs_its_own_event() {
+        let key = |id: u64| [&b"vear:"[..], &id.to_be_bytes()[..]].concat();
+        let events: Events = Ok((3..=5)
+            .map(|reqnum| (reqnum, Method::Create, key(reqnum)))
+            .collect());
+        let mut bytes = events.encode();
+        // past the result tag, the length and the first entry, then its reqnum
+        let entry = (3u64, Method::Create, key(3)).encode().len();
+        bytes[2 + entry + 8] = 9;
+
+        let batch = decode_events(&serde_json::json!(hex::encode(&bytes)))
+            .unwrap()
+            .unwrap();
+        let reqnums: Vec<_> = batch.events.iter().map(|event| event.reqnum).collect();
+        assert_eq!(reqnums, [3, 4, 5]);
+        assert_eq!(batch.events[2].method, Method::Create);
+        assert_eq!(batch.undecodable.len(), 1);
+        let undecodable = &batch.undecodable[0];
+        assert_eq!((undecodable.reqnum, &undecodable.key), (4, &key(4)));
+        assert_eq!(undecodable.raw, bytes[2 + entry..2 + 2 * entry]);
+        assert_eq!(batch.events[1], undecodable.stand_in());
+
+        // cut short, there's no telling the entries apart
+        let truncated = hex::encode(&bytes[..bytes.len() - 1]);
+        assert!(matches!(
+            decode_events(&serde_json::json!(truncated)),
+            Err(ResponseError::Decode { .. })
+        ));
+    }
+
+    #[test]
+    fn decodes_json_events_like_scale_ones() {
+        let key = [&b"vear:"[..], &7u64.to_be_bytes()[..]].concat();
+        let scale: TimedEvents = Ok(vec![(3, Method::Create, key.clone(), 1_700_000_000_000)]);
+        let json = serde_json::json!([
+            [3, "Create", hex::encode(&key), 1_700_000_000_000i64],
+            [4, "Delete", hex::encode(&key)]
+        ]);
+
+        let events = decode_json_events(&json).unwrap().unwrap().events;
+        let from_scale = decode_events(&serde_json::json!(hex::encode(scale.encode()))).unwrap();
+        assert_eq!(Ok(events[..1].to_vec().into()), from_scale);
+        assert_eq!(events[1].method, Method::Delete);
+        assert_eq!(events[1].source_time, None);
+
+        assert!(matches!(
+            decode_json_events(&serde_json::json!("0102")),
+            Err(ResponseError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn undecodable_json_events_are_kept_apart() {
+        let key = hex::encode([&b"vear:"[..], &7u64.to_be_bytes()[..]].concat());
+        let json = serde_json::json!([
+            [3, "Create", key],
+            [4, "Upsert", key],
+            [5, "Create", "zz"],
+            [6, "Delete", key]
+        ]);
+
+        let batch = decode_json_events(&json).unwrap().unwrap();
+        assert_eq!(batch.events.len(), 4);
+        let undecodable: Vec<_> = batch
+            .undecodable
+            .iter()
+            .map(|event| (event.reqnum, event.key.len()))
+            .collect();
+        assert_eq!(undecodable, [(4, 13), (5, 0)]);
+        assert_eq!(batch.events[3].method, Method::Delete);
+
+        // nothing to route it by
+        assert!(decode_json_events(&serde_json::json!([["three", "Create", key]])).is_err());
+    }
+
+    #[test]
+    fn invalid_utf8_in_a_string_field_fails_to_decode() {
+        let article = VeArticle {
+            title: "Weekly update".to_string(),
+            content: "@@@@".to_string(),
+            ..fixture::article(7)
+        };
+        let fetched: Result<Option<VeArticle>, String> = Ok(Some(article));
+        let mut bytes = fetched.encode();
+        let at = bytes.windows(4).position(|w| w == b"@@@@").unwrap();
+        bytes[at..at + 4].copy_from_slice(&[0xff, 0xfe, 0xfd, 0xfc]);
+
+        let value = serde_json::json!(hex::encode(&bytes));
+        let err = decode_response::<Result<Option<VeArticle>, String>>(&value).unwrap_err();
+        assert!(matches!(err, ResponseError::Decode { .. }));
+        assert_eq!(err.raw(), Some(&bytes[..]));
+    }
+
+    #[test]
+    fn undecodable_bytes_are_kept() {
+        let value = serde_json::json!("0102");
+        let err = decode_response::<u64>(&value).unwrap_err();
+        assert_eq!(err.raw(), Some(&[1u8, 2][..]));
+    }
+}
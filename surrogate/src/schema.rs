@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::fmt;
+use tokio_postgres::GenericClient;
+
+use crate::id::{IdColumnType, ID_COLUMNS};
+
+/// The columns this build reads and writes, with their `information_schema`
+/// data types. Keep in step with the migrations. Those of [`ID_COLUMNS`] are
+/// expected of the configured [`IdColumnType`] instead.
+pub const EXPECTED: &[(&str, &[(&str, &str)])] = &[
+    (
+        "subspaces",
+        &[
+            ("id", "bigint"),
+            ("title", "character varying"),
+            ("slug", "character varying"),
+            ("description", "text"),
+            ("banner", "character varying"),
+            ("status", "smallint"),
+            ("weight", "smallint"),
+            ("created_time", "bigint"),
+            ("source_time", "bigint"),
+            ("indexed_time", "bigint"),
+            ("description_plain", "text"),
+            ("source", "character varying"),
+            ("etag", "character varying"),
+        ],
+    ),
+    (
+        "articles",
+        &[
+            ("id", "bigint"),
+            ("title", "character varying"),
+            ("content", "text"),
+            ("author_id", "bigint"),
+            ("author_nickname", "character varying"),
+            ("subspace_id", "bigint"),
+            ("ext_link", "character varying"),
+            ("status", "smallint"),
+            ("weight", "smallint"),
+            ("created_time", "bigint"),
+            ("updated_time", "bigint"),
+            ("excerpt", "text"),
+            ("content_bytes", "bytea"),
+            ("content_encoding", "character varying"),
+            ("source_time", "bigint"),
+            ("indexed_time", "bigint"),
+            ("author_nickname_sanitized", "character varying"),
+            ("source", "character varying"),
+            ("etag", "character varying"),
+            ("subspace_slug", "character varying"),
+            ("content_html", "text"),
+            ("edited", "boolean"),
+        ],
+    ),
+    (
+        "comments",
+        &[
+            ("id", "bigint"),
+            ("content", "text"),
+            ("author_id", "bigint"),
+            ("author_nickname", "character varying"),
+            ("post_id", "bigint"),
+            ("status", "smallint"),
+            ("weight", "smallint"),
+            ("created_time", "bigint"),
+            ("source_time", "bigint"),
+            ("indexed_time", "bigint"),
+            ("author_nickname_sanitized", "character varying"),
+            ("source", "character varying"),
+            ("etag", "character varying"),
+            ("target_type", "character varying"),
+            ("content_html", "text"),
+        ],
+    ),
+    (
+        "users",
+        &[
+            ("id", "bigint"),
+            ("article_count", "bigint"),
+            ("comment_count", "bigint"),
+        ],
+    ),
+    (
+        "subspace_authors",
+        &[
+            ("subspace_id", "bigint"),
+            ("author_id", "bigint"),
+            ("items", "bigint"),
+        ],
+    ),
+    (
+        "subspace_stats",
+        &[
+            ("subspace_id", "bigint"),
+            ("article_count", "bigint"),
+            ("comment_count", "bigint"),
+            ("active_authors", "bigint"),
+            ("last_activity_time", "bigint"),
+        ],
+    ),
+    (
+        "change_feed",
+        &[
+            ("id", "bigint"),
+            ("reqnum", "bigint"),
+            ("envelope", "jsonb"),
+            ("recorded_time", "bigint"),
+        ],
+    ),
+    (
+        "dead_letter",
+        &[
+            ("reqnum", "bigint"),
+            ("sink", "character varying"),
+            ("prefix", "character varying"),
+            ("id", "bigint"),
+            ("method", "character varying"),
+            ("raw_bytes", "bytea"),
+            ("error", "text"),
+            ("first_seen", "bigint"),
+            ("attempts", "integer"),
+        ],
+    ),
+    (
+        "scrubbed_changes",
+        &[
+            ("reqnum", "bigint"),
+            ("prefix", "character varying"),
+            ("id", "bigint"),
+            ("original", "bytea"),
+            ("fixes", "text"),
+            ("error", "text"),
+            ("scrubbed_time", "bigint"),
+        ],
+    ),
+    (
+        "sync_state",
+        &[
+            ("avs_id", "character varying"),
+            ("sentinel", "bigint"),
+            ("updated_time", "bigint"),
+        ],
+    ),
+    (
+        "applied_events",
+        &[
+            ("avs_id", "character varying"),
+            ("reqnum", "bigint"),
+            ("applied_time", "bigint"),
+            ("key", "bytea"),
+            ("method", "character varying"),
+        ],
+    ),
+];
+
+/// How the database differs from [`EXPECTED`], one line per column.
+#[derive(Debug, PartialEq)]
+pub struct SchemaMismatch(pub Vec<String>);
+
+impl fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the database schema is out of date, run `surrogate migrate`:"
+        )?;
+        for line in &self.0 {
+            write!(f, "\n  {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SchemaMismatch {}
+
+/// Checks every expected column exists with the expected type, the id columns
+/// with `id_type`. Extra tables and columns are fine, they may belong to a
+/// newer build or to someone else.
+pub async fn validate<C: GenericClient>(
+    client: &C,
+    id_type: IdColumnType,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rows = client
+        .query(
+            "SELECT table_name::TEXT, column_name::TEXT, data_type::TEXT
+             FROM information_schema.columns
+             WHERE table_schema = current_schema()",
+            &[],
+        )
+        .await?;
+    let actual = rows
+        .iter()
+        .map(|row| ((row.get(0), row.get(1)), row.get(2)))
+        .collect();
+    compare(EXPECTED, id_type, &actual).map_err(Into::into)
+}
+
+fn compare(
+    expected: &[(&str, &[(&str, &str)])],
+    id_type: IdColumnType,
+    actual: &HashMap<(String, String), String>,
+) -> Result<(), SchemaMismatch> {
+    let mut mismatches = Vec::new();
+    for &(table, columns) in expected {
+        for &(column, data_type) in columns {
+            let data_type = if ID_COLUMNS.contains(&(table, column)) {
+                id_type.data_type()
+            } else {
+                data_type
+            };
+            match actual.get(&(table.to_string(), column.to_string())) {
+                None => mismatches.push(format!("{}.{} is missing", table, column)),
+                Some(found) if found != data_type => mismatches.push(format!(
+                    "{}.{} is {}, expected {}",
+                    table, column, found, data_type
+                )),
+                Some(_) => {}
+            }
+        }
+    }
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(SchemaMismatch(mismatches))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPECTED: &[(&str, &[(&str, &str)])] =
+        &[("articles", &[("id", "bigint"), ("vote_count", "integer")])];
+
+    fn columns(columns: &[(&str, &str, &str)]) -> HashMap<(String, String), String> {
+        columns
+            .iter()
+            .map(|&(table, column, data_type)| {
+                (
+                    (table.to_string(), column.to_string()),
+                    data_type.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matching_schema_passes_with_extra_columns() {
+        let actual = columns(&[
+            ("articles", "id", "bigint"),
+            ("articles", "vote_count", "integer"),
+            ("articles", "flair", "text"),
+        ]);
+        assert_eq!(compare(EXPECTED, IdColumnType::Bigint, &actual), Ok(()));
+    }
+
+    #[test]
+    fn missing_and_mistyped_columns_are_reported() {
+        let actual = columns(&[("articles", "id", "integer")]);
+        assert_eq!(
+            compare(EXPECTED, IdColumnType::Bigint, &actual),
+            Err(SchemaMismatch(vec![
+                "articles.id is integer, expected bigint".to_string(),
+                "articles.vote_count is missing".to_string(),
+            ]))
+        );
+    }
+
+    #[test]
+    fn id_columns_are_expected_of_the_configured_type() {
+        let actual = columns(&[
+            ("articles", "id", "numeric"),
+            ("articles", "vote_count", "integer"),
+        ]);
+        assert_eq!(compare(EXPECTED, IdColumnType::Numeric20, &actual), Ok(()));
+        assert_eq!(
+            compare(EXPECTED, IdColumnType::Bigint, &actual),
+            Err(SchemaMismatch(vec![
+                "articles.id is numeric, expected bigint".to_string()
+            ]))
+        );
+    }
+}
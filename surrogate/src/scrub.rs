@@ -0,0 +1,188 @@
+use serde_json::Value;
+use std::fmt;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Client;
+
+use crate::db::Change;
+use crate::dead_letter::unix_now;
+use crate::id::SqlId;
+use crate::key;
+
+/// A change made to a string of an entity so Postgres would take it.
+#[derive(Debug, PartialEq)]
+pub enum Fix {
+    /// NUL bytes were removed, text columns can't hold them.
+    StrippedNul { path: String },
+    /// The string was cut down from `from` bytes to the configured maximum.
+    Truncated { path: String, from: usize },
+}
+
+impl fmt::Display for Fix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StrippedNul { path } => write!(f, "{}: stripped NUL bytes", path),
+            Self::Truncated { path, from } => write!(f, "{}: truncated from {} bytes", path, from),
+        }
+    }
+}
+
+/// Whether `error` is Postgres refusing the text of a row, as opposed to
+/// anything else going wrong with the statement.
+pub fn rejects_text(error: &(dyn std::error::Error + 'static)) -> bool {
+    let rejections = [
+        // NUL bytes
+        SqlState::CHARACTER_NOT_IN_REPERTOIRE,
+        SqlState::UNTRANSLATABLE_CHARACTER,
+        SqlState::STRING_DATA_RIGHT_TRUNCATION,
+        // a field past 1GB, or an indexed one past the index row size
+        SqlState::PROGRAM_LIMIT_EXCEEDED,
+    ];
+    error
+        .downcast_ref::<tokio_postgres::Error>()
+        .and_then(tokio_postgres::Error::code)
+        .is_some_and(|code| rejections.contains(code))
+}
+
+/// Strips NUL bytes from every string in `value` and, given `max_bytes`, cuts
+/// longer strings down to it on a character boundary. Returns what it changed,
+/// nothing when `value` was fine as it was.
+pub fn scrub(value: &mut Value, max_bytes: Option<usize>) -> Vec<Fix> {
+    let mut fixes = Vec::new();
+    scrub_at(value, String::new(), max_bytes, &mut fixes);
+    fixes
+}
+
+fn scrub_at(value: &mut Value, path: String, max_bytes: Option<usize>, fixes: &mut Vec<Fix>) {
+    match value {
+        Value::String(s) => {
+            if s.contains('\0') {
+                s.retain(|c| c != '\0');
+                fixes.push(Fix::StrippedNul { path: path.clone() });
+            }
+            if let Some(max) = max_bytes.filter(|&max| s.len() > max) {
+                let from = s.len();
+                let cut = (0..=max)
+                    .rev()
+                    .find(|&i| s.is_char_boundary(i))
+                    .unwrap_or(0);
+                s.truncate(cut);
+                fixes.push(Fix::Truncated { path, from });
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                scrub_at(item, join(&path, &i.to_string()), max_bytes, fixes);
+            }
+        }
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                scrub_at(field, join(&path, name), max_bytes, fixes);
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+fn join(path: &str, segment: &str) -> String {
+    if path.is_empty() {
+        segment.to_string()
+    } else {
+        format!("{}.{}", path, segment)
+    }
+}
+
+/// Keeps the entity of `original` as fetched, before it was scrubbed into
+/// what got stored. As JSON bytes, JSONB can't hold NULs either.
+pub async fn record(
+    client: &Client,
+    original: &Change,
+    error: &str,
+    fixes: &[Fix],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (prefix, id) = key::split_key(&original.key);
+    let fixes = fixes
+        .iter()
+        .map(Fix::to_string)
+        .collect::<Vec<_>>()
+        .join("; ");
+    client
+        .execute(
+            "INSERT INTO scrubbed_changes (reqnum, prefix, id, original, fixes, error, scrubbed_time)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             ON CONFLICT (reqnum) DO UPDATE SET
+                original = $4,
+                fixes = $5,
+                error = $6,
+                scrubbed_time = $7",
+            &[
+                &(original.reqnum as i64),
+                &prefix,
+                &SqlId(id),
+                &serde_json::to_vec(&original.entity.to_json()?)?,
+                &fixes,
+                &error,
+                &unix_now(),
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use vemodel::VeArticle;
+
+    fn article(content: &str) -> Value {
+        json!({
+            "id": 7,
+            "title": "Weekly update",
+            "content": content,
+            "author_id": 3,
+            "author_nickname": "alice",
+            "subspace_id": 1,
+            "ext_link": "",
+            "status": 0,
+            "weight": 0,
+            "created_time": 1700000000,
+            "updated_time": 1700000000,
+        })
+    }
+
+    #[test]
+    fn strips_embedded_nul_bytes() {
+        let mut value = article("before\0after");
+        let fixes = scrub(&mut value, None);
+        assert_eq!(
+            fixes,
+            [Fix::StrippedNul {
+                path: "content".to_string()
+            }]
+        );
+        let article: VeArticle = serde_json::from_value(value).unwrap();
+        assert_eq!(article.content, "beforeafter");
+    }
+
+    #[test]
+    fn truncates_on_a_char_boundary() {
+        let mut value = json!({ "tags": ["ok", "héllo"] });
+        let fixes = scrub(&mut value, Some(2));
+        assert_eq!(value, json!({ "tags": ["ok", "h"] }));
+        assert_eq!(
+            fixes,
+            [Fix::Truncated {
+                path: "tags.1".to_string(),
+                from: 6
+            }]
+        );
+    }
+
+    #[test]
+    fn leaves_acceptable_values_alone() {
+        let mut value = article("nothing odd here");
+        let before = value.clone();
+        assert!(scrub(&mut value, Some(1024)).is_empty());
+        assert_eq!(value, before);
+    }
+}
@@ -0,0 +1,192 @@
+use parity_scale_codec::Encode;
+use tokio_postgres::{Client, Row};
+use tracing::info;
+
+use crate::config::Config;
+use crate::content::ContentError;
+use crate::db::{self, Change};
+use crate::id::SqlId;
+use crate::model::Model;
+use crate::query;
+use crate::sink::{BoxFuture, Sink};
+
+// ids kept per table of the rows that differ, enough to go and look at some
+const SAMPLE_DIFFS: usize = 5;
+
+/// Writes every applied change to a second, "shadow" database as well, from
+/// `VE_SHADOW_POSTGRES_CONFIG`, so a schema migration can be tried out on
+/// it against live traffic before the primary gets it. `verify-shadow`
+/// then compares the two, see [`compare`].
+///
+/// Changes are written like they are to the primary, each in a transaction
+/// of its own. The shadow keeps no sentinel: it follows the primary,
+/// whenever it was started, so it should be seeded from a copy of the
+/// primary first. Changes referencing rows it doesn't have fail and are
+/// dead-lettered under `shadow`, like those of any secondary sink.
+pub struct ShadowSink {
+    client: Client,
+    config: Config,
+}
+
+impl ShadowSink {
+    /// Connects to the shadow database and migrates it to this build's
+    /// schema, it's the new layout being validated.
+    pub async fn open(config: &Config, shadow: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut client = connect(config, shadow).await?;
+        let config = shadow_config(config, shadow);
+        db::setup_database(&mut client, &config, true).await?;
+        info!("Writing applied changes to the shadow database too");
+        Ok(Self { client, config })
+    }
+
+    async fn write(&self, change: &Change) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.batch_execute("BEGIN").await?;
+        match db::handle_database_operation(&self.client, &self.config, change).await {
+            Ok(()) => Ok(self.client.batch_execute("COMMIT").await?),
+            Err(e) => {
+                self.client.batch_execute("ROLLBACK").await?;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Sink for ShadowSink {
+    fn name(&self) -> &str {
+        "shadow"
+    }
+
+    fn apply<'a>(&'a self, change: &'a Change) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move { self.write(change).await.map_err(|e| e.to_string()) })
+    }
+}
+
+/// Connects to the shadow database at `shadow`.
+pub async fn connect(config: &Config, shadow: &str) -> Result<Client, Box<dyn std::error::Error>> {
+    db::connect(&shadow_config(config, shadow)).await
+}
+
+// The config of the primary with the shadow's connection, and no shadow of its own.
+fn shadow_config(config: &Config, shadow: &str) -> Config {
+    Config {
+        postgres_config: shadow.to_string(),
+        shadow_postgres_config: None,
+        ..config.clone()
+    }
+}
+
+/// How one table of the shadow compares with the primary's.
+#[derive(Debug, Default)]
+pub struct TableReport {
+    pub table: &'static str,
+    pub primary_rows: i64,
+    pub shadow_rows: i64,
+    /// Primary rows compared with the shadow's.
+    pub sampled: usize,
+    /// Sampled rows the shadow doesn't have.
+    pub missing: usize,
+    /// Sampled rows the shadow has other content for.
+    pub differing: usize,
+    /// The first few ids of the missing and differing rows.
+    pub ids: Vec<u64>,
+}
+
+impl TableReport {
+    pub fn matches(&self) -> bool {
+        self.primary_rows == self.shadow_rows && self.missing == 0 && self.differing == 0
+    }
+}
+
+/// Compares the row counts of every table and, for a random sample of up
+/// to `sample` rows of each (in the primary), their content in the shadow.
+/// Content is compared as the read paths see it, so columns the new layout
+/// adds or moves around don't count as differences so long as they read
+/// back the same. Read-only on both sides.
+pub async fn compare(
+    primary: &Client,
+    shadow: &Client,
+    sample: i64,
+) -> Result<Vec<TableReport>, Box<dyn std::error::Error>> {
+    let mut reports = Vec::new();
+    for model in Model::ALL {
+        let table = model.table();
+        let count = format!("SELECT COUNT(*) FROM {}", table);
+        let mut report = TableReport {
+            table,
+            primary_rows: primary.query_one(&count, &[]).await?.get(0),
+            shadow_rows: shadow.query_one(&count, &[]).await?.get(0),
+            ..Default::default()
+        };
+
+        let rows = primary
+            .query(
+                &format!("SELECT * FROM {} ORDER BY random() LIMIT $1", table),
+                &[&sample],
+            )
+            .await?;
+        let by_id = format!("SELECT * FROM {} WHERE id = $1", table);
+        for row in &rows {
+            let id: SqlId = row.get("id");
+            report.sampled += 1;
+            let same = match shadow.query_opt(&by_id, &[&id]).await? {
+                Some(shadow_row) => {
+                    let same = encoded(model, row)? == encoded(model, &shadow_row)?;
+                    report.differing += usize::from(!same);
+                    same
+                }
+                None => {
+                    report.missing += 1;
+                    false
+                }
+            };
+            if !same && report.ids.len() < SAMPLE_DIFFS {
+                report.ids.push(id as u64);
+            }
+        }
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+// The entity of `row` as the read paths build it, encoded to compare by.
+fn encoded(model: Model, row: &Row) -> Result<Vec<u8>, ContentError> {
+    Ok(match model {
+        Model::Subspace => query::subspace_from_row(row).encode(),
+        Model::Article => query::article_from_row(row)?.encode(),
+        Model::Comment => query::comment_from_row(row).encode(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shadow_config_has_no_shadow_of_its_own() {
+        let config = Config {
+            shadow_postgres_config: Some("host=shadow".to_string()),
+            ..Config::for_tests()
+        };
+        let shadow = shadow_config(&config, "host=shadow");
+        assert_eq!(shadow.postgres_config, "host=shadow");
+        assert_eq!(shadow.shadow_postgres_config, None);
+        assert_eq!(shadow.avs_id, config.avs_id);
+    }
+
+    #[test]
+    fn reports_match_only_when_nothing_differs() {
+        let report = TableReport {
+            table: "articles",
+            primary_rows: 10,
+            shadow_rows: 10,
+            sampled: 5,
+            ..Default::default()
+        };
+        assert!(report.matches());
+        assert!(!TableReport {
+            shadow_rows: 9,
+            ..report
+        }
+        .matches());
+    }
+}
@@ -0,0 +1,519 @@
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::{error, warn};
+
+use vemodel::Method;
+
+use crate::config::Config;
+use crate::db::{Change, Message};
+use crate::dead_letter::DeadLetter;
+use crate::file_sink::FileSink;
+use crate::shadow::ShadowSink;
+use crate::sse::EventStream;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Name the Postgres sink, always the primary one, goes by.
+pub const PRIMARY: &str = "postgres";
+
+/// The secondary sinks a `VE_SINK_METHODS` item may name.
+pub const SECONDARIES: [&str; 3] = ["file", "shadow", "sse"];
+
+// what a sink not in `VE_SINK_METHODS` is sent
+const ALL_METHODS: [Method; 3] = [Method::Create, Method::Update, Method::Delete];
+
+// Changes a secondary sink may fall behind the primary before they're dead-lettered
+const SINK_QUEUE: usize = 1000;
+
+/// A destination for changes besides Postgres. Each one is fed from its own
+/// queue by its own task, so a slow or failing sink holds up neither the
+/// others nor ingest.
+pub trait Sink: Send + Sync {
+    /// Identifies the sink in logs and in the dead-letter table.
+    fn name(&self) -> &str;
+
+    fn apply<'a>(&'a self, change: &'a Change) -> BoxFuture<'a, Result<(), String>>;
+
+    /// Makes everything applied so far durable, called once on shutdown.
+    fn close(&self) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Which sinks must have applied an event before the sentinel moves past it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SentinelAdvance {
+    /// Only Postgres. Secondary sinks retry on their own and dead-letter what
+    /// they give up on.
+    Primary,
+    /// Every sink. An event any sink failed is retried for all of them.
+    All,
+}
+
+impl FromStr for SentinelAdvance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(Self::Primary),
+            "all" => Ok(Self::All),
+            _ => Err(format!("unknown sentinel advance policy: {}", s)),
+        }
+    }
+}
+
+/// A `<sink>:<method>+<method>` item of `VE_SINK_METHODS`, the only
+/// changes that secondary sink is sent, e.g. `file:create+update` for a
+/// change log without deletes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SinkMethods(pub String, pub Vec<Method>);
+
+impl FromStr for SinkMethods {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (sink, methods) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <sink>:<method>+<method>, got {}", s))?;
+        if !SECONDARIES.contains(&sink) {
+            return Err(format!("unknown secondary sink: {}", sink));
+        }
+        let methods = methods
+            .split('+')
+            .map(|method| match method {
+                "create" => Ok(Method::Create),
+                "update" => Ok(Method::Update),
+                "delete" => Ok(Method::Delete),
+                _ => Err(format!("unknown method: {}", method)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self(sink.to_string(), methods))
+    }
+}
+
+/// Builds the secondary sinks enabled in the config.
+pub async fn build(config: &Config) -> Result<Vec<Box<dyn Sink>>, Box<dyn std::error::Error>> {
+    open(config, true, true).await
+}
+
+/// Builds the secondary sinks a command applying changes of its own goes
+/// through, alongside a surrogate that may well be running. The event stream
+/// isn't one, its address is the running surrogate's and so are its
+/// subscribers, and the change log only is with `change_log`, two writers
+/// appending to it would interleave.
+pub async fn build_for_command(
+    config: &Config,
+    change_log: bool,
+) -> Result<Vec<Box<dyn Sink>>, Box<dyn std::error::Error>> {
+    open(config, change_log, false).await
+}
+
+async fn open(
+    config: &Config,
+    change_log: bool,
+    stream: bool,
+) -> Result<Vec<Box<dyn Sink>>, Box<dyn std::error::Error>> {
+    let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+    if let Some(path) = config.change_log_path.as_ref().filter(|_| change_log) {
+        sinks.push(Box::new(FileSink::open(
+            path.clone(),
+            config.change_log_rotate_bytes,
+            config.change_log_flush,
+            config.change_log_compression,
+            config.change_log_fields.clone(),
+        )?));
+    }
+    if let Some(shadow) = &config.shadow_postgres_config {
+        sinks.push(Box::new(ShadowSink::open(config, shadow).await?));
+    }
+    if let Some(addr) = config.sse_addr.filter(|_| stream) {
+        sinks.push(Box::new(EventStream::open(config, addr).await?));
+    }
+    Ok(sinks)
+}
+
+struct Secondary {
+    name: String,
+    methods: Vec<Method>,
+    tx: mpsc::Sender<Message>,
+    task: JoinHandle<()>,
+}
+
+// (reqnum, sink, error) of a failed change
+type Failure = (u64, String, String);
+
+/// Hands every change to the primary writer and to each secondary sink
+/// taking changes of its method, every one of them unless `VE_SINK_METHODS`
+/// says otherwise.
+///
+/// Every sink applies the changes in the order they're sent, but each at
+/// its own pace, so how the sinks' work interleaves varies from run to run.
+/// With `VE_ORDERED_SINKS` a change is applied by one sink after the other,
+/// the secondaries in the order they're configured and Postgres last, and
+/// only then is the next one sent, at the cost of the sinks no longer
+/// working in parallel and a round trip to each per change: throughput
+/// drops to that of all the sinks' latencies added up. Meant for
+/// reproducible output, e.g. golden-file tests, not for production.
+pub struct Fanout {
+    primary: mpsc::Sender<Message>,
+    secondaries: Vec<Secondary>,
+    advance: SentinelAdvance,
+    ordered: bool,
+    // failures of ordered sends, kept for the next `flush`
+    settled: Mutex<Vec<Failure>>,
+}
+
+impl Fanout {
+    /// A fan-out to the primary writer alone.
+    pub fn primary_only(primary: mpsc::Sender<Message>) -> Self {
+        Self {
+            primary,
+            secondaries: Vec::new(),
+            advance: SentinelAdvance::Primary,
+            ordered: false,
+            settled: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Spawns a task per secondary sink, feeding it alongside the primary writer.
+    pub fn spawn(
+        primary: mpsc::Sender<Message>,
+        sinks: Vec<Box<dyn Sink>>,
+        config: &Config,
+    ) -> Self {
+        let secondaries = sinks
+            .into_iter()
+            .map(|sink| {
+                let (tx, rx) = mpsc::channel(SINK_QUEUE);
+                let name = sink.name().to_string();
+                let methods = config
+                    .sink_methods
+                    .get(&name)
+                    .cloned()
+                    .unwrap_or_else(|| ALL_METHODS.to_vec());
+                let task = tokio::spawn(run_sink(
+                    sink,
+                    config.max_attempts,
+                    config.sentinel_advance,
+                    rx,
+                    primary.clone(),
+                ));
+                Secondary {
+                    name,
+                    methods,
+                    tx,
+                    task,
+                }
+            })
+            .collect();
+        Self {
+            primary,
+            secondaries,
+            advance: config.sentinel_advance,
+            ordered: config.ordered_sinks,
+            settled: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The primary writer, which also takes dead letters and checkpoints.
+    pub fn primary(&self) -> &mpsc::Sender<Message> {
+        &self.primary
+    }
+
+    pub async fn send(&self, change: Change) -> Result<(), Box<dyn std::error::Error>> {
+        if self.ordered {
+            return self.send_ordered(change).await;
+        }
+        for secondary in self.taking(&change) {
+            let message = Message::Change(change.clone());
+            match self.advance {
+                SentinelAdvance::All => secondary.tx.send(message).await?,
+                SentinelAdvance::Primary => {
+                    // a sink that has fallen this far behind doesn't get to hold up ingest
+                    if let Err(e) = secondary.tx.try_send(message) {
+                        let error = format!("sink queue unavailable: {}", e);
+                        warn!(sink = %secondary.name, correlation_id = %change.correlation_id, "{}", error);
+                        let letter = DeadLetter::for_change(&change, &secondary.name, error, 0);
+                        self.primary.send(Message::DeadLetter(letter)).await?;
+                    }
+                }
+            }
+        }
+        self.primary.send(Message::Change(change)).await?;
+        Ok(())
+    }
+
+    // Sends `change` to one sink at a time, waiting for each to settle it.
+    async fn send_ordered(&self, change: Change) -> Result<(), Box<dyn std::error::Error>> {
+        let targets = self
+            .taking(&change)
+            .map(|s| (s.name.as_str(), &s.tx))
+            .chain([(PRIMARY, &self.primary)]);
+        for (name, tx) in targets {
+            tx.send(Message::Change(change.clone())).await?;
+            let (ack_tx, ack_rx) = oneshot::channel();
+            tx.send(Message::Flush(ack_tx)).await?;
+            let failures = ack_rx.await?;
+            let mut settled = self.settled.lock().unwrap();
+            settled.extend(
+                failures
+                    .into_iter()
+                    .map(|(reqnum, error)| (reqnum, name.to_string(), error)),
+            );
+        }
+        Ok(())
+    }
+
+    // The secondary sinks sent changes of the method of `change`. Those that
+    // aren't have nothing to apply nor to fail.
+    fn taking<'a>(&'a self, change: &'a Change) -> impl Iterator<Item = &'a Secondary> {
+        self.secondaries
+            .iter()
+            .filter(|secondary| secondary.methods.contains(&change.method))
+    }
+
+    /// Lets every secondary sink work through its queue and close, e.g. so a
+    /// file sink is fsynced before the process exits.
+    pub async fn shutdown(self) {
+        for secondary in self.secondaries {
+            drop(secondary.tx);
+            if let Err(e) = secondary.task.await {
+                error!(sink = %secondary.name, "Sink task failed: {}", e);
+            }
+        }
+    }
+
+    /// Waits for the sinks the sentinel depends on to settle the changes sent
+    /// so far, returning the `(reqnum, sink, error)` of those that failed.
+    pub async fn flush(&self) -> Result<Vec<Failure>, Box<dyn std::error::Error>> {
+        let mut targets = vec![(PRIMARY, &self.primary)];
+        if self.advance == SentinelAdvance::All {
+            targets.extend(self.secondaries.iter().map(|s| (s.name.as_str(), &s.tx)));
+        }
+
+        let mut acks = Vec::with_capacity(targets.len());
+        for (name, tx) in targets {
+            let (ack_tx, ack_rx) = oneshot::channel();
+            tx.send(Message::Flush(ack_tx)).await?;
+            acks.push((name, ack_rx));
+        }
+
+        let mut failures = std::mem::take(&mut *self.settled.lock().unwrap());
+        for (name, ack) in acks {
+            for (reqnum, error) in ack.await? {
+                failures.push((reqnum, name.to_string(), error));
+            }
+        }
+        Ok(failures)
+    }
+}
+
+async fn run_sink(
+    sink: Box<dyn Sink>,
+    attempts: u32,
+    advance: SentinelAdvance,
+    mut rx: mpsc::Receiver<Message>,
+    primary: mpsc::Sender<Message>,
+) {
+    let mut failed = Vec::new();
+    while let Some(message) = rx.recv().await {
+        match message {
+            Message::Change(change) => {
+                let Err(e) = apply_with_retries(sink.as_ref(), &change, attempts).await else {
+                    continue;
+                };
+                error!(sink = sink.name(), correlation_id = %change.correlation_id, "Sink failed: {}", e);
+                match advance {
+                    // the polling loop retries the event and dead-letters it if need be
+                    SentinelAdvance::All => failed.push((change.reqnum, e)),
+                    SentinelAdvance::Primary => {
+                        let letter = DeadLetter::for_change(&change, sink.name(), e, attempts);
+                        if primary.send(Message::DeadLetter(letter)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            Message::Flush(ack) => {
+                let _ = ack.send(std::mem::take(&mut failed));
+            }
+            // checkpoints and dead letters are the primary writer's business
+            Message::DeadLetter(_)
+            | Message::Checkpoint { .. }
+            | Message::Reset { .. }
+            | Message::Pause { .. } => {}
+        }
+    }
+    if let Err(e) = sink.close().await {
+        error!(sink = sink.name(), "Failed to close sink: {}", e);
+    }
+}
+
+// Tries a change up to `attempts` times, backing off exponentially in between.
+async fn apply_with_retries(sink: &dyn Sink, change: &Change, attempts: u32) -> Result<(), String> {
+    let mut delay = Duration::from_millis(200);
+    let mut attempt = 1;
+    loop {
+        match sink.apply(change).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= attempts => return Err(e),
+            Err(e) => {
+                warn!(sink = sink.name(), attempt, "Sink failed, retrying: {}", e);
+                sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    type Log = Arc<Mutex<Vec<(String, u64)>>>;
+
+    // Records what it applied, slowly enough for an unordered fanout to race.
+    struct Recording {
+        name: &'static str,
+        log: Log,
+    }
+
+    impl Sink for Recording {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn apply<'a>(&'a self, change: &'a Change) -> BoxFuture<'a, Result<(), String>> {
+            Box::pin(async move {
+                sleep(Duration::from_millis(2)).await;
+                let entry = (self.name.to_string(), change.reqnum);
+                self.log.lock().unwrap().push(entry);
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn ordered_fanout_applies_in_lockstep() {
+        let log = Log::default();
+        let (primary, mut rx) = mpsc::channel(SINK_QUEUE);
+        let primary_log = log.clone();
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                match message {
+                    Message::Change(change) => primary_log
+                        .lock()
+                        .unwrap()
+                        .push((PRIMARY.to_string(), change.reqnum)),
+                    Message::Flush(ack) => {
+                        let _ = ack.send(Vec::new());
+                    }
+                    _ => {}
+                }
+            }
+        });
+        let sinks: Vec<Box<dyn Sink>> = ["a", "b"]
+            .into_iter()
+            .map(|name| {
+                Box::new(Recording {
+                    name,
+                    log: log.clone(),
+                }) as Box<dyn Sink>
+            })
+            .collect();
+        let config = Config {
+            ordered_sinks: true,
+            ..Config::for_tests()
+        };
+        let fanout = Fanout::spawn(primary, sinks, &config);
+
+        for reqnum in 1..=3 {
+            fanout.send(Change::fixture(reqnum)).await.unwrap();
+        }
+        assert!(fanout.flush().await.unwrap().is_empty());
+        let expected: Vec<_> = (1..=3)
+            .flat_map(|reqnum| ["a", "b", PRIMARY].map(|name| (name.to_string(), reqnum)))
+            .collect();
+        assert_eq!(*log.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn sinks_are_sent_only_their_methods() {
+        let log = Log::default();
+        let (primary, mut rx) = mpsc::channel(SINK_QUEUE);
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if let Message::Flush(ack) = message {
+                    let _ = ack.send(Vec::new());
+                }
+            }
+        });
+        let sinks: Vec<Box<dyn Sink>> = ["file", "sse"]
+            .into_iter()
+            .map(|name| {
+                Box::new(Recording {
+                    name,
+                    log: log.clone(),
+                }) as Box<dyn Sink>
+            })
+            .collect();
+        let config = Config {
+            sentinel_advance: SentinelAdvance::All,
+            sink_methods: HashMap::from([("file".to_string(), vec![Method::Create])]),
+            ..Config::for_tests()
+        };
+        let fanout = Fanout::spawn(primary, sinks, &config);
+
+        fanout.send(Change::fixture(1)).await.unwrap();
+        fanout
+            .send(Change {
+                method: Method::Create,
+                ..Change::fixture(2)
+            })
+            .await
+            .unwrap();
+        assert!(fanout.flush().await.unwrap().is_empty());
+        let mut applied = log.lock().unwrap().clone();
+        applied.sort();
+        assert_eq!(
+            applied,
+            [
+                ("file".to_string(), 2),
+                ("sse".to_string(), 1),
+                ("sse".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_sink_methods() {
+        assert_eq!(
+            "file:create+update".parse(),
+            Ok(SinkMethods(
+                "file".to_string(),
+                vec![Method::Create, Method::Update]
+            ))
+        );
+        assert!("file".parse::<SinkMethods>().is_err());
+        assert!("postgres:create".parse::<SinkMethods>().is_err());
+        assert!("sse:upsert".parse::<SinkMethods>().is_err());
+    }
+
+    #[test]
+    fn parses_sentinel_advance() {
+        assert_eq!("primary".parse(), Ok(SentinelAdvance::Primary));
+        assert_eq!("all".parse(), Ok(SentinelAdvance::All));
+        assert!("some".parse::<SentinelAdvance>().is_err());
+    }
+}
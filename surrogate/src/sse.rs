@@ -0,0 +1,327 @@
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+use vemodel::Method;
+
+use crate::config::Config;
+use crate::db::{self, Change};
+use crate::metrics;
+use crate::model::Model;
+use crate::sink::{BoxFuture, Sink};
+
+// requests are a line or two, anything past this is cut off
+const MAX_REQUEST: usize = 4096;
+
+// how often an idle stream gets a comment, so proxies don't time it out
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Streams every applied change to web clients as Server-Sent Events, at
+/// `GET /events` on `VE_SSE_ADDR`, one `change` event per change:
+///
+/// ```text
+/// id: 42
+/// event: change
+/// data: {"reqnum":42,"model":"article","method":"Update","id":7,"subspace_id":3,"entity":{...},"correlation_id":"42-article7","source_time":null}
+/// ```
+///
+/// `?subspace=<id>` and `?model=<model>` narrow the stream down. Only
+/// subspaces and articles say which subspace they're in, so a stream of a
+/// subspace has none of its comments nor deletes other than its own.
+///
+/// Changes are streamed as this sink applies them, which may be before
+/// Postgres commits them, and aren't kept: a client only sees those applied
+/// while it's connected. Each client has `VE_SSE_BUFFER` changes of slack;
+/// one falling further behind misses the oldest and is sent a `lagged`
+/// event with how many, ingest never waits for it.
+///
+/// Entities are streamed with the author nickname the read paths serve,
+/// [`db::display_nickname`]'s, not the raw one.
+pub struct EventStream {
+    tx: broadcast::Sender<Arc<Published>>,
+    config: Config,
+}
+
+// A change as it's streamed, serialized once for every client.
+#[derive(Debug)]
+struct Published {
+    model: Model,
+    subspace_id: Option<u64>,
+    frame: String,
+}
+
+#[derive(Serialize)]
+struct Data<'a> {
+    reqnum: u64,
+    model: &'a str,
+    method: Method,
+    id: u64,
+    subspace_id: Option<u64>,
+    entity: serde_json::Value,
+    correlation_id: &'a str,
+    source_time: Option<i64>,
+}
+
+impl EventStream {
+    /// Listens on `addr` and streams to whoever connects from then on, each
+    /// with room for `VE_SSE_BUFFER` changes.
+    pub async fn open(config: &Config, addr: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let (tx, _) = broadcast::channel(config.sse_buffer.max(1));
+        tokio::spawn(serve(listener, tx.clone()));
+        info!("Streaming applied changes on {}", addr);
+        Ok(Self {
+            tx,
+            config: config.clone(),
+        })
+    }
+
+    fn publish(&self, change: &Change) -> serde_json::Result<()> {
+        let model = change.entity.model();
+        let subspace_id = change.entity.subspace_id();
+        let mut entity = change.entity.to_json()?;
+        if let Some(serde_json::Value::String(nickname)) = entity.get_mut("author_nickname") {
+            *nickname = db::display_nickname(&self.config, nickname);
+        }
+        let data = serde_json::to_string(&Data {
+            reqnum: change.reqnum,
+            model: model.as_str(),
+            method: change.method,
+            id: change.entity.id(),
+            subspace_id,
+            entity,
+            correlation_id: &change.correlation_id,
+            source_time: change.source_time,
+        })?;
+        let frame = format!("id: {}\nevent: change\ndata: {}\n\n", change.reqnum, data);
+        // no one listening is no one to tell
+        let _ = self.tx.send(Arc::new(Published {
+            model,
+            subspace_id,
+            frame,
+        }));
+        Ok(())
+    }
+}
+
+impl Sink for EventStream {
+    fn name(&self) -> &str {
+        "sse"
+    }
+
+    fn apply<'a>(&'a self, change: &'a Change) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move { self.publish(change).map_err(|e| e.to_string()) })
+    }
+}
+
+// Which changes a client asked for.
+#[derive(Debug, Default, PartialEq)]
+struct Filter {
+    subspace_id: Option<u64>,
+    model: Option<Model>,
+}
+
+impl Filter {
+    fn matches(&self, published: &Published) -> bool {
+        self.model.map_or(true, |model| model == published.model)
+            && self
+                .subspace_id
+                .map_or(true, |id| published.subspace_id == Some(id))
+    }
+}
+
+// The filter of the request starting with `request`, or the status and body
+// to turn it down with.
+fn parse_request(request: &[u8]) -> Result<Filter, (&'static str, String)> {
+    let line = request.split(|&b| b == b'\n').next().unwrap_or_default();
+    let mut parts = std::str::from_utf8(line)
+        .unwrap_or_default()
+        .split_whitespace();
+    let target = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => target,
+        (Some(_), Some(_)) => {
+            return Err(("405 Method Not Allowed", "method not allowed".to_string()))
+        }
+        _ => return Err(("400 Bad Request", "bad request".to_string())),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/events" {
+        return Err(("404 Not Found", "not found".to_string()));
+    }
+    let mut filter = Filter::default();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let bad = |e: String| ("400 Bad Request", format!("{}: {}", pair, e));
+        match pair.split_once('=') {
+            Some(("subspace", id)) => {
+                filter.subspace_id = Some(id.parse::<u64>().map_err(|e| bad(e.to_string()))?)
+            }
+            Some(("model", model)) => filter.model = Some(model.parse::<Model>().map_err(bad)?),
+            _ => return Err(bad("unknown parameter".to_string())),
+        }
+    }
+    Ok(filter)
+}
+
+async fn serve(listener: TcpListener, tx: broadcast::Sender<Arc<Published>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let rx = tx.subscribe();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    metrics::SSE_CLIENTS.set(tx.receiver_count() as u64);
+                    if let Err(e) = stream_to(stream, rx).await {
+                        // mostly a client going away
+                        info!("Event stream ended: {}", e);
+                    }
+                    // this client's receiver is dropped by now
+                    metrics::SSE_CLIENTS.set(tx.receiver_count() as u64);
+                });
+            }
+            Err(e) => warn!("Failed to accept an event stream client: {}", e),
+        }
+    }
+}
+
+async fn stream_to(
+    mut stream: TcpStream,
+    mut rx: broadcast::Receiver<Arc<Published>>,
+) -> std::io::Result<()> {
+    let mut request = vec![0; MAX_REQUEST];
+    let len = stream.read(&mut request).await?;
+    let filter = match parse_request(&request[..len]) {
+        Ok(filter) => filter,
+        Err((status, body)) => {
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await?;
+            return stream.shutdown().await;
+        }
+    };
+    stream
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n")
+        .await?;
+
+    let mut keep_alive = tokio::time::interval(KEEP_ALIVE);
+    loop {
+        let frame = tokio::select! {
+            received = rx.recv() => match received {
+                Ok(published) if filter.matches(&published) => published.frame.clone(),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    metrics::SSE_DROPPED_EVENTS.add(missed);
+                    format!("event: lagged\ndata: {}\n\n", missed)
+                }
+                Err(RecvError::Closed) => return stream.shutdown().await,
+            },
+            _ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+        };
+        stream.write_all(frame.as_bytes()).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Entity;
+
+    fn published(model: Model, subspace_id: Option<u64>) -> Published {
+        Published {
+            model,
+            subspace_id,
+            frame: String::new(),
+        }
+    }
+
+    #[test]
+    fn parses_the_filters_of_the_request() {
+        assert_eq!(
+            parse_request(b"GET /events HTTP/1.1\r\n"),
+            Ok(Filter::default())
+        );
+        assert_eq!(
+            parse_request(b"GET /events?subspace=3&model=article HTTP/1.1\r\n"),
+            Ok(Filter {
+                subspace_id: Some(3),
+                model: Some(Model::Article),
+            })
+        );
+        assert_eq!(
+            parse_request(b"GET /metrics HTTP/1.1\r\n").unwrap_err().0,
+            "404 Not Found"
+        );
+        assert_eq!(
+            parse_request(b"POST /events HTTP/1.1\r\n").unwrap_err().0,
+            "405 Method Not Allowed"
+        );
+        for bad in ["subspace=three", "model=user", "since=4"] {
+            let request = format!("GET /events?{} HTTP/1.1\r\n", bad);
+            assert_eq!(
+                parse_request(request.as_bytes()).unwrap_err().0,
+                "400 Bad Request"
+            );
+        }
+    }
+
+    #[test]
+    fn filters_by_subspace_and_model() {
+        let article = published(Model::Article, Some(3));
+        let comment = published(Model::Comment, None);
+        assert!(Filter::default().matches(&comment));
+
+        let subspace = Filter {
+            subspace_id: Some(3),
+            model: None,
+        };
+        assert!(subspace.matches(&article));
+        assert!(!subspace.matches(&published(Model::Article, Some(4))));
+        // comments don't say where they are
+        assert!(!subspace.matches(&comment));
+
+        let comments = Filter {
+            subspace_id: None,
+            model: Some(Model::Comment),
+        };
+        assert!(comments.matches(&comment));
+        assert!(!comments.matches(&article));
+    }
+
+    #[test]
+    fn streams_the_nickname_the_read_paths_serve() {
+        let (tx, mut rx) = broadcast::channel(1);
+        let stream = EventStream {
+            tx,
+            config: Config::for_tests(),
+        };
+        let article = serde_json::json!({
+            "id": 7,
+            "title": "Weekly update",
+            "content": "hello",
+            "author_id": 3,
+            "author_nickname": "ad\u{200b}min",
+            "subspace_id": 1,
+            "ext_link": "",
+            "status": 0,
+            "weight": 0,
+            "created_time": 1700000000,
+            "updated_time": 1700000000,
+        });
+        let change = Change {
+            entity: Entity::from_json(Model::Article, article).unwrap(),
+            ..Change::fixture(7)
+        };
+        stream.publish(&change).unwrap();
+        let published = rx.try_recv().unwrap();
+        assert_eq!(published.subspace_id, Some(1));
+        assert!(published.frame.contains(r#""author_nickname":"admin""#));
+    }
+}
@@ -0,0 +1,260 @@
+use serde::Serialize;
+use tokio_postgres::Client;
+use vemodel::{SubspaceId, UserId};
+
+use crate::config::Config;
+use crate::counts::{self, Counted};
+use crate::id::SqlId;
+
+// Every row of authored content that counts with the subspace it counts in:
+// an article its own, a comment that of the article its thread hangs off.
+fn content(config: &Config) -> String {
+    format!(
+        "
+        WITH RECURSIVE thread (id, author_id, status, created_time, post_id, target_type) AS (
+            SELECT id, author_id, status, created_time, post_id, target_type FROM comments
+            UNION
+            SELECT thread.id, thread.author_id, thread.status, thread.created_time, c.post_id, c.target_type
+            FROM thread JOIN comments c ON c.id = thread.post_id
+            WHERE thread.target_type = 'comment'
+        )
+        SELECT subspace_id, author_id, created_time, 'article' AS kind FROM articles
+        UNION ALL
+        SELECT a.subspace_id, thread.author_id, thread.created_time, 'comment'
+        FROM thread JOIN articles a ON a.id = thread.post_id
+        WHERE thread.target_type = 'article' AND {}",
+        counts::comment_counts(config, "thread.status", "a.subspace_id")
+    )
+}
+
+// The subspace and author of comment $1, up its thread if it's a reply, if it counts.
+fn comment_contribution(config: &Config) -> String {
+    format!(
+        "
+        WITH RECURSIVE thread (author_id, status, post_id, target_type) AS (
+            SELECT author_id, status, post_id, target_type FROM comments WHERE id = $1
+            UNION
+            SELECT thread.author_id, thread.status, c.post_id, c.target_type
+            FROM thread JOIN comments c ON c.id = thread.post_id
+            WHERE thread.target_type = 'comment'
+        )
+        SELECT a.subspace_id, thread.author_id
+        FROM thread JOIN articles a ON a.id = thread.post_id
+        WHERE thread.target_type = 'article' AND {}",
+        counts::comment_counts(config, "thread.status", "a.subspace_id")
+    )
+}
+
+/// The totals `subspace_stats` keeps per subspace, for dashboards to read
+/// with one cheap query rather than aggregate over the content.
+///
+/// Like the per-author counts they're moved along in the transaction of the
+/// change that creates, moves or deletes a row, see [`shift`]. Comments
+/// count in the subspace of their article, replies in that of the article
+/// their thread hangs off, and those whose article isn't indexed nowhere.
+/// In the subspaces of `VE_MODERATED_SUBSPACES` they only count once
+/// approved, see [`counts::comment_counts`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SubspaceStats {
+    pub subspace_id: SubspaceId,
+    pub article_count: i64,
+    pub comment_count: i64,
+    /// Authors with any article or comment in the subspace.
+    pub active_authors: i64,
+    /// Newest `created_time` of its content so far. Deleting that content
+    /// doesn't take it back.
+    pub last_activity_time: Option<i64>,
+}
+
+/// Where a row of authored content counts: its subspace and its author.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contribution {
+    pub subspace_id: SubspaceId,
+    pub author_id: UserId,
+}
+
+/// Where row `id` counts as stored, `None` without one or, for a comment,
+/// without its article or while it doesn't count.
+pub async fn contribution_of(
+    client: &Client,
+    config: &Config,
+    counted: Counted,
+    id: u64,
+) -> Result<Option<Contribution>, tokio_postgres::Error> {
+    let query = match counted {
+        Counted::Articles => {
+            "SELECT subspace_id, author_id FROM articles WHERE id = $1".to_string()
+        }
+        Counted::Comments => comment_contribution(config),
+    };
+    let row = client.query_opt(&query, &[&SqlId(id)]).await?;
+    Ok(row.map(|row| Contribution {
+        subspace_id: SubspaceId(row.get::<_, SqlId>("subspace_id").0),
+        author_id: UserId(row.get::<_, SqlId>("author_id").0),
+    }))
+}
+
+/// Moves a row's count from where it counted before a change to where it
+/// does after, bumping the last activity of the latter to `activity`, the
+/// row's `created_time`.
+///
+/// An article moving to another subspace takes its comments along, so both
+/// subspaces are recomputed from their rows instead, see [`recompute`].
+pub async fn shift(
+    client: &Client,
+    config: &Config,
+    counted: Counted,
+    from: Option<Contribution>,
+    to: Option<Contribution>,
+    activity: Option<i64>,
+) -> Result<(), tokio_postgres::Error> {
+    if let (Counted::Articles, Some(from), Some(to)) = (counted, from, to) {
+        if from.subspace_id != to.subspace_id {
+            recompute(client, config, from.subspace_id).await?;
+            return recompute(client, config, to.subspace_id).await;
+        }
+    }
+    for (contribution, delta) in counts::deltas(from, to) {
+        let activity = activity.filter(|_| delta > 0);
+        apply(client, counted, contribution, delta, activity).await?;
+    }
+    Ok(())
+}
+
+async fn apply(
+    client: &Client,
+    counted: Counted,
+    contribution: Contribution,
+    delta: i64,
+    activity: Option<i64>,
+) -> Result<(), tokio_postgres::Error> {
+    let (subspace_id, author_id) = (
+        SqlId(contribution.subspace_id.0),
+        SqlId(contribution.author_id.0),
+    );
+    // an author first seen on a delete starts from 0 rather than -1
+    let row = client
+        .query_one(
+            "INSERT INTO subspace_authors (subspace_id, author_id, items)
+             VALUES ($1, $2, GREATEST($3::BIGINT, 0))
+             ON CONFLICT (subspace_id, author_id) DO UPDATE SET
+                items = subspace_authors.items + $3::BIGINT
+             RETURNING items, (xmax = 0) AS inserted",
+            &[&subspace_id, &author_id, &delta],
+        )
+        .await?;
+    let (items, inserted): (i64, bool) = (row.get("items"), row.get("inserted"));
+    let before = if inserted { 0 } else { items - delta };
+    if items <= 0 {
+        client
+            .execute(
+                "DELETE FROM subspace_authors WHERE subspace_id = $1 AND author_id = $2",
+                &[&subspace_id, &author_id],
+            )
+            .await?;
+    }
+
+    let query = format!(
+        "INSERT INTO subspace_stats (subspace_id, {column}, active_authors, last_activity_time)
+         VALUES ($1, GREATEST($2::BIGINT, 0), GREATEST($3::BIGINT, 0), $4)
+         ON CONFLICT (subspace_id) DO UPDATE SET
+            {column} = subspace_stats.{column} + $2::BIGINT,
+            active_authors = subspace_stats.active_authors + $3::BIGINT,
+            last_activity_time = GREATEST(subspace_stats.last_activity_time, $4)",
+        column = counted.column()
+    );
+    client
+        .execute(
+            &query,
+            &[
+                &subspace_id,
+                &delta,
+                &active_delta(before, items),
+                &activity,
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+// How the number of active authors changes with one of them going from
+// `before` to `after` items in the subspace.
+fn active_delta(before: i64, after: i64) -> i64 {
+    i64::from(after > 0) - i64::from(before > 0)
+}
+
+/// Recomputes the stats of subspace `id` from its rows. Scans all comments
+/// to find those in it, meant for the rare move and for repairs.
+pub async fn recompute(
+    client: &Client,
+    config: &Config,
+    id: SubspaceId,
+) -> Result<(), tokio_postgres::Error> {
+    let id = SqlId(id.0);
+    client
+        .execute(
+            "DELETE FROM subspace_authors WHERE subspace_id = $1",
+            &[&id],
+        )
+        .await?;
+    let authors = format!(
+        "INSERT INTO subspace_authors (subspace_id, author_id, items)
+         SELECT subspace_id, author_id, COUNT(*) FROM ({}) content
+         WHERE subspace_id = $1 GROUP BY subspace_id, author_id",
+        content(config)
+    );
+    client.execute(&authors, &[&id]).await?;
+    let totals = format!(
+        "INSERT INTO subspace_stats (subspace_id, article_count, comment_count, active_authors, last_activity_time)
+         SELECT $1::{},
+                COUNT(*) FILTER (WHERE kind = 'article'),
+                COUNT(*) FILTER (WHERE kind = 'comment'),
+                COUNT(DISTINCT author_id),
+                MAX(created_time)
+         FROM ({}) content WHERE subspace_id = $1
+         ON CONFLICT (subspace_id) DO UPDATE SET
+            article_count = EXCLUDED.article_count,
+            comment_count = EXCLUDED.comment_count,
+            active_authors = EXCLUDED.active_authors,
+            last_activity_time = EXCLUDED.last_activity_time",
+        config.id_column_type.as_sql(),
+        content(config)
+    );
+    client.execute(&totals, &[&id]).await?;
+    Ok(())
+}
+
+/// The stats of subspace `id`, `None` until any content has counted in it.
+pub async fn get(
+    client: &Client,
+    id: SubspaceId,
+) -> Result<Option<SubspaceStats>, tokio_postgres::Error> {
+    let row = client
+        .query_opt(
+            "SELECT * FROM subspace_stats WHERE subspace_id = $1",
+            &[&SqlId(id.0)],
+        )
+        .await?;
+    Ok(row.map(|row| SubspaceStats {
+        subspace_id: id,
+        article_count: row.get("article_count"),
+        comment_count: row.get("comment_count"),
+        active_authors: row.get("active_authors"),
+        last_activity_time: row.get("last_activity_time"),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authors_are_active_while_they_have_content() {
+        assert_eq!(active_delta(0, 1), 1);
+        assert_eq!(active_delta(1, 2), 0);
+        assert_eq!(active_delta(2, 1), 0);
+        assert_eq!(active_delta(1, 0), -1);
+        // first seen on a delete
+        assert_eq!(active_delta(0, 0), 0);
+    }
+}
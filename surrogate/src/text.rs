@@ -0,0 +1,160 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Strips markdown syntax and HTML tags from `input`, returning plain text with
+/// all runs of whitespace collapsed to a single space.
+pub fn strip_markup(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for line in input.lines() {
+        let line = line.trim_start();
+        // code fences carry no text of their own, only their contents matter
+        if line.starts_with("```") || line.starts_with("~~~") {
+            continue;
+        }
+        strip_inline(strip_block_marker(line), &mut out);
+        out.push(' ');
+    }
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Builds a feed excerpt from markdown/HTML `content`: the plain text is cut to
+/// at most `max_graphemes` graphemes, backing off to the previous word boundary
+/// and followed by an ellipsis when anything was cut.
+pub fn excerpt(content: &str, max_graphemes: usize) -> String {
+    let plain = strip_markup(content);
+    let graphemes: Vec<&str> = plain.graphemes(true).collect();
+    if graphemes.len() <= max_graphemes {
+        return plain;
+    }
+
+    let mut cut = graphemes[..max_graphemes].concat();
+    // only back off when we stopped in the middle of a word, and never to nothing
+    if graphemes[max_graphemes] != " " {
+        if let Some(pos) = cut.rfind(' ') {
+            cut.truncate(pos);
+        }
+    }
+    let mut cut = cut.trim_end().to_string();
+    cut.push('…');
+    cut
+}
+
+// Removes heading, quote and list markers from the start of a line.
+fn strip_block_marker(line: &str) -> &str {
+    let mut line = line;
+    while let Some(rest) = line.strip_prefix('>') {
+        line = rest.trim_start();
+    }
+
+    let hashes = line.len() - line.trim_start_matches('#').len();
+    if hashes > 0 && line[hashes..].starts_with(' ') {
+        return line[hashes..].trim_start();
+    }
+
+    for marker in ["- ", "* ", "+ "] {
+        if let Some(rest) = line.strip_prefix(marker) {
+            return rest;
+        }
+    }
+
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits > 0 {
+        if let Some(rest) = line[digits..].strip_prefix(". ") {
+            return rest;
+        }
+    }
+
+    line
+}
+
+// Drops html tags, emphasis markers and link targets, keeping the visible text.
+fn strip_inline(line: &str, out: &mut String) {
+    let chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '<' => match chars[i..].iter().position(|&c| c == '>') {
+                Some(end) => {
+                    // keep words on either side of a tag apart, e.g. `a<br>b`
+                    out.push(' ');
+                    i += end;
+                }
+                None => out.push(c),
+            },
+            '!' if chars.get(i + 1) == Some(&'[') => {}
+            '[' => {}
+            ']' => {
+                // `[text](target)` keeps the text and drops the target
+                if chars.get(i + 1) == Some(&'(') {
+                    if let Some(end) = chars[i..].iter().position(|&c| c == ')') {
+                        i += end;
+                    }
+                }
+            }
+            '*' | '`' | '~' => {}
+            '_' => {
+                // keep underscores inside words such as snake_case identifiers
+                let inside_word = i > 0
+                    && chars[i - 1].is_alphanumeric()
+                    && chars.get(i + 1).is_some_and(|c| c.is_alphanumeric());
+                if inside_word {
+                    out.push(c);
+                }
+            }
+            _ => out.push(c),
+        }
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_markdown_and_html() {
+        let content = "# Title\n\n> quoted *bold* and `code`\n\n- [a link](https://x.y) <b>tag</b>\n1. snake_case";
+        assert_eq!(
+            strip_markup(content),
+            "Title quoted bold and code a link tag snake_case"
+        );
+    }
+
+    #[test]
+    fn drops_code_fences_and_images() {
+        let content = "```rust\nlet x = 1;\n```\n![alt text](img.png)";
+        assert_eq!(strip_markup(content), "let x = 1; alt text");
+    }
+
+    #[test]
+    fn subspace_description_becomes_one_line() {
+        let description = "## About\n\nTalk about **Rust**, see the [book](https://doc.rust-lang.org/book/).\n\n* be kind\n* no spam";
+        assert_eq!(
+            strip_markup(description),
+            "About Talk about Rust, see the book. be kind no spam"
+        );
+    }
+
+    #[test]
+    fn short_content_is_not_truncated() {
+        assert_eq!(excerpt("hello *world*", 20), "hello world");
+    }
+
+    #[test]
+    fn truncates_at_word_boundary() {
+        assert_eq!(excerpt("the quick brown fox", 12), "the quick…");
+        assert_eq!(excerpt("the quick brown fox", 10), "the quick…");
+    }
+
+    #[test]
+    fn counts_graphemes_not_bytes() {
+        // each flag is one grapheme made of two code points
+        let content = "🇯🇵🇯🇵 🇯🇵🇯🇵";
+        assert_eq!(excerpt(content, 3), "🇯🇵🇯🇵…");
+    }
+
+    #[test]
+    fn long_single_word_is_hard_cut() {
+        assert_eq!(excerpt("abcdefghij", 4), "abcd…");
+    }
+}
@@ -0,0 +1,220 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use tokio_postgres::Client;
+
+use crate::id::SqlId;
+
+// comments walked up a thread at most, however deep it is or loops
+const MAX_WALK: i32 = 1000;
+
+/// What becomes of a reply deeper than `VE_MAX_COMMENT_DEPTH`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeepReplies {
+    /// Fail it, so it's dead-lettered once out of attempts.
+    Reject,
+    /// Store it as a reply to its ancestor at the depth above the maximum.
+    Flatten,
+}
+
+impl FromStr for DeepReplies {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "flatten" => Ok(Self::Flatten),
+            _ => Err(format!("unknown deep reply policy: {}", s)),
+        }
+    }
+}
+
+/// What a comment replies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parent {
+    Article(u64),
+    Comment(u64),
+}
+
+/// Why a reply can't be stored where the nucleus puts it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ThreadError {
+    /// The reply would be its own ancestor, through these comments.
+    Cycle(Vec<u64>),
+    /// The reply is deeper than allowed, at least `depth` with the thread
+    /// walked only so far.
+    TooDeep { depth: usize, max: usize },
+}
+
+impl std::fmt::Display for ThreadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cycle(chain) => {
+                write!(f, "reply would close a cycle through comments {:?}", chain)
+            }
+            Self::TooDeep { depth, max } => {
+                write!(f, "reply at depth {} is past the maximum of {}", depth, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ThreadError {}
+
+/// Where reply `id` to comment `parent` goes, going by what the comments up
+/// its thread reply to in `parents`. Comments replying to an article are at
+/// depth 1, their replies at 2 and so on.
+///
+/// A reply that is, or would be, one of its own ancestors fails, and a
+/// thread that already loops above it fails it too. So does a reply past
+/// `max_depth` with [`DeepReplies::Reject`], one flattened goes to its
+/// ancestor at `max_depth - 1`, or to the article at a maximum of 1.
+pub fn place(
+    id: u64,
+    parent: u64,
+    parents: &HashMap<u64, Parent>,
+    max_depth: Option<usize>,
+    deep: DeepReplies,
+) -> Result<Parent, ThreadError> {
+    // from the parent up to the comment on the article, when the walk got there
+    let mut chain = vec![parent];
+    let mut seen = HashSet::from([parent]);
+    let root = loop {
+        let last = chain[chain.len() - 1];
+        if last == id {
+            return Err(ThreadError::Cycle(chain));
+        }
+        match parents.get(&last) {
+            Some(&Parent::Comment(up)) => {
+                chain.push(up);
+                if !seen.insert(up) {
+                    return Err(ThreadError::Cycle(chain));
+                }
+            }
+            Some(&Parent::Article(article)) => break Some(article),
+            None => break None,
+        }
+    };
+
+    let depth = chain.len() + 1;
+    let Some(max) = max_depth.filter(|&max| depth > max) else {
+        return Ok(Parent::Comment(parent));
+    };
+    match (deep, root) {
+        (DeepReplies::Flatten, Some(article)) if max == 1 => Ok(Parent::Article(article)),
+        (DeepReplies::Flatten, Some(_)) => Ok(Parent::Comment(chain[chain.len() - (max - 1)])),
+        _ => Err(ThreadError::TooDeep { depth, max }),
+    }
+}
+
+/// What the comments up the thread from comment `parent` reply to, for
+/// [`place`], as far as `MAX_WALK` comments up.
+pub async fn ancestry(
+    client: &Client,
+    parent: SqlId,
+) -> Result<HashMap<u64, Parent>, tokio_postgres::Error> {
+    let rows = client
+        .query(
+            "WITH RECURSIVE up (id, post_id, target_type, depth) AS (
+                 SELECT id, post_id, target_type, 1 FROM comments WHERE id = $1
+                 UNION ALL
+                 SELECT c.id, c.post_id, c.target_type, up.depth + 1
+                 FROM up JOIN comments c ON c.id = up.post_id
+                 WHERE up.target_type = 'comment' AND up.depth < $2
+             )
+             SELECT id, post_id, target_type FROM up",
+            &[&parent, &MAX_WALK],
+        )
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let post_id = row.get::<_, SqlId>("post_id").0;
+            let parent = match row.get::<_, &str>("target_type") {
+                "comment" => Parent::Comment(post_id),
+                _ => Parent::Article(post_id),
+            };
+            (row.get::<_, SqlId>("id").0, parent)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1 is on article 100, 2 replies to it, 3 to 2
+    fn thread() -> HashMap<u64, Parent> {
+        HashMap::from([
+            (1, Parent::Article(100)),
+            (2, Parent::Comment(1)),
+            (3, Parent::Comment(2)),
+        ])
+    }
+
+    #[test]
+    fn a_self_referential_comment_is_a_cycle() {
+        assert_eq!(
+            place(5, 5, &HashMap::new(), None, DeepReplies::Reject),
+            Err(ThreadError::Cycle(vec![5]))
+        );
+    }
+
+    #[test]
+    fn a_three_comment_cycle_is_found() {
+        // 1 replying to 3 would close 1 -> 3 -> 2 -> 1
+        assert_eq!(
+            place(1, 3, &thread(), None, DeepReplies::Reject),
+            Err(ThreadError::Cycle(vec![3, 2, 1]))
+        );
+        // and one already there above a new reply fails it too
+        let looped = HashMap::from([
+            (1, Parent::Comment(3)),
+            (2, Parent::Comment(1)),
+            (3, Parent::Comment(2)),
+        ]);
+        assert!(matches!(
+            place(4, 3, &looped, Some(10), DeepReplies::Flatten),
+            Err(ThreadError::Cycle(_))
+        ));
+    }
+
+    #[test]
+    fn deep_replies_are_rejected_or_flattened() {
+        // a reply to 3 is at depth 4
+        assert_eq!(
+            place(4, 3, &thread(), Some(4), DeepReplies::Reject),
+            Ok(Parent::Comment(3))
+        );
+        assert_eq!(
+            place(4, 3, &thread(), Some(3), DeepReplies::Reject),
+            Err(ThreadError::TooDeep { depth: 4, max: 3 })
+        );
+        assert_eq!(
+            place(4, 3, &thread(), Some(3), DeepReplies::Flatten),
+            Ok(Parent::Comment(2))
+        );
+        assert_eq!(
+            place(4, 3, &thread(), Some(2), DeepReplies::Flatten),
+            Ok(Parent::Comment(1))
+        );
+        assert_eq!(
+            place(4, 3, &thread(), Some(1), DeepReplies::Flatten),
+            Ok(Parent::Article(100))
+        );
+        // walked only so far, there's no telling where the top is
+        let partial = HashMap::from([(3, Parent::Comment(2))]);
+        assert_eq!(
+            place(4, 3, &partial, Some(1), DeepReplies::Flatten),
+            Err(ThreadError::TooDeep { depth: 3, max: 1 })
+        );
+    }
+
+    #[test]
+    fn parses_deep_replies() {
+        assert_eq!("reject".parse(), Ok(DeepReplies::Reject));
+        assert_eq!("flatten".parse(), Ok(DeepReplies::Flatten));
+        assert!("prune".parse::<DeepReplies>().is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use serde::Serialize;
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::{debug, error};
+
+use vemodel::VeArticle;
+
+use crate::config::Config;
+use crate::{db, query};
+
+/// Creates `trending_articles`, one row per visible article with its
+/// comment count and a score that decays with age:
+///
+/// ```text
+/// score = (comments + 1) / (age in hours + 2) ^ 1.8
+/// ```
+///
+/// Comments are those of `visible_comments`, so the approved ones only in
+/// moderated subspaces. There are no votes to count yet. The view is only as fresh as its last
+/// refresh, see [`run_refresh`]. It sits on top of the visibility views, so
+/// it's recreated along with them on every start.
+pub async fn create_view<C: tokio_postgres::GenericClient>(
+    client: &C,
+) -> Result<(), tokio_postgres::Error> {
+    client
+        .batch_execute(
+            "
+            CREATE MATERIALIZED VIEW trending_articles AS
+                SELECT a.id,
+                       COUNT(c.id) AS comment_count,
+                       ((COUNT(c.id) + 1)
+                           / power(GREATEST(EXTRACT(EPOCH FROM now()) - a.created_time, 0) / 3600 + 2, 1.8)
+                       )::DOUBLE PRECISION AS score
+                FROM visible_articles a
+                LEFT JOIN visible_comments c ON c.post_id = a.id
+                GROUP BY a.id, a.created_time;
+
+            -- REFRESH ... CONCURRENTLY needs a unique index
+            CREATE UNIQUE INDEX trending_articles_id ON trending_articles (id);
+            CREATE INDEX trending_articles_score ON trending_articles (score DESC);
+            ",
+        )
+        .await
+}
+
+pub async fn drop_view<C: tokio_postgres::GenericClient>(
+    client: &C,
+) -> Result<(), tokio_postgres::Error> {
+    client
+        .batch_execute("DROP MATERIALIZED VIEW IF EXISTS trending_articles")
+        .await
+}
+
+/// Refreshes `trending_articles` every `VE_TRENDING_REFRESH_SECS` for as long
+/// as the surrogate runs. Uses a connection of its own, the writer's sits in
+/// an open transaction most of the time.
+pub async fn run_refresh(config: Config, interval: Duration) {
+    let client = match db::connect(&config).await {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Trending refresh can't connect, not running: {}", e);
+            return;
+        }
+    };
+    loop {
+        tokio::time::sleep(interval).await;
+        // concurrently, so reads carry on against the old contents meanwhile
+        match client
+            .batch_execute("REFRESH MATERIALIZED VIEW CONCURRENTLY trending_articles")
+            .await
+        {
+            Ok(()) => debug!("Refreshed trending_articles"),
+            Err(e) => error!("Failed to refresh trending_articles: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrendingArticle {
+    pub article: VeArticle,
+    pub comment_count: i64,
+    pub score: f64,
+}
+
+/// Lists the top trending articles as of the last refresh, leaving out any
+/// hidden since.
+pub async fn list(
+    client: &Client,
+    limit: i64,
+) -> Result<Vec<TrendingArticle>, Box<dyn std::error::Error>> {
+    let rows = client
+        .query(
+            "SELECT a.*, t.comment_count, t.score
+             FROM trending_articles t
+             JOIN visible_articles a ON a.id = t.id
+             ORDER BY t.score DESC, a.id
+             LIMIT $1",
+            &[&limit],
+        )
+        .await?;
+    rows.iter()
+        .map(|row| {
+            Ok(TrendingArticle {
+                article: query::article_from_row(row)?,
+                comment_count: row.get("comment_count"),
+                score: row.get("score"),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,73 @@
+use jsonrpsee::core::client::ClientT;
+use jsonrpsee::http_client::HttpClient;
+use jsonrpsee::rpc_params;
+use parity_scale_codec::{Decode, Encode};
+
+use vemodel::{VeArticle, VeComment, VeSubspace};
+
+use crate::model::Registered;
+use crate::rpc;
+
+// errors kept per model, enough to tell one kind of drift from another
+const SAMPLE_ERRORS: usize = 3;
+
+/// How decoding a sample of one model went.
+#[derive(Debug, Default)]
+pub struct ModelReport {
+    pub model: &'static str,
+    pub decoded: usize,
+    /// Ids the nucleus has no entity for, nothing to decode.
+    pub missing: usize,
+    pub failed: usize,
+    /// The first few failures, as `(id, error)`.
+    pub errors: Vec<(u64, String)>,
+}
+
+/// Fetches ids `1..=sample` of every model from the nucleus and tries to
+/// decode them with the `vemodel` this is built against. Read-only, nothing
+/// touches the database.
+pub async fn verify_decode(
+    http_client: &HttpClient,
+    avs_id: &str,
+    sample: u64,
+) -> Result<Vec<ModelReport>, Box<dyn std::error::Error>> {
+    Ok(vec![
+        verify_model::<VeSubspace>(http_client, avs_id, sample).await?,
+        verify_model::<VeArticle>(http_client, avs_id, sample).await?,
+        verify_model::<VeComment>(http_client, avs_id, sample).await?,
+    ])
+}
+
+async fn verify_model<T: Decode + Registered>(
+    http_client: &HttpClient,
+    avs_id: &str,
+    sample: u64,
+) -> Result<ModelReport, Box<dyn std::error::Error>> {
+    let info = T::MODEL.info();
+    let mut report = ModelReport {
+        model: info.name,
+        ..Default::default()
+    };
+    for id in 1..=sample {
+        // ids encode like the bare u64, whichever model they belong to
+        let params = rpc_params![avs_id, info.fetch, hex::encode(id.encode())];
+        let res: serde_json::Value = http_client.request("nucleus_get", params).await?;
+        let error = match rpc::decode_response::<Result<Option<T>, String>>(&res) {
+            Ok(Ok(Some(_))) => {
+                report.decoded += 1;
+                continue;
+            }
+            Ok(Ok(None)) => {
+                report.missing += 1;
+                continue;
+            }
+            Ok(Err(e)) => format!("nucleus error: {}", e),
+            Err(e) => e.to_string(),
+        };
+        report.failed += 1;
+        if report.errors.len() < SAMPLE_ERRORS {
+            report.errors.push((id, error));
+        }
+    }
+    Ok(report)
+}
@@ -5,8 +5,8 @@ use std::isize;
 use vrs_core_sdk::{get, post, storage};
 
 use vemodel::{
-    Method, VeArticle, VeComment, VeSubspace, COMMON_KEY, PREFIX_ARTICLE_KEY, PREFIX_COMMENT_KEY,
-    PREFIX_SUBSPACE_KEY, REQNUM_KEY,
+    ArticleId, CommentId, Method, SubspaceId, VeArticle, VeComment, VeSubspace, COMMON_KEY,
+    PREFIX_ARTICLE_KEY, PREFIX_COMMENT_KEY, PREFIX_SUBSPACE_KEY, REQNUM_KEY,
 };
 
 // subspace
@@ -23,7 +23,7 @@ pub fn add_subspace(
 
     let max_id = get_max_id(PREFIX_SUBSPACE_KEY);
     // update the id field from the avs
-    sb.id = max_id;
+    sb.id = SubspaceId(max_id);
     let key = build_key(PREFIX_SUBSPACE_KEY, max_id);
     storage::put(&key, sb.encode()).map_err(|e| e.to_string())?;
 
@@ -44,7 +44,7 @@ pub fn update_subspace(
     };
 
     let id = sb.id;
-    let key = build_key(PREFIX_SUBSPACE_KEY, id);
+    let key = build_key(PREFIX_SUBSPACE_KEY, id.into());
     storage::put(&key, sb.encode()).map_err(|e| e.to_string())?;
 
     add_to_common_key(Method::Update, key)?;
@@ -53,12 +53,17 @@ pub fn update_subspace(
 }
 
 #[post]
-pub fn delete_subspace(id: u64, account: String, msg: String, sig: String) -> Result<(), String> {
+pub fn delete_subspace(
+    id: SubspaceId,
+    account: String,
+    msg: String,
+    sig: String,
+) -> Result<(), String> {
     if !validate(&account, &msg, &sig)? {
         return Err("signature validation error".to_string());
     };
 
-    let key = build_key(PREFIX_SUBSPACE_KEY, id);
+    let key = build_key(PREFIX_SUBSPACE_KEY, id.into());
     storage::del(&key).map_err(|e| e.to_string())?;
 
     add_to_common_key(Method::Delete, key)?;
@@ -67,8 +72,8 @@ pub fn delete_subspace(id: u64, account: String, msg: String, sig: String) -> Re
 }
 
 #[get]
-pub fn get_subspace(id: u64) -> Result<Option<VeSubspace>, String> {
-    let key = build_key(PREFIX_SUBSPACE_KEY, id);
+pub fn get_subspace(id: SubspaceId) -> Result<Option<VeSubspace>, String> {
+    let key = build_key(PREFIX_SUBSPACE_KEY, id.into());
     let r = storage::get(&key).map_err(|e| e.to_string())?;
     let instance = r.map(|d| VeSubspace::decode(&mut &d[..]).unwrap());
     Ok(instance)
@@ -88,7 +93,7 @@ pub fn add_article(
 
     let max_id = get_max_id(PREFIX_ARTICLE_KEY);
     // update the id field from the avs
-    sb.id = max_id;
+    sb.id = ArticleId(max_id);
     let key = build_key(PREFIX_ARTICLE_KEY, max_id);
     storage::put(&key, sb.encode()).map_err(|e| e.to_string())?;
     add_to_common_key(Method::Create, key)?;
@@ -108,7 +113,7 @@ pub fn update_article(
     };
 
     let id = sb.id;
-    let key = build_key(PREFIX_ARTICLE_KEY, id);
+    let key = build_key(PREFIX_ARTICLE_KEY, id.into());
     storage::put(&key, sb.encode()).map_err(|e| e.to_string())?;
     add_to_common_key(Method::Update, key)?;
 
@@ -116,12 +121,17 @@ pub fn update_article(
 }
 
 #[post]
-pub fn delete_article(id: u64, account: String, msg: String, sig: String) -> Result<(), String> {
+pub fn delete_article(
+    id: ArticleId,
+    account: String,
+    msg: String,
+    sig: String,
+) -> Result<(), String> {
     if !validate(&account, &msg, &sig)? {
         return Err("signature validation error".to_string());
     };
 
-    let key = build_key(PREFIX_ARTICLE_KEY, id);
+    let key = build_key(PREFIX_ARTICLE_KEY, id.into());
     storage::del(&key).map_err(|e| e.to_string())?;
     add_to_common_key(Method::Delete, key)?;
 
@@ -129,8 +139,8 @@ pub fn delete_article(id: u64, account: String, msg: String, sig: String) -> Res
 }
 
 #[get]
-pub fn get_article(id: u64) -> Result<Option<VeArticle>, String> {
-    let key = build_key(PREFIX_ARTICLE_KEY, id);
+pub fn get_article(id: ArticleId) -> Result<Option<VeArticle>, String> {
+    let key = build_key(PREFIX_ARTICLE_KEY, id.into());
     let r = storage::get(&key).map_err(|e| e.to_string())?;
     let instance = r.map(|d| VeArticle::decode(&mut &d[..]).unwrap());
     Ok(instance)
@@ -150,7 +160,7 @@ pub fn add_comment(
 
     let max_id = get_max_id(PREFIX_COMMENT_KEY);
     // update the id field from the avs
-    sb.id = max_id;
+    sb.id = CommentId(max_id);
     let key = build_key(PREFIX_COMMENT_KEY, max_id);
     storage::put(&key, sb.encode()).map_err(|e| e.to_string())?;
     add_to_common_key(Method::Create, key)?;
@@ -170,7 +180,7 @@ pub fn update_comment(
     };
 
     let id = sb.id;
-    let key = build_key(PREFIX_COMMENT_KEY, id);
+    let key = build_key(PREFIX_COMMENT_KEY, id.into());
     storage::put(&key, sb.encode()).map_err(|e| e.to_string())?;
     add_to_common_key(Method::Update, key)?;
 
@@ -178,12 +188,17 @@ pub fn update_comment(
 }
 
 #[post]
-pub fn delete_comment(id: u64, account: String, msg: String, sig: String) -> Result<(), String> {
+pub fn delete_comment(
+    id: CommentId,
+    account: String,
+    msg: String,
+    sig: String,
+) -> Result<(), String> {
     if !validate(&account, &msg, &sig)? {
         return Err("signature validation error".to_string());
     };
 
-    let key = build_key(PREFIX_COMMENT_KEY, id);
+    let key = build_key(PREFIX_COMMENT_KEY, id.into());
     storage::del(&key).map_err(|e| e.to_string())?;
     add_to_common_key(Method::Delete, key)?;
 
@@ -191,8 +206,8 @@ pub fn delete_comment(id: u64, account: String, msg: String, sig: String) -> Res
 }
 
 #[get]
-pub fn get_comment(id: u64) -> Result<Option<VeComment>, String> {
-    let key = build_key(PREFIX_COMMENT_KEY, id);
+pub fn get_comment(id: CommentId) -> Result<Option<VeComment>, String> {
+    let key = build_key(PREFIX_COMMENT_KEY, id.into());
     let r = storage::get(&key).map_err(|e| e.to_string())?;
     let instance = r.map(|d| VeComment::decode(&mut &d[..]).unwrap());
     Ok(instance)
@@ -206,6 +221,17 @@ pub fn check_all_range() -> Result<(), String> {
     Ok(())
 }
 
+/// The reqnum of the latest change, 0 before the first one. Unlike
+/// `get_reqnum`, reading it doesn't use one up.
+#[get]
+pub fn get_head_reqnum() -> Result<u64, String> {
+    let res = storage::get(REQNUM_KEY).map_err(|e| e.to_string())?;
+    match res {
+        Some(res) => u64::decode(&mut &res[..]).map_err(|e| e.to_string()),
+        None => Ok(0),
+    }
+}
+
 //
 //
 fn add_to_common_key(method: Method, model_ins: Vec<u8>) -> Result<(), String> {
@@ -228,6 +254,23 @@ fn add_to_common_key(method: Method, model_ins: Vec<u8>) -> Result<(), String> {
 
 #[post]
 pub fn get_from_common_key(sentinel: u64) -> Result<Vec<(u64, Method, Vec<u8>)>, String> {
+    take_from_common_key(sentinel, usize::MAX)
+}
+
+/// Like `get_from_common_key`, but returns no more than `limit` of the events
+/// after `sentinel`, the others are kept for the calls that follow.
+#[post]
+pub fn get_page_from_common_key(
+    sentinel: u64,
+    limit: u32,
+) -> Result<Vec<(u64, Method, Vec<u8>)>, String> {
+    take_from_common_key(sentinel, limit as usize)
+}
+
+fn take_from_common_key(
+    sentinel: u64,
+    limit: usize,
+) -> Result<Vec<(u64, Method, Vec<u8>)>, String> {
     let res = storage::get(COMMON_KEY).map_err(|e| e.to_string())?;
     if let Some(res) = res {
         let mut avec = Vec::<(u64, Method, Vec<u8>)>::decode(&mut &res[..]).unwrap();
@@ -239,9 +282,10 @@ pub fn get_from_common_key(sentinel: u64) -> Result<Vec<(u64, Method, Vec<u8>)>,
                 break;
             }
         }
-        let last_part = avec.split_off((index + 1) as usize);
+        let mut last_part = avec.split_off((index + 1) as usize);
 
         _ = storage::put(COMMON_KEY, last_part.encode()).map_err(|e| e.to_string());
+        last_part.truncate(limit);
         return Ok(last_part);
     }
 
@@ -1,7 +1,126 @@
 use parity_scale_codec::{Decode, Encode};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug, Decode, Encode, Deserialize, Serialize)]
+// Ids are distinct types per model so that, say, an article id can't be passed
+// where a subspace id is expected. They encode exactly like the bare `u64`, in
+// SCALE and in serde, so the wire format is unchanged.
+macro_rules! id_type {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(
+            Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Decode, Encode,
+            Deserialize, Serialize,
+        )]
+        #[serde(transparent)]
+        pub struct $name(pub u64);
+
+        impl From<u64> for $name {
+            fn from(id: u64) -> Self {
+                Self(id)
+            }
+        }
+
+        impl From<$name> for u64 {
+            fn from(id: $name) -> Self {
+                id.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Display::fmt(&self.0, f)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = std::num::ParseIntError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                s.parse().map(Self)
+            }
+        }
+    };
+}
+
+id_type!(
+    /// Id of a [`VeSubspace`].
+    SubspaceId
+);
+id_type!(
+    /// Id of a [`VeArticle`].
+    ArticleId
+);
+id_type!(
+    /// Id of a [`VeComment`].
+    CommentId
+);
+id_type!(
+    /// Id of a [`VeUser`], e.g. the author of an article or comment.
+    UserId
+);
+
+/// Where an entity ranks among its siblings, higher first, 0 by default.
+/// Encodes exactly like the bare `i16`, which admits negative weights as
+/// well; those are out of [`Weight::MIN`]..=[`Weight::MAX`] and no entity
+/// is meant to have one, see [`Weight::check`].
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Decode,
+    Encode,
+    Deserialize,
+    Serialize,
+)]
+#[serde(transparent)]
+pub struct Weight(pub i16);
+
+impl Weight {
+    pub const MIN: Self = Self(0);
+    pub const MAX: Self = Self(i16::MAX);
+
+    /// The weight if it's in range, for whatever takes it in from outside.
+    pub fn check(self) -> Result<Self, WeightOutOfRange> {
+        if (Self::MIN..=Self::MAX).contains(&self) {
+            Ok(self)
+        } else {
+            Err(WeightOutOfRange(self.0))
+        }
+    }
+}
+
+impl fmt::Display for Weight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// A weight below [`Weight::MIN`] or above [`Weight::MAX`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WeightOutOfRange(pub i16);
+
+impl fmt::Display for WeightOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "weight {} is out of range {}..={}",
+            self.0,
+            Weight::MIN,
+            Weight::MAX
+        )
+    }
+}
+
+impl std::error::Error for WeightOutOfRange {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Decode, Encode, Deserialize, Serialize)]
 pub enum Method {
     Create,
     Update,
@@ -10,7 +129,7 @@ pub enum Method {
 
 #[derive(Debug, Decode, Encode, Deserialize, Serialize)]
 pub struct VeUser {
-    pub id: u64,
+    pub id: UserId,
     pub title: String,
     pub slug: String,
     pub description: String,
@@ -59,6 +178,45 @@ pub struct VeEpisode {
     pub created_time: i64,
 }
 
+#[derive(Debug, Clone, Decode, Encode, Deserialize, Serialize)]
+pub struct VeSubspace {
+    pub id: SubspaceId,
+    pub title: String,
+    pub slug: String,
+    pub description: String,
+    pub banner: String,
+    pub status: i16,
+    pub weight: Weight,
+    pub created_time: i64,
+}
+
+#[derive(Debug, Clone, Decode, Encode, Deserialize, Serialize)]
+pub struct VeArticle {
+    pub id: ArticleId,
+    pub title: String,
+    pub content: String,
+    pub author_id: UserId,
+    pub author_nickname: String,
+    pub subspace_id: SubspaceId,
+    pub ext_link: String,
+    pub status: i16,
+    pub weight: Weight,
+    pub created_time: i64,
+    pub updated_time: i64,
+}
+
+#[derive(Debug, Clone, Decode, Encode, Deserialize, Serialize)]
+pub struct VeComment {
+    pub id: CommentId,
+    pub content: String,
+    pub author_id: UserId,
+    pub author_nickname: String,
+    pub post_id: ArticleId,
+    pub status: i16,
+    pub weight: Weight,
+    pub created_time: i64,
+}
+
 // const PREFIX_USER_KEY: &[u8; 5] = b"veus:";
 pub const PREFIX_SUBSPACE_KEY: &[u8; 5] = b"vesb:";
 pub const PREFIX_ARTICLE_KEY: &[u8; 5] = b"vear:";
@@ -67,10 +225,31 @@ pub const PREFIX_COMMENT_KEY: &[u8; 5] = b"veco:";
 pub const REQNUM_KEY: &[u8; 7] = b"_reqnum";
 pub const COMMON_KEY: &[u8; 7] = b"_common";
 
+/// The version of these types, to tell which a surrogate decodes entities as.
+/// Bumped with the crate version whenever one of them changes its encoding.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn it_works() {}
+
+    #[test]
+    fn ids_encode_like_bare_u64() {
+        assert_eq!(ArticleId(42).encode(), 42u64.encode());
+        assert_eq!(
+            SubspaceId::decode(&mut &7u64.encode()[..]).unwrap(),
+            SubspaceId(7)
+        );
+    }
+
+    #[test]
+    fn weights_encode_like_bare_i16_and_are_checked() {
+        assert_eq!(Weight(3).encode(), 3i16.encode());
+        assert_eq!(Weight::MAX.check(), Ok(Weight::MAX));
+        assert_eq!(Weight(0).check(), Ok(Weight(0)));
+        assert_eq!(Weight(-1).check(), Err(WeightOutOfRange(-1)));
+    }
 }